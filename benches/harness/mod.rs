@@ -0,0 +1,162 @@
+//! Synthetic state channel messages, packets and router clients for the
+//! benchmarks. Clients run against an in process mock router, with a gateway
+//! that is never connected to, so the state channels used are made known to
+//! the client up front instead of being looked up.
+use gateway_rs::{
+    router::{mock::MockRouter, RouterClient, RouterClientConfig},
+    service::gateway::GatewayService,
+    test_support::{mk_keyed_uri, mk_keypair, mk_state_channel},
+    CacheSettings, Packet, StateChannelMessage,
+};
+use helium_proto::{
+    routing_information::Data as RoutingData, BlockchainStateChannelBannerV1,
+    BlockchainStateChannelPurchaseV1, BlockchainStateChannelRejectionV1,
+    BlockchainStateChannelResponseV1, BlockchainStateChannelSummaryV1, BlockchainStateChannelV1,
+    RoutingInformation,
+};
+use slog::{o, Logger};
+use std::sync::Arc;
+use tokio::{runtime::Runtime, sync::mpsc};
+
+/// The DC amount of benchmarked state channels, large enough to never run out
+const SC_AMOUNT: u64 = u64::MAX / 2;
+
+pub fn mk_runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("benchmark runtime")
+}
+
+/// An unconfirmed data uplink from the given DevAddr, costing one DC
+pub fn mk_uplink(dev_addr: u32) -> Packet {
+    let mut payload = vec![0x40];
+    payload.extend_from_slice(&dev_addr.to_le_bytes());
+    payload.extend_from_slice(&[0, 1, 0, 1, 0xde, 0xad, 0xbe, 0xef]);
+    Packet::from(helium_proto::Packet {
+        frequency: 903.9,
+        datarate: "SF10BW125".to_string(),
+        snr: 5.5,
+        signal_strength: -80.0,
+        timestamp: 1_000_000,
+        payload,
+        routing: Some(RoutingInformation {
+            data: Some(RoutingData::Devaddr(dev_addr)),
+        }),
+        ..Default::default()
+    })
+}
+
+/// An unconfirmed data downlink to the given DevAddr
+pub fn mk_downlink(dev_addr: u32) -> helium_proto::Packet {
+    let mut payload = vec![0x60];
+    payload.extend_from_slice(&dev_addr.to_le_bytes());
+    payload.extend_from_slice(&[0, 1, 0, 0xde, 0xad, 0xbe, 0xef]);
+    helium_proto::Packet {
+        frequency: 923.3,
+        datarate: "SF12BW500".to_string(),
+        payload,
+        ..Default::default()
+    }
+}
+
+pub fn mk_banner(sc: BlockchainStateChannelV1) -> StateChannelMessage {
+    StateChannelMessage::from(BlockchainStateChannelBannerV1 { sc: Some(sc) })
+}
+
+pub fn mk_purchase(sc: BlockchainStateChannelV1) -> StateChannelMessage {
+    StateChannelMessage::from(BlockchainStateChannelPurchaseV1 {
+        sc: Some(sc),
+        ..Default::default()
+    })
+}
+
+pub fn mk_reject() -> StateChannelMessage {
+    StateChannelMessage::from(BlockchainStateChannelRejectionV1::default())
+}
+
+pub fn mk_response(downlink: helium_proto::Packet) -> StateChannelMessage {
+    StateChannelMessage::from(BlockchainStateChannelResponseV1 {
+        downlink: Some(downlink),
+        ..Default::default()
+    })
+}
+
+/// A router client against a mock router, with a known state channel that
+/// each purchase advances by one DC
+pub struct Bench {
+    pub client: RouterClient,
+    pub logger: Logger,
+    pubkeybin: Vec<u8>,
+    nonce: u64,
+    _router: MockRouter,
+    _downlinks: mpsc::Receiver<Packet>,
+}
+
+impl Bench {
+    /// Creates a client with its own store, named after the benchmark
+    pub async fn new(name: &str) -> Self {
+        let mut router = MockRouter::start(vec![]).await;
+        // Drain what the client sends so it does not pile up
+        let (_, drained) = mpsc::unbounded_channel();
+        let mut received = std::mem::replace(&mut router.received, drained);
+        tokio::spawn(async move { while received.recv().await.is_some() {} });
+
+        let mut cache_settings = CacheSettings::default();
+        cache_settings.store = std::env::temp_dir().join("gateway-rs-bench").join(name);
+        let _ = std::fs::remove_dir_all(&cache_settings.store);
+        // Room for the deepest benchmarked offer queue
+        cache_settings.max_packets = 1000;
+        let keypair = Arc::new(mk_keypair());
+        let pubkeybin = keypair.public_key().to_vec();
+        let (downlinks, downlinks_receiver) = mpsc::channel(10);
+        let gateway = GatewayService::new(mk_keyed_uri("http://127.0.0.1:1")).expect("gateway");
+        let client = RouterClient::new(
+            RouterClientConfig::default().with_cache_settings(cache_settings),
+            mk_keyed_uri(&router.uri),
+            gateway,
+            downlinks,
+            keypair.into(),
+        )
+        .await
+        .expect("router client");
+        let bench = Self {
+            client,
+            logger: Logger::root(slog::Discard, o!()),
+            pubkeybin,
+            nonce: 1,
+            _router: router,
+            _downlinks: downlinks_receiver,
+        };
+        let sc = mk_state_channel(&bench.state_channel(), 0, SC_AMOUNT);
+        bench
+            .client
+            .insert_active_state_channel(&sc)
+            .await
+            .expect("active state channel");
+        bench
+    }
+
+    /// The current state of the known state channel, with a summary for the
+    /// client of one DC per purchase
+    pub fn state_channel(&self) -> BlockchainStateChannelV1 {
+        BlockchainStateChannelV1 {
+            id: vec![1],
+            credits: SC_AMOUNT,
+            nonce: self.nonce,
+            summaries: vec![BlockchainStateChannelSummaryV1 {
+                client_pubkeybin: self.pubkeybin.clone(),
+                num_packets: self.nonce,
+                num_dcs: self.nonce,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Advances the known state channel by one packet and returns its
+    /// purchase
+    pub fn next_purchase(&mut self) -> StateChannelMessage {
+        self.nonce += 1;
+        mk_purchase(self.state_channel())
+    }
+}
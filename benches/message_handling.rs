@@ -0,0 +1,177 @@
+//! Benchmarks of the router client message handling hot path: handling each
+//! type of state channel message and offering queues of waiting packets.
+//!
+//! Run them with
+//!
+//!     cargo bench --features test-support --bench message_handling
+//!
+//! Absolute numbers depend on the machine, so rather than comparing against
+//! numbers written down here, record a baseline before a change and compare
+//! the change against it:
+//!
+//!     cargo bench --features test-support --bench message_handling -- --save-baseline before
+//!     cargo bench --features test-support --bench message_handling -- --baseline before
+//!
+//! Criterion then reports the change against the baseline for every
+//! benchmark in its output.
+mod harness;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use harness::Bench;
+use std::time::{Duration, Instant};
+
+/// Queue depths of waiting packets offered at once. These stay below the
+/// capacity of the state channel stream, which pauses offers when full.
+const OFFER_DEPTHS: [usize; 3] = [1, 10, 40];
+
+fn handle_state_channel_message(c: &mut Criterion) {
+    let runtime = harness::mk_runtime();
+    let mut group = c.benchmark_group("handle_state_channel_message");
+
+    let mut bench = runtime.block_on(Bench::new("purchase"));
+    group.bench_function("purchase", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut elapsed = Duration::from_secs(0);
+                for _ in 0..iters {
+                    bench
+                        .client
+                        .que_offered_packet(harness::mk_uplink(1))
+                        .await
+                        .expect("offered packet");
+                    let purchase = bench.next_purchase();
+                    let start = Instant::now();
+                    bench
+                        .client
+                        .handle_message(&bench.logger, purchase)
+                        .await
+                        .expect("purchase");
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        })
+    });
+
+    let mut bench = runtime.block_on(Bench::new("banner"));
+    group.bench_function("banner", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut elapsed = Duration::from_secs(0);
+                for _ in 0..iters {
+                    // An unchanged banner for the known state channel
+                    let banner = harness::mk_banner(bench.state_channel());
+                    let start = Instant::now();
+                    bench
+                        .client
+                        .handle_message(&bench.logger, banner)
+                        .await
+                        .expect("banner");
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        })
+    });
+
+    let mut bench = runtime.block_on(Bench::new("reject"));
+    group.bench_function("reject", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut elapsed = Duration::from_secs(0);
+                for _ in 0..iters {
+                    bench
+                        .client
+                        .que_offered_packet(harness::mk_uplink(1))
+                        .await
+                        .expect("offered packet");
+                    let start = Instant::now();
+                    bench
+                        .client
+                        .handle_message(&bench.logger, harness::mk_reject())
+                        .await
+                        .expect("reject");
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        })
+    });
+
+    let mut bench = runtime.block_on(Bench::new("response"));
+    group.bench_function("unsolicited_response", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut elapsed = Duration::from_secs(0);
+                for _ in 0..iters {
+                    // No uplink was sent for the device, so the downlink is
+                    // matched against the recent uplinks and dropped
+                    let response = harness::mk_response(harness::mk_downlink(2));
+                    let start = Instant::now();
+                    bench
+                        .client
+                        .handle_message(&bench.logger, response)
+                        .await
+                        .expect("response");
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        })
+    });
+    group.finish();
+}
+
+fn send_packet_offers(c: &mut Criterion) {
+    let runtime = harness::mk_runtime();
+    let mut group = c.benchmark_group("send_packet_offers");
+    for depth in OFFER_DEPTHS.iter() {
+        let mut bench = runtime.block_on(Bench::new(&format!("offers_{}", depth)));
+        group.bench_with_input(BenchmarkId::from_parameter(depth), depth, |b, depth| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut elapsed = Duration::from_secs(0);
+                    for _ in 0..iters {
+                        for dev_addr in 0..*depth {
+                            bench
+                                .client
+                                .store_waiting_packet(harness::mk_uplink(dev_addr as u32))
+                                .await
+                                .expect("waiting packet");
+                        }
+                        let start = Instant::now();
+                        bench
+                            .client
+                            .offer_waiting_packets(&bench.logger)
+                            .await
+                            .expect("offers");
+                        elapsed += start.elapsed();
+                        // Offer anything the full stream held back and
+                        // reject all offers, leaving an empty queue for the
+                        // next iteration
+                        while bench.client.packet_counts().await.0 > 0 {
+                            tokio::task::yield_now().await;
+                            bench
+                                .client
+                                .offer_waiting_packets(&bench.logger)
+                                .await
+                                .expect("offers");
+                        }
+                        for _ in 0..bench.client.packet_counts().await.1 {
+                            bench
+                                .client
+                                .handle_message(&bench.logger, harness::mk_reject())
+                                .await
+                                .expect("reject");
+                        }
+                    }
+                    elapsed
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, handle_state_channel_message, send_packet_offers);
+criterion_main!(benches);
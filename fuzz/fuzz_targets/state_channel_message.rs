@@ -0,0 +1,37 @@
+#![no_main]
+use gateway_rs::{PublicKey, StateChannel, StateChannelMessage};
+use helium_proto::Message;
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryFrom;
+
+// One of the default router keys, standing in for the gateway's own key
+const PUBLIC_KEY: &str = "11tk4zzbyfMPYYHYda255ACoqfYFVdrUSoCWrCYfn8BoyuYrERK";
+
+fuzz_target!(|data: &[u8]| {
+    let public_key: PublicKey = PUBLIC_KEY.parse().expect("public key");
+
+    // Stored state channels
+    if let Ok(sc) = StateChannel::try_from(data) {
+        let _ = sc.is_valid_purchase(&public_key, &sc, None, 0);
+        let _ = sc.is_overpaid(&sc);
+        let _ = sc.hash_key();
+    }
+
+    // State channel messages as received from a router
+    let message = match StateChannelMessage::decode(data) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+    let _ = message.downlink();
+    if let Some(sc) = message.state_channel() {
+        let mut buf = vec![0; 16];
+        sc.encode(&mut buf).expect("encoded state channel");
+        let known = StateChannel::try_from(&buf[..]).expect("decoded state channel");
+        let _ = known.is_banner_update(sc);
+        let _ = known.is_valid_purchase(&public_key, &known, None, 0);
+        let _ = known.total_dcs();
+        for summary in &sc.summaries {
+            let _ = known.is_valid_summary(summary);
+        }
+    }
+});
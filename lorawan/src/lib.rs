@@ -63,6 +63,9 @@ impl PHYPayload {
         let packet_type = mhdr.mtype();
         let mut data = vec![];
         reader.read_to_end(&mut data)?;
+        if data.len() < 4 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
         let mic = data.split_off(data.len() - 4);
         let mut payload = &data[..];
         let mut res = Self {
@@ -337,4 +340,12 @@ mod test {
         let payload = PHYPayload::read(Direction::Uplink, &mut data).unwrap();
         eprintln!("PAYLOAD {:?}", payload);
     }
+
+    #[test]
+    fn test_read_truncated() {
+        for data in [&[][..], &[0x40], &[0x40, 0xde, 0xad, 0xbe]].iter() {
+            let mut data = *data;
+            assert!(PHYPayload::read(Direction::Uplink, &mut data).is_err());
+        }
+    }
 }
@@ -0,0 +1,165 @@
+#[cfg(any(test, feature = "test-support"))]
+use std::time::Duration;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime},
+};
+
+/// A source of the current time. Time based logic takes its time from a
+/// clock so that it can be driven by a [`MockClock`] in tests.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current monotonic time
+    fn now(&self) -> Instant;
+    /// The current wall clock time
+    fn system_now(&self) -> SystemTime;
+}
+
+/// A shared clock
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The clock backed by the operating system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Returns the system clock as a shared clock
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A clock whose wall clock time never goes backwards. The monotonic time
+/// can not go back, but the wall clock of the host can, for example when NTP
+/// corrects it. A wall clock time before the latest one read is clamped to
+/// the latest one, so that times recorded in order stay in order, and the
+/// jump is counted.
+#[derive(Debug)]
+pub struct ClampedClock {
+    clock: SharedClock,
+    latest: Mutex<Option<SystemTime>>,
+    jumps: AtomicU64,
+}
+
+impl ClampedClock {
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            clock,
+            latest: Mutex::new(None),
+            jumps: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of wall clock times that were clamped since the
+    /// last call.
+    pub fn take_jumps(&self) -> u64 {
+        self.jumps.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl Clock for ClampedClock {
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        let now = self.clock.system_now();
+        let mut latest = self.latest.lock().expect("clamped clock lock");
+        match *latest {
+            Some(previous) if now < previous => {
+                self.jumps.fetch_add(1, Ordering::Relaxed);
+                previous
+            }
+            _ => {
+                *latest = Some(now);
+                now
+            }
+        }
+    }
+}
+
+/// A clock that only moves when advanced. Clones share the same time.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<Mutex<(Instant, SystemTime)>>);
+
+#[cfg(any(test, feature = "test-support"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new((Instant::now(), SystemTime::now()))))
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl MockClock {
+    pub fn advance(&self, duration: Duration) {
+        let mut times = self.0.lock().expect("mock clock lock");
+        times.0 += duration;
+        times.1 += duration;
+    }
+
+    /// Moves the wall clock back, as a correction of the host clock would.
+    /// The monotonic time never goes back and is left as is.
+    pub fn rewind(&self, duration: Duration) {
+        self.0.lock().expect("mock clock lock").1 -= duration;
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.0.lock().expect("mock clock lock").0
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.0.lock().expect("mock clock lock").1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_advance() {
+        let clock = MockClock::default();
+        let shared = clock.clone();
+        let (start, system_start) = (clock.now(), clock.system_now());
+        assert_eq!(start, shared.now());
+        shared.advance(Duration::from_secs(3));
+        assert_eq!(Duration::from_secs(3), clock.now() - start);
+        assert_eq!(
+            Duration::from_secs(3),
+            clock.system_now().duration_since(system_start).unwrap()
+        );
+    }
+
+    #[test]
+    fn clamps_backward_jumps() {
+        let clock = MockClock::default();
+        let clamped = ClampedClock::new(Arc::new(clock.clone()));
+        let start = clamped.system_now();
+        clock.rewind(Duration::from_secs(2));
+        assert_eq!(start, clamped.system_now());
+        // Until the clock catches up again
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(start, clamped.system_now());
+        assert_eq!(2, clamped.take_jumps());
+        assert_eq!(0, clamped.take_jumps());
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(start + Duration::from_secs(1), clamped.system_now());
+        assert_eq!(0, clamped.take_jumps());
+        assert_eq!(clock.now(), clamped.now());
+    }
+}
@@ -61,6 +61,8 @@ pub enum ServiceError {
     Rpc(#[from] tonic::Status),
     #[error("stream closed error")]
     Stream,
+    #[error("router {0} unreachable")]
+    Unreachable(String),
 }
 
 #[derive(Error, Debug)]
@@ -81,6 +83,14 @@ pub enum StateChannelError {
     Underpaid,
     #[error("state channel low balance too low")]
     LowBalance,
+    #[error("state channel purchase before banner")]
+    PurchaseBeforeBanner,
+    #[error("state channel expires too soon")]
+    Expiring,
+    #[error("state channel expired")]
+    Expired,
+    #[error("state channel nonce regressed")]
+    StaleNonce,
 }
 
 #[derive(Error, Debug)]
@@ -91,6 +101,8 @@ pub enum StateChannelSummaryError {
     PacketDCMismatch,
     #[error("invalid address")]
     InvalidAddress,
+    #[error("state channel summary for gateway dropped")]
+    Dropped,
 }
 
 macro_rules! from_err {
@@ -159,6 +171,22 @@ impl StateChannelError {
     pub fn low_balance() -> Error {
         Error::StateChannel(Self::LowBalance)
     }
+
+    pub fn purchase_before_banner() -> Error {
+        Error::StateChannel(Self::PurchaseBeforeBanner)
+    }
+
+    pub fn expiring() -> Error {
+        Error::StateChannel(Self::Expiring)
+    }
+
+    pub fn expired() -> Error {
+        Error::StateChannel(Self::Expired)
+    }
+
+    pub fn stale_nonce() -> Error {
+        Error::StateChannel(Self::StaleNonce)
+    }
 }
 
 impl Error {
@@ -167,4 +195,8 @@ impl Error {
     pub fn custom<T: ToString>(msg: T) -> Error {
         Error::Custom(msg.to_string())
     }
+
+    pub fn router_unreachable<T: ToString>(uri: T) -> Error {
+        Error::Service(ServiceError::Unreachable(uri.to_string()))
+    }
 }
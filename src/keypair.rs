@@ -2,11 +2,50 @@ use crate::*;
 use helium_crypto::{KeyTag, KeyType, Network};
 use rand::rngs::OsRng;
 use serde::{de, Deserialize, Deserializer};
-use std::{convert::TryFrom, fs, path, sync::Arc};
+use std::{collections::HashMap, convert::TryFrom, fs, path, sync::Arc};
 
 pub type Keypair = helium_crypto::Keypair;
 pub type PublicKey = helium_crypto::PublicKey;
 
+/// The keypairs to sign router messages with. An OUI can have a keypair of
+/// its own, for example to keep the accounting of the OUIs a gateway serves
+/// apart. OUIs without one use the default keypair.
+#[derive(Debug, Clone)]
+pub struct OuiKeypairs {
+    default: Arc<Keypair>,
+    ouis: HashMap<u32, Arc<Keypair>>,
+}
+
+impl OuiKeypairs {
+    pub fn new(default: Arc<Keypair>) -> Self {
+        Self {
+            default,
+            ouis: HashMap::new(),
+        }
+    }
+
+    pub fn with_oui(mut self, oui: u32, keypair: Arc<Keypair>) -> Self {
+        self.ouis.insert(oui, keypair);
+        self
+    }
+
+    /// Returns the keypair to sign messages for the given OUI with
+    pub fn for_oui(&self, oui: u32) -> Arc<Keypair> {
+        self.ouis.get(&oui).unwrap_or(&self.default).clone()
+    }
+
+    /// Returns the keypair of the gateway itself, the default keypair
+    pub fn gateway(&self) -> Arc<Keypair> {
+        self.default.clone()
+    }
+}
+
+impl From<Arc<Keypair>> for OuiKeypairs {
+    fn from(v: Arc<Keypair>) -> Self {
+        Self::new(v)
+    }
+}
+
 pub fn load_from_file(path: &str) -> error::Result<Keypair> {
     let data = fs::read(path)?;
     Ok(Keypair::try_from(&data[..])?)
@@ -46,3 +85,49 @@ where
         ))),
     }
 }
+
+/// Deserializes a list of base58 encoded public keys
+pub fn deserialize_public_keys<'de, D>(d: D) -> std::result::Result<Vec<PublicKey>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|key_string| {
+            key_string.parse::<PublicKey>().map_err(|err| {
+                de::Error::custom(format!("invalid public key: \"{}\": {:?}", key_string, err))
+            })
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct OuiKeypairFile {
+    oui: u32,
+    keypair: String,
+}
+
+/// Deserializes a list of OUIs with the location of their keypair file.
+/// Unlike the gateway keypair, a missing OUI keypair file is an error rather
+/// than generating a new one, since an OUI keypair is an identity that is
+/// expected to be set up ahead of time.
+pub fn deserialize_oui_keypairs<'de, D>(
+    d: D,
+) -> std::result::Result<HashMap<u32, Arc<Keypair>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<OuiKeypairFile>::deserialize(d)?
+        .into_iter()
+        .map(|entry| {
+            load_from_file(&entry.keypair)
+                .map(|keypair| (entry.oui, Arc::new(keypair)))
+                .map_err(|err| {
+                    de::Error::custom(format!(
+                        "unable to load key file \"{}\" for oui {}: {:?}",
+                        entry.keypair, entry.oui, err
+                    ))
+                })
+        })
+        .collect()
+}
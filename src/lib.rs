@@ -1,3 +1,4 @@
+pub mod clock;
 pub mod cmd;
 pub mod curl;
 pub mod error;
@@ -11,20 +12,25 @@ pub mod router;
 pub mod server;
 pub mod service;
 pub mod settings;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 pub mod updater;
 
 mod msg_sign;
 mod msg_verify;
 mod state_channel;
 
+#[cfg(any(test, feature = "test-support"))]
+pub use clock::MockClock;
+pub use clock::{Clock, SharedClock, SystemClock};
 pub use error::{Error, Result};
 pub use keyed_uri::KeyedUri;
-pub use keypair::{Keypair, PublicKey};
+pub use keypair::{Keypair, OuiKeypairs, PublicKey};
 pub use msg_sign::MsgSign;
 pub use msg_verify::MsgVerify;
-pub use packet::Packet;
-pub use region::Region;
-pub use settings::{CacheSettings, Settings};
+pub use packet::{CrcStatus, Packet, PacketId};
+pub use region::{Region, RegionProfile};
+pub use settings::{CacheSettings, ClientSettings, Settings};
 pub use state_channel::{StateChannel, StateChannelKey, StateChannelMessage};
 
 use futures::{Future as StdFuture, Stream as StdStream};
@@ -1,15 +1,51 @@
-use crate::{Error, Result};
+use crate::{settings::RegionFallback, Error, Region, Result};
 use helium_proto::{
     packet::PacketType, routing_information::Data as RoutingData, BlockchainStateChannelResponseV1,
     Eui, RoutingInformation,
 };
 use lorawan::PHYPayloadFrame;
-use semtech_udp::{pull_resp, push_data, CodingRate, DataRate, Modulation, StringOrNum};
+use semtech_udp::{
+    pull_resp,
+    push_data::{self, CRC},
+    CodingRate, DataRate, Modulation, StringOrNum,
+};
 use sha2::{Digest, Sha256};
-use std::{convert::TryFrom, fmt, ops::Deref, str::FromStr};
+use std::{convert::TryFrom, fmt, ops::Deref, str::FromStr, time::Duration};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet(helium_proto::Packet, CrcStatus);
+
+/// The outcome of the CRC check of an uplink as reported by the packet
+/// forwarder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrcStatus {
+    /// The CRC was checked and matched
+    Ok,
+    /// The CRC was checked and did not match, the payload is corrupt
+    Failed,
+    /// No CRC was checked, for example because the packet did not come from
+    /// a packet forwarder
+    Unknown,
+}
 
-#[derive(Debug, Clone)]
-pub struct Packet(helium_proto::Packet);
+/// A stable identifier for a packet, derived from a hash of its PHYPayload.
+/// Packets with identical payloads have the same id, regardless of their
+/// radio metadata, which allows correlating a packet across offers,
+/// purchases and downlinks.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PacketId(Vec<u8>);
+
+impl fmt::Display for PacketId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&base64::encode_config(&self.0, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+impl AsRef<[u8]> for PacketId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
 
 impl Deref for Packet {
     type Target = helium_proto::Packet;
@@ -54,21 +90,77 @@ impl TryFrom<push_data::RxPk> for Packet {
             rx2_window: None,
             oui: 0,
         };
-        Ok(Self(packet))
+        let crc = match push_data.get_crc_status() {
+            CRC::OK => CrcStatus::Ok,
+            CRC::Fail => CrcStatus::Failed,
+            CRC::Disabled => CrcStatus::Unknown,
+        };
+        Ok(Self(packet, crc))
     }
 }
 
 impl From<helium_proto::Packet> for Packet {
     fn from(v: helium_proto::Packet) -> Self {
-        Self(v)
+        Self(v, CrcStatus::Unknown)
     }
 }
 
 impl Packet {
+    /// Returns the outcome of the CRC check of the uplink
+    pub fn crc_status(&self) -> CrcStatus {
+        self.1
+    }
+
+    pub fn with_crc_status(mut self, crc: CrcStatus) -> Self {
+        self.1 = crc;
+        self
+    }
+
     pub fn routing(&self) -> &Option<RoutingInformation> {
         &self.0.routing
     }
 
+    /// Returns the DevAddr of the packet if it is routed by DevAddr
+    pub fn dev_addr(&self) -> Option<u32> {
+        match &self.0.routing {
+            Some(RoutingInformation {
+                data: Some(RoutingData::Devaddr(dev_addr)),
+            }) => Some(*dev_addr),
+            _ => None,
+        }
+    }
+
+    /// Returns whether this uplink is a join request
+    pub fn is_join_request(&self) -> bool {
+        matches!(
+            &self.0.routing,
+            Some(RoutingInformation {
+                data: Some(RoutingData::Eui(_)),
+            })
+        )
+    }
+
+    /// Returns the region of the packet as derived from its frequency, or
+    /// `None` if the frequency does not determine a single region.
+    pub fn region(&self) -> Option<Region> {
+        Region::from_frequency(self.0.frequency)
+    }
+
+    /// Returns the region of the packet, given the default region of the
+    /// gateway. A frequency shared by several regions is taken to be in the
+    /// default region if its band covers it. Otherwise the given fallback
+    /// policy applies when the region can not be determined from the packet
+    /// itself. A result of `None` means the packet should be dropped.
+    pub fn region_or(&self, fallback: RegionFallback, default: &Region) -> Result<Option<Region>> {
+        match (self.region(), fallback) {
+            (Some(region), _) => Ok(Some(region)),
+            (None, _) if default.covers(self.0.frequency) => Ok(Some(default.clone())),
+            (None, RegionFallback::Default) => Ok(Some(default.clone())),
+            (None, RegionFallback::Drop) => Ok(None),
+            (None, RegionFallback::Reject) => Err(Error::custom("undetermined packet region")),
+        }
+    }
+
     pub fn is_longfi(&self) -> bool {
         let mut decoded = [0xFE, 65];
         longfi::Datagram::decode(&self.0.payload, &mut decoded).is_ok()
@@ -142,13 +234,58 @@ impl Packet {
     }
 
     pub fn from_state_channel_response(response: BlockchainStateChannelResponseV1) -> Option<Self> {
-        response.downlink.map(Self)
+        response.downlink.map(Self::from)
+    }
+
+    /// Sets the transmit times the router left unset on this downlink
+    /// relative to the concentrator timestamp of the uplink it answers, using
+    /// the receive windows of the given region. Transmit times set by the
+    /// router are kept. The RX2 transmit time is only set if the downlink
+    /// carries an RX2 window.
+    ///
+    /// Concentrator timestamps are a 32 bit microsecond counter, so transmit
+    /// times wrap around with it.
+    pub fn with_transmit_time(mut self, uplink_timestamp: u64, region: &Region) -> Self {
+        let (rx1, rx2) = region.rx_windows(self.is_join_accept());
+        let after_uplink = |delay: Duration| {
+            (uplink_timestamp as u32).wrapping_add(delay.as_micros() as u32) as u64
+        };
+        if self.0.timestamp == 0 {
+            self.0.timestamp = after_uplink(rx1);
+        }
+        if let Some(rx2_window) = self.0.rx2_window.as_mut() {
+            if rx2_window.timestamp == 0 {
+                rx2_window.timestamp = after_uplink(rx2);
+            }
+        }
+        self
+    }
+
+    /// Returns the DevAddr a data downlink is addressed to, `None` for join
+    /// accepts and payloads that do not parse.
+    pub fn downlink_dev_addr(&self) -> Option<u32> {
+        match Self::parse_frame(lorawan::Direction::Downlink, self.payload()) {
+            Ok(PHYPayloadFrame::MACPayload(payload)) => Some(payload.dev_addr()),
+            _ => None,
+        }
+    }
+
+    /// Returns whether this downlink is a join accept
+    pub fn is_join_accept(&self) -> bool {
+        matches!(
+            Self::parse_frame(lorawan::Direction::Downlink, self.payload()),
+            Ok(PHYPayloadFrame::JoinAccept(_))
+        )
     }
 
     pub fn hash(&self) -> Vec<u8> {
         Sha256::digest(&self.0.payload).to_vec()
     }
 
+    pub fn id(&self) -> PacketId {
+        PacketId(self.hash())
+    }
+
     pub fn dc_payload(&self) -> u64 {
         const DC_PAYLOAD_SIZE: usize = 24;
         let payload_size = self.payload().len();
@@ -160,3 +297,155 @@ impl Packet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_packet(payload: &[u8], timestamp: u64) -> Packet {
+        Packet::from(helium_proto::Packet {
+            payload: payload.to_vec(),
+            timestamp,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn transmit_time() {
+        use helium_proto::Window;
+        // Unconfirmed data down and a (truncated) join accept
+        let data_down = [0x60, 4, 3, 2, 1, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef];
+        let mut join_accept = vec![0x20];
+        join_accept.extend_from_slice(&[0; 16]);
+        // US915 and EU868
+        for region in 0..=1 {
+            let region = Region::from_i32(region).unwrap();
+            let downlink = Packet::from(helium_proto::Packet {
+                payload: data_down.to_vec(),
+                rx2_window: Some(Window::default()),
+                ..Default::default()
+            })
+            .with_transmit_time(10_000_000, &region);
+            assert_eq!(11_000_000, downlink.timestamp);
+            assert_eq!(12_000_000, downlink.rx2_window.as_ref().unwrap().timestamp);
+
+            let downlink = mk_packet(&join_accept, 0).with_transmit_time(10_000_000, &region);
+            assert_eq!(15_000_000, downlink.timestamp);
+            assert!(downlink.rx2_window.is_none());
+
+            // The transmit times wrap around with the concentrator counter
+            let uplink_timestamp = (u32::MAX - 499_999) as u64;
+            let downlink = Packet::from(helium_proto::Packet {
+                payload: data_down.to_vec(),
+                rx2_window: Some(Window::default()),
+                ..Default::default()
+            })
+            .with_transmit_time(uplink_timestamp, &region);
+            assert_eq!(500_000, downlink.timestamp);
+            assert_eq!(1_500_000, downlink.rx2_window.as_ref().unwrap().timestamp);
+            let downlink = mk_packet(&join_accept, 0).with_transmit_time(uplink_timestamp, &region);
+            assert_eq!(4_500_000, downlink.timestamp);
+        }
+    }
+
+    #[test]
+    fn router_transmit_time() {
+        use helium_proto::Window;
+        let data_down = [0x60, 4, 3, 2, 1, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef];
+        let region = Region::from_i32(0).unwrap();
+        // Transmit times chosen by the router are kept
+        let downlink = Packet::from(helium_proto::Packet {
+            payload: data_down.to_vec(),
+            timestamp: 10_500_000,
+            rx2_window: Some(Window {
+                timestamp: 12_500_000,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .with_transmit_time(10_000_000, &region);
+        assert_eq!(10_500_000, downlink.timestamp);
+        assert_eq!(12_500_000, downlink.rx2_window.as_ref().unwrap().timestamp);
+        // Only the transmit time the router left unset is filled in
+        let downlink = Packet::from(helium_proto::Packet {
+            payload: data_down.to_vec(),
+            timestamp: 10_500_000,
+            rx2_window: Some(Window::default()),
+            ..Default::default()
+        })
+        .with_transmit_time(10_000_000, &region);
+        assert_eq!(10_500_000, downlink.timestamp);
+        assert_eq!(12_000_000, downlink.rx2_window.as_ref().unwrap().timestamp);
+    }
+
+    #[test]
+    fn crc_status() {
+        let packet = mk_packet(&[1], 0);
+        assert_eq!(CrcStatus::Unknown, packet.crc_status());
+        let packet = packet.with_crc_status(CrcStatus::Failed);
+        assert_eq!(CrcStatus::Failed, packet.clone().crc_status());
+    }
+
+    #[test]
+    fn packet_id() {
+        assert_eq!(mk_packet(&[1, 2, 3], 1).id(), mk_packet(&[1, 2, 3], 2).id());
+        assert_ne!(mk_packet(&[1, 2, 3], 1).id(), mk_packet(&[1, 2, 4], 1).id());
+    }
+
+    #[test]
+    fn region_fallback() {
+        let default = Region::from_i32(0).unwrap();
+        let eu868 = Region::from_i32(1).unwrap();
+        let known = Packet::from(helium_proto::Packet {
+            frequency: 868.1,
+            ..Default::default()
+        });
+        let unknown = Packet::from(helium_proto::Packet {
+            frequency: 923.2,
+            ..Default::default()
+        });
+        assert_eq!(
+            Some(eu868),
+            known.region_or(RegionFallback::Reject, &default).unwrap()
+        );
+        assert_eq!(
+            Some(default.clone()),
+            unknown
+                .region_or(RegionFallback::Default, &default)
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            unknown.region_or(RegionFallback::Drop, &default).unwrap()
+        );
+        assert!(unknown.region_or(RegionFallback::Reject, &default).is_err());
+
+        // A frequency shared by several regions is in the default region
+        // when its band covers it, under every fallback policy
+        let fallbacks = [
+            RegionFallback::Default,
+            RegionFallback::Drop,
+            RegionFallback::Reject,
+        ];
+        let cases = [(0, 923.2), (5, 916.8), (6, 923.2)];
+        for (region, frequency) in cases.iter() {
+            let default = Region::from_i32(*region).unwrap();
+            let shared = Packet::from(helium_proto::Packet {
+                frequency: *frequency,
+                ..Default::default()
+            });
+            for fallback in fallbacks.iter() {
+                assert_eq!(
+                    Some(default.clone()),
+                    shared.region_or(*fallback, &default).unwrap()
+                );
+            }
+        }
+        // but not when it is outside the band of the default region
+        let default = Region::from_i32(1).unwrap();
+        assert_eq!(
+            None,
+            unknown.region_or(RegionFallback::Drop, &default).unwrap()
+        );
+    }
+}
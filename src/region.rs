@@ -1,8 +1,35 @@
 use helium_proto::Region as ProtoRegion;
 use serde::{de, Deserialize, Deserializer};
-use std::fmt;
+use std::{fmt, time::Duration};
 
-#[derive(Debug, Clone)]
+/// The delay between the RX1 and RX2 receive windows, which is the same for
+/// all regions.
+pub const RX2_WINDOW_OFFSET: Duration = Duration::from_secs(1);
+
+/// The RX1 receive delay of a join accept, JOIN_ACCEPT_DELAY1 in the LoRaWAN
+/// regional parameters
+pub const JOIN_ACCEPT_DELAY: Duration = Duration::from_secs(5);
+
+/// The bytes of a PHYPayload around its MACPayload, the MHDR and the MIC
+const PHY_PAYLOAD_OVERHEAD: usize = 5;
+
+/// All supported regions
+const REGIONS: [ProtoRegion; 12] = [
+    ProtoRegion::Us915,
+    ProtoRegion::Eu868,
+    ProtoRegion::Eu433,
+    ProtoRegion::Cn470,
+    ProtoRegion::Cn779,
+    ProtoRegion::Au915,
+    ProtoRegion::As9231,
+    ProtoRegion::As9232,
+    ProtoRegion::As9233,
+    ProtoRegion::As9234,
+    ProtoRegion::Kr920,
+    ProtoRegion::In865,
+];
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Region(ProtoRegion);
 
 impl From<Region> for ProtoRegion {
@@ -57,6 +84,130 @@ impl fmt::Display for Region {
     }
 }
 
+impl Region {
+    /// Converts a protobuf encoded region value to a region, returning `None`
+    /// for unknown values.
+    pub fn from_i32(v: i32) -> Option<Self> {
+        ProtoRegion::from_i32(v).map(Self)
+    }
+
+    /// Derives the region from an uplink frequency in MHz. Only frequencies
+    /// that fall in exactly one supported region's band are mapped, since
+    /// several regions share parts of the 865 and 915 MHz bands. Returns
+    /// `None` if the region can not be determined.
+    pub fn from_frequency(frequency: f32) -> Option<Self> {
+        let mut covering = REGIONS
+            .iter()
+            .map(|region| Self(*region))
+            .filter(|region| region.covers(frequency));
+        match (covering.next(), covering.next()) {
+            (Some(region), None) => Some(region),
+            _ => None,
+        }
+    }
+
+    /// Returns the band of the region's frequency plan, the lowest and
+    /// highest frequency in MHz, from the LoRaWAN regional parameters.
+    pub fn band(&self) -> (f32, f32) {
+        match self.0 {
+            ProtoRegion::Us915 => (902.0, 928.0),
+            ProtoRegion::Eu868 => (863.0, 870.0),
+            ProtoRegion::Eu433 => (433.05, 434.79),
+            ProtoRegion::Cn470 => (470.0, 510.0),
+            ProtoRegion::Cn779 => (779.0, 787.0),
+            ProtoRegion::Au915 => (915.0, 928.0),
+            ProtoRegion::As9231 => (915.0, 928.0),
+            ProtoRegion::As9232 => (920.0, 923.0),
+            ProtoRegion::As9233 => (915.0, 921.0),
+            ProtoRegion::As9234 => (917.0, 920.0),
+            ProtoRegion::Kr920 => (920.9, 923.3),
+            ProtoRegion::In865 => (865.0, 867.0),
+        }
+    }
+
+    /// Returns whether the given frequency in MHz is in the band of the
+    /// region.
+    pub fn covers(&self, frequency: f32) -> bool {
+        let (low, high) = self.band();
+        (low..=high).contains(&frequency)
+    }
+
+    /// Returns the RX1 receive delay for the region. This is the default
+    /// RECEIVE_DELAY1 from the LoRaWAN regional parameters, which currently
+    /// is one second for all supported regions.
+    pub fn rx_delay(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    /// Returns the delays of the RX1 and RX2 receive windows after the end of
+    /// an uplink for the region. Join accepts use the join accept delays,
+    /// which are five and six seconds for all supported regions.
+    pub fn rx_windows(&self, join_accept: bool) -> (Duration, Duration) {
+        let rx1 = if join_accept {
+            JOIN_ACCEPT_DELAY
+        } else {
+            self.rx_delay()
+        };
+        (rx1, rx1 + RX2_WINDOW_OFFSET)
+    }
+
+    /// Returns the maximum PHYPayload size in bytes of an uplink at the given
+    /// datarate in this region, derived from the maximum MACPayload size M
+    /// in the LoRaWAN regional parameters. AS923 uses the limits without an
+    /// uplink dwell time. Returns `None` for datarates the region does not
+    /// define for uplinks, which are not limited.
+    pub fn max_payload(&self, datarate: &str) -> Option<usize> {
+        let max_mac_payload = match (self.0, datarate) {
+            (ProtoRegion::Us915, "SF10BW125") => 19,
+            (ProtoRegion::Us915, "SF9BW125") => 61,
+            (ProtoRegion::Us915, "SF8BW125") => 133,
+            (ProtoRegion::Us915, "SF7BW125") | (ProtoRegion::Us915, "SF8BW500") => 250,
+            (ProtoRegion::Us915, _) => return None,
+            (_, "SF12BW125") | (_, "SF11BW125") | (_, "SF10BW125") => 59,
+            (_, "SF9BW125") => 123,
+            (_, "SF8BW125") | (_, "SF7BW125") => 250,
+            (ProtoRegion::Eu868, "SF7BW250") | (ProtoRegion::Au915, "SF8BW500") => 250,
+            _ => return None,
+        };
+        Some(max_mac_payload + PHY_PAYLOAD_OVERHEAD)
+    }
+
+    /// Adjusts the given hold time of an uplink for reporting to a router
+    /// in this region. Once the RX2 window for an answer to the uplink has
+    /// closed no downlink can be delivered anymore so the reported hold time
+    /// is capped at the end of that window, which for join requests is the
+    /// second join accept window.
+    pub fn adjust_hold_time(&self, hold_time: Duration, join_request: bool) -> Duration {
+        let (_, rx2) = self.rx_windows(join_request);
+        hold_time.min(rx2)
+    }
+}
+
+/// Describes how packet offers are constructed for a region. Every offer
+/// carries the region it was received in; the profile selects which of the
+/// optional offer fields are populated so that offers can follow region
+/// specific requirements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionProfile {
+    pub region: Region,
+    /// Whether the frame counter of the packet is reported
+    pub fcnt: bool,
+    /// Whether the device routing information is reported
+    pub routing: bool,
+}
+
+impl Region {
+    /// Returns the offer profile for the region. All currently supported
+    /// regions report the full set of offer fields.
+    pub fn profile(&self) -> RegionProfile {
+        RegionProfile {
+            region: self.clone(),
+            fcnt: true,
+            routing: true,
+        }
+    }
+}
+
 impl From<Region> for i32 {
     fn from(region: Region) -> Self {
         region.0.into()
@@ -68,3 +219,111 @@ impl From<&Region> for i32 {
         region.0.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjust_hold_time() {
+        for region in [Region(ProtoRegion::Us915), Region(ProtoRegion::Eu868)].iter() {
+            // The RX2 window of a data uplink closes two seconds in
+            let held = Duration::from_millis(300);
+            assert_eq!(held, region.adjust_hold_time(held, false));
+            assert_eq!(
+                Duration::from_millis(1999),
+                region.adjust_hold_time(Duration::from_millis(1999), false)
+            );
+            assert_eq!(
+                Duration::from_secs(2),
+                region.adjust_hold_time(Duration::from_secs(5), false)
+            );
+            // Join requests are answered in the join accept windows, the
+            // second of which closes six seconds in
+            assert_eq!(
+                Duration::from_secs(5),
+                region.adjust_hold_time(Duration::from_secs(5), true)
+            );
+            assert_eq!(
+                Duration::from_secs(6),
+                region.adjust_hold_time(Duration::from_secs(9), true)
+            );
+        }
+    }
+
+    #[test]
+    fn max_payload() {
+        let us915 = Region(ProtoRegion::Us915);
+        let eu868 = Region(ProtoRegion::Eu868);
+        let au915 = Region(ProtoRegion::Au915);
+        assert_eq!(Some(24), us915.max_payload("SF10BW125"));
+        assert_eq!(Some(66), us915.max_payload("SF9BW125"));
+        assert_eq!(Some(138), us915.max_payload("SF8BW125"));
+        assert_eq!(Some(255), us915.max_payload("SF8BW500"));
+        // Not an uplink datarate in US915
+        assert_eq!(None, us915.max_payload("SF12BW125"));
+        assert_eq!(Some(64), eu868.max_payload("SF12BW125"));
+        assert_eq!(Some(64), eu868.max_payload("SF10BW125"));
+        assert_eq!(Some(128), eu868.max_payload("SF9BW125"));
+        assert_eq!(Some(255), eu868.max_payload("SF7BW250"));
+        assert_eq!(None, eu868.max_payload("SF8BW500"));
+        assert_eq!(Some(255), au915.max_payload("SF8BW500"));
+        assert_eq!(None, au915.max_payload(""));
+    }
+
+    #[test]
+    fn from_frequency() {
+        assert_eq!(
+            Some(Region(ProtoRegion::Us915)),
+            Region::from_frequency(903.9)
+        );
+        assert_eq!(
+            Some(Region(ProtoRegion::Eu868)),
+            Region::from_frequency(868.1)
+        );
+        assert_eq!(
+            Some(Region(ProtoRegion::Eu433)),
+            Region::from_frequency(433.175)
+        );
+        assert_eq!(
+            Some(Region(ProtoRegion::Cn470)),
+            Region::from_frequency(470.3)
+        );
+        assert_eq!(
+            Some(Region(ProtoRegion::Cn779)),
+            Region::from_frequency(779.5)
+        );
+        // Shared by the US915, AU915, AS923 and KR920 bands
+        assert_eq!(None, Region::from_frequency(923.2));
+        // Shared by the EU868 and IN865 bands
+        assert_eq!(None, Region::from_frequency(865.4));
+        assert_eq!(None, Region::from_frequency(0.0));
+    }
+
+    #[test]
+    fn covers() {
+        // A frequency from the default channels of each plan
+        let cases = [
+            (ProtoRegion::Us915, 903.9),
+            (ProtoRegion::Eu868, 868.1),
+            (ProtoRegion::Eu433, 433.175),
+            (ProtoRegion::Cn470, 470.3),
+            (ProtoRegion::Cn779, 779.5),
+            (ProtoRegion::Au915, 916.8),
+            (ProtoRegion::As9231, 923.2),
+            (ProtoRegion::As9232, 921.4),
+            (ProtoRegion::As9233, 916.6),
+            (ProtoRegion::As9234, 917.3),
+            (ProtoRegion::Kr920, 922.1),
+            (ProtoRegion::In865, 865.0625),
+        ];
+        assert_eq!(REGIONS.len(), cases.len());
+        for (region, frequency) in cases.iter() {
+            let region = Region(*region);
+            assert!(region.covers(*frequency), "{} {}", region, frequency);
+            assert!(!region.covers(0.0));
+        }
+        assert!(!Region(ProtoRegion::Us915).covers(868.1));
+        assert!(!Region(ProtoRegion::In865).covers(868.1));
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{PublicKey, StateChannel, StateChannelKey};
+use std::collections::HashMap;
+
+/// Reconciles the packets sent to a router with the packet counts the router
+/// reports for this gateway in its state channel summaries.
+///
+/// The summary count of a state channel when it is first seen is taken as
+/// the baseline, after which each packet sent against the state channel is
+/// expected to be reflected in the summaries that follow. A difference of
+/// more than the threshold is reported as drift once, after which the
+/// reported count becomes the new baseline.
+#[derive(Debug)]
+pub struct PacketAccounting {
+    threshold: u64,
+    channels: HashMap<Vec<u8>, ChannelAccount>,
+}
+
+#[derive(Debug)]
+struct ChannelAccount {
+    base: u64,
+    sent: u64,
+}
+
+/// A difference between the packets sent against a state channel and the
+/// packets the router reports for it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketDrift {
+    pub sc_id: String,
+    /// The number of packets the summary was expected to report
+    pub expected: u64,
+    /// The number of packets the summary reports
+    pub reported: u64,
+}
+
+impl PacketAccounting {
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Reconciles the summary crediting the given key in the given state
+    /// channel, after `sent` packets were sent that it is expected to
+    /// include. Returns the drift if it exceeds the threshold.
+    pub fn reconcile(
+        &mut self,
+        sc: &StateChannel,
+        public_key: &PublicKey,
+        sent: u64,
+    ) -> Option<PacketDrift> {
+        let reported = sc
+            .get_summary(public_key)
+            .map_or(0, |summary| summary.num_packets);
+        let account = self
+            .channels
+            .entry(sc.id().to_vec())
+            .or_insert_with(|| ChannelAccount {
+                base: reported.saturating_sub(sent),
+                sent: 0,
+            });
+        account.sent += sent;
+        let expected = account.base + account.sent;
+        let drift = if reported > expected {
+            reported - expected
+        } else {
+            expected - reported
+        };
+        if drift <= self.threshold {
+            return None;
+        }
+        account.base = reported.saturating_sub(account.sent);
+        Some(PacketDrift {
+            sc_id: sc.id_key(),
+            expected,
+            reported,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{mk_public_key, mk_state_channel};
+    use helium_proto::{BlockchainStateChannelSummaryV1, BlockchainStateChannelV1};
+
+    fn mk_sc(public_key: &PublicKey, num_packets: u64) -> StateChannel {
+        let sc = BlockchainStateChannelV1 {
+            id: vec![1],
+            credits: 100,
+            summaries: vec![BlockchainStateChannelSummaryV1 {
+                client_pubkeybin: public_key.to_vec(),
+                num_packets,
+                num_dcs: num_packets,
+            }],
+            ..Default::default()
+        };
+        mk_state_channel(&sc, 1000, 100)
+    }
+
+    #[test]
+    fn matched_summaries() {
+        let public_key = mk_public_key();
+        let mut accounting = PacketAccounting::new(0);
+        // The banner sets the baseline, each purchase adds a sent packet
+        assert!(accounting
+            .reconcile(&mk_sc(&public_key, 5), &public_key, 0)
+            .is_none());
+        for num_packets in 6..=8 {
+            assert!(accounting
+                .reconcile(&mk_sc(&public_key, num_packets), &public_key, 1)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn drifting_summaries() {
+        let public_key = mk_public_key();
+        let mut accounting = PacketAccounting::new(1);
+        assert!(accounting
+            .reconcile(&mk_sc(&public_key, 5), &public_key, 0)
+            .is_none());
+        // Within the threshold
+        assert!(accounting
+            .reconcile(&mk_sc(&public_key, 7), &public_key, 1)
+            .is_none());
+        let drift = accounting
+            .reconcile(&mk_sc(&public_key, 10), &public_key, 1)
+            .expect("drift");
+        assert_eq!((7, 10), (drift.expected, drift.reported));
+        // The drift is reported once
+        assert!(accounting
+            .reconcile(&mk_sc(&public_key, 11), &public_key, 1)
+            .is_none());
+    }
+}
@@ -0,0 +1,284 @@
+use crate::StateChannelMessage;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The banners of the state channel stream of a router client: whether one
+/// was ever received, the timeout for the first banner after a connect, the
+/// debounce of bursts of banners before offering, and the banners held
+/// within the reorder window so that banners and purchases for a state
+/// channel are applied in nonce order. A zero duration disables the
+/// respective timeout, debounce or reordering.
+#[derive(Debug)]
+pub struct BannerState {
+    timeout: Duration,
+    debounce: Duration,
+    debounce_max: Duration,
+    reorder_window: Duration,
+    received: bool,
+    timeout_at: Option<Instant>,
+    debounce_start: Option<Instant>,
+    debounce_at: Option<Instant>,
+    held: Vec<StateChannelMessage>,
+    release_at: Option<Instant>,
+}
+
+impl BannerState {
+    pub fn new(
+        timeout: Duration,
+        debounce: Duration,
+        debounce_max: Duration,
+        reorder_window: Duration,
+    ) -> Self {
+        Self {
+            timeout,
+            debounce,
+            debounce_max,
+            reorder_window,
+            received: false,
+            timeout_at: None,
+            debounce_start: None,
+            debounce_at: None,
+            held: vec![],
+            release_at: None,
+        }
+    }
+
+    /// Returns whether a banner was ever received. Stays set across
+    /// reconnects.
+    pub fn received(&self) -> bool {
+        self.received
+    }
+
+    /// Records a received banner, which ends the banner timeout.
+    pub fn record_banner(&mut self) {
+        self.received = true;
+        self.timeout_at = None;
+    }
+
+    /// Returns when the connection gives up on seeing a banner, if it is
+    /// waiting for one.
+    pub fn timeout_at(&self) -> Option<Instant> {
+        self.timeout_at
+    }
+
+    /// Starts the banner timeout at the given time unless it is already
+    /// running.
+    pub fn start_timeout(&mut self, now: Instant) {
+        if self.timeout_at.is_none() && self.timeout > Duration::from_secs(0) {
+            self.timeout_at = Some(now + self.timeout);
+        }
+    }
+
+    pub fn end_timeout(&mut self) {
+        self.timeout_at = None;
+    }
+
+    /// Returns when the waiting packets are offered after a burst of
+    /// banners, if a burst is being debounced.
+    pub fn debounce_at(&self) -> Option<Instant> {
+        self.debounce_at
+    }
+
+    /// Debounces a banner received at the given time, offering once no new
+    /// banner arrived within the debounce, or once the burst has lasted the
+    /// maximum debounce. Returns false if banners are not debounced, in
+    /// which case offers are made right away.
+    pub fn debounce(&mut self, now: Instant) -> bool {
+        if self.debounce == Duration::from_secs(0) {
+            return false;
+        }
+        let start = *self.debounce_start.get_or_insert(now);
+        let mut deadline = now + self.debounce;
+        if self.debounce_max > Duration::from_secs(0) {
+            deadline = deadline.min(start + self.debounce_max);
+        }
+        self.debounce_at = Some(deadline);
+        true
+    }
+
+    pub fn end_debounce(&mut self) {
+        self.debounce_at = None;
+        self.debounce_start = None;
+    }
+
+    pub fn is_reordering(&self) -> bool {
+        self.reorder_window > Duration::from_secs(0)
+    }
+
+    /// Returns when the held banners are released, if any are held.
+    pub fn release_at(&self) -> Option<Instant> {
+        self.release_at
+    }
+
+    pub fn held(&self) -> &[StateChannelMessage] {
+        &self.held
+    }
+
+    /// Holds the given banner, received at the given time, until the
+    /// reorder window passes. Only the latest banner for a state channel is
+    /// held, a banner for an earlier nonce than a held one is dropped.
+    pub fn hold(&mut self, banner: StateChannelMessage, now: Instant) {
+        if let Some((id, nonce)) = banner.state_channel().map(|sc| (sc.id.clone(), sc.nonce)) {
+            let superseded = self
+                .held
+                .iter()
+                .filter_map(StateChannelMessage::state_channel)
+                .any(|held_sc| held_sc.id == id && held_sc.nonce >= nonce);
+            if !superseded {
+                self.held
+                    .retain(|held| held.state_channel().map_or(true, |sc| sc.id != id));
+                self.held.push(banner);
+            }
+        }
+        if self.release_at.is_none() {
+            self.release_at = Some(now + self.reorder_window);
+        }
+    }
+
+    /// Takes the held banners of the given state channel for nonces before
+    /// the given one, which are applied ahead of a purchase for that nonce.
+    /// Later banners stay held.
+    pub fn take_earlier(&mut self, id: &[u8], nonce: u64) -> Vec<StateChannelMessage> {
+        let (earlier, held): (Vec<_>, Vec<_>) = std::mem::take(&mut self.held)
+            .into_iter()
+            .partition(|held| {
+                held.state_channel()
+                    .map_or(false, |sc| sc.id == id && sc.nonce < nonce)
+            });
+        self.held = held;
+        earlier
+    }
+
+    /// Takes all held banners in nonce order once the reorder window passes.
+    pub fn release(&mut self) -> Vec<StateChannelMessage> {
+        self.release_at = None;
+        let mut banners = std::mem::take(&mut self.held);
+        banners.sort_by_key(|banner| banner.state_channel().map(|sc| sc.nonce));
+        banners
+    }
+
+    /// Drops the held banners, which belong to a lost stream.
+    pub fn clear_held(&mut self) {
+        self.held.clear();
+        self.release_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_proto::{BlockchainStateChannelBannerV1, BlockchainStateChannelV1};
+
+    fn mk_banner(id: u8, nonce: u64) -> StateChannelMessage {
+        StateChannelMessage::from(BlockchainStateChannelBannerV1 {
+            sc: Some(BlockchainStateChannelV1 {
+                id: vec![id],
+                nonce,
+                ..Default::default()
+            }),
+        })
+    }
+
+    fn mk_state(timeout: u64, debounce: u64, debounce_max: u64) -> BannerState {
+        BannerState::new(
+            Duration::from_secs(timeout),
+            Duration::from_millis(debounce),
+            Duration::from_millis(debounce_max),
+            Duration::from_millis(100),
+        )
+    }
+
+    fn nonces(banners: &[StateChannelMessage]) -> Vec<(u8, u64)> {
+        banners
+            .iter()
+            .filter_map(StateChannelMessage::state_channel)
+            .map(|sc| (sc.id[0], sc.nonce))
+            .collect()
+    }
+
+    #[test]
+    fn timeout() {
+        let now = Instant::now();
+        let mut disabled = mk_state(0, 0, 0);
+        disabled.start_timeout(now);
+        assert!(disabled.timeout_at().is_none());
+
+        let mut state = mk_state(10, 0, 0);
+        state.start_timeout(now);
+        let deadline = now + Duration::from_secs(10);
+        assert_eq!(Some(deadline), state.timeout_at());
+        // A running timeout is not extended
+        state.start_timeout(now + Duration::from_secs(5));
+        assert_eq!(Some(deadline), state.timeout_at());
+        assert!(!state.received());
+        state.record_banner();
+        assert!(state.received());
+        assert!(state.timeout_at().is_none());
+        // Timing out does not forget the banner
+        state.start_timeout(now);
+        state.end_timeout();
+        assert!(state.timeout_at().is_none());
+        assert!(state.received());
+    }
+
+    #[test]
+    fn debounce() {
+        let now = Instant::now();
+        assert!(!mk_state(0, 0, 0).debounce(now));
+
+        let mut state = mk_state(0, 500, 800);
+        assert!(state.debounce(now));
+        assert_eq!(Some(now + Duration::from_millis(500)), state.debounce_at());
+        // Every banner pushes the deadline out, up to the maximum debounce
+        assert!(state.debounce(now + Duration::from_millis(200)));
+        assert_eq!(Some(now + Duration::from_millis(700)), state.debounce_at());
+        assert!(state.debounce(now + Duration::from_millis(400)));
+        assert_eq!(Some(now + Duration::from_millis(800)), state.debounce_at());
+        // A new burst starts over
+        state.end_debounce();
+        assert!(state.debounce_at().is_none());
+        let later = now + Duration::from_secs(2);
+        assert!(state.debounce(later));
+        assert_eq!(
+            Some(later + Duration::from_millis(500)),
+            state.debounce_at()
+        );
+
+        // Without a maximum the debounce lasts as long as the burst
+        let mut state = mk_state(0, 500, 0);
+        for step in 0..5 {
+            assert!(state.debounce(now + Duration::from_millis(step * 400)));
+        }
+        assert_eq!(Some(now + Duration::from_millis(2100)), state.debounce_at());
+    }
+
+    #[test]
+    fn reorder() {
+        let now = Instant::now();
+        let mut state = mk_state(0, 0, 0);
+        assert!(state.is_reordering());
+        state.hold(mk_banner(1, 3), now);
+        let release_at = now + Duration::from_millis(100);
+        assert_eq!(Some(release_at), state.release_at());
+        // Only the latest banner of a state channel is held
+        state.hold(mk_banner(1, 2), now + Duration::from_millis(10));
+        state.hold(mk_banner(2, 5), now + Duration::from_millis(20));
+        state.hold(mk_banner(2, 6), now + Duration::from_millis(30));
+        state.hold(mk_banner(3, 1), now + Duration::from_millis(40));
+        assert_eq!(Some(release_at), state.release_at());
+        assert_eq!(vec![(1, 3), (2, 6), (3, 1)], nonces(state.held()));
+        // A purchase takes the earlier banners of its state channel only
+        assert!(state.take_earlier(&[1], 3).is_empty());
+        assert_eq!(vec![(1, 3)], nonces(&state.take_earlier(&[1], 4)));
+        assert_eq!(vec![(2, 6), (3, 1)], nonces(state.held()));
+        // Released in nonce order
+        assert_eq!(vec![(3, 1), (2, 6)], nonces(&state.release()));
+        assert!(state.held().is_empty());
+        assert!(state.release_at().is_none());
+
+        state.hold(mk_banner(1, 1), now);
+        state.clear_held();
+        assert!(state.held().is_empty());
+        assert!(state.release_at().is_none());
+    }
+}
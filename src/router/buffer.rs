@@ -0,0 +1,119 @@
+use helium_proto::{blockchain_state_channel_message_v1::Msg, BlockchainStateChannelMessageV1};
+use std::collections::VecDeque;
+
+/// A bounded buffer for state channel messages received from a router but
+/// not yet handled.
+///
+/// When the buffer is full the oldest banner is dropped to make room, since
+/// a later banner supersedes it. If there is no buffered banner an incoming
+/// banner is dropped instead. Purchases and all other messages are never
+/// dropped, even if that grows the buffer past its capacity, since losing
+/// them would lose packets that were already paid for.
+#[derive(Debug)]
+pub struct MessageBuffer {
+    capacity: usize,
+    messages: VecDeque<BlockchainStateChannelMessageV1>,
+    dropped: u64,
+}
+
+impl MessageBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: VecDeque::with_capacity(capacity),
+            dropped: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// The number of messages dropped since the buffer was created.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn push(&mut self, message: BlockchainStateChannelMessageV1) {
+        if self.messages.len() >= self.capacity.max(1) {
+            match self.messages.iter().position(|message| is_banner(message)) {
+                Some(index) => {
+                    self.messages.remove(index);
+                    self.dropped += 1;
+                }
+                None if is_banner(&message) => {
+                    self.dropped += 1;
+                    return;
+                }
+                None => (),
+            }
+        }
+        self.messages.push_back(message);
+    }
+
+    pub fn pop(&mut self) -> Option<BlockchainStateChannelMessageV1> {
+        self.messages.pop_front()
+    }
+}
+
+fn is_banner(message: &BlockchainStateChannelMessageV1) -> bool {
+    matches!(message.msg, Some(Msg::Banner(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_proto::{BlockchainStateChannelBannerV1, BlockchainStateChannelPurchaseV1};
+
+    fn mk_banner() -> BlockchainStateChannelMessageV1 {
+        BlockchainStateChannelMessageV1 {
+            msg: Some(Msg::Banner(BlockchainStateChannelBannerV1::default())),
+        }
+    }
+
+    fn mk_purchase() -> BlockchainStateChannelMessageV1 {
+        BlockchainStateChannelMessageV1 {
+            msg: Some(Msg::Purchase(BlockchainStateChannelPurchaseV1::default())),
+        }
+    }
+
+    fn drain(buffer: &mut MessageBuffer) -> Vec<bool> {
+        let mut banners = vec![];
+        while let Some(message) = buffer.pop() {
+            banners.push(is_banner(&message));
+        }
+        banners
+    }
+
+    #[test]
+    fn flood_drops_oldest_banner() {
+        let mut buffer = MessageBuffer::new(3);
+        buffer.push(mk_banner());
+        buffer.push(mk_purchase());
+        buffer.push(mk_banner());
+        buffer.push(mk_purchase());
+        buffer.push(mk_purchase());
+        assert_eq!(2, buffer.dropped());
+        assert_eq!(vec![false, false, false], drain(&mut buffer));
+    }
+
+    #[test]
+    fn flood_never_drops_purchases() {
+        let mut buffer = MessageBuffer::new(2);
+        for _ in 0..5 {
+            buffer.push(mk_purchase());
+        }
+        buffer.push(mk_banner());
+        assert_eq!(1, buffer.dropped());
+        assert_eq!(5, buffer.len());
+        assert_eq!(vec![false; 5], drain(&mut buffer));
+    }
+}
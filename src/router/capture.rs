@@ -0,0 +1,67 @@
+use crate::Packet;
+use std::collections::VecDeque;
+
+/// A bounded buffer of forwarded downlinks, kept in the order they were
+/// forwarded. A capacity of zero disables capturing.
+#[derive(Debug)]
+pub struct DownlinkCapture {
+    capacity: usize,
+    packets: VecDeque<helium_proto::Packet>,
+}
+
+impl DownlinkCapture {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            packets: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn record(&mut self, packet: &Packet) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.packets.push_back(packet.clone().to_packet());
+        if self.packets.len() > self.capacity {
+            self.packets.pop_front();
+        }
+    }
+
+    /// Returns the captured downlinks, oldest first.
+    pub fn export(&self) -> Vec<helium_proto::Packet> {
+        self.packets.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_packet(timestamp: u64) -> Packet {
+        Packet::from(helium_proto::Packet {
+            timestamp,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn capture_prunes_past_capacity() {
+        let mut capture = DownlinkCapture::new(2);
+        for timestamp in 1..=3 {
+            capture.record(&mk_packet(timestamp));
+        }
+        let timestamps: Vec<u64> = capture.export().iter().map(|p| p.timestamp).collect();
+        assert_eq!(vec![2, 3], timestamps);
+    }
+
+    #[test]
+    fn capture_disabled() {
+        let mut capture = DownlinkCapture::new(0);
+        capture.record(&mk_packet(1));
+        assert!(capture.export().is_empty());
+    }
+}
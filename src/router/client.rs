@@ -1,170 +1,1979 @@
 use crate::{
+    clock::{self, ClampedClock, Clock},
     error::{Error, StateChannelError},
-    router::{Dispatch, QuePacket, RouterStore},
+    router::{
+        downlink, event::EVENT_CAPACITY, recent::RECENT_UPLINK_WINDOW, BackgroundWrite,
+        BackgroundWriter, BannerState, ClientEvent, ClientMetrics, DecisionTrace, DevAddrCounts,
+        DevAddrMetrics, DevAddrRules, Dispatch, DispatchedDownlink, DownlinkCapture, DownlinkDedup,
+        DownlinkDelivery, DownlinkDispatcher, DropReason, EconomyMode, EconomyState, GatewayHealth,
+        GatewayLookups, LimitedOffers, MessageBuffer, MessageTap, MetricsSnapshot, OfferLimiter,
+        OfferThrottle, OwnerResolver, PacketAccounting, QuePacket, RecentJoins, RecentUplinks,
+        ReconnectPriority, Redispatch, RouterStore, ScLifecycle, SentPackets, SessionSummary,
+        SignedExport, StateChannelSelector, StatsdSink, TapMessage, TraceCheck, TraceEntry,
+        TraceStep, TraceTarget, UplinkSampling, ValidationGovernor,
+    },
     service::gateway::GatewayService,
     service::router::{Service as RouterService, StateChannelService},
-    CacheSettings, KeyedUri, Keypair, Packet, Region, Result, StateChannel, StateChannelKey,
-    StateChannelMessage,
+    settings::{
+        EarlyPurchasePolicy, ExpiredPurchasePolicy, RegionChangePolicy, RejectAction,
+        RouterProtocol, StreamClosePolicy,
+    },
+    CacheSettings, ClientSettings, CrcStatus, KeyedUri, Keypair, OuiKeypairs, Packet, PacketId,
+    PublicKey, Region, Result, SharedClock, StateChannel, StateChannelKey, StateChannelMessage,
 };
-use helium_proto::{blockchain_state_channel_message_v1::Msg, BlockchainStateChannelV1};
-use slog::{info, o, warn, Logger};
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use futures::FutureExt;
+use helium_crypto::{Sign, Verify};
+use helium_proto::{
+    blockchain_state_channel_message_v1::Msg, BlockchainStateChannelMessageV1,
+    BlockchainStateChannelV1,
+};
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::{JoinError, JoinHandle},
+    time,
+};
+
+/// The longest wait before reconnecting a closed or failed state channel
+/// stream
+pub const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The number of rejects, without any purchase identifying a packet in
+/// between, after which a router on auto detection is taken to be on the
+/// original protocol
+pub const PROTOCOL_DETECT_REJECTS: u32 = 3;
+
+/// Why a router client stopped running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitReason {
+    /// The gateway is shutting down
+    Shutdown,
+    /// Downlinks can no longer be delivered
+    DownlinksClosed,
+    /// The router closed the state channel stream
+    StreamClosed,
+    /// The state channel stream failed
+    StreamError,
+}
+
+/// A problem with the configuration of a router client, as reported by
+/// `RouterClient::validate_config`
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ConfigProblem {
+    /// The router uri lacks an http or https scheme or a host to connect to
+    #[error("invalid router uri {0}")]
+    InvalidUri(String),
+    /// Signatures made with the keypair do not verify against its public key
+    #[error("unusable keypair")]
+    UnusableKeypair,
+    /// A store setting is out of its usable range
+    #[error("invalid store setting {0}")]
+    InvalidStoreSetting(&'static str),
+}
+
+/// The outcome of running a router client, including the packets that were
+/// left undelivered in its store
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunExit {
+    pub reason: ExitReason,
+    /// Packets that were never offered
+    pub waiting: usize,
+    /// Offered packets that were not purchased
+    pub queued: usize,
+    /// The activity of the run
+    pub summary: SessionSummary,
+}
+
+/// The known state channels along with their lookups by a gateway, and the
+/// highest block height the gateway reported while looking them up
+struct Revalidation {
+    height: u64,
+    lookups: Vec<(StateChannel, Result<StateChannel>)>,
+}
 
 pub struct RouterClient {
     client: RouterService,
     oui: u32,
     region: Region,
     keypair: Arc<Keypair>,
+    gateway_keypair: Arc<Keypair>,
     downlinks: mpsc::Sender<Packet>,
+    downlinks_closed: bool,
+    downlink_dispatcher: Option<DownlinkDispatcher>,
     gateway: GatewayService,
     store: RouterStore,
+    writer: BackgroundWriter,
     state_channel: StateChannelService,
+    sc_messages: MessageBuffer,
+    recent_uplinks: RecentUplinks,
+    recent_joins: RecentJoins,
+    offer_throttle: OfferThrottle,
+    sampling: UplinkSampling,
+    protocol: RouterProtocol,
+    /// Rejects since the start or the last purchase while the router
+    /// protocol is being detected
+    protocol_rejects: u32,
+    sent_packets: SentPackets,
+    downlink_capture: DownlinkCapture,
+    downlink_dedup: DownlinkDedup,
+    message_tap: MessageTap,
+    decision_trace: DecisionTrace,
+    devaddr_metrics: DevAddrMetrics,
+    devaddr_rules: DevAddrRules,
+    metrics: ClientMetrics,
+    economy: EconomyState,
+    /// Whether the last offers stopped at the in flight DC cap or the
+    /// balance of the selected state channel with packets left waiting
+    offers_paused: bool,
+    gateway_lookups: GatewayLookups,
+    validations: ValidationGovernor,
+    limited_offers: LimitedOffers,
+    reconnect_priority: ReconnectPriority,
+    redispatch: Option<mpsc::Sender<Redispatch>>,
+    gateway_health: GatewayHealth,
+    gateway_retry: Option<time::Instant>,
+    /// The state channel lookups of a running revalidation along with its
+    /// cause
+    revalidation: Option<(JoinHandle<Revalidation>, &'static str)>,
+    lookup_failed: bool,
+    owners: OwnerResolver,
+    accounting: PacketAccounting,
+    sc_selector: StateChannelSelector,
+    /// The last selected state channel, along with the store generation,
+    /// block height and selector generation it was selected at
+    sc_selection: Mutex<Option<((u64, u64, u64), Option<StateChannel>)>>,
+    events: Option<broadcast::Sender<ClientEvent>>,
+    sc_lifecycle: Option<broadcast::Sender<ScLifecycle>>,
+    compact_interval: Duration,
+    banners: BannerState,
+    offer_deadline: Option<time::Instant>,
+    reconnect_deadline: Option<time::Instant>,
+    connect_deadline: Option<time::Instant>,
+    session_start: Option<(time::Instant, MetricsSnapshot)>,
+    reconnect_attempts: u32,
+    /// Whether the pending reconnect already waited for busier routers to
+    /// reconnect first
+    reconnect_ranked: bool,
+    clock: SharedClock,
+    // The wall clock times of tapped messages and traced decisions, clamped
+    // so they do not go backwards when the host clock is corrected
+    wall_clock: Arc<ClampedClock>,
+    cache_settings: CacheSettings,
+    settings: ClientSettings,
+}
+
+/// Optional configuration of a router client. The values default to the
+/// bundled default settings, with OUI 0 in the US915 region, and can be
+/// overridden before creating the client with the required wiring: the
+/// router uri, gateway, downlink channel and keypairs.
+#[derive(Clone)]
+pub struct RouterClientConfig {
+    oui: u32,
+    region: Region,
+    cache_settings: CacheSettings,
+    settings: ClientSettings,
+    economy_mode: EconomyMode,
+    gateway_lookups: GatewayLookups,
+    validations: ValidationGovernor,
+    offer_limiter: OfferLimiter,
+    reconnect_priority: ReconnectPriority,
+    downlink_dedup: Option<DownlinkDedup>,
+    redispatch: Option<mpsc::Sender<Redispatch>>,
+    router_owner: Option<Vec<u8>>,
+    clock: SharedClock,
+}
+
+impl Default for RouterClientConfig {
+    fn default() -> Self {
+        Self {
+            oui: 0,
+            region: Region::from_i32(0).expect("us915 region"),
+            cache_settings: CacheSettings::default(),
+            settings: ClientSettings::default(),
+            economy_mode: EconomyMode::default(),
+            gateway_lookups: GatewayLookups::default(),
+            validations: ValidationGovernor::default(),
+            offer_limiter: OfferLimiter::default(),
+            reconnect_priority: ReconnectPriority::default(),
+            downlink_dedup: None,
+            redispatch: None,
+            router_owner: None,
+            clock: clock::system(),
+        }
+    }
+}
+
+impl RouterClientConfig {
+    pub fn with_oui(mut self, oui: u32) -> Self {
+        self.oui = oui;
+        self
+    }
+
+    pub fn with_region(mut self, region: Region) -> Self {
+        self.region = region;
+        self
+    }
+
+    pub fn with_cache_settings(mut self, cache_settings: CacheSettings) -> Self {
+        self.cache_settings = cache_settings;
+        self
+    }
+
+    /// Use the given timeouts, limits and policies.
+    pub fn with_settings(mut self, settings: ClientSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Use the given, usually shared, economy mode flag to decide whether
+    /// offers are sent.
+    pub fn with_economy_mode(mut self, economy_mode: EconomyMode) -> Self {
+        self.economy_mode = economy_mode;
+        self
+    }
+
+    /// Share the given limit on concurrent gateway lookups with other
+    /// clients.
+    pub fn with_gateway_lookups(mut self, gateway_lookups: GatewayLookups) -> Self {
+        self.gateway_lookups = gateway_lookups;
+        self
+    }
+
+    /// Share the given limit on concurrent state channel validations with
+    /// other clients.
+    pub fn with_validation_governor(mut self, validations: ValidationGovernor) -> Self {
+        self.validations = validations;
+        self
+    }
+
+    /// Share the given limit on the rate of offers with other clients.
+    pub fn with_offer_limiter(mut self, offer_limiter: OfferLimiter) -> Self {
+        self.offer_limiter = offer_limiter;
+        self
+    }
+
+    /// Rank reconnects against those of other clients by traffic with the
+    /// given ranking.
+    pub fn with_reconnect_priority(mut self, reconnect_priority: ReconnectPriority) -> Self {
+        self.reconnect_priority = reconnect_priority;
+        self
+    }
+
+    /// Share the given downlink deduplication with other clients, so that a
+    /// downlink that more than one router sends is only forwarded once.
+    /// Without one the client deduplicates its own downlinks within the
+    /// configured window.
+    pub fn with_downlink_dedup(mut self, downlink_dedup: DownlinkDedup) -> Self {
+        self.downlink_dedup = Some(downlink_dedup);
+        self
+    }
+
+    /// Hand packets that are rejected with a reason configured for
+    /// redispatch to the given channel, usually that of the dispatcher.
+    /// Without a channel such packets are dropped.
+    pub fn with_redispatch(mut self, redispatch: mpsc::Sender<Redispatch>) -> Self {
+        self.redispatch = Some(redispatch);
+        self
+    }
+
+    /// Use the given owner of the OUI of the router, as reported by the
+    /// gateway, to resolve state channel owner rotations.
+    pub fn with_router_owner(mut self, owner: Vec<u8>) -> Self {
+        self.router_owner = Some(owner);
+        self
+    }
+
+    /// Use the given clock for packet hold times and expiry.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
 }
 
 impl RouterClient {
+    /// Creates a client for the given router with the given configuration.
+    /// The client signs its messages with the keypair for its OUI.
     pub async fn new(
-        oui: u32,
-        region: Region,
+        config: RouterClientConfig,
         uri: KeyedUri,
         gateway: GatewayService,
         downlinks: mpsc::Sender<Packet>,
-        keypair: Arc<Keypair>,
-        settings: CacheSettings,
+        keypairs: OuiKeypairs,
     ) -> Result<Self> {
-        let mut client = RouterService::new(uri.clone())?;
+        let RouterClientConfig {
+            oui,
+            region,
+            cache_settings,
+            settings,
+            economy_mode,
+            gateway_lookups,
+            validations,
+            offer_limiter,
+            reconnect_priority,
+            downlink_dedup,
+            redispatch,
+            router_owner,
+            clock,
+        } = config;
+        let mut client = RouterService::new(
+            uri.clone(),
+            &settings.tls,
+            Duration::from_secs(settings.keepalive),
+        )?;
+        if settings.probe_router {
+            client.probe().await?;
+        }
         let state_channel = client.state_channel()?;
-        let store = RouterStore::new(&uri.public_key.to_string(), &settings).await?;
+        let statsd = match &settings.statsd {
+            Some(addr) => StatsdSink::new(addr, &uri.public_key.to_string())?,
+            None => StatsdSink::default(),
+        };
+        let store = RouterStore::new(&uri.public_key.to_string(), &cache_settings)
+            .await?
+            .with_clock(clock.clone());
+        let recent_uplinks =
+            RecentUplinks::new(RECENT_UPLINK_WINDOW, cache_settings.max_packets as usize);
+        let sc_messages = MessageBuffer::new(settings.message_buffer);
+        let downlink_capture = DownlinkCapture::new(settings.downlink_capture);
+        let devaddr_metrics = DevAddrMetrics::new(settings.devaddr_metrics);
+        let devaddr_rules = DevAddrRules::for_router(&settings.devaddr_rules, &uri.public_key);
+        let compact_interval = Duration::from_secs(cache_settings.compact_interval);
+        let downlink_dispatcher = if settings.downlink_concurrency > 0 {
+            Some(
+                DownlinkDispatcher::new(
+                    downlinks.clone(),
+                    Duration::from_millis(settings.downlink_timeout),
+                    settings.downlink_concurrency,
+                )
+                .with_send_mode(settings.downlink_send),
+            )
+        } else {
+            None
+        };
+        let wall_clock = Arc::new(ClampedClock::new(clock.clone()));
+        let decision_trace =
+            DecisionTrace::for_devaddrs(&settings.trace_devaddrs).with_clock(wall_clock.clone());
+        let banners = BannerState::new(
+            Duration::from_secs(settings.banner_timeout),
+            Duration::from_millis(settings.banner_debounce),
+            Duration::from_millis(settings.banner_debounce_max),
+            Duration::from_millis(settings.sc_reorder_window),
+        );
+        let downlink_dedup = downlink_dedup
+            .unwrap_or_else(|| DownlinkDedup::new(Duration::from_millis(settings.downlink_dedup)));
+        let mut owners = OwnerResolver::new(settings.validation.clone());
+        if let Some(owner) = router_owner {
+            owners.set_router_owner(owner);
+        }
         Ok(Self {
             client,
             oui,
             region,
-            keypair,
+            keypair: keypairs.for_oui(oui),
+            gateway_keypair: keypairs.gateway(),
             downlinks,
+            downlinks_closed: false,
+            downlink_dispatcher,
+            writer: BackgroundWriter::new(store.clone(), settings.background_writes),
             store,
             state_channel,
+            sc_messages,
             gateway,
+            recent_uplinks,
+            recent_joins: RecentJoins::new(Duration::from_secs(settings.recent_join)),
+            offer_throttle: OfferThrottle::new(Duration::from_millis(
+                settings.devaddr_offer_interval,
+            )),
+            sampling: UplinkSampling::new(settings.uplink_sampling),
+            protocol: settings.router_protocol,
+            protocol_rejects: 0,
+            sent_packets: SentPackets::new(cache_settings.max_packets as usize),
+            downlink_capture,
+            downlink_dedup,
+            message_tap: MessageTap::new(settings.message_tap).with_clock(wall_clock.clone()),
+            decision_trace,
+            devaddr_metrics,
+            devaddr_rules,
+            metrics: ClientMetrics::default().with_statsd(statsd),
+            economy: EconomyState::new(economy_mode),
+            offers_paused: false,
+            gateway_lookups,
+            validations,
+            limited_offers: LimitedOffers::new(offer_limiter),
+            reconnect_priority,
+            redispatch,
+            gateway_health: GatewayHealth::new(settings.gateway_failures, settings.message_buffer),
+            gateway_retry: None,
+            revalidation: None,
+            lookup_failed: false,
+            owners,
+            accounting: PacketAccounting::new(settings.packet_drift),
+            sc_selector: StateChannelSelector::new(settings.sc_selection),
+            sc_selection: Mutex::new(None),
+            events: None,
+            sc_lifecycle: None,
+            compact_interval,
+            banners,
+            offer_deadline: None,
+            reconnect_deadline: None,
+            connect_deadline: None,
+            session_start: None,
+            reconnect_attempts: 0,
+            reconnect_ranked: false,
+            clock,
+            wall_clock,
+            cache_settings,
+            settings,
         })
     }
 
-    pub async fn run(
+    /// Subscribes to the activity events of this client. Events are only
+    /// published once there is at least one subscriber, and publishing never
+    /// blocks the client: subscribers that lag behind lose events.
+    pub fn subscribe(&mut self) -> broadcast::Receiver<ClientEvent> {
+        match &self.events {
+            Some(events) => events.subscribe(),
+            None => {
+                let (events, receiver) = broadcast::channel(EVENT_CAPACITY);
+                self.events = Some(events);
+                receiver
+            }
+        }
+    }
+
+    fn emit(&self, event: ClientEvent) {
+        if let Some(events) = &self.events {
+            // An error only means there are no subscribers right now
+            let _ = events.send(event);
+        }
+    }
+
+    /// Subscribes to the lifecycle transitions of the state channels known
+    /// to this client: accepting a new state channel, advancing the nonce of
+    /// a known one, and its expiry and removal from the store. Like
+    /// `subscribe`, transitions are only published once there is a
+    /// subscriber and lagging subscribers lose transitions.
+    pub fn subscribe_sc_lifecycle(&mut self) -> broadcast::Receiver<ScLifecycle> {
+        match &self.sc_lifecycle {
+            Some(sc_lifecycle) => sc_lifecycle.subscribe(),
+            None => {
+                let (sc_lifecycle, receiver) = broadcast::channel(EVENT_CAPACITY);
+                self.sc_lifecycle = Some(sc_lifecycle);
+                receiver
+            }
+        }
+    }
+
+    fn emit_sc_lifecycle(&self, transition: ScLifecycle) {
+        if let Some(sc_lifecycle) = &self.sc_lifecycle {
+            let _ = sc_lifecycle.send(transition);
+        }
+    }
+
+    /// Publishes the archival of the state channels removed from the store
+    /// since the last call, however they were removed. Those among the
+    /// given expired state channels are published as expired first.
+    fn emit_removed_state_channels(&self, expired: &[String]) {
+        for sc_id in self.store.take_removed_state_channels() {
+            if expired.contains(&sc_id) {
+                self.emit_sc_lifecycle(ScLifecycle::Expired {
+                    sc_id: sc_id.clone(),
+                });
+            }
+            self.emit_sc_lifecycle(ScLifecycle::Archived { sc_id });
+        }
+    }
+
+    /// Stores the given state channel as the known version for its id,
+    /// publishing the archival of state channels evicted to make room.
+    async fn store_state_channel(&self, sc: &StateChannel) -> Result {
+        let stored = self.store.overwrite_state_channel(&sc.id_key(), sc).await;
+        self.emit_removed_state_channels(&[]);
+        stored
+    }
+
+    /// Keeps the given rejected state channel as a conflicting version,
+    /// publishing the archival of state channels evicted to make room. This
+    /// is not left to the background writer since the conflicting version is
+    /// what later validation of the state channel checks against.
+    async fn write_rejected_state_channel(&self, sc: StateChannel) -> Result {
+        let written = self.store.append_state_channel(&sc.id_key(), &sc).await;
+        self.emit_removed_state_channels(&[]);
+        written
+    }
+
+    /// Hands the given reject log or metrics write to the background writer,
+    /// logging rather than failing when an inline write fails.
+    async fn write_background(&self, logger: &Logger, write: BackgroundWrite) {
+        if let Err(err) = self.writer.write(write).await {
+            warn!(logger, "failed to write store record {:?}", err);
+        }
+    }
+
+    /// Returns the protocol version offers and packets to the router are
+    /// constructed for. Until a router on the original protocol is detected
+    /// this is the current protocol.
+    pub fn protocol_version(&self) -> RouterProtocol {
+        match self.protocol {
+            RouterProtocol::Auto => RouterProtocol::V2,
+            protocol => protocol,
+        }
+    }
+
+    /// Tells the client the protocol version of the router, for example as
+    /// learned from an out of band handshake. Auto detects the version from
+    /// the purchases and rejects of the router again.
+    pub fn set_protocol_version(&mut self, protocol: RouterProtocol) {
+        self.protocol = protocol;
+        self.protocol_rejects = 0;
+    }
+
+    /// Detects the router protocol from a reject. A router on the original
+    /// protocol rejects the offers carrying the newer fields, so a run of
+    /// rejects without any purchase identifying a packet in between takes
+    /// the router to be on the original protocol.
+    fn detect_protocol_from_reject(&mut self, logger: &Logger) {
+        if self.protocol != RouterProtocol::Auto {
+            return;
+        }
+        self.protocol_rejects += 1;
+        if self.protocol_rejects >= PROTOCOL_DETECT_REJECTS {
+            info!(logger, "detected router on the original protocol from rejects";
+                "rejects" => self.protocol_rejects);
+            self.protocol = RouterProtocol::V1;
+        }
+    }
+
+    /// Detects the router protocol from a purchase. Only routers on the
+    /// original protocol leave out the hash of the purchased packet, a
+    /// purchase identifying its packet settles on the current protocol.
+    fn detect_protocol_from_purchase(&mut self, logger: &Logger, packet_hash: &[u8]) {
+        if self.protocol != RouterProtocol::Auto {
+            return;
+        }
+        if packet_hash.is_empty() {
+            info!(logger, "detected router on the original protocol");
+            self.protocol = RouterProtocol::V1;
+        } else {
+            debug!(logger, "detected router on the current protocol");
+            self.protocol = RouterProtocol::V2;
+        }
+        self.protocol_rejects = 0;
+    }
+
+    /// Returns the captured forwarded downlinks, oldest first. This is empty
+    /// unless downlink capturing is enabled in the client settings.
+    pub fn export_downlinks(&self) -> Vec<helium_proto::Packet> {
+        self.downlink_capture.export()
+    }
+
+    /// Returns the tapped raw messages of this client, oldest first, in the
+    /// serialized form `replay` takes. This is empty unless the message tap
+    /// is enabled in the client settings.
+    pub fn export_messages(&self) -> Vec<u8> {
+        self.message_tap.export()
+    }
+
+    /// Returns the tapped raw messages of this client recorded within the
+    /// given time range, signed with the keypair of the gateway rather than
+    /// the keypair of the OUI of this client, so the export can be checked to
+    /// come from this gateway and be unaltered.
+    pub fn export_audit(&self, range: Range<SystemTime>) -> Result<SignedExport> {
+        SignedExport::sign(
+            &self.gateway_keypair,
+            &range,
+            self.message_tap.export_between(&range),
+        )
+    }
+
+    /// Replays messages exported by `export_messages`, usually against a
+    /// fresh client, and returns the offers and packets this client sent and
+    /// the downlinks it forwarded in response, in order. The captured
+    /// uplinks and router messages are handled one after the other, with
+    /// downlinks delivered in order and no timers running, so replaying the
+    /// same capture gives the same output. The outputs in the capture are
+    /// skipped, they are what the result can be compared against. Sent
+    /// messages do go to the router of this client, which should therefore
+    /// be a test router.
+    pub async fn replay(&mut self, logger: &Logger, capture: &[u8]) -> Result<Vec<TapMessage>> {
+        let messages = MessageTap::decode(capture)?;
+        let logger = self.context_logger(logger);
+        let tap = std::mem::replace(&mut self.message_tap, MessageTap::new(usize::MAX));
+        let downlink_dispatcher = self.downlink_dispatcher.take();
+        for message in messages {
+            match message {
+                TapMessage::Uplink(packet) => {
+                    let packet = Packet::from(packet);
+                    let packet_id = packet.id();
+                    let dev_addr = packet.dev_addr();
+                    if let Err(err) = self.handle_uplink(&logger, packet).await {
+                        log_failed_uplink(&logger, &packet_id, dev_addr, &err);
+                    }
+                }
+                TapMessage::Received(message) => {
+                    self.receive_message(message);
+                    self.handle_buffered_messages(&logger).await;
+                }
+                TapMessage::Sent(_) | TapMessage::Downlink(_) => (),
+            }
+        }
+        self.downlink_dispatcher = downlink_dispatcher;
+        let replayed = std::mem::replace(&mut self.message_tap, tap);
+        Ok(replayed
+            .messages()
+            .into_iter()
+            .filter(TapMessage::is_output)
+            .collect())
+    }
+
+    /// Makes the given state channel the known, active, state channel without
+    /// receiving it in a banner, so tests can feed purchases against a known
+    /// channel. This skips all validation.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn insert_active_state_channel(&self, sc: &StateChannel) -> Result {
+        self.store_state_channel(sc).await
+    }
+
+    /// Expires the state channel offers are currently made against at the
+    /// current block height, or at the first block when the height is not
+    /// known, and compacts the store at that height. The state channel is
+    /// then handled like one that expired naturally: it is removed from the
+    /// store, offers fail over to the next selected state channel, and with
+    /// none left the next uplink connects to the router again. Returns the
+    /// id of the expired state channel, if there was one.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn expire_active_channel(&mut self, logger: &Logger) -> Result<Option<String>> {
+        let sc = match self.selected_state_channel().await? {
+            Some(sc) => sc,
+            None => return Ok(None),
+        };
+        let sc_id = sc.id_key();
+        let height = self.gateway.height().max(1);
+        self.store
+            .overwrite_state_channel(&sc_id, &sc.with_expiry_at_block(height))
+            .await?;
+        info!(logger, "forcing expiry of active state channel";
+            "sc_id" => &sc_id,
+            "height" => height);
+        self.compact_store(logger, height).await;
+        Ok(Some(sc_id))
+    }
+
+    /// Handles the given message as if it was received from the router, so
+    /// benchmarks can drive message handling directly.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn handle_message(
         &mut self,
-        mut uplinks: mpsc::Receiver<Dispatch>,
-        shutdown: triggered::Listener,
         logger: &Logger,
+        message: StateChannelMessage,
     ) -> Result {
-        let logger = logger.new(o!(
+        self.handle_state_channel_message(logger, message).await
+    }
+
+    /// Stores the given uplink as waiting to be offered, without offering it.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn store_waiting_packet(&self, packet: Packet) -> Result {
+        self.store.store_waiting_packet(packet).await
+    }
+
+    /// Stores the given uplink as offered, awaiting a purchase or reject.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn que_offered_packet(&self, packet: Packet) -> Result {
+        let packet = QuePacket::new(packet, self.clock.now()).with_region(self.region.clone());
+        self.store.que_packet(packet).await
+    }
+
+    /// Offers the waiting packets, as on a banner.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn offer_waiting_packets(&mut self, logger: &Logger) -> Result {
+        self.send_packet_offers(logger).await
+    }
+
+    /// Records the given uplink as sent to the router, so that downlinks
+    /// answering it are forwarded.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn record_sent_uplink(&mut self, packet: Packet) {
+        self.recent_uplinks
+            .record(&QuePacket::new(packet, self.clock.now()));
+    }
+
+    /// Handles the given downlink as if it was received in a response from
+    /// the router, so the downlink path to the concentrator can be tested
+    /// without a router. As with a router response the downlink is only
+    /// forwarded if it answers a recent uplink, see `record_sent_uplink`.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn inject_downlink(&mut self, logger: &Logger, packet: Packet) {
+        self.handle_downlink(logger, &packet.to_packet()).await
+    }
+
+    /// Returns the number of waiting and of offered packets.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn packet_counts(&self) -> (usize, usize) {
+        self.store.packet_counts().await
+    }
+
+    /// Returns the number of state channel messages dropped because they
+    /// arrived faster than they could be handled.
+    pub fn dropped_messages(&self) -> u64 {
+        self.sc_messages.dropped()
+    }
+
+    /// Returns a snapshot of the activity counters of this client.
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            dropped_messages: self.sc_messages.dropped(),
+            background_write_failures: self.writer.failures(),
+            ..self.metrics.snapshot()
+        }
+    }
+
+    /// Returns the offer, purchase and reject counts for the given DevAddr if
+    /// per device counts are enabled and the device is being tracked.
+    pub fn devaddr_metrics(&self, dev_addr: u32) -> Option<&DevAddrCounts> {
+        self.devaddr_metrics.get(dev_addr)
+    }
+
+    /// Returns why the most recently dropped packet for the given DevAddr was
+    /// not sent to the router. Like the other per device counts this is only
+    /// tracked when per device counts are enabled.
+    pub fn last_drop_reason(&self, dev_addr: u32) -> Option<DropReason> {
+        self.devaddr_metrics.get(dev_addr)?.last_drop
+    }
+
+    /// Starts recording every decision made for the packets matching the
+    /// given target, from receiving the uplink to sending or dropping it.
+    pub fn trace_packets(&mut self, target: TraceTarget) {
+        self.decision_trace.trace(target)
+    }
+
+    /// Returns the decisions recorded for the traced packet with the given
+    /// id, oldest first, or `None` if the packet was not traced.
+    pub fn packet_trace(&self, id: &PacketId) -> Option<Vec<TraceEntry>> {
+        self.decision_trace.trace_of(id).map(<[TraceEntry]>::to_vec)
+    }
+
+    /// Logs the decision traces of the traced packets whose handling ended
+    /// since the last call, which is how traces configured in the settings
+    /// are retrieved in the field.
+    fn log_completed_traces(&mut self, logger: &Logger) {
+        for (id, entries) in self.decision_trace.take_completed() {
+            let steps: Vec<String> = entries.iter().map(ToString::to_string).collect();
+            info!(logger, "packet decision trace";
+                "packet_id" => id.to_string(),
+                "trace" => steps.join(", "));
+        }
+    }
+
+    fn trace(&mut self, packet: &Packet, step: TraceStep) {
+        self.decision_trace.record(packet, step)
+    }
+
+    /// Counts a dropped packet against its device and traces the drop.
+    fn record_drop(&mut self, packet: &Packet, reason: DropReason) {
+        self.devaddr_metrics.record_drop(packet.dev_addr(), reason);
+        self.trace(packet, TraceStep::Dropped(reason));
+    }
+
+    /// Returns the known state channel offers are made against, as picked by
+    /// the configured state channel selection among the unexpired ones of the
+    /// client region. The selection is kept until the known state channels,
+    /// the block height, the received banners or the known state channel
+    /// regions change. The selected state channel is protected from eviction
+    /// in the store.
+    pub async fn selected_state_channel(&self) -> Result<Option<StateChannel>> {
+        let key = (
+            self.store.sc_generation(),
+            self.gateway.height(),
+            self.sc_selector.generation(),
+        );
+        if let Some((selected_at, selected)) = &*self.sc_selection.lock().expect("selection lock") {
+            if *selected_at == key {
+                return Ok(selected.clone());
+            }
+        }
+        let mut scs = self.store.state_channels().await?;
+        let selected = self
+            .sc_selector
+            .select(&scs, key.1, &self.region)
+            .map(|sc| sc.id_key())
+            .and_then(|sc_id| {
+                let index = scs.iter().position(|sc| sc.id_key() == sc_id)?;
+                Some(scs.swap_remove(index))
+            });
+        self.store
+            .protect_state_channel(selected.as_ref().map(|sc| sc.id_key()));
+        *self.sc_selection.lock().expect("selection lock") = Some((key, selected.clone()));
+        Ok(selected)
+    }
+
+    /// Returns whether the router ever sent this client a banner, which
+    /// tells a client that never got through to its router apart from one
+    /// that is connected but idle. Stays set across reconnects.
+    pub fn banner_received(&self) -> bool {
+        self.banners.received()
+    }
+
+    /// Returns the id key of the state channel offers are currently made
+    /// against, as picked by `selected_state_channel`, or `None` before a
+    /// state channel to offer against is known.
+    pub async fn active_state_channel_id(&self) -> Result<Option<String>> {
+        Ok(self.selected_state_channel().await?.map(|sc| sc.id_key()))
+    }
+
+    /// Returns the remaining DC balance of the selected state channel, or
+    /// `None` if there is no unexpired state channel to offer against.
+    pub async fn remaining_balance(&self) -> Result<Option<u64>> {
+        Ok(self
+            .selected_state_channel()
+            .await?
+            .map(|sc| sc.remaining_balance()))
+    }
+
+    /// Returns the site tag of this client, if one is configured.
+    pub fn site(&self) -> Option<&str> {
+        self.settings.site.as_deref()
+    }
+
+    /// Returns a logger that attributes records to this client and, when
+    /// configured, its site. The state channel protocol has no field to
+    /// carry the site to the router, so the site is only recorded locally.
+    fn context_logger(&self, logger: &Logger) -> Logger {
+        logger.new(o!(
             "module" => "router",
             "public_key" => self.client.uri.public_key.to_string(),
             "uri" => self.client.uri.uri.to_string(),
             "oui" => self.oui,
-        ));
+            "site" => self.settings.site.clone(),
+        ))
+    }
+
+    /// Checks the configuration of a client for the given router without
+    /// creating it, so nothing is connected to or written to the store, and
+    /// returns the problems found: a router uri that can not be connected to,
+    /// a keypair that does not sign and store settings the client can not run
+    /// with. Regions are not checked here since unsupported regions are
+    /// already refused when the settings are read.
+    pub fn validate_config(
+        uri: &KeyedUri,
+        keypair: &Keypair,
+        cache_settings: &CacheSettings,
+    ) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        let valid_scheme = matches!(uri.uri.scheme_str(), Some("http") | Some("https"));
+        if !valid_scheme || uri.uri.host().is_none() {
+            problems.push(ConfigProblem::InvalidUri(uri.uri.to_string()));
+        }
+        if !signs_for(keypair, keypair.public_key()) {
+            problems.push(ConfigProblem::UnusableKeypair);
+        }
+        if cache_settings.store.as_os_str().is_empty() {
+            problems.push(ConfigProblem::InvalidStoreSetting("store"));
+        }
+        if cache_settings.max_packets == 0 {
+            problems.push(ConfigProblem::InvalidStoreSetting("max_packets"));
+        }
+        if cache_settings.compact_interval == 0 {
+            problems.push(ConfigProblem::InvalidStoreSetting("compact_interval"));
+        }
+        problems
+    }
+
+    pub async fn run(
+        &mut self,
+        mut uplinks: mpsc::Receiver<Dispatch>,
+        shutdown: triggered::Listener,
+        logger: &Logger,
+    ) -> Result<RunExit> {
+        let logger = self.context_logger(logger);
         info!(logger, "starting");
+        self.session_start = Some((time::Instant::now(), self.metrics.snapshot()));
+        for path in self.store.quarantined() {
+            warn!(logger, "quarantined corrupt store data";
+                "path" => path.display().to_string());
+        }
+        self.prune_state_channels(&logger, "restart").await;
 
+        self.start_connect_jitter(&logger);
+        match self.store.load_packets().await {
+            Ok(0) => (),
+            Ok(restored) => info!(logger, "restored saved packets"; "restored" => restored),
+            Err(err) => warn!(logger, "failed to restore saved packets {:?}", err),
+        }
+        // A compact interval of 0 fails `validate_config` but would make the
+        // interval panic, so it disables compaction instead
+        let mut compact_timer = if self.compact_interval > Duration::from_secs(0) {
+            Some(time::interval(self.compact_interval))
+        } else {
+            None
+        };
         loop {
             tokio::select! {
                 _ = shutdown.clone() => {
                     info!(logger, "shutting down");
-                    return Ok(())
+                    match self.store.save_packets().await {
+                        Ok(saved) => debug!(logger, "saved waiting packets"; "saved" => saved),
+                        Err(err) => warn!(logger, "failed to save waiting packets {:?}", err),
+                    }
+                    return Ok(self.exit(&logger, ExitReason::Shutdown).await)
                 },
+                _ = tick(compact_timer.as_mut()) => self.compact_store(&logger, self.gateway.height()).await,
                 uplink = uplinks.recv() => match uplink {
-                    Some(Dispatch::Packet(packet)) => match self.handle_uplink(&logger, packet).await {
-                        Ok(()) =>  (),
-                        Err(err) => warn!(logger, "ignoring failed uplink {:?}", err)
+                    Some(Dispatch::Packet(packet)) => {
+                        let packet_id = packet.id();
+                        let dev_addr = packet.dev_addr();
+                        if let Err(err) = self.handle_uplink(&logger, packet).await {
+                            log_failed_uplink(&logger, &packet_id, dev_addr, &err);
+                        }
+                    },
+                    Some(Dispatch::Redispatch(redispatch)) => {
+                        let Redispatch { packet, offers, .. } = redispatch;
+                        let packet_id = packet.id();
+                        let dev_addr = packet.dev_addr();
+                        if let Err(err) = self.handle_offered_uplink(&logger, packet, offers).await {
+                            log_failed_uplink(&logger, &packet_id, dev_addr, &err);
+                        }
+                    },
+                    Some(Dispatch::Gateway(gateway)) => self.handle_gateway_swap(&logger, gateway).await,
+                    Some(Dispatch::RouterOwner(owner)) => self.owners.set_router_owner(owner),
+                    Some(Dispatch::FlushDevAddr(dev_addr)) => match self.flush_devaddr(&logger, dev_addr).await {
+                        Ok(flushed) => debug!(logger, "flushed device";
+                            "dev_addr" => format!("{:08x}", dev_addr), "offered" => flushed),
+                        Err(err) => warn!(logger, "failed to flush device {:?}", err;
+                            "dev_addr" => format!("{:08x}", dev_addr)),
                     },
-                    Some(Dispatch::Gateway(gateway)) => {
-                        info!(logger, "using new gateway";
-                            "public_key" => gateway.uri.public_key.to_string(),
-                            "uri" => gateway.uri.uri.to_string());
-                        self.gateway = gateway;
+                    Some(Dispatch::Region(region)) => {
+                        if let Err(err) = self.set_region(&logger, region).await {
+                            warn!(logger, "failed to apply region change {:?}", err);
+                        }
                     },
                     None => warn!(logger, "ignoring closed uplinks channel"),
                 },
+                _ = wait_until(self.banners.timeout_at()) => self.handle_banner_timeout(&logger).await,
+                _ = wait_until(self.banners.debounce_at()) => self.handle_banner_debounce(&logger).await,
+                _ = wait_until(self.banners.release_at()) => self.release_held_banners(&logger).await,
+                _ = wait_until(self.offer_deadline) => self.handle_offer_timeout(&logger).await,
+                _ = wait_until(self.limited_offers.resume_at()) => self.handle_offer_limit(&logger).await,
+                _ = wait_until(self.gateway_retry) => self.retry_held_messages(&logger).await,
+                _ = wait_until(self.reconnect_deadline) => self.handle_reconnect(&logger).await,
+                _ = wait_until(self.connect_deadline) => self.handle_connect_jitter(&logger).await,
+                (revalidation, cause) = next_revalidation(&mut self.revalidation) =>
+                    self.handle_revalidation(&logger, revalidation, cause).await,
+                dispatched = next_dispatched(&mut self.downlink_dispatcher) => {
+                    self.handle_delivery(&logger, dispatched);
+                    if self.downlinks_closed {
+                        warn!(logger, "downlinks channel closed, shutting down");
+                        return Ok(self.exit(&logger, ExitReason::DownlinksClosed).await)
+                    }
+                },
                 sc_message = self.state_channel.message() =>  match sc_message {
                     Ok(Some(message)) => {
-                        if let Some(inner_msg) = message.msg {
-                        match self.handle_state_channel_message(&logger, inner_msg.into()).await {
-                            Ok(()) => (),
-                            Err(err) => warn!(logger, "state channel handling error {:?}", err),
+                        if self.reconnect_attempts > 0 {
+                            let now = self.clock.now();
+                            self.reconnect_priority.reconnected(self.oui, &self.client.uri.uri, now);
+                        }
+                        self.reconnect_attempts = 0;
+                        self.receive_message(message);
+                        let ended = self.buffer_ready_messages(&logger);
+                        self.handle_buffered_messages(&logger).await;
+                        if self.downlinks_closed {
+                            warn!(logger, "downlinks channel closed, shutting down");
+                            return Ok(self.exit(&logger, ExitReason::DownlinksClosed).await)
+                        }
+                        if let Some(reason) = ended {
+                            if !self.handle_connection_lost(&logger, reason) {
+                                return Ok(self.exit(&logger, reason).await)
+                            }
                         }
-                    }
                     },
-                    Ok(None) => return Ok(()),
+                    Ok(None) => if !self.handle_connection_lost(&logger, ExitReason::StreamClosed) {
+                        return Ok(self.exit(&logger, ExitReason::StreamClosed).await)
+                    },
                     Err(err) => {
                         warn!(logger, "state channel error {:?}", err);
-                        return Ok(())
+                        if !self.handle_connection_lost(&logger, ExitReason::StreamError) {
+                            return Ok(self.exit(&logger, ExitReason::StreamError).await)
+                        }
                     }
                 }
             }
+            self.log_completed_traces(&logger);
         }
     }
 
-    async fn handle_uplink(&mut self, logger: &Logger, uplink: Packet) -> Result {
-        if self.store.state_channel_count().await? == 0 {
-            // No banner received yet, start connect
-            self.state_channel.connect().await?;
+    fn receive_message(&mut self, message: BlockchainStateChannelMessageV1) {
+        self.message_tap.record_received(&message);
+        self.sc_messages.push(message);
+    }
+
+    /// Moves state channel messages that are already available into the
+    /// message buffer without waiting for more, so that a flood of messages
+    /// is subject to the buffer drop policy instead of queueing up on the
+    /// stream. Returns how the stream ended if it closed or failed.
+    fn buffer_ready_messages(&mut self, logger: &Logger) -> Option<ExitReason> {
+        let dropped = self.sc_messages.dropped();
+        let mut ended = None;
+        for _ in 0..self.sc_messages.capacity() {
+            match self.state_channel.message().now_or_never() {
+                Some(Ok(Some(message))) => self.receive_message(message),
+                Some(Ok(None)) => {
+                    ended = Some(ExitReason::StreamClosed);
+                    break;
+                }
+                Some(Err(err)) => {
+                    warn!(logger, "state channel error {:?}", err);
+                    ended = Some(ExitReason::StreamError);
+                    break;
+                }
+                None => break,
+            }
         }
-        self.send_packet(logger, Some(&QuePacket::from(uplink)))
-            .await
-        // self.store.store_waiting_packet(uplink)?;
-        // if self.store.state_channel_count().await? == 0 {
-        //     // No banner received yet, start connect
-        //     return self.state_channel.connect().await;
-        // }
-        // self.send_packet_offers(logger).await
+        if self.sc_messages.dropped() > dropped {
+            warn!(logger, "dropped state channel messages";
+                "dropped" => self.sc_messages.dropped() - dropped,
+                "total_dropped" => self.sc_messages.dropped());
+        }
+        ended
     }
 
-    async fn handle_state_channel_message(
+    async fn handle_buffered_messages(&mut self, logger: &Logger) {
+        while let Some(message) = self.sc_messages.pop() {
+            if !self.gateway_health.is_available() && needs_gateway(&message) {
+                self.hold_message(logger, message);
+                continue;
+            }
+            self.handle_buffered_message(logger, message).await;
+        }
+    }
+
+    async fn handle_buffered_message(
         &mut self,
         logger: &Logger,
-        message: StateChannelMessage,
-    ) -> Result {
-        match message.msg() {
-            Msg::Response(response) => {
-                if let Some(packet) = Packet::from_state_channel_response(response.to_owned()) {
-                    self.handle_downlink(logger, &packet).await;
+        message: BlockchainStateChannelMessageV1,
+    ) {
+        let inner_msg = match &message.msg {
+            Some(inner_msg) => inner_msg.clone(),
+            None => return,
+        };
+        self.lookup_failed = false;
+        match self
+            .handle_state_channel_message(logger, inner_msg.into())
+            .await
+        {
+            Ok(()) if needs_gateway(&message) => {
+                if self.gateway_health.record_success() {
+                    info!(logger, "gateway available";
+                        "held" => self.gateway_health.held_count());
+                    self.emit(ClientEvent::GatewayAvailable);
                 }
-                Ok(())
             }
-            Msg::Packet(_) => Err(Error::custom("unexpected state channel packet message")),
-            Msg::Offer(_) => Err(Error::custom("unexpected state channel offer message")),
-            Msg::Purchase(purchase) => {
-                let packet = self.store.deque_packet();
-                let purchase_sc = self
-                    .mk_state_channel(purchase.sc.to_owned(), |known_sc, new_sc| {
-                        if let Some(known_sc) = known_sc {
-                            return known_sc.is_valid_purchase(new_sc, packet.as_ref());
-                        }
-                        Ok(())
-                    })
-                    .await?;
-                info!(logger, "received purchase";
-                    "sc_id" => purchase_sc.id_key());
-                self.send_packet(logger, packet.as_ref()).await
+            Ok(()) => (),
+            Err(err) if self.lookup_failed && self.gateway_health.is_enabled() => {
+                warn!(logger, "state channel lookup failed {:?}", err);
+                if self.gateway_health.record_failure() {
+                    warn!(logger, "gateway unavailable";
+                        "uri" => self.gateway.uri.uri.to_string());
+                    self.emit(ClientEvent::GatewayUnavailable);
+                }
+                self.hold_message(logger, message);
             }
-            Msg::Banner(banner) => {
-                let banner_sc = self
-                    .mk_state_channel(banner.sc.to_owned(), |_, _| Ok(()))
-                    .await?;
-                info!(logger, "received banner";
-                    "sc_id" => banner_sc.id_key());
+            Err(err) => warn!(logger, "state channel handling error {:?}", err),
+        }
+    }
+
+    fn hold_message(&mut self, logger: &Logger, message: BlockchainStateChannelMessageV1) {
+        if self.gateway_health.hold(message) {
+            warn!(logger, "dropped held state channel message");
+        }
+        if self.gateway_retry.is_none() {
+            self.gateway_retry =
+                Some(time::Instant::now() + Duration::from_secs(self.settings.gateway_retry));
+        }
+    }
+
+    /// Handles the messages held while the gateway was unavailable, in
+    /// order. Messages after one that fails on the gateway again stay held
+    /// for the next attempt.
+    async fn retry_held_messages(&mut self, logger: &Logger) {
+        self.gateway_retry = None;
+        let mut held = self.gateway_health.take_held();
+        while let Some(message) = held.pop_front() {
+            self.handle_buffered_message(logger, message).await;
+            if self.gateway_health.held_count() > 0 {
+                break;
+            }
+        }
+        for message in held {
+            self.hold_message(logger, message);
+        }
+    }
+
+    /// Changes the region of the client. Offered packets that were not yet
+    /// purchased are handled according to the region change policy.
+    async fn set_region(&mut self, logger: &Logger, region: Region) -> Result {
+        if region == self.region {
+            return Ok(());
+        }
+        info!(logger, "changing region";
+            "from" => self.region.to_string(),
+            "to" => region.to_string(),
+            "policy" => format!("{:?}", self.settings.region_change));
+        self.region = region;
+        // The state channels usable for offers depend on the region
+        *self.sc_selection.lock().expect("selection lock") = None;
+        match self.settings.region_change {
+            RegionChangePolicy::Keep => Ok(()),
+            RegionChangePolicy::Reoffer => {
+                let requeued = self.store.requeue_queued_packets().await;
+                if requeued == 0 {
+                    return Ok(());
+                }
                 self.send_packet_offers(logger).await
             }
-            Msg::Reject(_) => {
-                let _ = self.store.deque_packet();
-                Ok(())
+        }
+    }
+
+    /// Switches to the given gateway, which keeps the block height known
+    /// from the one it replaces. Revalidation looks the known state channels
+    /// up with the new gateway in the background, replacing the lookups of
+    /// any earlier swap that are still running.
+    async fn handle_gateway_swap(&mut self, logger: &Logger, mut gateway: GatewayService) {
+        info!(logger, "using new gateway";
+            "public_key" => gateway.uri.public_key.to_string(),
+            "uri" => gateway.uri.uri.to_string());
+        gateway.record_height(self.gateway.height());
+        self.gateway = gateway;
+        if !self.settings.revalidate_on_gateway_swap {
+            return;
+        }
+        match self.lookup_state_channels().await {
+            Ok(lookups) => {
+                let lookups = (tokio::spawn(lookups), "gateway_swap");
+                if let Some((previous, _)) = self.revalidation.replace(lookups) {
+                    previous.abort();
+                }
             }
+            Err(err) => log_pruned(logger, Err(err), "gateway_swap"),
         }
     }
 
-    async fn mk_state_channel<F>(
+    /// Prunes the known state channels by the lookups of a finished
+    /// revalidation.
+    async fn handle_revalidation(
         &mut self,
-        sc: Option<BlockchainStateChannelV1>,
-        final_validation: F,
-    ) -> Result<StateChannel>
-    where
-        F: Fn(Option<&StateChannel>, &StateChannel) -> Result,
-    {
+        logger: &Logger,
+        revalidation: std::result::Result<Revalidation, JoinError>,
+        cause: &'static str,
+    ) {
+        match revalidation {
+            Ok(revalidation) => {
+                let pruned = self.apply_revalidation(logger, revalidation).await;
+                log_pruned(logger, pruned, cause);
+            }
+            Err(err) => warn!(logger, "state channel lookups failed {:?}", err;
+                "cause" => cause),
+        }
+    }
+
+    /// Revalidates the known state channels, logging how many were pruned
+    /// for the given cause.
+    async fn prune_state_channels(&mut self, logger: &Logger, cause: &'static str) {
+        let pruned = self.revalidate_state_channels(logger).await;
+        log_pruned(logger, pruned, cause);
+    }
+
+    /// Compacts the store at the given block height, publishing the expiry
+    /// and removal of the compacted state channels.
+    async fn compact_store(&mut self, logger: &Logger, height: u64) {
+        let jumps = self.wall_clock.take_jumps();
+        if jumps > 0 {
+            warn!(logger, "clock jumped backwards, clamped recorded times";
+                "clamped" => jumps);
+        }
+        self.offer_throttle.prune(self.clock.now());
+        let expired = self.store.expire_packets().await;
+        if !expired.is_empty() {
+            debug!(logger, "dropped expired packets"; "dropped" => expired.len());
+            for packet in &expired {
+                self.record_drop(packet, DropReason::Expired);
+            }
+        }
+        // Also publishes the state channels evicted by background writes
+        // since the last store update
+        let expired = match self.store.compact_state_channels(height).await {
+            Ok((0, _)) => vec![],
+            Ok((removed, removed_scs)) => {
+                info!(logger, "compacted store"; "removed" => removed);
+                removed_scs
+            }
+            Err(err) => {
+                warn!(logger, "store compaction error {:?}", err);
+                vec![]
+            }
+        };
+        self.emit_removed_state_channels(&expired);
+        self.write_background(logger, BackgroundWrite::Metrics(self.metrics_snapshot()))
+            .await;
+    }
+
+    /// Validates the known state channels again by the view of the current
+    /// gateway and removes those that are expired at its block height or
+    /// that it does not report as active. State channels the gateway fails
+    /// to answer for are kept. Returns the number of removed state channels.
+    async fn revalidate_state_channels(&mut self, logger: &Logger) -> Result<usize> {
+        let revalidation = self.lookup_state_channels().await?.await;
+        self.apply_revalidation(logger, revalidation).await
+    }
+
+    /// Returns the lookups of the known state channels with the current
+    /// gateway. The lookups run concurrently within the gateway lookup limit
+    /// and do not borrow the client, so they can run in the background.
+    async fn lookup_state_channels(
+        &self,
+    ) -> Result<impl std::future::Future<Output = Revalidation> + Send + 'static> {
+        let scs = self.store.state_channels().await?;
+        let gateway = self.gateway.clone();
+        let limit = self.gateway_lookups.clone();
+        Ok(async move {
+            let lookups = scs.into_iter().map(|sc| {
+                let mut gateway = gateway.clone();
+                let limit = limit.clone();
+                async move {
+                    let lookup = limit
+                        .run(StateChannel::from_sc(
+                            BlockchainStateChannelV1::from(sc.clone()),
+                            &mut gateway,
+                        ))
+                        .await;
+                    (sc, lookup, gateway.height())
+                }
+            });
+            let mut height = gateway.height();
+            let lookups = futures::future::join_all(lookups)
+                .await
+                .into_iter()
+                .map(|(sc, lookup, reported)| {
+                    height = height.max(reported);
+                    (sc, lookup)
+                })
+                .collect();
+            Revalidation { height, lookups }
+        })
+    }
+
+    /// Removes the state channels that are expired at the block height of
+    /// the given revalidation or that its lookups did not find active, and
+    /// records the height with the current gateway. Returns the number of
+    /// removed state channels.
+    async fn apply_revalidation(
+        &mut self,
+        logger: &Logger,
+        revalidation: Revalidation,
+    ) -> Result<usize> {
+        let Revalidation { height, lookups } = revalidation;
+        self.gateway.record_height(height);
+        let mut pruned = 0;
+        for (sc, lookup) in lookups {
+            let sc_id = sc.id_key();
+            let result = self
+                .check_gateway_view(&sc, height)
+                .and_then(|_| lookup)
+                .and_then(|sc| self.check_gateway_view(&sc, height));
+            match result {
+                Ok(()) => (),
+                Err(Error::StateChannel(err)) => {
+                    debug!(logger, "pruning state channel {:?}", err;
+                        "sc_id" => &sc_id);
+                    self.store.remove_state_channel(&sc_id).await?;
+                    pruned += 1;
+                    if matches!(err, StateChannelError::Expired) {
+                        self.emit_removed_state_channels(&[sc_id]);
+                    } else {
+                        self.emit_removed_state_channels(&[]);
+                    }
+                }
+                Err(err) => warn!(logger, "failed to revalidate state channel {:?}", err;
+                    "sc_id" => &sc_id),
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Checks the given state channel against the given gateway block
+    /// height, if the gateway reported one.
+    fn check_gateway_view(&self, sc: &StateChannel, height: u64) -> Result {
+        if height > 0 && sc.expiry_at_block() <= height {
+            return Err(StateChannelError::expired());
+        }
+        sc.is_valid_with(self.owners.validation(), height)
+    }
+
+    async fn handle_uplink(&mut self, logger: &Logger, uplink: Packet) -> Result {
+        self.handle_offered_uplink(logger, uplink, 0).await
+    }
+
+    /// Handles an uplink that was already offered the given number of times,
+    /// by the router that rejected it before it was redispatched to this
+    /// client. The offers count towards the maximum offer attempts.
+    async fn handle_offered_uplink(
+        &mut self,
+        logger: &Logger,
+        uplink: Packet,
+        offers: u32,
+    ) -> Result {
+        self.trace(&uplink, TraceStep::Received);
+        if uplink.crc_status() == CrcStatus::Failed {
+            debug!(logger, "dropping uplink with failed crc";
+                "packet_id" => uplink.id().to_string());
+            self.record_drop(&uplink, DropReason::Crc);
+            self.metrics.record_crc_drop();
+            return Ok(());
+        }
+        self.trace(&uplink, TraceStep::Passed(TraceCheck::Crc));
+        if self.store.contains_packet(&uplink).await {
+            debug!(logger, "dropping duplicate uplink";
+                "packet_id" => uplink.id().to_string());
+            self.record_drop(&uplink, DropReason::Duplicate);
+            return Ok(());
+        }
+        self.trace(&uplink, TraceStep::Passed(TraceCheck::Dedup));
+        // Failed uplinks are left out of the tap since it does not keep the
+        // crc status
+        self.message_tap.record_uplink(&uplink);
+        match uplink.region_or(self.settings.region_fallback, &self.region) {
+            Ok(Some(region)) => {
+                self.metrics.record_region_uplink(&region);
+                self.trace(&uplink, TraceStep::Passed(TraceCheck::Region));
+            }
+            Ok(None) => {
+                debug!(logger, "dropping uplink with undetermined region";
+                    "packet_id" => uplink.id().to_string());
+                self.record_drop(&uplink, DropReason::Region);
+                return Ok(());
+            }
+            Err(err) => {
+                self.record_drop(&uplink, DropReason::Region);
+                return Err(err);
+            }
+        }
+        if is_below_snr(&uplink, self.settings.min_snr) {
+            self.record_drop(&uplink, DropReason::LowSnr);
+            debug!(logger, "dropping uplink below minimum snr";
+                "packet_id" => uplink.id().to_string(),
+                "snr" => uplink.snr);
+            self.metrics.record_low_snr_drop();
+            return Ok(());
+        }
+        self.trace(&uplink, TraceStep::Passed(TraceCheck::Snr));
+        if let Some(dev_addr) = uplink.dev_addr() {
+            if !self.devaddr_rules.allows(dev_addr) {
+                debug!(logger, "dropping uplink of denied device";
+                    "packet_id" => uplink.id().to_string(),
+                    "dev_addr" => format!("{:08x}", dev_addr));
+                self.record_drop(&uplink, DropReason::Denied);
+                self.metrics.record_denied_drop();
+                return Ok(());
+            }
+            if !self.recent_joins.admit(dev_addr, self.clock.now()) {
+                debug!(logger, "dropping uplink of device without recent join";
+                    "packet_id" => uplink.id().to_string(),
+                    "dev_addr" => format!("{:08x}", dev_addr));
+                self.record_drop(&uplink, DropReason::NotJoined);
+                return Ok(());
+            }
+            if !self.offer_throttle.allows(dev_addr, self.clock.now()) {
+                debug!(logger, "dropping uplink within device offer interval";
+                    "packet_id" => uplink.id().to_string(),
+                    "dev_addr" => format!("{:08x}", dev_addr));
+                self.record_drop(&uplink, DropReason::Throttled);
+                self.metrics.record_throttled_drop();
+                return Ok(());
+            }
+        }
+        self.trace(&uplink, TraceStep::Passed(TraceCheck::Filter));
+        if !self.sampling.sample() {
+            debug!(logger, "dropping uplink left out by sampling";
+                "packet_id" => uplink.id().to_string());
+            self.record_drop(&uplink, DropReason::Sampled);
+            self.metrics.record_sampled_drop();
+            return Ok(());
+        }
+        self.trace(&uplink, TraceStep::Passed(TraceCheck::Sampling));
+        self.trace(&uplink, TraceStep::Waiting);
+        self.store
+            .store_waiting_offered_packet(uplink, offers)
+            .await?;
+        if self.connect_deadline.is_some() {
+            // Hold uplinks to offer them after the delayed first connect
+            return Ok(());
+        }
+        if self.store.state_channel_count().await? == 0 {
+            // No banner received yet, start connect and offer on the banner
+            return self.connect().await;
+        }
+        self.send_packet_offers(logger).await
+    }
+
+    /// Sets up the state channel connection unless it is already up, and
+    /// starts the banner timeout. Uplinks are handled one at a time, so
+    /// uplinks that arrive together before the first banner share the
+    /// connection set up for the first of them.
+    async fn connect(&mut self) -> Result {
+        if !self.state_channel.is_connected() {
+            self.state_channel.connect().await?;
+            self.emit(ClientEvent::Connected);
+        }
+        self.banners.start_timeout(time::Instant::now());
+        Ok(())
+    }
+
+    /// Schedules a reconnect after the reconnect backoff of a state channel
+    /// stream that ended for the given reason. A stream the router closed is
+    /// reconnected if the stream close policy asks for it, a failed stream if
+    /// keepalives are enabled. Returns false if the client should stop
+    /// instead.
+    fn handle_connection_lost(&mut self, logger: &Logger, reason: ExitReason) -> bool {
+        // Banners held for reordering belong to the lost stream
+        self.banners.clear_held();
+        let reconnect = match reason {
+            ExitReason::StreamError => self.settings.keepalive > 0,
+            _ => self.settings.stream_close == StreamClosePolicy::Reconnect,
+        };
+        if !reconnect {
+            return false;
+        }
+        let delay = reconnect_delay(self.settings.reconnect_backoff, self.reconnect_attempts);
+        self.reconnect_priority
+            .lost(self.oui, &self.client.uri.uri, self.clock.now());
+        self.reconnect_ranked = false;
+        info!(logger, "state channel connection lost, reconnecting";
+            "reason" => format!("{:?}", reason),
+            "delay" => delay.as_millis() as u64);
+        self.emit(ClientEvent::ConnectionLost);
+        self.state_channel.disconnect();
+        self.reconnect_attempts = self.reconnect_attempts.saturating_add(1);
+        self.reconnect_deadline = Some(time::Instant::now() + delay);
+        true
+    }
+
+    /// Holds off the first connect by a random delay of up to the configured
+    /// connect jitter.
+    fn start_connect_jitter(&mut self, logger: &Logger) {
+        let delay = connect_jitter(self.settings.connect_jitter, &mut rand::thread_rng());
+        if delay > Duration::from_secs(0) {
+            debug!(logger, "delaying first connect";
+                "delay" => delay.as_millis() as u64);
+            self.connect_deadline = Some(time::Instant::now() + delay);
+        }
+    }
+
+    /// Connects once the connect jitter has passed if uplinks were held in
+    /// the meantime, otherwise the next uplink connects as usual.
+    async fn handle_connect_jitter(&mut self, logger: &Logger) {
+        self.connect_deadline = None;
+        if self.store.packet_counts().await.0 == 0 {
+            return;
+        }
+        if let Err(err) = self.connect().await {
+            warn!(logger, "failed to connect {:?}", err);
+            return;
+        }
+        // With a known state channel the held uplinks need no banner
+        if self
+            .store
+            .state_channel_count()
+            .await
+            .map_or(false, |count| count > 0)
+        {
+            if let Err(err) = self.send_packet_offers(logger).await {
+                warn!(logger, "failed to send offers {:?}", err);
+            }
+        }
+    }
+
+    /// Reconnects once the reconnect backoff passed. The first time after a
+    /// lost connection the reconnect waits for busier routers that lost
+    /// their connection as well to reconnect first, which by the end of the
+    /// backoff includes all routers dropped by the same outage.
+    async fn handle_reconnect(&mut self, logger: &Logger) {
+        self.reconnect_deadline = None;
+        if !self.reconnect_ranked {
+            self.reconnect_ranked = true;
+            let wait =
+                self.reconnect_priority
+                    .delay(self.oui, &self.client.uri.uri, self.clock.now());
+            if wait > Duration::from_secs(0) {
+                debug!(logger, "waiting for busier routers to reconnect";
+                    "delay" => wait.as_millis() as u64);
+                self.reconnect_deadline = Some(time::Instant::now() + wait);
+                return;
+            }
+        }
+        if let Err(err) = self.connect().await {
+            let delay = reconnect_delay(self.settings.reconnect_backoff, self.reconnect_attempts);
+            warn!(logger, "failed to reconnect {:?}", err;
+                "delay" => delay.as_millis() as u64);
+            self.reconnect_attempts = self.reconnect_attempts.saturating_add(1);
+            self.reconnect_deadline = Some(time::Instant::now() + delay);
+        }
+    }
+
+    /// Reports the packets left undelivered when the client stops
+    async fn exit(&self, logger: &Logger, reason: ExitReason) -> RunExit {
+        self.reconnect_priority
+            .forget(self.oui, &self.client.uri.uri);
+        let (waiting, queued) = self.store.packet_counts().await;
+        let summary = self
+            .session_start
+            .as_ref()
+            .map(|(started, metrics)| self.metrics.snapshot().since(metrics, started.elapsed()))
+            .unwrap_or_default();
+        info!(logger, "stopped";
+            "reason" => format!("{:?}", reason),
+            "waiting" => waiting,
+            "queued" => queued,
+            "offers" => summary.offers,
+            "purchases" => summary.purchases,
+            "rejects" => summary.rejects,
+            "downlinks" => summary.downlinks,
+            "dc_spent" => summary.dc_spent,
+            "uptime" => summary.uptime.as_secs());
+        RunExit {
+            reason,
+            waiting,
+            queued,
+            summary,
+        }
+    }
+
+    /// Gives up on a connection that did not see a banner within the banner
+    /// timeout. The waiting packets are kept for the next connection, the
+    /// waiting queue bounds and packet expiry keep them from piling up.
+    async fn handle_banner_timeout(&mut self, logger: &Logger) {
+        self.banners.end_timeout();
+        let (waiting, _) = self.store.packet_counts().await;
+        warn!(logger, "no banner received after connect";
+            "timeout" => self.settings.banner_timeout,
+            "waiting" => waiting);
+        if self.settings.banner_retry {
+            self.state_channel.disconnect();
+            if let Err(err) = self.connect().await {
+                warn!(logger, "failed to reconnect {:?}", err);
+            }
+        }
+    }
+
+    /// Offers the waiting packets once no new banner arrived within the
+    /// banner debounce.
+    async fn handle_banner_debounce(&mut self, logger: &Logger) {
+        self.banners.end_debounce();
+        if let Err(err) = self.send_packet_offers(logger).await {
+            warn!(logger, "failed to send offers {:?}", err);
+        }
+    }
+
+    /// Offers the waiting packets held back by the shared offer rate limit
+    /// once the limit allows more offers.
+    async fn handle_offer_limit(&mut self, logger: &Logger) {
+        self.limited_offers.resume();
+        if let Err(err) = self.send_packet_offers(logger).await {
+            warn!(logger, "failed to send offers {:?}", err);
+        }
+    }
+
+    /// Drops offered packets the router did not purchase or reject within
+    /// the maximum queued age of the store and waits for the next queued
+    /// packet to time out.
+    async fn handle_offer_timeout(&mut self, logger: &Logger) {
+        let dropped = self.store.expire_offers().await;
+        if !dropped.is_empty() {
+            for packet in &dropped {
+                self.record_drop(packet, DropReason::OfferTimeout);
+            }
+            self.metrics.record_offer_timeouts(dropped.len());
+            info!(logger, "dropped unanswered offers";
+                "dropped" => dropped.len());
+        }
+        self.schedule_offer_timeout().await;
+    }
+
+    /// Wakes up when the longest waiting queued packet times out, if any. The
+    /// time out itself is decided by the clock of the store.
+    async fn schedule_offer_timeout(&mut self) {
+        self.offer_deadline = self
+            .store
+            .next_offer_expiry()
+            .await
+            .map(|expiry| time::Instant::now() + expiry);
+    }
+
+    /// Handles a state channel message, holding banners within the reorder
+    /// window so that banners and purchases for a state channel are applied
+    /// in nonce order.
+    async fn handle_state_channel_message(
+        &mut self,
+        logger: &Logger,
+        message: StateChannelMessage,
+    ) -> Result {
+        if !self.banners.is_reordering() {
+            return self.apply_state_channel_message(logger, message).await;
+        }
+        let (id, nonce) = match message.state_channel() {
+            Some(sc) => (sc.id.clone(), sc.nonce),
+            None => return self.apply_state_channel_message(logger, message).await,
+        };
+        if matches!(message.msg(), Msg::Banner(_)) {
+            // The banner only counts as received once it is applied
+            self.banners.hold(message, time::Instant::now());
+            return Ok(());
+        }
+        // Banners the purchase follows are applied before it, later ones stay
+        // held
+        for banner in self.banners.take_earlier(&id, nonce) {
+            self.apply_held_banner(logger, banner).await;
+        }
+        self.apply_state_channel_message(logger, message).await
+    }
+
+    /// Applies the held banners in nonce order once the reorder window
+    /// passes.
+    async fn release_held_banners(&mut self, logger: &Logger) {
+        for banner in self.banners.release() {
+            self.apply_held_banner(logger, banner).await;
+        }
+    }
+
+    /// Applies a held banner unless a later state of its state channel was
+    /// applied while it was held.
+    async fn apply_held_banner(&mut self, logger: &Logger, banner: StateChannelMessage) {
+        if let Some(sc) = banner.state_channel() {
+            if let Ok(Some(known_sc)) = self.store.get_state_channel(&sc.id).await {
+                if known_sc.nonce() > sc.nonce {
+                    debug!(logger, "dropping superseded banner";
+                        "sc_id" => known_sc.id_key(),
+                        "nonce" => sc.nonce,
+                        "known_nonce" => known_sc.nonce());
+                    return;
+                }
+            }
+        }
+        if let Err(err) = self.apply_state_channel_message(logger, banner).await {
+            warn!(logger, "failed to apply held banner {:?}", err);
+        }
+    }
+
+    async fn apply_state_channel_message(
+        &mut self,
+        logger: &Logger,
+        message: StateChannelMessage,
+    ) -> Result {
+        match message.msg() {
+            Msg::Response(response) => {
+                if let Some(packet) = Packet::from_state_channel_response(response.to_owned()) {
+                    self.handle_downlink(logger, &packet).await;
+                }
+                Ok(())
+            }
+            Msg::Packet(_) => Err(Error::custom("unexpected state channel packet message")),
+            Msg::Offer(_) => Err(Error::custom("unexpected state channel offer message")),
+            Msg::Purchase(purchase) => {
+                self.detect_protocol_from_purchase(logger, &purchase.packet_hash);
+                let duplicate = match self.store.purchased_packet(&purchase.packet_hash).await {
+                    // A queued retransmission of a sent payload carries its
+                    // own timestamp, so its purchase is not a duplicate
+                    Some((id, timestamp)) => self.sent_packets.contains(&id, timestamp),
+                    None => self.sent_packets.contains_hash(&purchase.packet_hash),
+                };
+                if duplicate {
+                    // The router sent the purchase again, the packet was
+                    // already delivered and paid for
+                    warn!(logger, "ignoring duplicate purchase";
+                        "packet_hash" => base64::encode(&purchase.packet_hash));
+                    self.metrics.record_duplicate_purchase();
+                    return Ok(());
+                }
+                if self.settings.expired_purchase == ExpiredPurchasePolicy::Ignore
+                    && self.store.is_expired_offer(&purchase.packet_hash).await
+                {
+                    // The purchased packet is no longer queued to deliver
+                    warn!(logger, "ignoring purchase of expired packet";
+                        "packet_hash" => base64::encode(&purchase.packet_hash));
+                    self.metrics.record_expired_purchase();
+                    return Ok(());
+                }
+                if self.settings.early_purchase == EarlyPurchasePolicy::Reject
+                    && self.store.state_channel_count().await? == 0
+                {
+                    return Err(StateChannelError::purchase_before_banner());
+                }
+                let packet = match self.settings.expired_purchase {
+                    ExpiredPurchasePolicy::Dequeue
+                        if self.store.is_expired_offer(&purchase.packet_hash).await =>
+                    {
+                        self.store.deque_packet().await
+                    }
+                    _ => {
+                        self.store
+                            .deque_purchased_packet(&purchase.packet_hash)
+                            .await
+                    }
+                };
+                let dc_tolerance = self.settings.purchase_dc_tolerance;
+                let keypair = self.keypair.clone();
+                let purchase_sc = self
+                    .mk_state_channel(logger, purchase.sc.to_owned(), |known_sc, new_sc| {
+                        if let Some(known_sc) = known_sc {
+                            return known_sc.is_valid_purchase(
+                                keypair.public_key(),
+                                new_sc,
+                                packet.as_ref(),
+                                dc_tolerance,
+                            );
+                        }
+                        Ok(())
+                    })
+                    .await;
+                let purchase_sc = match purchase_sc {
+                    Ok(purchase_sc) => purchase_sc,
+                    Err(err) => {
+                        if let (true, Some(packet)) = (self.lookup_failed, packet) {
+                            // Keep the packet for when the purchase is
+                            // handled again after the gateway recovers
+                            self.store.requeue_queued_packet(packet).await;
+                        }
+                        return Err(err);
+                    }
+                };
+                if self.protocol_version() == RouterProtocol::V2 {
+                    // Routers on the original protocol leave out the region
+                    // the packet was purchased in
+                    if let Some(region) = Region::from_i32(purchase.region) {
+                        self.sc_selector.record_region(purchase_sc.id_key(), region);
+                    }
+                }
+                let packet = match packet {
+                    Some(packet) => packet,
+                    None => {
+                        // A spurious or duplicate purchase. The state channel
+                        // is still the router's latest, but there is nothing
+                        // to deliver for it.
+                        warn!(logger, "ignoring purchase without queued packet";
+                            "sc_id" => purchase_sc.id_key());
+                        return Ok(());
+                    }
+                };
+                info!(logger, "received purchase";
+                    "sc_id" => purchase_sc.id_key());
+                self.trace(
+                    &packet,
+                    TraceStep::Purchased {
+                        sc_id: purchase_sc.id_key(),
+                    },
+                );
+                self.devaddr_metrics.record_purchase(packet.dev_addr());
+                self.metrics.record_purchase(packet.dc_payload());
+                self.reconnect_priority.record_purchase(
+                    self.oui,
+                    &self.client.uri.uri,
+                    self.clock.now(),
+                );
+                self.metrics
+                    .record_region_purchase(&self.traffic_region(packet.packet()));
+                self.emit(ClientEvent::Purchased {
+                    sc_id: purchase_sc.id_key(),
+                });
+                self.send_packet(logger, Some(&packet)).await?;
+                self.reconcile(logger, &purchase_sc, 1);
+                if self.offers_paused {
+                    // The purchase lowered the in flight exposure, resume
+                    // offers that were paused at the cap
+                    self.send_packet_offers(logger).await?;
+                }
+                Ok(())
+            }
+            Msg::Banner(banner) => {
+                let banner_sc = match self.known_banner_sc(banner.sc.as_ref()).await? {
+                    Some(known_sc) => known_sc,
+                    None => {
+                        self.mk_state_channel(logger, banner.sc.to_owned(), |_, _| Ok(()))
+                            .await?
+                    }
+                };
+                self.banners.record_banner();
+                info!(logger, "received banner";
+                    "sc_id" => banner_sc.id_key());
+                self.reconcile(logger, &banner_sc, 0);
+                self.sc_selector.record_banner(banner_sc.id_key());
+                if !self
+                    .sc_selector
+                    .is_valid_region(&banner_sc.id_key(), &self.region)
+                {
+                    warn!(logger, "not offering against state channel for other region";
+                        "sc_id" => banner_sc.id_key(),
+                        "sc_region" => self.sc_selector.region(&banner_sc.id_key()).map(|r| r.to_string()),
+                        "region" => self.region.to_string());
+                    return Ok(());
+                }
+                self.emit(ClientEvent::BannerReceived {
+                    sc_id: banner_sc.id_key(),
+                });
+                if self.banners.debounce(time::Instant::now()) {
+                    return Ok(());
+                }
+                self.send_packet_offers(logger).await
+            }
+            Msg::Reject(rejection) => {
+                let action = self.reject_action(rejection.reject);
+                debug!(logger, "received reject";
+                    "reason" => rejection.reject,
+                    "action" => format!("{:?}", action));
+                self.detect_protocol_from_reject(logger);
+                let packet = self.store.deque_packet().await;
+                let since_epoch = self
+                    .wall_clock
+                    .system_now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                let line = format!(
+                    "{} {} {}",
+                    since_epoch.as_secs(),
+                    rejection.reject,
+                    packet
+                        .as_ref()
+                        .map_or("-".to_string(), |packet| packet.id().to_string())
+                );
+                self.write_background(logger, BackgroundWrite::RejectLog(line))
+                    .await;
+                if let Some(packet) = packet {
+                    self.devaddr_metrics.record_reject(packet.dev_addr());
+                    self.trace(&packet, TraceStep::Rejected);
+                    if action != RejectAction::Redispatch || !self.redispatch(logger, &packet) {
+                        self.record_drop(&packet, DropReason::Rejected);
+                    }
+                }
+                self.metrics.record_reject();
+                self.emit(ClientEvent::Rejected);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the configured action for a rejection with the given reason
+    /// code, dropping the packet for codes that are not configured.
+    fn reject_action(&self, code: u32) -> RejectAction {
+        self.settings
+            .reject_actions
+            .iter()
+            .find(|setting| setting.code == code)
+            .map_or(RejectAction::Drop, |setting| setting.action)
+    }
+
+    /// Hands a rejected packet to the dispatcher for the default router.
+    /// Returns false if the packet could not be handed off.
+    fn redispatch(&mut self, logger: &Logger, packet: &QuePacket) -> bool {
+        let redispatch = match &self.redispatch {
+            Some(redispatch) => redispatch,
+            None => return false,
+        };
+        match redispatch.try_send(Redispatch {
+            uri: self.client.uri.uri.clone(),
+            packet: packet.packet().clone(),
+            offers: packet.offers(),
+        }) {
+            Ok(()) => {
+                self.metrics.record_redispatch();
+                true
+            }
+            Err(_) => {
+                warn!(logger, "dropping rejected packet, redispatch unavailable";
+                    "packet_id" => packet.id().to_string());
+                false
+            }
+        }
+    }
+
+    /// Returns the known state channel for a banner if the banner does not
+    /// change it, skipping validation and storage of an identical state
+    /// channel. Fails if the banner regresses the state channel nonce.
+    async fn known_banner_sc(
+        &self,
+        sc: Option<&BlockchainStateChannelV1>,
+    ) -> Result<Option<StateChannel>> {
+        let sc = match sc {
+            Some(sc) => sc,
+            None => return Ok(None),
+        };
+        match self.store.get_state_channel(&sc.id).await? {
+            Some(known_sc) if !known_sc.is_banner_update(sc)? => Ok(Some(known_sc)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reconciles the packets sent against the given state channel with the
+    /// packet count the router reports for this gateway.
+    fn reconcile(&mut self, logger: &Logger, sc: &StateChannel, sent: u64) {
+        if let Some(drift) = self
+            .accounting
+            .reconcile(sc, self.keypair.public_key(), sent)
+        {
+            warn!(logger, "router packet count drifted";
+                "sc_id" => drift.sc_id,
+                "expected" => drift.expected,
+                "reported" => drift.reported);
+            self.metrics.record_packet_drift();
+        }
+    }
+
+    /// Looks up the given state channel on the gateway, noting whether the
+    /// gateway failed to answer.
+    async fn lookup_state_channel(&mut self, sc: BlockchainStateChannelV1) -> Result<StateChannel> {
+        let result = self
+            .gateway_lookups
+            .run(StateChannel::from_sc(sc, &mut self.gateway))
+            .await;
+        if let Err(Error::Service(_)) = result {
+            self.lookup_failed = true;
+        }
+        result
+    }
+
+    async fn mk_state_channel<F>(
+        &mut self,
+        logger: &Logger,
+        sc: Option<BlockchainStateChannelV1>,
+        final_validation: F,
+    ) -> Result<StateChannel>
+    where
+        F: Fn(Option<&StateChannel>, &StateChannel) -> Result,
+    {
+        let logged_sc = if self.settings.log_rejected_sc {
+            sc.clone()
+        } else {
+            None
+        };
+        let validations = self.validations.clone();
+        let result = validations
+            .run(self.validate_state_channel(logger, sc, final_validation))
+            .await;
+        if let (Err(Error::StateChannel(err)), Some(sc)) = (&result, logged_sc) {
+            log_rejected_sc(logger, &sc, err);
+        }
+        result
+    }
+
+    async fn validate_state_channel<F>(
+        &mut self,
+        logger: &Logger,
+        sc: Option<BlockchainStateChannelV1>,
+        final_validation: F,
+    ) -> Result<StateChannel>
+    where
+        F: Fn(Option<&StateChannel>, &StateChannel) -> Result,
+    {
         if sc.is_none() {
             return Err(StateChannelError::not_found());
         }
@@ -175,27 +1984,32 @@ impl RouterClient {
             if sc.id == known_sc.id() {
                 let sc = known_sc.with_sc(sc)?;
                 final_validation(Some(&known_sc), &sc)?;
-                self.store
-                    .overwrite_state_channel(&sc.id_key(), &sc)
-                    .await?;
+                self.store_state_channel(&sc).await?;
+                self.owners.accept(&sc);
+                if sc.nonce() > known_sc.nonce() {
+                    self.emit_sc_lifecycle(ScLifecycle::Updated {
+                        sc_id: sc.id_key(),
+                        nonce: sc.nonce(),
+                    });
+                }
                 Ok(sc)
             } else {
                 // the new sc has a different id
-                let sc = StateChannel::from_sc(sc, &mut self.gateway).await?;
+                let sc = self.lookup_state_channel(sc).await?;
                 match known_sc.is_valid_sc_for(self.keypair.public_key(), &sc) {
                     Ok(()) => match final_validation(Some(&known_sc), &sc) {
                         Ok(()) => {
                             // TODO: Add check to ensure that the received state channel
                             // is newer than the last known one.
-                            self.store
-                                .overwrite_state_channel(&sc.id_key(), &sc)
-                                .await?;
+                            self.store_state_channel(&sc).await?;
+                            self.owners.accept(&sc);
+                            self.emit_sc_lifecycle(ScLifecycle::Created { sc_id: sc.id_key() });
                             Ok(sc)
                         }
                         Err(err) => Err(err),
                     },
                     Err(Error::StateChannel(err)) => {
-                        self.store.append_state_channel(&sc.id_key(), &sc).await?;
+                        self.write_rejected_state_channel(sc).await?;
                         Err(Error::StateChannel(err))
                     }
                     Err(err) => Err(err),
@@ -203,19 +2017,30 @@ impl RouterClient {
             }
         } else {
             // No previously known sc with that id
-            let sc = StateChannel::from_sc(sc, &mut self.gateway).await?;
-            match sc.is_valid_for(self.keypair.public_key()) {
+            let sc = self.lookup_state_channel(sc).await?;
+            // The gateway confirmed the state channel is active for its
+            // owner, which may be a rotated owner key
+            let validation = sc.is_valid_for(self.keypair.public_key()).and_then(|_| {
+                sc.is_valid_with(&self.owners.validation_for(&sc), self.gateway.height())
+            });
+            match validation {
                 Ok(()) => match final_validation(None, &sc) {
                     Ok(()) => {
-                        self.store
-                            .overwrite_state_channel(&sc.id_key(), &sc)
-                            .await?;
+                        self.store_state_channel(&sc).await?;
+                        if let Some(previous) = self.owners.rotation(&sc) {
+                            warn!(logger, "trusting rotated state channel owner";
+                                "sc_id" => sc.id_key(),
+                                "owner" => base64::encode(sc.owner()),
+                                "previous_owner" => base64::encode(previous));
+                        }
+                        self.owners.accept(&sc);
+                        self.emit_sc_lifecycle(ScLifecycle::Created { sc_id: sc.id_key() });
                         Ok(sc)
                     }
                     Err(err) => Err(err),
                 },
                 Err(Error::StateChannel(err)) => {
-                    self.store.append_state_channel(&sc.id_key(), &sc).await?;
+                    self.write_rejected_state_channel(sc).await?;
                     Err(Error::StateChannel(err))
                 }
                 Err(err) => Err(err),
@@ -224,52 +2049,4448 @@ impl RouterClient {
     }
 
     async fn handle_downlink(&mut self, logger: &Logger, packet: &helium_proto::Packet) {
-        match self.downlinks.send(packet.clone().into()).await {
-            Ok(()) => (),
-            Err(_) => {
-                warn!(logger, "failed to push downlink")
+        let packet = Packet::from(packet.clone());
+        let uplink = match self.recent_uplinks.match_downlink(&packet) {
+            Some(uplink) => uplink,
+            None => {
+                warn!(logger, "dropping unsolicited downlink {}", packet);
+                return;
             }
+        };
+        let packet_id = uplink.id;
+        if self
+            .downlink_dedup
+            .is_duplicate(&packet.hash(), self.clock.now())
+        {
+            debug!(logger, "dropping duplicate downlink {}", packet;
+                "packet_id" => packet_id.to_string());
+            self.metrics.record_duplicate_downlink();
+            return;
         }
+        // Transmit in the receive windows of the uplink the downlink answers
+        let packet = packet.with_transmit_time(uplink.timestamp, &self.region);
+        info!(logger, "forwarding downlink {}", packet;
+            "packet_id" => packet_id.to_string());
+        if let Some(dispatcher) = self.downlink_dispatcher.as_mut() {
+            dispatcher.dispatch(packet_id, packet);
+            return;
+        }
+        let timeout = Duration::from_millis(self.settings.downlink_timeout);
+        let delivery = downlink::deliver_with(
+            self.settings.downlink_send,
+            &self.downlinks,
+            packet.clone(),
+            timeout,
+        )
+        .await;
+        self.handle_delivery(
+            logger,
+            DispatchedDownlink {
+                id: packet_id,
+                packet,
+                delivery,
+            },
+        );
     }
 
-    async fn send_packet_offers(&mut self, logger: &Logger) -> Result {
-        if self.state_channel.capacity() == 0 {
-            return Ok(());
-        }
-        while let Some(packet) = self.store.pop_waiting_packet() {
-            self.send_offer(logger, &packet).await?;
-            self.store.que_packet(packet)?;
-            if self.state_channel.capacity() == 0 {
-                return Ok(());
+    fn handle_delivery(&mut self, logger: &Logger, dispatched: DispatchedDownlink) {
+        let DispatchedDownlink {
+            id: packet_id,
+            packet,
+            delivery,
+        } = dispatched;
+        match delivery {
+            DownlinkDelivery::Sent => {
+                if packet.is_join_accept() {
+                    // The DevAddr is only known if the router included it
+                    match packet.dev_addr() {
+                        Some(dev_addr) => self.recent_joins.record(dev_addr, self.clock.now()),
+                        None => self.recent_joins.record_accept(self.clock.now()),
+                    }
+                }
+                self.downlink_dedup.record(packet.hash(), self.clock.now());
+                self.downlink_capture.record(&packet);
+                self.message_tap.record_downlink(&packet);
+                self.metrics.record_downlink();
+                self.emit(ClientEvent::DownlinkForwarded);
             }
+            DownlinkDelivery::TimedOut | DownlinkDelivery::Full => {
+                warn!(logger, "dropping downlink, downlinks channel full";
+                    "packet_id" => packet_id.to_string())
+            }
+            DownlinkDelivery::Closed => self.downlinks_closed = true,
         }
-        Ok(())
     }
 
-    async fn send_offer(&mut self, _logger: &Logger, packet: &QuePacket) -> Result {
-        match StateChannelMessage::offer(
-            packet.packet().clone(),
-            &self.keypair,
-            self.region.clone(),
-        ) {
-            Ok(message) => Ok(self.state_channel.send(message.to_message()).await?),
-            Err(err) => Err(err),
+    /// Checks the economy mode flag, logging when it changed since the last
+    /// check. Returns true if offers should be suppressed.
+    fn check_economy_mode(&mut self, logger: &Logger) -> bool {
+        let (enabled, changed) = self.economy.check();
+        if changed && enabled {
+            info!(logger, "economy mode enabled, suppressing offers");
+        } else if changed {
+            info!(logger, "economy mode disabled, resuming offers");
         }
+        enabled
     }
 
-    async fn send_packet(&mut self, _logger: &Logger, packet: Option<&QuePacket>) -> Result {
-        if packet.is_none() {
-            return Ok(());
+    /// Returns the region traffic of the given packet is counted in, its
+    /// derived region or else the region of this client.
+    fn traffic_region(&self, packet: &Packet) -> Region {
+        packet.region().unwrap_or_else(|| self.region.clone())
+    }
+
+    async fn send_packet_offers(&mut self, logger: &Logger) -> Result {
+        self.offer_packets(logger, None).await.map(|_| ())
+    }
+
+    /// Offers the waiting packets of the given DevAddr right away, ahead of
+    /// the waiting packets of other devices, for example to drain a device
+    /// that is migrated to another gateway. A running client flushes the
+    /// devices dispatched to it with `Dispatch::FlushDevAddr`, see
+    /// `Dispatcher::devaddr_flush`. Offers stop like they do on a
+    /// banner when the state channel stream is full or the in flight DC cap
+    /// is reached, leaving the remaining packets waiting. Returns the number
+    /// of offered packets.
+    pub async fn flush_devaddr(&mut self, logger: &Logger, dev_addr: u32) -> Result<usize> {
+        self.offer_packets(logger, Some(dev_addr)).await
+    }
+
+    /// Offers waiting packets, of only the given DevAddr if set, and returns
+    /// the number of offered packets.
+    async fn offer_packets(&mut self, logger: &Logger, dev_addr: Option<u32>) -> Result<usize> {
+        let mut offered = 0;
+        if self.check_economy_mode(logger) || self.state_channel.capacity() == 0 {
+            return Ok(offered);
         }
-        let packet = packet.unwrap();
-        match StateChannelMessage::packet(
-            packet.packet().clone(),
-            &self.keypair,
-            self.region.clone(),
-            packet.hold_time().as_millis() as u64,
-        ) {
-            Ok(message) => Ok(self.state_channel.send(message.to_message()).await?),
-            Err(err) => Err(err),
+        // Offers held back by the offer rate limit resume, in order, once the
+        // limit allows
+        if self.limited_offers.is_held() {
+            return Ok(offered);
+        }
+        let selected = match self.selected_state_channel().await? {
+            Some(sc) => Some((sc.id_key(), sc.remaining_balance())),
+            None if self.store.state_channel_count().await? > 0 => {
+                debug!(logger, "not offering without an unexpired state channel");
+                return Ok(offered);
+            }
+            None => None,
+        };
+        // Offers in flight stay within the balance left on the selected state
+        // channel, as well as the in flight DC cap
+        let max_inflight_dc = match (&selected, self.settings.max_inflight_dc) {
+            (Some((sc_id, 0)), _) => {
+                debug!(logger, "not offering against an exhausted state channel";
+                    "sc_id" => sc_id);
+                return Ok(offered);
+            }
+            (Some((_, balance)), 0) => *balance,
+            (Some((_, balance)), max_inflight_dc) => max_inflight_dc.min(*balance),
+            (None, max_inflight_dc) => max_inflight_dc,
+        };
+        let sc_id = selected.map(|(sc_id, _)| sc_id);
+        loop {
+            let packet = match dev_addr {
+                Some(dev_addr) => {
+                    self.store
+                        .pop_offerable_devaddr_packet(dev_addr, max_inflight_dc)
+                        .await
+                }
+                None => self.store.pop_offerable_packet(max_inflight_dc).await,
+            };
+            let packet = match packet {
+                Some(packet) => packet,
+                None => break,
+            };
+            let max_offer_attempts = self.settings.max_offer_attempts;
+            if max_offer_attempts > 0 && packet.offers() >= max_offer_attempts {
+                warn!(logger, "dropping packet over maximum offer attempts";
+                    "packet_id" => packet.id().to_string(),
+                    "offers" => packet.offers());
+                self.record_drop(&packet, DropReason::MaxOfferAttempts);
+                self.metrics.record_max_offer_attempts_exceeded();
+                continue;
+            }
+            if let Some(wait) = self.limited_offers.try_acquire(self.clock.now()) {
+                debug!(logger, "pausing offers at offer rate limit";
+                    "wait" => wait.as_millis() as u64);
+                self.store.requeue_waiting_packet(packet).await?;
+                return Ok(offered);
+            }
+            match self.send_offer(logger, &packet, sc_id.as_deref()).await {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(err) => {
+                    // Put the packet back where it came from so it is offered
+                    // again, in order, on the next pass
+                    self.store.requeue_waiting_packet(packet).await?;
+                    return Err(err);
+                }
+            }
+            self.store
+                .que_packet(packet.with_region(self.region.clone()))
+                .await?;
+            if self.offer_deadline.is_none() {
+                self.schedule_offer_timeout().await;
+            }
+            offered += 1;
+            if self.state_channel.capacity() == 0 {
+                return Ok(offered);
+            }
+        }
+        if dev_addr.is_none() {
+            self.offers_paused = self.store.packet_counts().await.0 > 0;
+            if self.offers_paused {
+                debug!(logger, "pausing offers at in flight dc cap";
+                    "inflight_dc" => self.store.inflight_dc().await,
+                    "max_inflight_dc" => max_inflight_dc);
+            }
+        }
+        Ok(offered)
+    }
+
+    /// Offers the given packet to the router. Returns false if the packet
+    /// was dropped instead, because its payload exceeds the maximum payload
+    /// size of its region at its datarate.
+    async fn send_offer(
+        &mut self,
+        logger: &Logger,
+        packet: &QuePacket,
+        sc_id: Option<&str>,
+    ) -> Result<bool> {
+        let region = self.traffic_region(packet.packet());
+        if let Some(max_payload) = region.max_payload(&packet.datarate) {
+            if packet.payload().len() > max_payload {
+                debug!(logger, "dropping packet over maximum region payload";
+                    "packet_id" => packet.id().to_string(),
+                    "region" => region.to_string(),
+                    "datarate" => &packet.datarate,
+                    "size" => packet.payload().len(),
+                    "max_payload" => max_payload);
+                self.record_drop(packet, DropReason::Oversized);
+                self.metrics.record_oversized_drop();
+                return Ok(false);
+            }
+        }
+        match StateChannelMessage::offer(
+            packet.packet().clone(),
+            &self.keypair,
+            &self.region.profile(),
+            self.protocol_version(),
+        ) {
+            Ok(message) => {
+                let message = message.to_message();
+                self.state_channel.send(message.clone()).await?;
+                self.message_tap.record_sent(&message);
+                debug!(logger, "sent offer";
+                    "packet_id" => packet.id().to_string(),
+                    "sc_id" => sc_id);
+                self.devaddr_metrics.record_offer(packet.dev_addr());
+                if let Some(dev_addr) = packet.dev_addr() {
+                    self.offer_throttle.record(dev_addr, self.clock.now());
+                }
+                self.trace(packet, TraceStep::Offered);
+                self.metrics.record_offer();
+                self.metrics.record_region_offer(&region);
+                self.emit(ClientEvent::Offered {
+                    packet_hash: packet.hash(),
+                });
+                Ok(true)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn send_packet(&mut self, logger: &Logger, packet: Option<&QuePacket>) -> Result {
+        if packet.is_none() {
+            return Ok(());
+        }
+        let packet = packet.unwrap();
+        // Packets are delivered in the region they were offered in
+        let region = packet.region().unwrap_or(&self.region).clone();
+        let now = self.clock.now();
+        let hold_time =
+            region.adjust_hold_time(packet.hold_time_at(now), packet.packet().is_join_request());
+        // The packet message has no TTL field yet, the TTL is only logged
+        // until the protocol can carry it
+        let ttl = packet.ttl_at(now, &region);
+        match StateChannelMessage::packet(
+            packet.packet().clone(),
+            &self.keypair,
+            region,
+            hold_time.as_millis() as u64,
+            self.protocol_version(),
+        ) {
+            Ok(message) => {
+                let message = message.to_message();
+                self.state_channel.send(message.clone()).await?;
+                self.message_tap.record_sent(&message);
+                info!(logger, "sent packet";
+                    "packet_id" => packet.id().to_string(),
+                    "hold_time" => hold_time.as_millis() as u64,
+                    "ttl" => ttl.as_millis() as u64);
+                self.trace(packet, TraceStep::Sent);
+                self.recent_uplinks.record(packet);
+                self.sent_packets
+                    .record(packet.id().clone(), packet.packet().timestamp);
+                self.metrics.record_hold_time(hold_time);
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Waits for the next finished concurrent downlink delivery, forever when
+/// downlinks are delivered one at a time
+async fn next_dispatched(dispatcher: &mut Option<DownlinkDispatcher>) -> DispatchedDownlink {
+    match dispatcher {
+        Some(dispatcher) => dispatcher.delivered().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits for the state channel lookups of the given revalidation, if any,
+/// to finish, returning them along with the cause of the revalidation.
+async fn next_revalidation(
+    revalidation: &mut Option<(JoinHandle<Revalidation>, &'static str)>,
+) -> (std::result::Result<Revalidation, JoinError>, &'static str) {
+    match revalidation {
+        Some((lookups, cause)) => {
+            let (lookups, cause) = (lookups.await, *cause);
+            *revalidation = None;
+            (lookups, cause)
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// Logs how many state channels a revalidation for the given cause pruned.
+fn log_pruned(logger: &Logger, pruned: Result<usize>, cause: &'static str) {
+    match pruned {
+        Ok(0) => (),
+        Ok(pruned) => info!(logger, "pruned state channels";
+            "pruned" => pruned, "cause" => cause),
+        Err(err) => warn!(logger, "failed to revalidate state channels {:?}", err;
+            "cause" => cause),
+    }
+}
+
+async fn wait_until(deadline: Option<time::Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits for the next tick of the given interval, never completing without
+/// one
+async fn tick(interval: Option<&mut time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// Returns whether signatures made with the given keypair verify against the
+/// given public key
+fn signs_for(keypair: &Keypair, public_key: &PublicKey) -> bool {
+    const PROBE: &[u8] = b"router client config check";
+    keypair
+        .sign(PROBE)
+        .map(|signature| public_key.verify(PROBE, &signature).is_ok())
+        .unwrap_or(false)
+}
+
+/// Picks a random delay of up to the given number of milliseconds
+fn connect_jitter<R: rand::Rng>(max: u64, rng: &mut R) -> Duration {
+    if max == 0 {
+        return Duration::from_secs(0);
+    }
+    Duration::from_millis(rng.gen_range(0..=max))
+}
+
+/// The wait before the given number of the reconnect attempt, doubling the
+/// given backoff in milliseconds for every earlier attempt
+pub(crate) fn reconnect_delay(backoff: u64, attempts: u32) -> Duration {
+    let factor = 1u64.checked_shl(attempts).unwrap_or(u64::MAX);
+    Duration::from_millis(backoff.saturating_mul(factor)).min(MAX_RECONNECT_BACKOFF)
+}
+
+/// Whether the given uplink is weaker than the given minimum SNR, if any
+fn is_below_snr(uplink: &Packet, min_snr: Option<f32>) -> bool {
+    min_snr.map_or(false, |min_snr| uplink.snr < min_snr)
+}
+
+/// Whether handling the given message may need a state channel lookup on the
+/// gateway
+fn needs_gateway(message: &BlockchainStateChannelMessageV1) -> bool {
+    matches!(message.msg, Some(Msg::Banner(_)) | Some(Msg::Purchase(_)))
+}
+
+fn log_failed_uplink(logger: &Logger, packet_id: &PacketId, dev_addr: Option<u32>, err: &Error) {
+    warn!(logger, "ignoring failed uplink {:?}", err;
+        "packet_id" => packet_id.to_string(),
+        "dev_addr" => dev_addr.map(|dev_addr| format!("{:08x}", dev_addr)));
+}
+
+/// Logs the decoded contents of a state channel that failed validation. Each
+/// summary is logged as its encoded client key, packet and dc counts, with
+/// keys encoded the way state channel ids are everywhere else.
+fn log_rejected_sc(logger: &Logger, sc: &BlockchainStateChannelV1, err: &StateChannelError) {
+    let summaries: Vec<String> = sc
+        .summaries
+        .iter()
+        .map(|summary| {
+            format!(
+                "{}/{}/{}",
+                summary.client_pubkeybin.id_key(),
+                summary.num_packets,
+                summary.num_dcs
+            )
+        })
+        .collect();
+    debug!(logger, "rejected state channel {:?}", err;
+        "sc_id" => sc.id.id_key(),
+        "nonce" => sc.nonce,
+        "owner" => sc.owner.id_key(),
+        "credits" => sc.credits,
+        "summaries" => summaries.join(","));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        router::{
+            mock::{MockRouter, Step},
+            RegionCounts,
+        },
+        settings::{RegionFallback, ScSelection},
+        test_support::{mk_keyed_uri, mk_keypair, mk_public_key, mk_state_channel},
+        Clock, MockClock,
+    };
+    use slog::{Drain, Never, OwnedKVList, Record, KV};
+    use std::{
+        fmt::{self, Write},
+        path::PathBuf,
+        sync::Mutex,
+    };
+
+    /// A drain that captures the message and key values of each record
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    struct KeyValues(String);
+
+    impl slog::Serializer for KeyValues {
+        fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+            write!(self.0, " {}={}", key, val).expect("formatted key value");
+            Ok(())
+        }
+    }
+
+    impl Drain for Capture {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, values: &OwnedKVList) -> std::result::Result<(), Never> {
+            let mut kvs = KeyValues(record.msg().to_string());
+            record
+                .kv()
+                .serialize(record, &mut kvs)
+                .expect("serialized key values");
+            values
+                .serialize(record, &mut kvs)
+                .expect("serialized logger values");
+            self.0.lock().expect("capture lock").push(kvs.0);
+            Ok(())
+        }
+    }
+
+    fn mk_settings() -> (CacheSettings, ClientSettings) {
+        let mut config = config::Config::new();
+        config
+            .merge(config::File::with_name("config/default.toml"))
+            .expect("default config");
+        let mut cache_settings: CacheSettings = config.get("cache").expect("cache settings");
+        cache_settings.store = std::env::temp_dir().join("gateway-rs-test");
+        (
+            cache_settings,
+            config.get("client").expect("client settings"),
+        )
+    }
+
+    /// The uri of a router that is never connected to
+    const NO_ROUTER: &str = "http://127.0.0.1:1";
+
+    /// Creates a client for a router that is never connected to
+    async fn mk_client(settings: ClientSettings) -> RouterClient {
+        mk_client_for(NO_ROUTER, settings).await
+    }
+
+    async fn mk_client_for(router_uri: &str, settings: ClientSettings) -> RouterClient {
+        mk_client_with(router_uri, mk_config(settings)).await
+    }
+
+    /// Returns the configuration of test clients, with OUI 1 and a store in
+    /// the test directory.
+    fn mk_config(settings: ClientSettings) -> RouterClientConfig {
+        let (cache_settings, _) = mk_settings();
+        RouterClientConfig::default()
+            .with_oui(1)
+            .with_cache_settings(cache_settings)
+            .with_settings(settings)
+    }
+
+    async fn mk_client_with(router_uri: &str, config: RouterClientConfig) -> RouterClient {
+        let (downlinks, _) = mpsc::channel(10);
+        let gateway = GatewayService::new(mk_keyed_uri(NO_ROUTER)).expect("gateway");
+        RouterClient::new(
+            config,
+            mk_keyed_uri(router_uri),
+            gateway,
+            downlinks,
+            Arc::new(mk_keypair()).into(),
+        )
+        .await
+        .expect("router client")
+    }
+
+    fn mk_logger() -> Logger {
+        Logger::root(slog::Discard, o!())
+    }
+
+    fn mk_sc(nonce: u64, num_dcs: u64) -> BlockchainStateChannelV1 {
+        BlockchainStateChannelV1 {
+            id: vec![1],
+            credits: 100,
+            nonce,
+            summaries: vec![helium_proto::BlockchainStateChannelSummaryV1 {
+                client_pubkeybin: vec![1],
+                num_packets: num_dcs,
+                num_dcs,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn mk_active_sc(sc: &BlockchainStateChannelV1) -> StateChannel {
+        mk_expiring_sc(sc, 1000)
+    }
+
+    fn mk_expiring_sc(sc: &BlockchainStateChannelV1, expiry_at_block: u64) -> StateChannel {
+        mk_state_channel(sc, expiry_at_block, 100)
+    }
+
+    fn mk_purchase(sc: BlockchainStateChannelV1) -> StateChannelMessage {
+        StateChannelMessage::from(helium_proto::BlockchainStateChannelPurchaseV1 {
+            sc: Some(sc),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn purchase_against_active_state_channel() {
+        let (_, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        let active_sc = mk_active_sc(&mk_sc(1, 10));
+        client
+            .insert_active_state_channel(&active_sc)
+            .await
+            .unwrap();
+
+        // The purchase advances the active channel
+        let purchase_sc = mk_sc(2, 11);
+        client
+            .handle_state_channel_message(&logger, mk_purchase(purchase_sc.clone()))
+            .await
+            .unwrap();
+        let stored = client.store.get_state_channel(vec![1]).await.unwrap();
+        assert_eq!(
+            mk_active_sc(&purchase_sc).hash_key(),
+            stored.unwrap().hash_key()
+        );
+
+        // A purchase for more than the channel holds is refused
+        assert!(matches!(
+            client
+                .handle_state_channel_message(&logger, mk_purchase(mk_sc(3, 200)))
+                .await,
+            Err(Error::StateChannel(StateChannelError::Overpaid))
+        ));
+    }
+
+    #[tokio::test]
+    async fn purchase_dropping_our_summary() {
+        use crate::error::StateChannelSummaryError;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        let pubkeybin = client.keypair.public_key().to_vec();
+        let with_our_summary = |mut sc: BlockchainStateChannelV1, num_dcs| {
+            sc.summaries
+                .push(helium_proto::BlockchainStateChannelSummaryV1 {
+                    client_pubkeybin: pubkeybin.clone(),
+                    num_packets: num_dcs,
+                    num_dcs,
+                });
+            sc
+        };
+        client
+            .insert_active_state_channel(&mk_active_sc(&with_our_summary(mk_sc(1, 10), 5)))
+            .await
+            .unwrap();
+
+        client
+            .handle_state_channel_message(&logger, mk_purchase(with_our_summary(mk_sc(2, 10), 6)))
+            .await
+            .unwrap();
+        // The next purchase no longer accounts for this gateway
+        assert!(matches!(
+            client
+                .handle_state_channel_message(&logger, mk_purchase(mk_sc(3, 20)))
+                .await,
+            Err(Error::StateChannel(StateChannelError::Summary(
+                StateChannelSummaryError::Dropped
+            )))
+        ));
+        let stored = client.store.get_state_channel(vec![1]).await.unwrap();
+        // The stored channel still holds the last accepted purchase
+        assert_eq!(16, stored.unwrap().total_dcs());
+    }
+
+    #[tokio::test]
+    async fn logs_rejected_sc() {
+        for enabled in [false, true].iter() {
+            let (_, mut settings) = mk_settings();
+            assert!(!settings.log_rejected_sc);
+            settings.log_rejected_sc = *enabled;
+            let mut client = mk_client(settings).await;
+            let capture = Capture::default();
+            let logger = Logger::root(capture.clone(), o!());
+            client
+                .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+                .await
+                .unwrap();
+
+            // Overpaid, the summary exceeds the channel amount
+            let mut sc = mk_sc(3, 200);
+            sc.owner = vec![2];
+            assert!(client
+                .handle_state_channel_message(&logger, mk_purchase(sc))
+                .await
+                .is_err());
+            let records = capture.0.lock().unwrap();
+            let rejected = records
+                .iter()
+                .find(|record| record.starts_with("rejected state channel"));
+            if !enabled {
+                assert!(rejected.is_none());
+                continue;
+            }
+            let rejected = rejected.expect("rejected sc record");
+            assert!(rejected.contains("Overpaid"));
+            // Keys are encoded like sc ids, without padding
+            assert!(!rejected.contains("=="));
+            assert!(rejected.contains("sc_id=AQ"));
+            assert!(rejected.contains("nonce=3"));
+            assert!(rejected.contains("owner=Ag"));
+            assert!(rejected.contains("credits=100"));
+            assert!(rejected.contains("summaries=AQ/200/200"));
+        }
+    }
+
+    #[tokio::test]
+    async fn sc_reorder_window() {
+        let mk_banner = |sc| {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 { sc: Some(sc) })
+        };
+        let mut outcomes = vec![];
+        for purchase_first in [true, false].iter() {
+            let mut router = MockRouter::start(vec![]).await;
+            let (_, mut settings) = mk_settings();
+            assert_eq!(0, settings.sc_reorder_window);
+            settings.sc_reorder_window = 1000;
+            let mut client = mk_client_for(&router.uri, settings).await;
+            let logger = mk_logger();
+            client
+                .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+                .await
+                .unwrap();
+            que_offered(&client, 1).await;
+
+            // The banner follows the purchase, whichever arrives first
+            let mut messages = vec![mk_purchase(mk_sc(2, 11)), mk_banner(mk_sc(3, 11))];
+            if !purchase_first {
+                messages.reverse();
+            }
+            for message in messages {
+                client
+                    .handle_state_channel_message(&logger, message)
+                    .await
+                    .unwrap();
+            }
+            let sent = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent packet")
+                .expect("router message");
+            assert!(matches!(sent.msg, Some(Msg::Packet(_))));
+            assert!(client.banners.release_at().is_some());
+            client.release_held_banners(&logger).await;
+            assert!(client.banners.held().is_empty());
+
+            let stored = client
+                .store
+                .get_state_channel(vec![1])
+                .await
+                .unwrap()
+                .expect("stored sc");
+            assert_eq!(3, stored.nonce());
+            outcomes.push((
+                stored.hash(),
+                client.packet_counts().await,
+                client.metrics_snapshot().purchases,
+            ));
+        }
+        assert_eq!(outcomes[0], outcomes[1]);
+
+        // A held banner only counts as received once it is applied, so one
+        // that fails validation leaves the banner timeout running
+        let router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        settings.sc_reorder_window = 1000;
+        settings.banner_timeout = 10;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(5, 10)))
+            .await
+            .unwrap();
+        client.connect().await.unwrap();
+        let regressed = mk_banner(mk_sc(3, 10));
+        client
+            .handle_state_channel_message(&logger, regressed)
+            .await
+            .unwrap();
+        assert!(!client.banner_received());
+        client.release_held_banners(&logger).await;
+        assert!(!client.banner_received());
+        assert!(client.banners.timeout_at().is_some());
+
+        // Banners held when the stream is lost are not applied to the next
+        // stream
+        client
+            .handle_state_channel_message(&logger, mk_banner(mk_sc(6, 11)))
+            .await
+            .unwrap();
+        assert_eq!(1, client.banners.held().len());
+        client.handle_connection_lost(&logger, ExitReason::StreamClosed);
+        assert!(client.banners.held().is_empty());
+        assert!(client.banners.release_at().is_none());
+        assert_eq!(
+            5,
+            client
+                .store
+                .get_state_channel(vec![1])
+                .await
+                .unwrap()
+                .unwrap()
+                .nonce()
+        );
+    }
+
+    #[tokio::test]
+    async fn purchase_without_queued_packet() {
+        let (_, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+
+        client
+            .handle_state_channel_message(&logger, mk_purchase(mk_sc(2, 11)))
+            .await
+            .unwrap();
+        let records = capture.0.lock().unwrap();
+        assert!(records
+            .iter()
+            .any(|record| record.starts_with("ignoring purchase without queued packet")));
+        assert!(!records
+            .iter()
+            .any(|record| record.starts_with("received purchase")));
+        // Nothing was delivered or counted as a purchase
+        let snapshot = client.metrics_snapshot();
+        assert_eq!(0, snapshot.purchases);
+        assert_eq!(0, snapshot.dc_spent);
+        assert_eq!(0, snapshot.hold_time.p50);
+    }
+
+    #[tokio::test]
+    async fn early_purchase() {
+        for policy in [EarlyPurchasePolicy::Seed, EarlyPurchasePolicy::Reject].iter() {
+            let (_, mut settings) = mk_settings();
+            settings.early_purchase = *policy;
+            let mut client = mk_client(settings).await;
+            let logger = mk_logger();
+            que_offered(&client, 1).await;
+            assert_eq!(0, client.store.state_channel_count().await.unwrap());
+
+            let err = client
+                .handle_state_channel_message(&logger, mk_purchase(mk_sc(2, 11)))
+                .await
+                .expect_err("unvalidated purchase");
+            // Seeding looks the state channel up like for a banner, which
+            // fails against the unreachable gateway, while rejecting does
+            // not get that far
+            match policy {
+                EarlyPurchasePolicy::Seed => {
+                    assert!(matches!(err, Error::Service(_)));
+                    assert!(client.lookup_failed);
+                }
+                EarlyPurchasePolicy::Reject => {
+                    assert!(matches!(
+                        err,
+                        Error::StateChannel(StateChannelError::PurchaseBeforeBanner)
+                    ));
+                    assert!(!client.lookup_failed);
+                }
+            }
+            // Either way the queued packet is kept for a later purchase
+            assert_eq!((0, 1), client.packet_counts().await);
+            assert_eq!(0, client.store.state_channel_count().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn banner_timeout() {
+        let router = MockRouter::start(vec![]).await;
+        let (cache_settings, mut settings) = mk_settings();
+        settings.banner_timeout = 1;
+        assert!(settings.banner_retry);
+        let max_packets = cache_settings.max_packets as usize;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+
+        for attempt in 1..=3u8 {
+            client.connect().await.unwrap();
+            let deadline = client.banners.timeout_at().expect("banner deadline");
+            assert!(deadline <= time::Instant::now() + Duration::from_secs(1));
+            for tag in 1..=8 {
+                client
+                    .store
+                    .store_waiting_packet(Packet::from(helium_proto::Packet {
+                        payload: mk_payload(1, attempt * 10 + tag),
+                        ..Default::default()
+                    }))
+                    .await
+                    .unwrap();
+            }
+            let waiting = max_packets.min(attempt as usize * 8);
+            assert_eq!((waiting, 0), client.packet_counts().await);
+            client.handle_banner_timeout(&logger).await;
+            // The waiting packets are kept for the next connection, but do
+            // not pile up past the waiting queue bound across attempts
+            assert_eq!((waiting, 0), client.packet_counts().await);
+            // The retry connected again and restarted the timeout
+            assert!(client.state_channel.is_connected());
+            assert!(client.banners.timeout_at().is_some());
+        }
+        let records = capture.0.lock().unwrap();
+        assert_eq!(
+            3,
+            records
+                .iter()
+                .filter(|record| record.starts_with("no banner received after connect"))
+                .count()
+        );
+    }
+
+    async fn que_offered(client: &RouterClient, payload: u8) {
+        let packet = QuePacket::from(Packet::from(helium_proto::Packet {
+            payload: vec![payload],
+            ..Default::default()
+        }));
+        client
+            .store
+            .que_packet(packet.with_region(client.region.clone()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn region_change_keeps_queued() {
+        let (_, settings) = mk_settings();
+        assert_eq!(RegionChangePolicy::Keep, settings.region_change);
+        let mut client = mk_client(settings).await;
+        let old_region = client.region.clone();
+        que_offered(&client, 1).await;
+        let new_region = Region::from_i32(1).unwrap();
+        client
+            .set_region(&mk_logger(), new_region.clone())
+            .await
+            .unwrap();
+        assert_eq!(new_region, client.region);
+        let packet = client.store.deque_packet().await.unwrap();
+        assert_eq!(Some(&old_region), packet.region());
+    }
+
+    #[tokio::test]
+    async fn region_change_reoffers_queued() {
+        let (_, mut settings) = mk_settings();
+        settings.region_change = RegionChangePolicy::Reoffer;
+        let economy_mode = EconomyMode::default();
+        // Suppress the actual offers, this client has no router to talk to
+        economy_mode.set(true);
+        let mut client = mk_client_with(
+            NO_ROUTER,
+            mk_config(settings).with_economy_mode(economy_mode),
+        )
+        .await;
+        que_offered(&client, 1).await;
+        que_offered(&client, 2).await;
+        client
+            .set_region(&mk_logger(), Region::from_i32(1).unwrap())
+            .await
+            .unwrap();
+        assert_eq!((2, 0), client.store.packet_counts().await);
+        assert!(client
+            .store
+            .pop_waiting_packet()
+            .await
+            .unwrap()
+            .region()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn region_change_dispatched() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        client
+            .store
+            .store_waiting_packet(mk_devaddr_uplink(1, 0.0))
+            .await
+            .unwrap();
+
+        // A running client offers in the region dispatched to it
+        let eu868 = Region::from_i32(1).unwrap();
+        let (dispatch, uplinks) = mpsc::channel(10);
+        dispatch
+            .send(Dispatch::Region(eu868.clone()))
+            .await
+            .unwrap();
+        dispatch.send(Dispatch::FlushDevAddr(1)).await.unwrap();
+        let (shutdown, shutdown_listener) = triggered::trigger();
+        let watch = async {
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent offer")
+                .expect("router message");
+            shutdown.trigger();
+            message
+        };
+        let (exit, message) = tokio::join!(client.run(uplinks, shutdown_listener, &logger), watch);
+        assert_eq!(ExitReason::Shutdown, exit.unwrap().reason);
+        match message.msg {
+            Some(Msg::Offer(offer)) => assert_eq!(i32::from(&eu868), offer.region),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert_eq!(eu868, client.region);
+    }
+
+    #[tokio::test]
+    async fn economy_mode() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let economy_mode = EconomyMode::default();
+        economy_mode.set(true);
+        let mut client = mk_client_with(
+            &router.uri,
+            mk_config(settings).with_economy_mode(economy_mode.clone()),
+        )
+        .await;
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        let mut uplinks = (1..).map(|tag| {
+            let mut uplink = mk_devaddr_uplink(1, 0.0).to_packet();
+            uplink.payload = mk_payload(1, tag);
+            Packet::from(uplink)
+        });
+        // Whether the next message the router receives, if any, is an offer
+        async fn offered(router: &mut MockRouter) -> Option<bool> {
+            time::timeout(Duration::from_millis(200), router.received.recv())
+                .await
+                .ok()
+                .flatten()
+                .map(|message| matches!(message.msg, Some(Msg::Offer(_))))
+        }
+
+        // Uplinks are stored but not offered while the flag is set
+        for _ in 0..2 {
+            let uplink = uplinks.next().unwrap();
+            client.handle_uplink(&logger, uplink).await.unwrap();
+        }
+        assert_eq!(None, offered(&mut router).await);
+        assert_eq!((2, 0), client.packet_counts().await);
+
+        // Clearing the flag offers the stored packets along with the next
+        economy_mode.set(false);
+        let uplink = uplinks.next().unwrap();
+        client.handle_uplink(&logger, uplink).await.unwrap();
+        for _ in 0..3 {
+            assert_eq!(Some(true), offered(&mut router).await);
+        }
+        assert_eq!((0, 3), client.packet_counts().await);
+
+        economy_mode.set(true);
+        let uplink = uplinks.next().unwrap();
+        client.handle_uplink(&logger, uplink).await.unwrap();
+        assert_eq!(None, offered(&mut router).await);
+        assert_eq!((1, 3), client.packet_counts().await);
+        assert_eq!(3, client.metrics_snapshot().offers);
+
+        let records = capture.0.lock().unwrap();
+        let changes: Vec<&str> = records
+            .iter()
+            .filter(|record| record.starts_with("economy mode"))
+            .map(|record| record.as_str())
+            .collect();
+        assert_eq!(
+            vec![
+                "economy mode enabled, suppressing offers",
+                "economy mode disabled, resuming offers",
+                "economy mode enabled, suppressing offers",
+            ],
+            changes
+        );
+    }
+
+    #[tokio::test]
+    async fn economy_mode_on_router_messages() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        settings.max_inflight_dc = 1;
+        let economy_mode = EconomyMode::default();
+        let mut client = mk_client_with(
+            &router.uri,
+            mk_config(settings).with_economy_mode(economy_mode.clone()),
+        )
+        .await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        for payload in 1..=2 {
+            client
+                .store
+                .store_waiting_packet(Packet::from(helium_proto::Packet {
+                    payload: vec![payload],
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+        }
+        async fn next_msg(router: &mut MockRouter) -> Option<Msg> {
+            time::timeout(Duration::from_millis(200), router.received.recv())
+                .await
+                .ok()
+                .flatten()
+                .and_then(|message| message.msg)
+        }
+        let mk_banner = |sc| {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 { sc: Some(sc) })
+        };
+        // Offers pause at the cap with the second packet waiting
+        assert_eq!(1, client.offer_packets(&logger, None).await.unwrap());
+        assert!(matches!(next_msg(&mut router).await, Some(Msg::Offer(_))));
+
+        // With economy mode on, a purchase still delivers the packet the
+        // router paid for but does not resume the paused offers
+        economy_mode.set(true);
+        client
+            .handle_state_channel_message(&logger, mk_purchase(mk_sc(2, 11)))
+            .await
+            .unwrap();
+        assert!(matches!(next_msg(&mut router).await, Some(Msg::Packet(_))));
+        assert!(next_msg(&mut router).await.is_none());
+        // and neither does a banner
+        client
+            .handle_state_channel_message(&logger, mk_banner(mk_sc(2, 11)))
+            .await
+            .unwrap();
+        assert!(next_msg(&mut router).await.is_none());
+        assert_eq!((1, 0), client.packet_counts().await);
+
+        // The next banner after economy mode ends offers the waiting packet
+        economy_mode.set(false);
+        client
+            .handle_state_channel_message(&logger, mk_banner(mk_sc(2, 11)))
+            .await
+            .unwrap();
+        assert!(matches!(next_msg(&mut router).await, Some(Msg::Offer(_))));
+        assert_eq!((0, 1), client.packet_counts().await);
+    }
+
+    #[tokio::test]
+    async fn banner_region() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        async fn next_msg(router: &mut MockRouter) -> Option<Msg> {
+            time::timeout(Duration::from_millis(200), router.received.recv())
+                .await
+                .ok()
+                .flatten()
+                .and_then(|message| message.msg)
+        }
+        let mk_banner = |sc| {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 { sc: Some(sc) })
+        };
+
+        // A banner for a state channel in the region of the client
+        client
+            .store
+            .store_waiting_packet(mk_devaddr_uplink(1, 0.0))
+            .await
+            .unwrap();
+        client
+            .handle_state_channel_message(&logger, mk_banner(mk_sc(1, 10)))
+            .await
+            .unwrap();
+        assert!(matches!(next_msg(&mut router).await, Some(Msg::Offer(_))));
+        assert_eq!((0, 1), client.packet_counts().await);
+
+        // The router purchases the packet in another region against the
+        // state channel
+        let eu868 = Region::from_i32(1).unwrap();
+        client
+            .handle_state_channel_message(
+                &logger,
+                StateChannelMessage::from(helium_proto::BlockchainStateChannelPurchaseV1 {
+                    sc: Some(mk_sc(2, 11)),
+                    region: eu868.clone().into(),
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(next_msg(&mut router).await, Some(Msg::Packet(_))));
+
+        // Banners for the state channel no longer offer the waiting packets
+        client
+            .store
+            .store_waiting_packet(mk_devaddr_uplink(2, 0.0))
+            .await
+            .unwrap();
+        client
+            .handle_state_channel_message(&logger, mk_banner(mk_sc(2, 11)))
+            .await
+            .unwrap();
+        assert!(next_msg(&mut router).await.is_none());
+        assert_eq!((1, 0), client.packet_counts().await);
+        assert!(client.selected_state_channel().await.unwrap().is_none());
+        let records = capture.0.lock().unwrap();
+        assert_eq!(
+            1,
+            records
+                .iter()
+                .filter(|record| record
+                    .starts_with("not offering against state channel for other region"))
+                .filter(|record| record.contains("sc_region=EU868"))
+                .count()
+        );
+    }
+
+    #[tokio::test]
+    async fn inflight_dc_cap() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        settings.max_inflight_dc = 2;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        async fn next_msg(router: &mut MockRouter) -> Option<Msg> {
+            time::timeout(Duration::from_millis(200), router.received.recv())
+                .await
+                .ok()
+                .flatten()
+                .and_then(|message| message.msg)
+        }
+
+        // Uplinks are offered up to the cap, the rest wait
+        for tag in 1..=3 {
+            let mut uplink = mk_devaddr_uplink(1, 0.0).to_packet();
+            uplink.payload = mk_payload(1, tag);
+            client
+                .handle_uplink(&logger, Packet::from(uplink))
+                .await
+                .unwrap();
+        }
+        for _ in 0..2 {
+            assert!(matches!(next_msg(&mut router).await, Some(Msg::Offer(_))));
+        }
+        assert!(next_msg(&mut router).await.is_none());
+        assert_eq!((1, 2), client.packet_counts().await);
+
+        // A purchase delivers a queued packet and resumes offers
+        client
+            .handle_state_channel_message(&logger, mk_purchase(mk_sc(2, 11)))
+            .await
+            .unwrap();
+        assert!(matches!(next_msg(&mut router).await, Some(Msg::Packet(_))));
+        assert!(matches!(next_msg(&mut router).await, Some(Msg::Offer(_))));
+        assert!(next_msg(&mut router).await.is_none());
+        assert_eq!((0, 2), client.packet_counts().await);
+    }
+
+    #[tokio::test]
+    async fn balance_caps_offers() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        assert_eq!(0, settings.max_inflight_dc);
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        async fn offers(router: &mut MockRouter) -> usize {
+            let mut offers = 0;
+            while let Ok(Some(message)) =
+                time::timeout(Duration::from_millis(200), router.received.recv()).await
+            {
+                if matches!(message.msg, Some(Msg::Offer(_))) {
+                    offers += 1;
+                }
+            }
+            offers
+        }
+        let uplinks = |tags: std::ops::RangeInclusive<u8>| {
+            tags.map(|tag| {
+                let mut uplink = mk_devaddr_uplink(1, 0.0).to_packet();
+                uplink.payload = mk_payload(1, tag);
+                Packet::from(uplink)
+            })
+        };
+
+        // Nothing is offered against a state channel without any balance
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 100)))
+            .await
+            .unwrap();
+        for uplink in uplinks(1..=2) {
+            client.handle_uplink(&logger, uplink).await.unwrap();
+        }
+        assert_eq!(0, offers(&mut router).await);
+        assert_eq!((2, 0), client.packet_counts().await);
+
+        // Offers in flight stay within the balance left on the selected state
+        // channel, one DC per packet here
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 97)))
+            .await
+            .unwrap();
+        for uplink in uplinks(3..=4) {
+            client.handle_uplink(&logger, uplink).await.unwrap();
+        }
+        assert_eq!(3, offers(&mut router).await);
+        assert_eq!((1, 3), client.packet_counts().await);
+    }
+
+    #[tokio::test]
+    async fn shutdown_reports_unflushed() {
+        let (_, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        for payload in 1..=2 {
+            client
+                .store
+                .store_waiting_packet(Packet::from(helium_proto::Packet {
+                    payload: vec![payload],
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+        }
+        que_offered(&client, 3).await;
+
+        let (trigger, shutdown) = triggered::trigger();
+        trigger.trigger();
+        let (_uplinks_tx, uplinks) = mpsc::channel(1);
+        let exit = client.run(uplinks, shutdown, &mk_logger()).await.unwrap();
+        assert_eq!(ExitReason::Shutdown, exit.reason);
+        assert_eq!((2, 1), (exit.waiting, exit.queued));
+        assert_eq!(
+            SessionSummary {
+                uptime: exit.summary.uptime,
+                ..Default::default()
+            },
+            exit.summary
+        );
+    }
+
+    #[tokio::test]
+    async fn event_sequence() {
+        // A data downlink to DevAddr 1
+        let downlink = helium_proto::Packet {
+            payload: vec![0x60, 1, 0, 0, 0, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        };
+        let sc = mk_sc(1, 10);
+        let router = MockRouter::start(vec![vec![
+            Step::Send(
+                StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 {
+                    sc: Some(sc.clone()),
+                })
+                .to_message(),
+            ),
+            Step::Receive,
+            Step::Send(mk_purchase(mk_sc(2, 11)).to_message()),
+            Step::Receive,
+            Step::Send(
+                StateChannelMessage::from(helium_proto::BlockchainStateChannelResponseV1 {
+                    downlink: Some(downlink),
+                    ..Default::default()
+                })
+                .to_message(),
+            ),
+            Step::Send(
+                StateChannelMessage::from(
+                    helium_proto::BlockchainStateChannelRejectionV1::default(),
+                )
+                .to_message(),
+            ),
+        ]])
+        .await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let (downlinks, _downlinks) = mpsc::channel(10);
+        client.downlinks = downlinks;
+        let active_sc = mk_active_sc(&sc);
+        client
+            .insert_active_state_channel(&active_sc)
+            .await
+            .unwrap();
+        let uplink = mk_devaddr_uplink(1, 0.0);
+        client
+            .store
+            .store_waiting_packet(uplink.clone())
+            .await
+            .unwrap();
+        let mut events = client.subscribe();
+        client.connect().await.unwrap();
+
+        let (_uplinks, uplinks) = mpsc::channel(10);
+        let (shutdown, shutdown_listener) = triggered::trigger();
+        let logger = mk_logger();
+        let watch = async {
+            let mut received = vec![];
+            while received.last() != Some(&ClientEvent::Rejected) {
+                let event = time::timeout(Duration::from_secs(10), events.recv())
+                    .await
+                    .expect("client event")
+                    .expect("event subscription");
+                received.push(event);
+            }
+            shutdown.trigger();
+            received
+        };
+        let (exit, received) = tokio::join!(client.run(uplinks, shutdown_listener, &logger), watch);
+        assert_eq!(ExitReason::Shutdown, exit.unwrap().reason);
+        assert_eq!(
+            vec![
+                ClientEvent::Connected,
+                ClientEvent::BannerReceived {
+                    sc_id: active_sc.id_key()
+                },
+                ClientEvent::Offered {
+                    packet_hash: uplink.hash()
+                },
+                ClientEvent::Purchased {
+                    sc_id: active_sc.id_key()
+                },
+                ClientEvent::DownlinkForwarded,
+                ClientEvent::Rejected,
+            ],
+            received
+        );
+    }
+
+    #[tokio::test]
+    async fn session_summary() {
+        // A data downlink to DevAddr 1
+        let downlink = helium_proto::Packet {
+            payload: vec![0x60, 1, 0, 0, 0, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        };
+        let router = MockRouter::start(vec![vec![
+            Step::Send(mk_purchase(mk_sc(2, 11)).to_message()),
+            Step::Receive,
+            Step::Send(
+                StateChannelMessage::from(helium_proto::BlockchainStateChannelResponseV1 {
+                    downlink: Some(downlink),
+                    ..Default::default()
+                })
+                .to_message(),
+            ),
+            Step::Send(
+                StateChannelMessage::from(
+                    helium_proto::BlockchainStateChannelRejectionV1::default(),
+                )
+                .to_message(),
+            ),
+        ]])
+        .await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let (downlinks, _downlinks) = mpsc::channel(10);
+        client.downlinks = downlinks;
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        // The purchased packet the downlink answers, and a rejected one
+        client
+            .store
+            .que_packet(
+                QuePacket::from(mk_devaddr_uplink(1, 0.0)).with_region(client.region.clone()),
+            )
+            .await
+            .unwrap();
+        que_offered(&client, 2).await;
+        // Activity before the run is not part of its summary
+        client.metrics.record_offer();
+        client.connect().await.unwrap();
+        let mut events = client.subscribe();
+
+        let (_uplinks, uplinks) = mpsc::channel(10);
+        let (shutdown, shutdown_listener) = triggered::trigger();
+        let logger = mk_logger();
+        let watch = async {
+            let mut pending = vec![ClientEvent::DownlinkForwarded, ClientEvent::Rejected];
+            while !pending.is_empty() {
+                let event = time::timeout(Duration::from_secs(10), events.recv())
+                    .await
+                    .expect("session event")
+                    .expect("client event");
+                pending.retain(|pending| *pending != event);
+            }
+            shutdown.trigger();
+        };
+        let (exit, _) = tokio::join!(client.run(uplinks, shutdown_listener, &logger), watch);
+        let summary = exit.unwrap().summary;
+        assert_eq!(
+            SessionSummary {
+                offers: 0,
+                purchases: 1,
+                rejects: 1,
+                downlinks: 1,
+                dc_spent: 1,
+                uptime: summary.uptime,
+            },
+            summary
+        );
+        assert!(summary.uptime > Duration::from_secs(0));
+        assert_eq!(1, client.metrics_snapshot().offers);
+    }
+
+    #[tokio::test]
+    async fn offer_timeout_drops_unanswered() {
+        let (cache_settings, settings) = mk_settings();
+        let clock = MockClock::default();
+        let mut client = mk_client_with(
+            NO_ROUTER,
+            mk_config(settings).with_clock(Arc::new(clock.clone())),
+        )
+        .await;
+        que_offered(&client, 1).await;
+        clock
+            .advance(Duration::from_secs(cache_settings.max_queued_age) + Duration::from_millis(1));
+        client.handle_offer_timeout(&mk_logger()).await;
+        assert_eq!((0, 0), client.store.packet_counts().await);
+        assert_eq!(1, client.metrics_snapshot().offer_timeouts);
+        assert!(client.offer_deadline.is_none());
+    }
+
+    #[tokio::test]
+    async fn purchase_after_expiry() {
+        use crate::settings::ExpiredPurchasePolicy;
+        for policy in [
+            ExpiredPurchasePolicy::Ignore,
+            ExpiredPurchasePolicy::Dequeue,
+        ]
+        .iter()
+        {
+            let mut router = MockRouter::start(vec![]).await;
+            let (cache_settings, mut settings) = mk_settings();
+            assert_eq!(ExpiredPurchasePolicy::Ignore, settings.expired_purchase);
+            settings.expired_purchase = *policy;
+            let max_queued_age = Duration::from_secs(cache_settings.max_queued_age);
+            let clock = MockClock::default();
+            let mut client = mk_client_with(
+                &router.uri,
+                mk_config(settings).with_clock(Arc::new(clock.clone())),
+            )
+            .await;
+            let logger = mk_logger();
+            client
+                .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+                .await
+                .unwrap();
+            // The first packet expires before its purchase arrives
+            que_offered(&client, 1).await;
+            clock.advance(max_queued_age / 2);
+            que_offered(&client, 2).await;
+            clock.advance(max_queued_age / 2 + Duration::from_millis(1));
+            client.handle_offer_timeout(&logger).await;
+            assert_eq!((0, 1), client.store.packet_counts().await);
+
+            let expired_hash = Packet::from(helium_proto::Packet {
+                payload: vec![1],
+                ..Default::default()
+            })
+            .hash();
+            let purchase =
+                StateChannelMessage::from(helium_proto::BlockchainStateChannelPurchaseV1 {
+                    sc: Some(mk_sc(2, 11)),
+                    packet_hash: expired_hash,
+                    ..Default::default()
+                });
+            client
+                .handle_state_channel_message(&logger, purchase)
+                .await
+                .unwrap();
+            let sent = time::timeout(Duration::from_millis(500), router.received.recv()).await;
+            match policy {
+                ExpiredPurchasePolicy::Ignore => {
+                    assert!(sent.is_err());
+                    assert_eq!((0, 1), client.store.packet_counts().await);
+                    assert_eq!(1, client.metrics_snapshot().expired_purchases);
+                }
+                ExpiredPurchasePolicy::Dequeue => {
+                    // The packet queued after the expired one goes out
+                    let message = sent.expect("sent packet").expect("router message");
+                    match message.msg {
+                        Some(Msg::Packet(packet)) => {
+                            assert_eq!(vec![2], packet.packet.unwrap().payload)
+                        }
+                        other => panic!("unexpected message {:?}", other),
+                    }
+                    assert_eq!((0, 0), client.store.packet_counts().await);
+                    assert_eq!(0, client.metrics_snapshot().expired_purchases);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn config_defaults() {
+        let (cache_settings, defaults) = mk_settings();
+        let client = mk_client_with(
+            NO_ROUTER,
+            RouterClientConfig::default().with_cache_settings(cache_settings),
+        )
+        .await;
+        assert_eq!(0, client.oui);
+        assert_eq!(Region::from_i32(0).unwrap(), client.region);
+        assert_eq!(defaults.banner_timeout, client.settings.banner_timeout);
+        assert_eq!(defaults.message_buffer, client.sc_messages.capacity());
+        assert!(!client.economy.mode().is_enabled());
+    }
+
+    #[tokio::test]
+    async fn config_overrides() {
+        let (cache_settings, mut settings) = mk_settings();
+        settings.banner_timeout = 5;
+        settings.message_buffer = 4;
+        let economy_mode = EconomyMode::default();
+        economy_mode.set(true);
+        let clock = MockClock::default();
+        let config = RouterClientConfig::default()
+            .with_oui(7)
+            .with_region(Region::from_i32(1).unwrap())
+            .with_cache_settings(cache_settings)
+            .with_settings(settings)
+            .with_economy_mode(economy_mode)
+            .with_clock(Arc::new(clock.clone()));
+        let client = mk_client_with(NO_ROUTER, config).await;
+        assert_eq!(7, client.oui);
+        assert_eq!(Region::from_i32(1).unwrap(), client.region);
+        assert_eq!(5, client.settings.banner_timeout);
+        assert_eq!(4, client.sc_messages.capacity());
+        assert!(client.economy.mode().is_enabled());
+        assert_eq!(clock.now(), client.clock.now());
+    }
+
+    #[tokio::test]
+    async fn gateway_outage_holds_messages() {
+        let (_, mut settings) = mk_settings();
+        settings.gateway_failures = 1;
+        let mut client = mk_client(settings).await;
+        let mut events = client.subscribe();
+        let logger = mk_logger();
+        let sc = mk_sc(1, 10);
+
+        // The gateway of this client is unreachable, so looking up the
+        // unknown state channel of the banner fails
+        client.sc_messages.push(
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 {
+                sc: Some(sc.clone()),
+            })
+            .to_message(),
+        );
+        client.handle_buffered_messages(&logger).await;
+        assert!(!client.gateway_health.is_available());
+        assert_eq!(1, client.gateway_health.held_count());
+        assert!(client.gateway_retry.is_some());
+        assert_eq!(ClientEvent::GatewayUnavailable, events.try_recv().unwrap());
+
+        // Messages arriving during the outage are held without a lookup
+        let purchase_sc = mk_sc(2, 11);
+        client
+            .sc_messages
+            .push(mk_purchase(purchase_sc.clone()).to_message());
+        client.handle_buffered_messages(&logger).await;
+        assert_eq!(2, client.gateway_health.held_count());
+
+        // Once the state channel is known its messages validate without a
+        // lookup, which stands in for the gateway recovering
+        client
+            .insert_active_state_channel(&mk_active_sc(&sc))
+            .await
+            .unwrap();
+        client.retry_held_messages(&logger).await;
+        assert!(client.gateway_health.is_available());
+        assert_eq!(0, client.gateway_health.held_count());
+        assert!(client.gateway_retry.is_none());
+        let mut recovered = vec![];
+        while let Ok(event) = events.try_recv() {
+            recovered.push(event);
+        }
+        assert!(recovered.contains(&ClientEvent::GatewayAvailable));
+        // The held purchase was handled after the banner
+        let stored = client.store.get_state_channel(vec![1]).await.unwrap();
+        assert_eq!(
+            mk_active_sc(&purchase_sc).hash_key(),
+            stored.unwrap().hash_key()
+        );
+    }
+
+    fn mk_snr_uplink(snr: f32) -> Packet {
+        Packet::from(helium_proto::Packet {
+            payload: vec![1],
+            snr,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn min_snr() {
+        assert!(!is_below_snr(&mk_snr_uplink(-20.0), None));
+        assert!(is_below_snr(&mk_snr_uplink(-20.0), Some(-15.0)));
+        assert!(!is_below_snr(&mk_snr_uplink(-15.0), Some(-15.0)));
+        assert!(!is_below_snr(&mk_snr_uplink(5.5), Some(-15.0)));
+    }
+
+    #[tokio::test]
+    async fn drops_low_snr_uplink() {
+        let (_, mut settings) = mk_settings();
+        assert!(settings.min_snr.is_none());
+        settings.min_snr = Some(-15.0);
+        let mut client = mk_client(settings).await;
+        client
+            .handle_uplink(&mk_logger(), mk_snr_uplink(-20.0))
+            .await
+            .unwrap();
+        assert_eq!(1, client.metrics_snapshot().low_snr_drops);
+        // The dropped uplink did not cause a connection to the router
+        assert!(!client.state_channel.is_connected());
+    }
+
+    /// An unconfirmed data up PHYPayload from the given DevAddr, with the
+    /// given tag as its FRMPayload to tell payloads apart
+    fn mk_payload(dev_addr: u32, tag: u8) -> Vec<u8> {
+        let mut payload = vec![0x40];
+        payload.extend_from_slice(&dev_addr.to_le_bytes());
+        payload.extend_from_slice(&[0, 1, 0, 1, tag, 0xde, 0xad, 0xbe, 0xef]);
+        payload
+    }
+
+    fn mk_devaddr_uplink(dev_addr: u32, snr: f32) -> Packet {
+        use helium_proto::{routing_information::Data as RoutingData, RoutingInformation};
+        Packet::from(helium_proto::Packet {
+            payload: mk_payload(dev_addr, 1),
+            snr,
+            routing: Some(RoutingInformation {
+                data: Some(RoutingData::Devaddr(dev_addr)),
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn last_drop_reasons() {
+        let (cache_settings, mut settings) = mk_settings();
+        settings.devaddr_metrics = 10;
+        settings.min_snr = Some(-15.0);
+        settings.region_fallback = RegionFallback::Drop;
+        let clock = MockClock::default();
+        let mut client = mk_client_with(
+            NO_ROUTER,
+            mk_config(settings).with_clock(Arc::new(clock.clone())),
+        )
+        .await;
+        let logger = mk_logger();
+
+        // Without a frequency the region of the uplink is undetermined
+        client
+            .handle_uplink(&logger, mk_devaddr_uplink(1, 0.0))
+            .await
+            .unwrap();
+        client.settings.region_fallback = RegionFallback::Default;
+        client
+            .handle_uplink(&logger, mk_devaddr_uplink(2, -20.0))
+            .await
+            .unwrap();
+
+        let region = client.region.clone();
+        let que_offered = |dev_addr| {
+            QuePacket::from(mk_devaddr_uplink(dev_addr, 0.0)).with_region(region.clone())
+        };
+        client.store.que_packet(que_offered(3)).await.unwrap();
+        client
+            .handle_state_channel_message(
+                &logger,
+                StateChannelMessage::from(
+                    helium_proto::BlockchainStateChannelRejectionV1::default(),
+                ),
+            )
+            .await
+            .unwrap();
+        client.store.que_packet(que_offered(4)).await.unwrap();
+        clock
+            .advance(Duration::from_secs(cache_settings.max_queued_age) + Duration::from_millis(1));
+        client.handle_offer_timeout(&logger).await;
+
+        assert_eq!(Some(DropReason::Region), client.last_drop_reason(1));
+        assert_eq!(Some(DropReason::LowSnr), client.last_drop_reason(2));
+        assert_eq!(Some(DropReason::Rejected), client.last_drop_reason(3));
+        assert_eq!(Some(DropReason::OfferTimeout), client.last_drop_reason(4));
+        assert_eq!(None, client.last_drop_reason(5));
+
+        // Hold uplinks in the store rather than connecting for them
+        client.connect_deadline = Some(time::Instant::now() + Duration::from_secs(60));
+        client
+            .handle_uplink(&logger, mk_devaddr_uplink(5, 0.0))
+            .await
+            .unwrap();
+        assert_eq!(None, client.last_drop_reason(5));
+        client
+            .handle_uplink(&logger, mk_devaddr_uplink(5, 0.0))
+            .await
+            .unwrap();
+        assert_eq!(Some(DropReason::Duplicate), client.last_drop_reason(5));
+        assert_eq!((1, 0), client.store.packet_counts().await);
+
+        clock
+            .advance(Duration::from_secs(cache_settings.max_packet_age) + Duration::from_millis(1));
+        client.compact_store(&logger, 0).await;
+        assert_eq!(Some(DropReason::Expired), client.last_drop_reason(5));
+        assert_eq!((0, 0), client.store.packet_counts().await);
+        // The earlier drop reasons are kept
+        assert_eq!(Some(DropReason::Rejected), client.last_drop_reason(3));
+    }
+
+    #[tokio::test]
+    async fn decision_trace() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        assert!(settings.trace_devaddrs.is_empty());
+        settings.trace_devaddrs = vec![1];
+        let clock = MockClock::default();
+        let mut client = mk_client_with(
+            &router.uri,
+            mk_config(settings).with_clock(Arc::new(clock.clone())),
+        )
+        .await;
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        // Hold the uplinks as waiting to offer them together
+        client.connect_deadline = Some(time::Instant::now() + Duration::from_secs(60));
+        let mk_uplink = |dev_addr, tag| {
+            let mut uplink = mk_devaddr_uplink(dev_addr, 0.0).to_packet();
+            uplink.payload = mk_payload(dev_addr, tag);
+            Packet::from(uplink)
+        };
+        let purchased = mk_uplink(1, 1);
+        let rejected = mk_uplink(1, 2);
+        let untraced = mk_uplink(2, 3);
+        let failed = mk_uplink(1, 4).with_crc_status(CrcStatus::Failed);
+        let start = clock.system_now();
+        for uplink in [&purchased, &rejected, &untraced].iter() {
+            client
+                .handle_uplink(&logger, (*uplink).clone())
+                .await
+                .unwrap();
+        }
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(3, client.offer_packets(&logger, None).await.unwrap());
+        // The router buys the first offered packet and rejects the second
+        client
+            .handle_state_channel_message(&logger, mk_purchase(mk_sc(2, 11)))
+            .await
+            .unwrap();
+        client
+            .handle_state_channel_message(
+                &logger,
+                StateChannelMessage::from(
+                    helium_proto::BlockchainStateChannelRejectionV1::default(),
+                ),
+            )
+            .await
+            .unwrap();
+        // Three offers and the purchased packet
+        for _ in 0..4 {
+            time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent message")
+                .expect("router message");
+        }
+        client.handle_uplink(&logger, failed.clone()).await.unwrap();
+
+        let steps = |packet: &Packet| -> Vec<TraceStep> {
+            client
+                .packet_trace(&packet.id())
+                .expect("packet trace")
+                .into_iter()
+                .map(|entry| entry.step)
+                .collect()
+        };
+        let checks = vec![
+            TraceStep::Received,
+            TraceStep::Passed(TraceCheck::Crc),
+            TraceStep::Passed(TraceCheck::Dedup),
+            TraceStep::Passed(TraceCheck::Region),
+            TraceStep::Passed(TraceCheck::Snr),
+            TraceStep::Passed(TraceCheck::Filter),
+            TraceStep::Passed(TraceCheck::Sampling),
+            TraceStep::Waiting,
+            TraceStep::Offered,
+        ];
+        let mut expected = checks.clone();
+        expected.extend(vec![
+            TraceStep::Purchased {
+                sc_id: mk_active_sc(&mk_sc(1, 10)).id_key(),
+            },
+            TraceStep::Sent,
+        ]);
+        assert_eq!(expected, steps(&purchased));
+        let mut expected = checks;
+        expected.extend(vec![
+            TraceStep::Rejected,
+            TraceStep::Dropped(DropReason::Rejected),
+        ]);
+        assert_eq!(expected, steps(&rejected));
+        assert_eq!(
+            vec![TraceStep::Received, TraceStep::Dropped(DropReason::Crc)],
+            steps(&failed)
+        );
+        assert!(client.packet_trace(&untraced.id()).is_none());
+
+        let trace = client.packet_trace(&purchased.id()).unwrap();
+        assert_eq!(start, trace[0].at);
+        assert_eq!(start + Duration::from_millis(100), trace[8].at);
+
+        // The traces of the sent and dropped packets are logged
+        client.log_completed_traces(&logger);
+        let records = capture.0.lock().unwrap();
+        let logged: Vec<&String> = records
+            .iter()
+            .filter(|record| record.starts_with("packet decision trace"))
+            .collect();
+        assert_eq!(3, logged.len());
+        assert!(logged[0].contains(&format!("packet_id={}", purchased.id())));
+        assert!(logged[0].contains("Passed(Dedup)@"));
+        assert!(logged[0].contains(&format!(
+            "Sent@{}",
+            (start + Duration::from_millis(100))
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        )));
+        assert!(logged[2].contains(&format!("packet_id={}", failed.id())));
+    }
+
+    #[tokio::test]
+    async fn selects_state_channel() {
+        let (_, mut settings) = mk_settings();
+        assert_eq!(ScSelection::Latest, settings.sc_selection);
+        settings.sc_selection = ScSelection::Balance;
+        let mut client = mk_client(settings).await;
+        assert!(client.selected_state_channel().await.unwrap().is_none());
+        let scs = [
+            mk_sc(1, 10),
+            BlockchainStateChannelV1 {
+                id: vec![2],
+                ..mk_sc(1, 40)
+            },
+        ];
+        for sc in scs.iter() {
+            client
+                .insert_active_state_channel(&mk_active_sc(sc))
+                .await
+                .unwrap();
+        }
+        let selected = client.selected_state_channel().await.unwrap().unwrap();
+        assert_eq!(&[1u8][..], selected.id());
+        // The selection is kept for the current state channels
+        let selected_at = |client: &RouterClient| {
+            client
+                .sc_selection
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|(selected_at, _)| *selected_at)
+        };
+        assert_eq!(
+            Some((client.store.sc_generation(), 0, 0)),
+            selected_at(&client)
+        );
+        // and made again once they change
+        client
+            .store
+            .remove_state_channel(&scs[0].id.id_key())
+            .await
+            .unwrap();
+        let selected = client.selected_state_channel().await.unwrap().unwrap();
+        assert_eq!(&[2u8][..], selected.id());
+        client
+            .insert_active_state_channel(&mk_active_sc(&scs[0]))
+            .await
+            .unwrap();
+
+        client.sc_selector = StateChannelSelector::new(ScSelection::Latest);
+        client.sc_selector.record_banner(scs[1].id.id_key());
+        let selected = client.selected_state_channel().await.unwrap().unwrap();
+        assert_eq!(&[2u8][..], selected.id());
+    }
+
+    #[tokio::test]
+    async fn banner_received() {
+        let (_, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        assert!(!client.banner_received());
+        let sc = mk_sc(1, 10);
+        client
+            .insert_active_state_channel(&mk_active_sc(&sc))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            client
+                .handle_message(
+                    &logger,
+                    StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 {
+                        sc: Some(sc.clone()),
+                    }),
+                )
+                .await
+                .unwrap();
+            assert!(client.banner_received());
+        }
+        // Other messages and a lost connection leave the flag set
+        client
+            .handle_message(
+                &logger,
+                StateChannelMessage::from(
+                    helium_proto::BlockchainStateChannelRejectionV1::default(),
+                ),
+            )
+            .await
+            .unwrap();
+        client.handle_connection_lost(&logger, ExitReason::StreamClosed);
+        assert!(client.banner_received());
+    }
+
+    #[tokio::test]
+    async fn expire_active_channel() {
+        let mk_gateway = |height| {
+            GatewayService::new(mk_keyed_uri("http://127.0.0.1:1"))
+                .unwrap()
+                .with_height(height)
+        };
+        // The client ends up in the same state after a forced expiry as after
+        // the active state channel expired naturally
+        let mut outcomes = vec![];
+        for forced in [false, true].iter() {
+            let (_, settings) = mk_settings();
+            let mut client = mk_client(settings).await;
+            let logger = mk_logger();
+            for (id, expiry_at_block) in [(1, 100), (2, 200)].iter() {
+                let sc = BlockchainStateChannelV1 {
+                    id: vec![*id],
+                    ..mk_sc(1, 10)
+                };
+                client
+                    .insert_active_state_channel(&mk_expiring_sc(&sc, *expiry_at_block))
+                    .await
+                    .unwrap();
+            }
+            let mut sc_lifecycle = client.subscribe_sc_lifecycle();
+            client.gateway = mk_gateway(50);
+            let active = client.active_state_channel_id().await.unwrap();
+            if *forced {
+                assert_eq!(active, client.expire_active_channel(&logger).await.unwrap());
+            } else {
+                client.gateway = mk_gateway(100);
+                client.compact_store(&logger, 100).await;
+            }
+            let mut transitions = vec![];
+            while let Ok(transition) = sc_lifecycle.try_recv() {
+                transitions.push(transition);
+            }
+            outcomes.push((
+                active,
+                client.active_state_channel_id().await.unwrap(),
+                client.store.state_channel_count().await.unwrap(),
+                transitions,
+            ));
+
+            // Expiring the remaining state channel leaves nothing to expire
+            assert!(client
+                .expire_active_channel(&logger)
+                .await
+                .unwrap()
+                .is_some());
+            assert_eq!(0, client.store.state_channel_count().await.unwrap());
+            assert_eq!(None, client.expire_active_channel(&logger).await.unwrap());
+        }
+        let sc_id = outcomes[0].0.clone().unwrap();
+        assert_eq!(
+            (
+                Some(sc_id.clone()),
+                Some(
+                    mk_expiring_sc(
+                        &BlockchainStateChannelV1 {
+                            id: vec![2],
+                            ..mk_sc(1, 10)
+                        },
+                        200
+                    )
+                    .id_key()
+                ),
+                1,
+                vec![
+                    ScLifecycle::Expired {
+                        sc_id: sc_id.clone()
+                    },
+                    ScLifecycle::Archived { sc_id },
+                ]
+            ),
+            outcomes[0]
+        );
+        assert_eq!(outcomes[0], outcomes[1]);
+
+        // With no state channel left the next uplink connects to the router
+        // again, to offer once its banner names a new state channel
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        let mut events = client.subscribe();
+        assert!(client
+            .expire_active_channel(&logger)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(0, client.store.state_channel_count().await.unwrap());
+        assert!(!client.state_channel.is_connected());
+        client
+            .handle_uplink(&logger, mk_devaddr_uplink(1, 0.0))
+            .await
+            .unwrap();
+        assert!(client.state_channel.is_connected());
+        let mut connected = false;
+        while let Ok(event) = events.try_recv() {
+            connected |= matches!(event, ClientEvent::Connected);
+        }
+        assert!(connected);
+        assert_eq!((1, 0), client.packet_counts().await);
+        assert!(
+            time::timeout(Duration::from_millis(200), router.received.recv())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn active_state_channel_id() {
+        let (_, settings) = mk_settings();
+        assert_eq!(ScSelection::Latest, settings.sc_selection);
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        assert_eq!(None, client.active_state_channel_id().await.unwrap());
+
+        // Known state channels, so the banners need no lookup
+        let scs: Vec<BlockchainStateChannelV1> = (1..=2)
+            .map(|id| BlockchainStateChannelV1 {
+                id: vec![id],
+                ..mk_sc(1, 10)
+            })
+            .collect();
+        for sc in scs.iter() {
+            client
+                .insert_active_state_channel(&mk_active_sc(sc))
+                .await
+                .unwrap();
+        }
+        for sc in [&scs[1], &scs[0], &scs[1]].iter() {
+            client
+                .handle_message(
+                    &logger,
+                    StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 {
+                        sc: Some((*sc).clone()),
+                    }),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(sc.id.id_key()),
+                client.active_state_channel_id().await.unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn remaining_balance() {
+        let (_, settings) = mk_settings();
+        let client = mk_client(settings).await;
+        assert_eq!(None, client.remaining_balance().await.unwrap());
+        // Two summaries of 30 and 12 DC against 100 credits
+        let mut sc = mk_sc(1, 30);
+        sc.summaries
+            .push(helium_proto::BlockchainStateChannelSummaryV1 {
+                client_pubkeybin: vec![2],
+                num_packets: 12,
+                num_dcs: 12,
+            });
+        client
+            .insert_active_state_channel(&mk_active_sc(&sc))
+            .await
+            .unwrap();
+        assert_eq!(Some(58), client.remaining_balance().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_stream_error() {
+        let mut router = MockRouter::start(vec![
+            vec![Step::Fail(tonic::Code::Unavailable)],
+            vec![
+                Step::Send(mk_purchase(mk_sc(2, 11)).to_message()),
+                Step::Receive,
+            ],
+        ])
+        .await;
+        let (_, mut settings) = mk_settings();
+        settings.keepalive = 30;
+        settings.reconnect_backoff = 10;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let mut events = client.subscribe();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        que_offered(&client, 1).await;
+        client
+            .store
+            .store_waiting_packet(Packet::from(helium_proto::Packet {
+                payload: vec![2],
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        client.connect().await.unwrap();
+
+        // Keep the uplinks sender so the uplinks channel stays open
+        let (_uplinks, uplinks) = mpsc::channel(10);
+        let (shutdown, shutdown_listener) = triggered::trigger();
+        let logger = mk_logger();
+        let delivered = async {
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("delivered packet")
+                .expect("router message");
+            shutdown.trigger();
+            message
+        };
+        let (exit, message) =
+            tokio::join!(client.run(uplinks, shutdown_listener, &logger), delivered);
+
+        // The purchase on the new stream delivered the queued packet
+        assert!(matches!(message.msg, Some(Msg::Packet(_))));
+        assert_eq!(2, router.connections());
+        // The client kept running until shut down and kept the waiting packet
+        let exit = exit.unwrap();
+        assert_eq!(ExitReason::Shutdown, exit.reason);
+        assert_eq!((1, 0), (exit.waiting, exit.queued));
+        let mut received = vec![];
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert!(received.contains(&ClientEvent::ConnectionLost));
+        assert_eq!(
+            2,
+            received
+                .iter()
+                .filter(|event| **event == ClientEvent::Connected)
+                .count()
+        );
+        assert!(received
+            .iter()
+            .any(|event| matches!(event, ClientEvent::Purchased { .. })));
+    }
+
+    #[tokio::test]
+    async fn recent_join_gate() {
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.recent_join);
+        settings.recent_join = 60;
+        settings.devaddr_metrics = 10;
+        let mut client = mk_client(settings).await;
+        let (downlinks, mut received) = mpsc::channel(10);
+        client.downlinks = downlinks;
+        let logger = mk_logger();
+
+        // Before any join every device is dropped before reaching the router
+        client
+            .handle_uplink(&logger, mk_devaddr_uplink(2, 0.0))
+            .await
+            .unwrap();
+        assert_eq!(Some(DropReason::NotJoined), client.last_drop_reason(2));
+        assert!(!client.state_channel.is_connected());
+
+        // A join request goes out and the router answers with a join accept
+        // that, like in production, carries no DevAddr
+        let join_request = Packet::from(helium_proto::Packet {
+            payload: vec![0; 23],
+            routing: Some(helium_proto::RoutingInformation {
+                data: Some(helium_proto::routing_information::Data::Eui(
+                    helium_proto::Eui {
+                        deveui: 1,
+                        appeui: 1,
+                    },
+                )),
+            }),
+            ..Default::default()
+        });
+        client.record_sent_uplink(join_request);
+        let mut join_accept = vec![0x20];
+        join_accept.extend_from_slice(&[0; 16]);
+        client
+            .handle_downlink(
+                &logger,
+                &helium_proto::Packet {
+                    payload: join_accept,
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(received.recv().await.is_some());
+
+        // The first data uplink of a new device after the accept is the
+        // joined device, it passes the gate and fails to connect to the
+        // unreachable router
+        assert!(client
+            .handle_uplink(&logger, mk_devaddr_uplink(1, 0.0))
+            .await
+            .is_err());
+        assert_eq!(None, client.last_drop_reason(1));
+        // Other new devices are still dropped
+        client
+            .handle_uplink(&logger, mk_devaddr_uplink(3, 0.0))
+            .await
+            .unwrap();
+        assert_eq!(Some(DropReason::NotJoined), client.last_drop_reason(3));
+        // while the joined device keeps passing
+        let mut uplink = mk_devaddr_uplink(1, 0.0).to_packet();
+        uplink.payload = mk_payload(1, 2);
+        assert!(client
+            .handle_uplink(&logger, Packet::from(uplink))
+            .await
+            .is_err());
+        assert_eq!(None, client.last_drop_reason(1));
+    }
+
+    #[tokio::test]
+    async fn drops_oversized_offers() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        settings.devaddr_metrics = 10;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        // US915 allows 24 bytes at SF10BW125 and 66 bytes at SF9BW125
+        for (datarate, size) in [("SF10BW125", 25), ("SF10BW125", 24), ("SF9BW125", 25)].iter() {
+            client
+                .store
+                .store_waiting_packet(Packet::from(helium_proto::Packet {
+                    frequency: 903.9,
+                    datarate: datarate.to_string(),
+                    payload: vec![*size as u8; *size],
+                    routing: mk_devaddr_uplink(1, 0.0).routing().clone(),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+        }
+        assert_eq!(2, client.offer_packets(&logger, None).await.unwrap());
+        for _ in 0..2 {
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent offer")
+                .expect("router message");
+            assert!(matches!(message.msg, Some(Msg::Offer(_))));
+        }
+        assert_eq!((0, 2), client.packet_counts().await);
+        assert_eq!(1, client.metrics_snapshot().oversized_drops);
+        assert_eq!(Some(DropReason::Oversized), client.last_drop_reason(1));
+
+        // Uplinks offered as they arrive are checked the same way
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        client
+            .handle_uplink(
+                &logger,
+                Packet::from(helium_proto::Packet {
+                    frequency: 903.9,
+                    datarate: "SF10BW125".to_string(),
+                    payload: vec![2; 25],
+                    routing: mk_devaddr_uplink(2, 0.0).routing().clone(),
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap();
+        assert!(
+            time::timeout(Duration::from_millis(200), router.received.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!((0, 2), client.packet_counts().await);
+        assert_eq!(2, client.metrics_snapshot().oversized_drops);
+        assert_eq!(Some(DropReason::Oversized), client.last_drop_reason(2));
+    }
+
+    #[tokio::test]
+    async fn router_protocol() {
+        let cases = [
+            (RouterProtocol::V2, RouterProtocol::V2),
+            (RouterProtocol::V1, RouterProtocol::V1),
+            (RouterProtocol::Auto, RouterProtocol::V1),
+        ];
+        for (protocol, detected) in cases.iter() {
+            let mut router = MockRouter::start(vec![]).await;
+            let (_, mut settings) = mk_settings();
+            assert_eq!(RouterProtocol::V2, settings.router_protocol);
+            settings.router_protocol = *protocol;
+            let mut client = mk_client_for(&router.uri, settings).await;
+            let logger = mk_logger();
+            client
+                .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+                .await
+                .unwrap();
+            // The region field is left out in packets for routers on the
+            // original protocol, which also leave out the packet hash of the
+            // purchased packet
+            client.region = Region::from_i32(1).unwrap();
+            que_offered(&client, 1).await;
+            client
+                .handle_state_channel_message(&logger, mk_purchase(mk_sc(2, 11)))
+                .await
+                .unwrap();
+            assert_eq!(*detected, client.protocol_version());
+
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent packet")
+                .expect("router message");
+            let legacy = *detected == RouterProtocol::V1;
+            match message.msg {
+                Some(Msg::Packet(packet)) => {
+                    assert_eq!(if legacy { 0 } else { 1 }, packet.region)
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+
+        // A router on the original protocol that rejects the offers carrying
+        // the newer fields, and so never purchases, is detected from its
+        // rejects and later offers leave those fields out
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        settings.router_protocol = RouterProtocol::Auto;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        client.region = Region::from_i32(1).unwrap();
+        let mk_reject = || {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelRejectionV1 {
+                reject: 1,
+                ..Default::default()
+            })
+        };
+        for payload in 0..PROTOCOL_DETECT_REJECTS {
+            assert_eq!(RouterProtocol::V2, client.protocol_version());
+            que_offered(&client, payload as u8).await;
+            client
+                .handle_state_channel_message(&logger, mk_reject())
+                .await
+                .unwrap();
+        }
+        assert_eq!(RouterProtocol::V1, client.protocol_version());
+        client
+            .store
+            .store_waiting_packet(mk_devaddr_uplink(1, 0.0))
+            .await
+            .unwrap();
+        assert_eq!(1, client.offer_packets(&logger, None).await.unwrap());
+        let message = time::timeout(Duration::from_secs(10), router.received.recv())
+            .await
+            .expect("sent offer")
+            .expect("router message");
+        match message.msg {
+            Some(Msg::Offer(offer)) => assert_eq!(0, offer.region),
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        // A purchase identifying its packet settles on the current protocol,
+        // which later rejects do not change
+        client.set_protocol_version(RouterProtocol::Auto);
+        que_offered(&client, 9).await;
+        let purchase = StateChannelMessage::from(helium_proto::BlockchainStateChannelPurchaseV1 {
+            sc: Some(mk_sc(2, 11)),
+            packet_hash: Packet::from(helium_proto::Packet {
+                payload: vec![9],
+                ..Default::default()
+            })
+            .hash(),
+            ..Default::default()
+        });
+        client
+            .handle_state_channel_message(&logger, purchase)
+            .await
+            .unwrap();
+        for payload in 0..PROTOCOL_DETECT_REJECTS {
+            que_offered(&client, payload as u8).await;
+            client
+                .handle_state_channel_message(&logger, mk_reject())
+                .await
+                .unwrap();
+        }
+        assert_eq!(RouterProtocol::V2, client.protocol_version());
+
+        // Being told the version overrides detection
+        let (_, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        client.set_protocol_version(RouterProtocol::V1);
+        assert_eq!(RouterProtocol::V1, client.protocol_version());
+        client.set_protocol_version(RouterProtocol::Auto);
+        assert_eq!(RouterProtocol::V2, client.protocol_version());
+    }
+
+    #[tokio::test]
+    async fn uplink_sampling() {
+        for (sampling, offered) in [(0, 20), (1, 20), (4, 5), (3, 7)].iter() {
+            let (_, mut settings) = mk_settings();
+            assert_eq!(0, settings.uplink_sampling);
+            settings.uplink_sampling = *sampling;
+            let mut client = mk_client(settings).await;
+            let logger = mk_logger();
+            // Hold the uplinks as waiting to count the offered ones, the
+            // waiting packets have room for all of them
+            client.connect_deadline = Some(time::Instant::now() + Duration::from_secs(60));
+            for dev_addr in 0..20 {
+                client
+                    .handle_uplink(&logger, mk_devaddr_uplink(dev_addr, 0.0))
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(*offered, client.packet_counts().await.0);
+            assert_eq!(
+                20 - *offered as u64,
+                client.metrics_snapshot().sampled_drops
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn max_offer_attempts() {
+        use crate::settings::RejectActionSetting;
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.max_offer_attempts);
+        settings.max_offer_attempts = 3;
+        settings.devaddr_metrics = 10;
+        settings.reject_actions = vec![RejectActionSetting {
+            code: 1,
+            action: RejectAction::Redispatch,
+        }];
+        let (redispatch, mut redispatched) = mpsc::channel(10);
+        let mut client =
+            mk_client_with(&router.uri, mk_config(settings).with_redispatch(redispatch)).await;
+        let logger = mk_logger();
+        let mk_reject = || {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelRejectionV1 {
+                reject: 1,
+                ..Default::default()
+            })
+        };
+        // The packet is always rejected and comes back redispatched, its
+        // offers adding up across the redispatches
+        let (mut uplink, mut offers) = (mk_devaddr_uplink(1, 0.0), 0);
+        for attempt in 1..=3 {
+            client
+                .handle_offered_uplink(&logger, uplink, offers)
+                .await
+                .unwrap();
+            assert_eq!(1, client.offer_packets(&logger, None).await.unwrap());
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent offer")
+                .expect("router message");
+            assert!(matches!(message.msg, Some(Msg::Offer(_))));
+            client
+                .handle_state_channel_message(&logger, mk_reject())
+                .await
+                .unwrap();
+            let rejected = redispatched.try_recv().expect("redispatched packet");
+            assert_eq!(attempt, rejected.offers);
+            uplink = rejected.packet;
+            offers = rejected.offers;
+        }
+        client
+            .handle_offered_uplink(&logger, uplink, offers)
+            .await
+            .unwrap();
+        assert_eq!(0, client.offer_packets(&logger, None).await.unwrap());
+        assert!(redispatched.try_recv().is_err());
+        assert_eq!((0, 0), client.packet_counts().await);
+        assert_eq!(1, client.metrics_snapshot().max_offer_attempts_exceeded);
+        assert_eq!(
+            Some(DropReason::MaxOfferAttempts),
+            client.last_drop_reason(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn offer_failure_keeps_waiting_order() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        // The second payload is too short to parse, failing its offer
+        let payloads = vec![mk_payload(1, 1), vec![0x40], mk_payload(1, 3)];
+        for payload in &payloads {
+            client
+                .store
+                .store_waiting_packet(Packet::from(helium_proto::Packet {
+                    payload: payload.clone(),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+        }
+        assert!(client.send_packet_offers(&logger).await.is_err());
+        let message = time::timeout(Duration::from_secs(10), router.received.recv())
+            .await
+            .expect("sent offer")
+            .expect("router message");
+        assert!(matches!(message.msg, Some(Msg::Offer(_))));
+        assert_eq!((2, 1), client.packet_counts().await);
+        let mut waiting = vec![];
+        while let Some(packet) = client.store.pop_waiting_packet().await {
+            waiting.push(packet.payload().to_vec());
+        }
+        assert_eq!(payloads[1..].to_vec(), waiting);
+    }
+
+    #[tokio::test]
+    async fn flushes_devaddr() {
+        use helium_proto::{routing_information::Data as RoutingData, RoutingInformation};
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        for (tag, dev_addr) in [1, 2, 1, 3].iter().enumerate() {
+            client
+                .store
+                .store_waiting_packet(Packet::from(helium_proto::Packet {
+                    payload: mk_payload(*dev_addr, tag as u8),
+                    routing: Some(RoutingInformation {
+                        data: Some(RoutingData::Devaddr(*dev_addr)),
+                    }),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(2, client.flush_devaddr(&logger, 1).await.unwrap());
+        for tag in [0u8, 2].iter() {
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent offer")
+                .expect("router message");
+            match message.msg {
+                Some(Msg::Offer(offer)) => {
+                    assert_eq!(
+                        Some(RoutingInformation {
+                            data: Some(RoutingData::Devaddr(1)),
+                        }),
+                        offer.routing
+                    );
+                    assert_eq!(
+                        Packet::from(helium_proto::Packet {
+                            payload: mk_payload(1, *tag),
+                            ..Default::default()
+                        })
+                        .hash(),
+                        offer.packet_hash
+                    );
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        // The other devices keep waiting, in order
+        assert_eq!((2, 2), client.packet_counts().await);
+        assert_eq!(0, client.flush_devaddr(&logger, 1).await.unwrap());
+
+        // A running client flushes a device dispatched to it
+        let (dispatch, uplinks) = mpsc::channel(10);
+        dispatch.send(Dispatch::FlushDevAddr(3)).await.unwrap();
+        let (shutdown, shutdown_listener) = triggered::trigger();
+        let watch = async {
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent offer")
+                .expect("router message");
+            shutdown.trigger();
+            message
+        };
+        let (exit, message) = tokio::join!(client.run(uplinks, shutdown_listener, &logger), watch);
+        assert_eq!(ExitReason::Shutdown, exit.unwrap().reason);
+        match message.msg {
+            Some(Msg::Offer(offer)) => assert_eq!(
+                Some(RoutingInformation {
+                    data: Some(RoutingData::Devaddr(3)),
+                }),
+                offer.routing
+            ),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert_eq!((1, 3), client.packet_counts().await);
+        let waiting = client.store.pop_waiting_packet().await.unwrap();
+        assert_eq!(Some(2), waiting.dev_addr());
+    }
+
+    #[tokio::test]
+    async fn probes_router() {
+        use crate::error::ServiceError;
+        let router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        assert!(!settings.probe_router);
+        // Without the probe an unreachable router is only found on use
+        mk_client(settings.clone()).await;
+
+        settings.probe_router = true;
+        mk_client_for(&router.uri, settings.clone()).await;
+        let (cache_settings, _) = mk_settings();
+        let (downlinks, _) = mpsc::channel(10);
+        let gateway = GatewayService::new(mk_keyed_uri("http://127.0.0.1:1")).expect("gateway");
+        let result = RouterClient::new(
+            RouterClientConfig::default()
+                .with_cache_settings(cache_settings)
+                .with_settings(settings),
+            mk_keyed_uri(NO_ROUTER),
+            gateway,
+            downlinks,
+            Arc::new(mk_keypair()).into(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(Error::Service(ServiceError::Unreachable(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn devaddr_offer_interval() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.devaddr_offer_interval);
+        settings.devaddr_offer_interval = 1000;
+        settings.devaddr_metrics = 10;
+        let clock = MockClock::default();
+        let mut client = mk_client_with(
+            &router.uri,
+            mk_config(settings).with_clock(Arc::new(clock.clone())),
+        )
+        .await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        let mk_uplink = |tag| {
+            let mut uplink = mk_devaddr_uplink(1, 0.0).to_packet();
+            uplink.payload = mk_payload(1, tag);
+            Packet::from(uplink)
+        };
+
+        // Rapid uplinks with distinct payloads, only the first in each
+        // interval is offered to the router
+        let mut tag = 0;
+        for _ in 0..2 {
+            for _ in 0..3 {
+                tag += 1;
+                client.handle_uplink(&logger, mk_uplink(tag)).await.unwrap();
+                clock.advance(Duration::from_millis(100));
+            }
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent offer")
+                .expect("router message");
+            assert!(matches!(message.msg, Some(Msg::Offer(_))));
+            clock.advance(Duration::from_millis(700));
+        }
+        assert!(
+            time::timeout(Duration::from_millis(100), router.received.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!(4, client.metrics_snapshot().throttled_drops);
+        assert_eq!(Some(DropReason::Throttled), client.last_drop_reason(1));
+
+        // An uplink that is dropped instead of offered does not hold back
+        // the next uplink of its device. US915 allows 24 bytes at SF10BW125.
+        for size in [25, 24].iter() {
+            client
+                .handle_uplink(
+                    &logger,
+                    Packet::from(helium_proto::Packet {
+                        frequency: 903.9,
+                        datarate: "SF10BW125".to_string(),
+                        payload: vec![*size as u8; *size],
+                        routing: mk_devaddr_uplink(2, 0.0).routing().clone(),
+                        ..Default::default()
+                    }),
+                )
+                .await
+                .unwrap();
+        }
+        let message = time::timeout(Duration::from_secs(10), router.received.recv())
+            .await
+            .expect("sent offer")
+            .expect("router message");
+        assert!(matches!(message.msg, Some(Msg::Offer(_))));
+        assert_eq!(Some(DropReason::Oversized), client.last_drop_reason(2));
+        assert_eq!(4, client.metrics_snapshot().throttled_drops);
+
+        // Compaction forgets offers a full interval old
+        assert_eq!(2, client.offer_throttle.len());
+        clock.advance(Duration::from_millis(1000));
+        client.compact_store(&logger, 0).await;
+        assert!(client.offer_throttle.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hold_time_grows_while_queued() {
+        use helium_proto::{routing_information::Data as RoutingData, Eui, RoutingInformation};
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let clock = MockClock::default();
+        let mut client = mk_client_with(
+            &router.uri,
+            mk_config(settings).with_clock(Arc::new(clock.clone())),
+        )
+        .await;
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        let packet = QuePacket::new(
+            Packet::from(helium_proto::Packet {
+                payload: vec![1],
+                ..Default::default()
+            }),
+            clock.now(),
+        );
+        let join = QuePacket::new(
+            Packet::from(helium_proto::Packet {
+                payload: vec![2],
+                routing: Some(RoutingInformation {
+                    data: Some(RoutingData::Eui(Eui::default())),
+                }),
+                ..Default::default()
+            }),
+            clock.now(),
+        );
+
+        let mut hold_times = vec![];
+        for (millis, packet) in [(300, &packet), (600, &packet), (2100, &packet), (0, &join)].iter()
+        {
+            clock.advance(Duration::from_millis(*millis));
+            client.send_packet(&logger, Some(*packet)).await.unwrap();
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent packet")
+                .expect("router message");
+            match message.msg {
+                Some(Msg::Packet(packet)) => hold_times.push(packet.hold_time),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        // Held past its RX2 window the data uplink is reported as held until
+        // the window closed, the join request until its second join accept
+        // window closes
+        assert_eq!(vec![300, 900, 2000, 3000], hold_times);
+        assert_eq!(clock.now() - Duration::from_millis(3000), packet.received());
+        // The TTL runs down to when the last receive window closes
+        let ttls: Vec<String> = capture
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| record.starts_with("sent packet"))
+            .filter_map(|record| record.split(' ').find(|kv| kv.starts_with("ttl=")))
+            .map(str::to_string)
+            .collect();
+        assert_eq!(vec!["ttl=1700", "ttl=1100", "ttl=0", "ttl=3000"], ttls);
+    }
+
+    #[tokio::test]
+    async fn recorded_times_clamped_on_clock_jump() {
+        let (_, mut settings) = mk_settings();
+        settings.message_tap = 10;
+        let clock = MockClock::default();
+        let mut client = mk_client_with(
+            NO_ROUTER,
+            mk_config(settings).with_clock(Arc::new(clock.clone())),
+        )
+        .await;
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        let packet = Packet::from(helium_proto::Packet {
+            payload: vec![1],
+            ..Default::default()
+        });
+        let start = clock.system_now();
+        client.message_tap.record_uplink(&packet);
+        // A correction of the host clock while the packet is held
+        clock.rewind(Duration::from_millis(700));
+        client.message_tap.record_uplink(&packet);
+        let held = QuePacket::new(packet, clock.now());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), held.hold_time_at(clock.now()));
+
+        // Both messages are recorded at the time before the jump
+        let export = client
+            .export_audit(start..start + Duration::from_millis(1))
+            .unwrap();
+        assert_eq!(
+            2,
+            export
+                .verify(client.gateway_keypair.public_key())
+                .unwrap()
+                .len()
+        );
+        client.compact_store(&logger, 0).await;
+        client.compact_store(&logger, 0).await;
+        let records = capture.0.lock().unwrap();
+        let warnings: Vec<&String> = records
+            .iter()
+            .filter(|record| record.starts_with("clock jumped backwards"))
+            .collect();
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("clamped=1"));
+    }
+
+    #[tokio::test]
+    async fn duplicate_purchase() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        que_offered(&client, 1).await;
+        que_offered(&client, 2).await;
+        let purchase = || {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelPurchaseV1 {
+                sc: Some(mk_sc(2, 11)),
+                packet_hash: Packet::from(helium_proto::Packet {
+                    payload: vec![1],
+                    ..Default::default()
+                })
+                .hash(),
+                ..Default::default()
+            })
+        };
+
+        for _ in 0..2 {
+            client
+                .handle_state_channel_message(&logger, purchase())
+                .await
+                .unwrap();
+        }
+        let message = time::timeout(Duration::from_secs(10), router.received.recv())
+            .await
+            .expect("sent packet")
+            .expect("router message");
+        match message.msg {
+            Some(Msg::Packet(packet)) => assert_eq!(vec![1], packet.packet.unwrap().payload),
+            other => panic!("unexpected message {:?}", other),
+        }
+        // The repeated purchase did not take the next queued packet
+        assert_eq!((0, 1), client.store.packet_counts().await);
+        let snapshot = client.metrics_snapshot();
+        assert_eq!(1, snapshot.purchases);
+        assert_eq!(1, snapshot.duplicate_purchases);
+    }
+
+    #[tokio::test]
+    async fn retransmitted_purchase() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        let region = client.region.clone();
+        let packet = |timestamp| {
+            QuePacket::from(Packet::from(helium_proto::Packet {
+                payload: vec![1],
+                timestamp,
+                ..Default::default()
+            }))
+            .with_region(region.clone())
+        };
+        let purchase = |nonce| {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelPurchaseV1 {
+                sc: Some(mk_sc(nonce, 10 + nonce)),
+                packet_hash: packet(0).id().as_ref().to_vec(),
+                ..Default::default()
+            })
+        };
+
+        // The same payload sent again, with its own timestamp
+        for (timestamp, nonce) in [(1, 2), (2, 3)] {
+            client.store.que_packet(packet(timestamp)).await.unwrap();
+            client
+                .handle_state_channel_message(&logger, purchase(nonce))
+                .await
+                .unwrap();
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent packet")
+                .expect("router message");
+            match message.msg {
+                Some(Msg::Packet(sent)) => assert_eq!(timestamp, sent.packet.unwrap().timestamp),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        // Only a purchase repeated once both were sent is a duplicate
+        client
+            .handle_state_channel_message(&logger, purchase(4))
+            .await
+            .unwrap();
+        let snapshot = client.metrics_snapshot();
+        assert_eq!(2, snapshot.purchases);
+        assert_eq!(1, snapshot.duplicate_purchases);
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        assert_eq!(Duration::from_millis(100), reconnect_delay(100, 0));
+        assert_eq!(Duration::from_millis(400), reconnect_delay(100, 2));
+        assert_eq!(MAX_RECONNECT_BACKOFF, reconnect_delay(100, 20));
+        assert_eq!(MAX_RECONNECT_BACKOFF, reconnect_delay(100, 200));
+    }
+
+    #[test]
+    fn connect_jitter_bounds() {
+        use rand::{rngs::StdRng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(42);
+        assert_eq!(Duration::from_secs(0), connect_jitter(0, &mut rng));
+        let delays: Vec<Duration> = (0..100).map(|_| connect_jitter(500, &mut rng)).collect();
+        assert!(delays
+            .iter()
+            .all(|delay| *delay <= Duration::from_millis(500)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[tokio::test]
+    async fn delays_first_connect() {
+        let router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.connect_jitter);
+        settings.connect_jitter = 300;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let (uplinks_tx, uplinks) = mpsc::channel(10);
+        let (shutdown, shutdown_listener) = triggered::trigger();
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        let start = time::Instant::now();
+        let connected = async {
+            uplinks_tx
+                .send(Dispatch::Packet(Packet::from(helium_proto::Packet {
+                    payload: vec![1],
+                    ..Default::default()
+                })))
+                .await
+                .unwrap();
+            while router.connections() == 0 {
+                time::sleep(Duration::from_millis(5)).await;
+                assert!(start.elapsed() < Duration::from_secs(10), "connected");
+            }
+            let elapsed = start.elapsed();
+            shutdown.trigger();
+            elapsed
+        };
+        let (exit, elapsed) =
+            tokio::join!(client.run(uplinks, shutdown_listener, &logger), connected);
+        assert_eq!(ExitReason::Shutdown, exit.unwrap().reason);
+        // The uplink connected no sooner than the picked delay, which is only
+        // logged when it is not zero, and at most the jitter later, allowing
+        // for the time to set up the stream
+        let delay = capture
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|record| record.starts_with("delaying first connect"))
+            .and_then(|record| record.split("delay=").nth(1))
+            .map_or(0, |delay| {
+                delay
+                    .split_whitespace()
+                    .next()
+                    .and_then(|delay| delay.parse().ok())
+                    .expect("logged delay")
+            });
+        assert!(delay <= 300);
+        assert!(elapsed >= Duration::from_millis(delay));
+        assert!(elapsed < Duration::from_millis(300) + Duration::from_secs(1));
+        assert_eq!(1, router.connections());
+    }
+
+    #[tokio::test]
+    async fn holds_uplinks_until_jittered_connect() {
+        let router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        settings.connect_jitter = 60_000;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        // Retry the random pick until it is not zero
+        while client.connect_deadline.is_none() {
+            client.start_connect_jitter(&logger);
+        }
+        let deadline = client.connect_deadline.unwrap();
+        assert!(deadline <= time::Instant::now() + Duration::from_secs(60));
+
+        let uplink = Packet::from(helium_proto::Packet {
+            payload: vec![1],
+            ..Default::default()
+        });
+        client.handle_uplink(&logger, uplink).await.unwrap();
+        assert_eq!((1, 0), client.store.packet_counts().await);
+        assert!(!client.state_channel.is_connected());
+
+        client.handle_connect_jitter(&logger).await;
+        assert!(client.connect_deadline.is_none());
+        assert!(client.state_channel.is_connected());
+    }
+
+    #[tokio::test]
+    async fn debounces_banner_burst() {
+        let router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.banner_debounce);
+        settings.banner_debounce = 500;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        let sc = mk_sc(1, 0);
+        client
+            .insert_active_state_channel(&mk_active_sc(&sc))
+            .await
+            .unwrap();
+        client.connect().await.unwrap();
+        let banner = || {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 {
+                sc: Some(sc.clone()),
+            })
+        };
+        // A burst of banners with packets arriving in between offers nothing
+        // while the burst lasts
+        let mut deadlines = vec![];
+        for dev_addr in 1..=3 {
+            client
+                .store_waiting_packet(mk_devaddr_uplink(dev_addr, 0.0))
+                .await
+                .unwrap();
+            client.handle_message(&logger, banner()).await.unwrap();
+            assert_eq!((dev_addr as usize, 0), client.store.packet_counts().await);
+            deadlines.push(client.banners.debounce_at().expect("debounce deadline"));
+        }
+        // Every banner pushed the offers out further
+        assert!(deadlines.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        // Once settled all packets are offered in a single pass
+        client.handle_banner_debounce(&logger).await;
+        assert!(client.banners.debounce_at().is_none());
+        assert_eq!((0, 3), client.store.packet_counts().await);
+    }
+
+    #[tokio::test]
+    async fn bounds_banner_debounce() {
+        let (_, mut settings) = mk_settings();
+        assert_eq!(2000, settings.banner_debounce_max);
+        settings.banner_debounce = 500;
+        settings.banner_debounce_max = 800;
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        let sc = mk_sc(1, 0);
+        client
+            .insert_active_state_channel(&mk_active_sc(&sc))
+            .await
+            .unwrap();
+        let banner = || {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 {
+                sc: Some(sc.clone()),
+            })
+        };
+        let start = time::Instant::now();
+        client.handle_message(&logger, banner()).await.unwrap();
+        // A banner that keeps coming does not push the offers out past the
+        // maximum debounce from the first banner of the burst
+        time::sleep(Duration::from_millis(400)).await;
+        client.handle_message(&logger, banner()).await.unwrap();
+        let deadline = client.banners.debounce_at().expect("debounce deadline");
+        assert!(deadline <= start + Duration::from_millis(800));
+        assert!(deadline < time::Instant::now() + Duration::from_millis(500));
+
+        // The next burst is bounded from its own first banner
+        client.handle_banner_debounce(&logger).await;
+        let next = time::Instant::now();
+        client.handle_message(&logger, banner()).await.unwrap();
+        let deadline = client.banners.debounce_at().expect("debounce deadline");
+        assert!(deadline >= next + Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn concurrent_first_uplinks_connect_once() {
+        let router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let mut events = client.subscribe();
+        let logger = mk_logger();
+        // Both uplinks arrive before the first banner
+        for payload in 1..=2 {
+            client
+                .handle_uplink(
+                    &logger,
+                    Packet::from(helium_proto::Packet {
+                        payload: vec![payload],
+                        ..Default::default()
+                    }),
+                )
+                .await
+                .unwrap();
+        }
+        // They wait for the banner on the connection set up for the first
+        assert_eq!((2, 0), client.packet_counts().await);
+        assert_eq!(1, router.connections());
+        let mut connects = 0;
+        while let Ok(event) = events.try_recv() {
+            if event == ClientEvent::Connected {
+                connects += 1;
+            }
+        }
+        assert_eq!(1, connects);
+    }
+
+    /// Runs the given client until the given condition holds for the mock
+    /// router, then shuts the client down
+    async fn run_until<F>(client: &mut RouterClient, router: &MockRouter, done: F) -> RunExit
+    where
+        F: Fn(&MockRouter) -> bool,
+    {
+        // Keep the uplinks sender so the uplinks channel stays open
+        let (_uplinks, uplinks) = mpsc::channel(10);
+        let (shutdown, shutdown_listener) = triggered::trigger();
+        let logger = mk_logger();
+        let watch = async {
+            while !done(router) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            shutdown.trigger();
+        };
+        let run = async { tokio::join!(client.run(uplinks, shutdown_listener, &logger), watch).0 };
+        time::timeout(Duration::from_secs(10), run)
+            .await
+            .expect("client exit")
+            .expect("run exit")
+    }
+
+    #[tokio::test]
+    async fn stream_close_terminates() {
+        let router = MockRouter::start(vec![vec![Step::Close]]).await;
+        let (_, mut settings) = mk_settings();
+        settings.stream_close = StreamClosePolicy::Terminate;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        client.connect().await.unwrap();
+        // Never shut down, the client stops by itself
+        let exit = run_until(&mut client, &router, |_| false).await;
+        assert_eq!(ExitReason::StreamClosed, exit.reason);
+        assert_eq!(1, router.connections());
+    }
+
+    #[tokio::test]
+    async fn busier_routers_reconnect_first() {
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.reconnect_priority);
+        settings.reconnect_backoff = 10;
+        let priority = ReconnectPriority::new(Duration::from_secs(1));
+        let logger = mk_logger();
+        let mut clients = vec![];
+        for (port, purchases) in [(1, 0), (2, 20), (3, 10)].iter() {
+            let client = mk_client_with(
+                &format!("http://127.0.0.1:{}", mk_config(port), settings.clone())
+                    .with_reconnect_priority(priority.clone()),
+            )
+            .await;
+            for _ in 0..*purchases {
+                priority.record_purchase(client.oui, &client.client.uri.uri, client.clock.now());
+            }
+            clients.push(client);
+        }
+
+        // A mass outage closes all streams at once, and the reconnects are
+        // ranked once the backoff passed
+        for client in clients.iter_mut() {
+            assert!(client.handle_connection_lost(&logger, ExitReason::StreamClosed));
+            assert!(
+                client.reconnect_deadline.unwrap() < time::Instant::now() + Duration::from_secs(1)
+            );
+        }
+        let ranked = time::Instant::now();
+        let mut delays = vec![];
+        for client in clients.iter_mut() {
+            client.handle_reconnect(&logger).await;
+            // Connecting to the unreachable router fails and backs off again
+            delays.push(
+                client
+                    .reconnect_deadline
+                    .map_or(Duration::from_secs(0), |deadline| {
+                        deadline.saturating_duration_since(ranked)
+                    }),
+            );
+        }
+        let mut order: Vec<usize> = (0..clients.len()).collect();
+        order.sort_by_key(|index| delays[*index]);
+        assert_eq!(vec![1, 2, 0], order);
+        // The busiest router tried to connect right away, the others follow
+        // one step apart
+        assert!(delays[1] < Duration::from_secs(1));
+        assert!(delays[2] >= Duration::from_secs(1));
+        assert!(delays[0] >= Duration::from_secs(2));
+
+        // A router that reconnects on its own does not wait
+        let mut client = mk_client_with(
+            "http://127.0.0.1:4",
+            mk_config(settings).with_reconnect_priority(priority.clone()),
+        )
+        .await;
+        for other in clients.iter() {
+            priority.reconnected(other.oui, &other.client.uri.uri, other.clock.now());
+        }
+        assert!(client.handle_connection_lost(&logger, ExitReason::StreamClosed));
+        let ranked = time::Instant::now();
+        client.handle_reconnect(&logger).await;
+        assert!(client
+            .reconnect_deadline
+            .map_or(true, |deadline| deadline < ranked + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn stream_close_reconnects() {
+        let router = MockRouter::start(vec![vec![Step::Close], vec![Step::Close]]).await;
+        let (_, mut settings) = mk_settings();
+        assert_eq!(StreamClosePolicy::Reconnect, settings.stream_close);
+        settings.reconnect_backoff = 10;
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let mut events = client.subscribe();
+        client.connect().await.unwrap();
+        let exit = run_until(&mut client, &router, |router| router.connections() >= 3).await;
+        assert_eq!(ExitReason::Shutdown, exit.reason);
+        // Each close without a message in between doubled the backoff
+        assert_eq!(2, client.reconnect_attempts);
+        let mut received = vec![];
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            2,
+            received
+                .iter()
+                .filter(|event| **event == ClientEvent::ConnectionLost)
+                .count()
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_downlinks() {
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.downlink_concurrency);
+        settings.downlink_concurrency = 4;
+        let mut client = mk_client(settings).await;
+        let (downlinks, mut receiver) = mpsc::channel(10);
+        client.downlinks = downlinks.clone();
+        client.downlink_dispatcher = Some(DownlinkDispatcher::new(
+            downlinks,
+            Duration::from_secs(1),
+            4,
+        ));
+        let mut events = client.subscribe();
+        let logger = mk_logger();
+        let mk_data_down = |dev_addr: u8, fcnt: u8| helium_proto::Packet {
+            payload: vec![0x60, dev_addr, 0, 0, 0, 0, fcnt, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        };
+        // Uplinks the downlinks answer
+        for dev_addr in 1..=2 {
+            let downlink = Packet::from(mk_data_down(dev_addr, 0));
+            let uplink = mk_devaddr_uplink(downlink.downlink_dev_addr().unwrap(), 0.0);
+            client.recent_uplinks.record(&QuePacket::from(uplink));
+        }
+
+        for (dev_addr, fcnt) in [(1, 1), (2, 1), (1, 2)].iter() {
+            client
+                .handle_downlink(&logger, &mk_data_down(*dev_addr, *fcnt))
+                .await;
+        }
+        for _ in 0..3 {
+            let dispatched = next_dispatched(&mut client.downlink_dispatcher).await;
+            client.handle_delivery(&logger, dispatched);
+            assert_eq!(ClientEvent::DownlinkForwarded, events.try_recv().unwrap());
+        }
+        let mut first_device = vec![];
+        for _ in 0..3 {
+            let downlink = receiver.recv().await.unwrap();
+            if downlink.payload()[1] == 1 {
+                first_device.push(downlink.payload()[6]);
+            }
+        }
+        assert_eq!(vec![1, 2], first_device);
+    }
+
+    #[tokio::test]
+    async fn injected_downlinks() {
+        let (_, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        let (downlinks, mut receiver) = mpsc::channel(10);
+        client.downlinks = downlinks;
+        let logger = mk_logger();
+        // A data downlink to DevAddr 1
+        let downlink = Packet::from(helium_proto::Packet {
+            payload: vec![0x60, 1, 0, 0, 0, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        });
+        // Without an uplink to answer the downlink is unsolicited
+        client.inject_downlink(&logger, downlink.clone()).await;
+        assert!(receiver.try_recv().is_err());
+
+        let mut uplink = mk_devaddr_uplink(1, 0.0).to_packet();
+        uplink.timestamp = 10_000_000;
+        client.record_sent_uplink(Packet::from(uplink));
+        client.inject_downlink(&logger, downlink.clone()).await;
+        let forwarded = receiver.try_recv().expect("injected downlink");
+        assert_eq!(downlink.payload(), forwarded.payload());
+        // Timed for the receive window of the uplink
+        assert_eq!(11_000_000, forwarded.timestamp);
+        assert_eq!(1, client.metrics_snapshot().downlinks);
+    }
+
+    #[tokio::test]
+    async fn deduplicates_downlinks() {
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.downlink_dedup);
+        settings.downlink_dedup = 500;
+        settings.downlink_timeout = 50;
+        let clock = MockClock::default();
+        let mut client = mk_client_with(
+            NO_ROUTER,
+            mk_config(settings.clone()).with_clock(Arc::new(clock.clone())),
+        )
+        .await;
+        let (downlinks, mut receiver) = mpsc::channel(10);
+        client.downlinks = downlinks.clone();
+        let logger = mk_logger();
+        client.record_sent_uplink(mk_devaddr_uplink(1, 0.0));
+        // A data downlink to DevAddr 1
+        let downlink = Packet::from(helium_proto::Packet {
+            payload: vec![0x60, 1, 0, 0, 0, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        });
+
+        client.inject_downlink(&logger, downlink.clone()).await;
+        clock.advance(Duration::from_millis(100));
+        client.inject_downlink(&logger, downlink.clone()).await;
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(1, client.metrics_snapshot().duplicate_downlinks);
+
+        // Once the window passed the same downlink is forwarded again
+        clock.advance(Duration::from_millis(400));
+        client.inject_downlink(&logger, downlink.clone()).await;
+        assert!(receiver.try_recv().is_ok());
+        assert_eq!(2, client.metrics_snapshot().downlinks);
+
+        // A downlink that fails to be delivered is not a duplicate when the
+        // router sends it again
+        let retransmit = Packet::from(helium_proto::Packet {
+            payload: vec![0x60, 1, 0, 0, 0, 0, 2, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        });
+        let (full, _full_receiver) = mpsc::channel(1);
+        full.try_send(Packet::from(helium_proto::Packet::default()))
+            .unwrap();
+        client.downlinks = full;
+        client.inject_downlink(&logger, retransmit.clone()).await;
+        client.downlinks = downlinks;
+        client.inject_downlink(&logger, retransmit.clone()).await;
+        assert!(receiver.try_recv().is_ok());
+        assert_eq!(1, client.metrics_snapshot().duplicate_downlinks);
+
+        // Clients sharing the deduplication forward a downlink once
+        let (other_downlinks, mut other_receiver) = mpsc::channel(10);
+        let mut other = mk_client_with(
+            NO_ROUTER,
+            mk_config(settings)
+                .with_clock(Arc::new(clock.clone()))
+                .with_downlink_dedup(client.downlink_dedup.clone()),
+        )
+        .await;
+        other.downlinks = other_downlinks;
+        other.record_sent_uplink(mk_devaddr_uplink(1, 0.0));
+        other.inject_downlink(&logger, retransmit).await;
+        assert!(other_receiver.try_recv().is_err());
+        assert_eq!(1, other.metrics_snapshot().duplicate_downlinks);
+    }
+
+    #[tokio::test]
+    async fn downlink_send_modes() {
+        use crate::settings::DownlinkSendMode;
+        let logger = mk_logger();
+        let downlink = helium_proto::Packet {
+            payload: vec![0x60, 1, 0, 0, 0, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        };
+        for mode in vec![DownlinkSendMode::Wait, DownlinkSendMode::Drop] {
+            let (_, mut settings) = mk_settings();
+            assert_eq!(DownlinkSendMode::Wait, settings.downlink_send);
+            settings.downlink_send = mode;
+            settings.downlink_timeout = 200;
+            let mut client = mk_client(settings).await;
+            // A full channel nobody drains
+            let (downlinks, _receiver) = mpsc::channel(1);
+            downlinks
+                .try_send(Packet::from(helium_proto::Packet::default()))
+                .unwrap();
+            client.downlinks = downlinks;
+            let mut events = client.subscribe();
+            let uplink = mk_devaddr_uplink(
+                Packet::from(downlink.clone()).downlink_dev_addr().unwrap(),
+                0.0,
+            );
+            client.recent_uplinks.record(&QuePacket::from(uplink));
+
+            let start = time::Instant::now();
+            client.handle_downlink(&logger, &downlink).await;
+            match mode {
+                DownlinkSendMode::Wait => assert!(start.elapsed() >= Duration::from_millis(200)),
+                DownlinkSendMode::Drop => assert!(start.elapsed() < Duration::from_millis(200)),
+            }
+            assert!(events.try_recv().is_err());
+            assert!(!client.downlinks_closed);
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_crc_failed_uplink() {
+        let (_, mut settings) = mk_settings();
+        settings.devaddr_metrics = 10;
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        client
+            .handle_uplink(
+                &logger,
+                mk_devaddr_uplink(1, 0.0).with_crc_status(CrcStatus::Failed),
+            )
+            .await
+            .unwrap();
+        assert_eq!(1, client.metrics_snapshot().crc_drops);
+        assert_eq!(Some(DropReason::Crc), client.last_drop_reason(1));
+        assert!(!client.state_channel.is_connected());
+
+        // Checked and unchecked uplinks go on to the unreachable router
+        for crc in [CrcStatus::Ok, CrcStatus::Unknown].iter() {
+            assert!(client
+                .handle_uplink(&logger, mk_devaddr_uplink(2, 0.0).with_crc_status(*crc))
+                .await
+                .is_err());
+        }
+        assert_eq!(1, client.metrics_snapshot().crc_drops);
+        assert_eq!(None, client.last_drop_reason(2));
+    }
+
+    #[test]
+    fn validates_config() {
+        let (mut cache_settings, _) = mk_settings();
+        // Validating creates nothing in the store
+        cache_settings.store = std::env::temp_dir().join("gateway-rs-test-validate");
+        let _ = std::fs::remove_dir_all(&cache_settings.store);
+        let keypair = mk_keypair();
+        let mut uri = mk_keyed_uri("http://127.0.0.1:1");
+        assert_eq!(
+            Vec::<ConfigProblem>::new(),
+            RouterClient::validate_config(&uri, &keypair, &cache_settings)
+        );
+        assert!(!cache_settings.store.exists());
+
+        uri.uri = "/router".parse().unwrap();
+        cache_settings.store = PathBuf::new();
+        cache_settings.max_packets = 0;
+        cache_settings.compact_interval = 0;
+        assert_eq!(
+            vec![
+                ConfigProblem::InvalidUri("/router".to_string()),
+                ConfigProblem::InvalidStoreSetting("store"),
+                ConfigProblem::InvalidStoreSetting("max_packets"),
+                ConfigProblem::InvalidStoreSetting("compact_interval"),
+            ],
+            RouterClient::validate_config(&uri, &keypair, &cache_settings)
+        );
+        // A uri with a host but no scheme can not be connected to either
+        uri.uri = "router.example.com:8080".parse().unwrap();
+        assert_eq!(
+            Some(&ConfigProblem::InvalidUri(
+                "router.example.com:8080".to_string()
+            )),
+            RouterClient::validate_config(&uri, &keypair, &cache_settings).first()
+        );
+    }
+
+    #[test]
+    fn unusable_keypair() {
+        let keypair = mk_keypair();
+        assert!(signs_for(&keypair, keypair.public_key()));
+        // Signatures that verify against another key are of no use
+        assert!(!signs_for(&keypair, &mk_public_key()));
+    }
+
+    #[tokio::test]
+    async fn revalidates_on_gateway_swap() {
+        for enabled in [false, true].iter() {
+            let (_, mut settings) = mk_settings();
+            assert!(!settings.revalidate_on_gateway_swap);
+            settings.revalidate_on_gateway_swap = *enabled;
+            let mut client = mk_client(settings).await;
+            let logger = mk_logger();
+            client.gateway = GatewayService::new(mk_keyed_uri("http://127.0.0.1:2"))
+                .unwrap()
+                .with_height(50);
+            // Both are live by the old gateway
+            let mut expiring = mk_sc(1, 10);
+            expiring.id = vec![1];
+            let mut live = mk_sc(1, 10);
+            live.id = vec![2];
+            let expiring = mk_expiring_sc(&expiring, 100);
+            let live = mk_expiring_sc(&live, 1000);
+            for sc in [&expiring, &live].iter() {
+                client.insert_active_state_channel(sc).await.unwrap();
+            }
+
+            // The chain moves past the expiry of one of them before the swap.
+            // The new gateway has not reported a height yet but keeps the one
+            // of the old gateway. It is not reachable, so the state channel
+            // that is live by that height is kept without confirming it is
+            // active.
+            client.gateway.record_height(500);
+            let gateway = GatewayService::new(mk_keyed_uri("http://127.0.0.1:1")).unwrap();
+            assert_eq!(0, gateway.height());
+            client.handle_gateway_swap(&logger, gateway).await;
+            assert_eq!(500, client.gateway.height());
+            assert_eq!(*enabled, client.revalidation.is_some());
+            if *enabled {
+                // The lookups run in the background of the select loop
+                let (revalidation, cause) = next_revalidation(&mut client.revalidation).await;
+                assert_eq!("gateway_swap", cause);
+                client
+                    .handle_revalidation(&logger, revalidation, cause)
+                    .await;
+                assert!(client.revalidation.is_none());
+            }
+            let mut known: Vec<String> = client
+                .store
+                .state_channels()
+                .await
+                .unwrap()
+                .iter()
+                .map(|sc| sc.id_key())
+                .collect();
+            known.sort();
+            if *enabled {
+                assert_eq!(vec![live.id_key()], known);
+            } else {
+                assert_eq!(vec![expiring.id_key(), live.id_key()], known);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn evictions_archived() {
+        let (cache_settings, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        let name = client.client.uri.public_key.to_string();
+        client.store = RouterStore::new(
+            &name,
+            &CacheSettings {
+                max_state_channels: 1,
+                ..cache_settings
+            },
+        )
+        .await
+        .unwrap();
+        let mut sc_lifecycle = client.subscribe_sc_lifecycle();
+        let mut first = mk_sc(1, 10);
+        first.id = vec![1];
+        let mut second = mk_sc(1, 10);
+        second.id = vec![2];
+        let first = mk_expiring_sc(&first, 100);
+        let second = mk_expiring_sc(&second, 1000);
+        client.insert_active_state_channel(&first).await.unwrap();
+        assert!(sc_lifecycle.try_recv().is_err());
+
+        // Making room for another state channel archives the evicted one
+        client.insert_active_state_channel(&second).await.unwrap();
+        assert_eq!(
+            ScLifecycle::Archived {
+                sc_id: first.id_key()
+            },
+            sc_lifecycle.try_recv().unwrap()
+        );
+        assert!(sc_lifecycle.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn revalidation_prunes_inactive() {
+        let (_, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        let mut inactive = mk_sc(1, 10);
+        inactive.id = vec![1];
+        let mut unanswered = mk_sc(1, 10);
+        unanswered.id = vec![2];
+        let inactive = mk_expiring_sc(&inactive, 1000);
+        let unanswered = mk_expiring_sc(&unanswered, 1000);
+        for sc in [&inactive, &unanswered].iter() {
+            client.insert_active_state_channel(sc).await.unwrap();
+        }
+        let mut sc_lifecycle = client.subscribe_sc_lifecycle();
+
+        // The state channel the gateway does not know as active is pruned,
+        // the one the gateway failed to answer for is kept
+        let revalidation = Revalidation {
+            height: 600,
+            lookups: vec![
+                (inactive.clone(), Err(StateChannelError::inactive())),
+                (unanswered.clone(), Err(Error::custom("unreachable"))),
+            ],
+        };
+        assert_eq!(
+            1,
+            client
+                .apply_revalidation(&logger, revalidation)
+                .await
+                .unwrap()
+        );
+        assert_eq!(600, client.gateway.height());
+        let known: Vec<String> = client
+            .store
+            .state_channels()
+            .await
+            .unwrap()
+            .iter()
+            .map(|sc| sc.id_key())
+            .collect();
+        assert_eq!(vec![unanswered.id_key()], known);
+        assert_eq!(
+            ScLifecycle::Archived {
+                sc_id: inactive.id_key()
+            },
+            sc_lifecycle.try_recv().unwrap()
+        );
+        assert!(sc_lifecycle.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn revalidates_on_restart() {
+        let (cache_settings, settings) = mk_settings();
+        let mut expiring = mk_sc(1, 10);
+        expiring.id = vec![1];
+        let mut live = mk_sc(1, 10);
+        live.id = vec![2];
+        let expiring = mk_expiring_sc(&expiring, 100);
+        let live = mk_expiring_sc(&live, 1000);
+        let client = mk_client(settings.clone()).await;
+        for sc in [&expiring, &live].iter() {
+            client.insert_active_state_channel(sc).await.unwrap();
+        }
+        let name = client.client.uri.public_key.to_string();
+        drop(client);
+
+        // The restarted client opens the same store and prunes the state
+        // channel that expired while it was down
+        let mut client = mk_client(settings).await;
+        client.store = RouterStore::new(&name, &cache_settings).await.unwrap();
+        client.gateway = GatewayService::new(mk_keyed_uri("http://127.0.0.1:1"))
+            .unwrap()
+            .with_height(500);
+        let (_uplinks, uplinks) = mpsc::channel(10);
+        let (shutdown, shutdown_listener) = triggered::trigger();
+        shutdown.trigger();
+        let exit = client
+            .run(uplinks, shutdown_listener, &mk_logger())
+            .await
+            .unwrap();
+        assert_eq!(ExitReason::Shutdown, exit.reason);
+        let known: Vec<String> = client
+            .store
+            .state_channels()
+            .await
+            .unwrap()
+            .iter()
+            .map(|sc| sc.id_key())
+            .collect();
+        assert_eq!(vec![live.id_key()], known);
+    }
+
+    #[tokio::test]
+    async fn background_writes() {
+        use tokio::sync::Semaphore;
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.background_writes);
+        settings.background_writes = 4;
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        let records = client.store.records().to_path_buf();
+        let _ = tokio::fs::remove_dir_all(&records).await;
+        let log_path = records.join("rejects.log");
+        let mk_line = |line: &str| BackgroundWrite::RejectLog(line.to_string());
+
+        // Without a queue writes happen inline
+        let inline = BackgroundWriter::new(client.store.clone(), 0);
+        inline.write(mk_line("inline")).await.unwrap();
+        assert_eq!(
+            "inline\n",
+            tokio::fs::read_to_string(&log_path).await.unwrap()
+        );
+
+        // A held up background writer does not block handling a reject
+        let gate = Arc::new(Semaphore::new(0));
+        client.writer = BackgroundWriter::with_gate(client.store.clone(), 4, gate.clone());
+        que_offered(&client, 5).await;
+        let reject = StateChannelMessage::from(helium_proto::BlockchainStateChannelRejectionV1 {
+            reject: 3,
+            ..Default::default()
+        });
+        time::timeout(
+            Duration::from_secs(1),
+            client.handle_state_channel_message(&logger, reject),
+        )
+        .await
+        .expect("handled reject")
+        .unwrap();
+        assert_eq!(1, client.metrics_snapshot().rejects);
+        assert_eq!(
+            "inline\n",
+            tokio::fs::read_to_string(&log_path).await.unwrap()
+        );
+
+        // while accepted state channels are written before the purchase is
+        // handled
+        let purchase_sc = mk_sc(2, 11);
+        time::timeout(
+            Duration::from_secs(1),
+            client.handle_state_channel_message(&logger, mk_purchase(purchase_sc.clone())),
+        )
+        .await
+        .expect("handled purchase")
+        .unwrap();
+        let stored = client.store.get_state_channel(vec![1]).await.unwrap();
+        assert_eq!(
+            mk_active_sc(&purchase_sc).hash_key(),
+            stored.unwrap().hash_key()
+        );
+
+        // and so are the conflicting versions of rejected state channels
+        let conflicting = mk_active_sc(&mk_sc(2, 12));
+        client
+            .write_rejected_state_channel(conflicting)
+            .await
+            .unwrap();
+        assert!(client.store.get_state_channel(vec![1]).await.is_err());
+
+        gate.add_permits(1);
+        client.writer.flush().await;
+        let log = tokio::fs::read_to_string(&log_path).await.unwrap();
+        let rejected: Vec<&str> = log.lines().nth(1).unwrap().split(' ').collect();
+        assert_eq!("3", rejected[1]);
+        assert_eq!(
+            QuePacket::from(Packet::from(helium_proto::Packet {
+                payload: vec![5],
+                ..Default::default()
+            }))
+            .id()
+            .to_string(),
+            rejected[2]
+        );
+
+        // A full queue waits for room instead of overtaking the queued writes
+        client.writer = BackgroundWriter::with_gate(client.store.clone(), 1, gate.clone());
+        client.writer.write(mk_line("first")).await.unwrap();
+        client.writer.write(mk_line("second")).await.unwrap();
+        tokio::join!(client.writer.write(mk_line("third")), async {
+            time::sleep(Duration::from_millis(50)).await;
+            gate.add_permits(3);
+        })
+        .0
+        .unwrap();
+        client.writer.flush().await;
+        let log = tokio::fs::read_to_string(&log_path).await.unwrap();
+        let tail: Vec<&str> = log.lines().skip(2).collect();
+        assert_eq!(vec!["first", "second", "third"], tail);
+        assert_eq!(0, client.metrics_snapshot().background_write_failures);
+
+        // Background failures show up in the metrics
+        tokio::fs::remove_dir_all(&records).await.unwrap();
+        tokio::fs::write(&records, b"").await.unwrap();
+        client.writer.write(mk_line("failed")).await.unwrap();
+        gate.add_permits(1);
+        client.writer.flush().await;
+        assert_eq!(1, client.metrics_snapshot().background_write_failures);
+        tokio::fs::remove_file(&records).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sc_lifecycle() {
+        let (_, settings) = mk_settings();
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        let active = mk_active_sc(&mk_sc(1, 10));
+        let mut expiring = mk_sc(1, 10);
+        expiring.id = vec![2];
+        let expiring = mk_expiring_sc(&expiring, 100);
+        for sc in [&active, &expiring].iter() {
+            client.insert_active_state_channel(sc).await.unwrap();
+        }
+        let mut sc_lifecycle = client.subscribe_sc_lifecycle();
+
+        // A purchase advances the nonce, an unchanged banner does not
+        let purchase_sc = mk_sc(2, 11);
+        client
+            .handle_state_channel_message(&logger, mk_purchase(purchase_sc.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            ScLifecycle::Updated {
+                sc_id: active.id_key(),
+                nonce: 2
+            },
+            sc_lifecycle.try_recv().unwrap()
+        );
+        client
+            .handle_message(
+                &logger,
+                StateChannelMessage::from(helium_proto::BlockchainStateChannelBannerV1 {
+                    sc: Some(purchase_sc),
+                }),
+            )
+            .await
+            .unwrap();
+        assert!(sc_lifecycle.try_recv().is_err());
+
+        // Revalidation prunes the state channel expired by the gateway view
+        client.gateway = GatewayService::new(mk_keyed_uri("http://127.0.0.1:1"))
+            .unwrap()
+            .with_height(500);
+        assert_eq!(1, client.revalidate_state_channels(&logger).await.unwrap());
+        let expiring_id = expiring.id_key();
+        assert_eq!(
+            ScLifecycle::Expired {
+                sc_id: expiring_id.clone()
+            },
+            sc_lifecycle.try_recv().unwrap()
+        );
+        assert_eq!(
+            ScLifecycle::Archived { sc_id: expiring_id },
+            sc_lifecycle.try_recv().unwrap()
+        );
+
+        // Compaction past its expiry removes the remaining state channel
+        client.gateway = GatewayService::new(mk_keyed_uri("http://127.0.0.1:1"))
+            .unwrap()
+            .with_height(2000);
+        client.compact_store(&logger, client.gateway.height()).await;
+        assert_eq!(
+            ScLifecycle::Expired {
+                sc_id: active.id_key()
+            },
+            sc_lifecycle.try_recv().unwrap()
+        );
+        assert_eq!(
+            ScLifecycle::Archived {
+                sc_id: active.id_key()
+            },
+            sc_lifecycle.try_recv().unwrap()
+        );
+        assert!(sc_lifecycle.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn region_counts() {
+        let router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let logger = mk_logger();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        client.connect().await.unwrap();
+        // Hold the uplinks as waiting so they are offered together
+        client.connect_deadline = Some(time::Instant::now() + Duration::from_secs(60));
+        for frequency in [903.9, 868.1, 868.3].iter() {
+            let mut uplink = mk_devaddr_uplink(1, 0.0).to_packet();
+            uplink.frequency = *frequency;
+            client
+                .handle_uplink(&logger, Packet::from(uplink))
+                .await
+                .unwrap();
+        }
+        client.offer_waiting_packets(&logger).await.unwrap();
+        // The purchase is for the first offered, US915, packet
+        client
+            .handle_message(&logger, mk_purchase(mk_sc(2, 11)))
+            .await
+            .unwrap();
+
+        let regions = client.metrics_snapshot().regions;
+        assert_eq!(
+            RegionCounts {
+                uplinks: 1,
+                offers: 1,
+                purchases: 1,
+            },
+            regions["US915"]
+        );
+        assert_eq!(
+            RegionCounts {
+                uplinks: 2,
+                offers: 2,
+                purchases: 0,
+            },
+            regions["EU868"]
+        );
+    }
+
+    #[tokio::test]
+    async fn devaddr_rules() {
+        use crate::settings::{DevAddrAction, DevAddrRule};
+        let (_, mut settings) = mk_settings();
+        assert!(settings.devaddr_rules.is_empty());
+        settings.devaddr_metrics = 10;
+        let mk_rule = |router: Option<String>, start, end, action| DevAddrRule {
+            router,
+            start,
+            end,
+            action,
+        };
+        settings.devaddr_rules = vec![
+            mk_rule(None, 0x10, 0x1f, DevAddrAction::Allow),
+            mk_rule(None, 0x18, 0x18, DevAddrAction::Deny),
+            // A rule for another router
+            mk_rule(
+                Some(mk_keypair().public_key().to_string()),
+                0x12,
+                0x12,
+                DevAddrAction::Deny,
+            ),
+        ];
+        let mut client = mk_client(settings).await;
+        let logger = mk_logger();
+        // Denied, and outside of the allowed range
+        for dev_addr in [0x18, 0x30].iter() {
+            client
+                .handle_uplink(&logger, mk_devaddr_uplink(*dev_addr, 0.0))
+                .await
+                .unwrap();
+            assert_eq!(Some(DropReason::Denied), client.last_drop_reason(*dev_addr));
+        }
+        assert_eq!(2, client.metrics_snapshot().denied_drops);
+        assert!(!client.state_channel.is_connected());
+        // Allowed uplinks go on to the unreachable router
+        for dev_addr in [0x10, 0x12, 0x1f].iter() {
+            assert!(client
+                .handle_uplink(&logger, mk_devaddr_uplink(*dev_addr, 0.0))
+                .await
+                .is_err());
+            assert_eq!(None, client.last_drop_reason(*dev_addr));
+        }
+        assert_eq!(2, client.metrics_snapshot().denied_drops);
+    }
+
+    #[tokio::test]
+    async fn site_tag() {
+        let mut router = MockRouter::start(vec![]).await;
+        let (_, mut settings) = mk_settings();
+        assert!(settings.site.is_none());
+        settings.site = Some("ams-1".to_string());
+        let mut client = mk_client_for(&router.uri, settings).await;
+        assert_eq!(Some("ams-1"), client.site());
+        let capture = Capture::default();
+        let logger = client.context_logger(&Logger::root(capture.clone(), o!()));
+        let packet = QuePacket::from(mk_devaddr_uplink(1, 0.0));
+        client.send_offer(&logger, &packet, None).await.unwrap();
+
+        let message = time::timeout(Duration::from_secs(10), router.received.recv())
+            .await
+            .expect("sent offer")
+            .expect("router message");
+        assert!(matches!(message.msg, Some(Msg::Offer(_))));
+        let records = capture.0.lock().unwrap();
+        let offer = records
+            .iter()
+            .find(|record| record.starts_with("sent offer"))
+            .expect("offer record");
+        assert!(offer.contains("site=ams-1"));
+    }
+
+    #[tokio::test]
+    async fn correlates_downlink_logs() {
+        let router = MockRouter::start(vec![]).await;
+        let (_, settings) = mk_settings();
+        let mut client = mk_client_for(&router.uri, settings).await;
+        let (downlinks, mut received) = mpsc::channel(10);
+        client.downlinks = downlinks;
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        let uplink = mk_devaddr_uplink(1, 0.0);
+        client.handle_uplink(&logger, uplink.clone()).await.unwrap();
+        client
+            .handle_state_channel_message(&logger, mk_purchase(mk_sc(2, 11)))
+            .await
+            .unwrap();
+        let downlink = helium_proto::Packet {
+            payload: vec![0x60, 1, 0, 0, 0, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        };
+        client.handle_downlink(&logger, &downlink).await;
+        assert!(received.recv().await.is_some());
+
+        // The offer, the sent packet and the downlink answering it all log
+        // the id of the uplink
+        let token = format!("packet_id={}", uplink.id());
+        let records = capture.0.lock().unwrap();
+        for message in ["sent offer", "sent packet", "forwarding downlink"].iter() {
+            let record = records
+                .iter()
+                .find(|record| record.starts_with(message))
+                .expect("logged record");
+            assert!(record.contains(&token), "{}", record);
+        }
+    }
+
+    /// Creates a client with the message tap enabled and a known state
+    /// channel that signs with the given keypair, along with the receiver of
+    /// its downlinks
+    async fn mk_tapped_client(
+        router: &MockRouter,
+        keypair: &Arc<Keypair>,
+        clock: &MockClock,
+    ) -> (RouterClient, mpsc::Receiver<Packet>) {
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.message_tap);
+        settings.message_tap = 10;
+        let mut client = mk_client_with(
+            &router.uri,
+            mk_config(settings).with_clock(Arc::new(clock.clone())),
+        )
+        .await;
+        client.keypair = keypair.clone();
+        client
+            .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+            .await
+            .unwrap();
+        let (downlinks, receiver) = mpsc::channel(10);
+        client.downlinks = downlinks;
+        (client, receiver)
+    }
+
+    #[tokio::test]
+    async fn replays_capture() {
+        let router = MockRouter::start(vec![]).await;
+        let keypair = Arc::new(mk_keypair());
+        let clock = MockClock::default();
+        let logger = mk_logger();
+        let (mut client, mut downlinks) = mk_tapped_client(&router, &keypair, &clock).await;
+        // An uplink that is offered, purchased and answered with a downlink
+        client
+            .handle_uplink(&logger, mk_devaddr_uplink(1, 0.0))
+            .await
+            .unwrap();
+        client.receive_message(mk_purchase(mk_sc(2, 11)).to_message());
+        client.handle_buffered_messages(&logger).await;
+        let response = StateChannelMessage::from(helium_proto::BlockchainStateChannelResponseV1 {
+            downlink: Some(helium_proto::Packet {
+                payload: vec![0x60, 1, 0, 0, 0, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        client.receive_message(response.to_message());
+        client.handle_buffered_messages(&logger).await;
+        assert!(downlinks.recv().await.is_some());
+        let capture = client.export_messages();
+        let captured: Vec<TapMessage> = MessageTap::decode(&capture)
+            .unwrap()
+            .into_iter()
+            .filter(TapMessage::is_output)
+            .collect();
+        assert!(matches!(
+            captured[..],
+            [
+                TapMessage::Sent(_),
+                TapMessage::Sent(_),
+                TapMessage::Downlink(_)
+            ]
+        ));
+
+        let (mut fresh, mut downlinks) = mk_tapped_client(&router, &keypair, &clock).await;
+        let replayed = fresh.replay(&logger, &capture).await.unwrap();
+        assert_eq!(captured, replayed);
+        assert!(downlinks.recv().await.is_some());
+        // Replaying is not recorded over the tap of the client
+        assert!(fresh.export_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shared_validation_governor() {
+        let router = MockRouter::start(vec![]).await;
+        let governor = ValidationGovernor::new(1);
+        let logger = mk_logger();
+        let mut clients = vec![];
+        for _ in 0..6 {
+            let (_, settings) = mk_settings();
+            let client = mk_client_with(
+                &router.uri,
+                mk_config(settings).with_validation_governor(governor.clone()),
+            )
+            .await;
+            client
+                .insert_active_state_channel(&mk_active_sc(&mk_sc(1, 10)))
+                .await
+                .unwrap();
+            que_offered(&client, 1).await;
+            clients.push(client);
+        }
+        // Other work holds the limit for a while
+        let (acquired_tx, acquired) = tokio::sync::oneshot::channel();
+        let holder = tokio::spawn({
+            let governor = governor.clone();
+            async move {
+                governor
+                    .run(async {
+                        let _ = acquired_tx.send(());
+                        time::sleep(Duration::from_millis(200)).await;
+                    })
+                    .await;
+            }
+        });
+        acquired.await.unwrap();
+        let start = time::Instant::now();
+        // All clients validate a purchase at once, one at a time
+        let purchases = clients
+            .iter_mut()
+            .map(|client| client.handle_message(&logger, mk_purchase(mk_sc(2, 11))));
+        for result in futures::future::join_all(purchases).await {
+            result.unwrap();
+        }
+        // The purchases waited for the room the holder left
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        holder.await.unwrap();
+        for client in &clients {
+            assert_eq!(1, client.metrics_snapshot().purchases);
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_offer_limit() {
+        let mut router = MockRouter::start(vec![]).await;
+        let clock = MockClock::default();
+        let limiter = OfferLimiter::new(20);
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let (_, settings) = mk_settings();
+            let client = mk_client_with(
+                &router.uri,
+                mk_config(settings)
+                    .with_clock(Arc::new(clock.clone()))
+                    .with_offer_limiter(limiter.clone()),
+            )
+            .await;
+            for tag in 0..15 {
+                client
+                    .store
+                    .store_waiting_packet(Packet::from(helium_proto::Packet {
+                        payload: mk_payload(1, tag),
+                        ..Default::default()
+                    }))
+                    .await
+                    .unwrap();
+            }
+            clients.push(client);
+        }
+        let logger = mk_logger();
+        // A burst of 20 offers goes out right away across both clients
+        assert_eq!(15, clients[0].offer_packets(&logger, None).await.unwrap());
+        assert_eq!(5, clients[1].offer_packets(&logger, None).await.unwrap());
+        // The second client is not held up by the limit, its remaining
+        // packets wait for the limit deadline
+        let deadline = clients[1]
+            .limited_offers
+            .resume_at()
+            .expect("offer limit deadline");
+        assert!(deadline <= time::Instant::now() + Duration::from_millis(50));
+        assert_eq!(10, clients[1].store.packet_counts().await.0);
+        assert_eq!(0, clients[1].offer_packets(&logger, None).await.unwrap());
+        // 20 offers per second refill in half a second for the rest
+        clock.advance(Duration::from_millis(250));
+        clients[1].handle_offer_limit(&logger).await;
+        assert_eq!(5, clients[1].store.packet_counts().await.0);
+        assert!(clients[1].limited_offers.is_held());
+        clock.advance(Duration::from_millis(250));
+        clients[1].handle_offer_limit(&logger).await;
+        assert_eq!(0, clients[1].store.packet_counts().await.0);
+        assert!(!clients[1].limited_offers.is_held());
+        for _ in 0..30 {
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent offer")
+                .expect("router message");
+            assert!(matches!(message.msg, Some(Msg::Offer(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_actions() {
+        use crate::settings::RejectActionSetting;
+        let (_, mut settings) = mk_settings();
+        assert!(settings.reject_actions.is_empty());
+        // Code 1 for a device the router does not know, code 2 for a lack of
+        // balance
+        settings.reject_actions = vec![
+            RejectActionSetting {
+                code: 1,
+                action: RejectAction::Redispatch,
+            },
+            RejectActionSetting {
+                code: 2,
+                action: RejectAction::Drop,
+            },
+        ];
+        let (redispatch, mut redispatched) = mpsc::channel(10);
+        let mut client =
+            mk_client_with(NO_ROUTER, mk_config(settings).with_redispatch(redispatch)).await;
+        let logger = mk_logger();
+        let mk_reject = |reject| {
+            StateChannelMessage::from(helium_proto::BlockchainStateChannelRejectionV1 {
+                reject,
+                ..Default::default()
+            })
+        };
+
+        que_offered(&client, 5).await;
+        client
+            .handle_state_channel_message(&logger, mk_reject(1))
+            .await
+            .unwrap();
+        let rejected = redispatched.try_recv().expect("redispatched packet");
+        assert_eq!(vec![5u8], rejected.packet.payload());
+        assert_eq!(1, rejected.offers);
+        assert_eq!(client.client.uri.uri, rejected.uri);
+
+        que_offered(&client, 6).await;
+        client
+            .handle_state_channel_message(&logger, mk_reject(2))
+            .await
+            .unwrap();
+        assert!(redispatched.try_recv().is_err());
+        assert_eq!(0, client.store.packet_counts().await.1);
+        let snapshot = client.metrics_snapshot();
+        assert_eq!(2, snapshot.rejects);
+        assert_eq!(1, snapshot.redispatched);
+    }
+
+    #[tokio::test]
+    async fn lost_connection() {
+        let (_, mut settings) = mk_settings();
+        assert_eq!(0, settings.keepalive);
+        let logger = mk_logger();
+        let mut client = mk_client(settings.clone()).await;
+        let mut events = client.subscribe();
+        // Without keepalives a failed stream stops the client
+        assert!(!client.handle_connection_lost(&logger, ExitReason::StreamError));
+        assert!(events.try_recv().is_err());
+        assert!(client.reconnect_deadline.is_none());
+
+        settings.keepalive = 30;
+        settings.reconnect_backoff = 100;
+        let mut client = mk_client(settings).await;
+        let mut events = client.subscribe();
+        // With keepalives it is reconnected after the same backoff as a
+        // closed stream, doubling while the reconnects keep failing
+        let mut delays = vec![];
+        for _ in 0..2 {
+            let lost = time::Instant::now();
+            assert!(client.handle_connection_lost(&logger, ExitReason::StreamError));
+            delays.push(client.reconnect_deadline.unwrap() - lost);
+            assert_eq!(ClientEvent::ConnectionLost, events.try_recv().unwrap());
+            assert!(!client.state_channel.is_connected());
+        }
+        assert!(delays[0] >= Duration::from_millis(100));
+        assert!(delays[1] >= Duration::from_millis(200));
+        assert_eq!(2, client.reconnect_attempts);
+    }
+
+    #[test]
+    fn failed_uplink_log_context() {
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone(), o!());
+        let packet = Packet::from(helium_proto::Packet {
+            payload: vec![1, 2, 3],
+            ..Default::default()
+        });
+        let err = Error::custom("uplink failure");
+        log_failed_uplink(&logger, &packet.id(), Some(0x01020304), &err);
+
+        let records = capture.0.lock().unwrap();
+        assert_eq!(1, records.len());
+        assert!(records[0].starts_with("ignoring failed uplink"));
+        assert!(records[0].contains(&format!("packet_id={}", packet.id())));
+        assert!(records[0].contains("dev_addr=01020304"));
+    }
+
+    #[tokio::test]
+    async fn per_oui_keypairs() {
+        use crate::MsgVerify;
+        let mut router = MockRouter::start(vec![]).await;
+        let default = Arc::new(mk_keypair());
+        let oui_2 = Arc::new(mk_keypair());
+        let keypairs = OuiKeypairs::new(default.clone()).with_oui(2, oui_2.clone());
+        let logger = mk_logger();
+        for (oui, keypair, other) in vec![(1, &default, &oui_2), (2, &oui_2, &default)] {
+            let (cache_settings, _) = mk_settings();
+            let (downlinks, _) = mpsc::channel(10);
+            let gateway = GatewayService::new(mk_keyed_uri("http://127.0.0.1:1")).expect("gateway");
+            let mut client = RouterClient::new(
+                RouterClientConfig::default()
+                    .with_oui(oui)
+                    .with_cache_settings(cache_settings),
+                mk_keyed_uri(&router.uri),
+                gateway,
+                downlinks,
+                keypairs.clone(),
+            )
+            .await
+            .expect("router client");
+            let packet = QuePacket::from(Packet::from(helium_proto::Packet {
+                payload: mk_payload(1, oui as u8),
+                ..Default::default()
+            }));
+            client.send_offer(&logger, &packet, None).await.unwrap();
+            let message = time::timeout(Duration::from_secs(10), router.received.recv())
+                .await
+                .expect("sent offer")
+                .expect("router message");
+            match message.msg {
+                Some(Msg::Offer(offer)) => {
+                    assert_eq!(keypair.public_key().to_vec(), offer.hotspot);
+                    assert!(offer.verify(keypair.public_key()).is_ok());
+                    assert!(offer.verify(other.public_key()).is_err());
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+            // Audit exports are signed by the gateway whatever the OUI
+            let export = client
+                .export_audit(SystemTime::UNIX_EPOCH..SystemTime::now())
+                .unwrap();
+            assert!(export.verify(default.public_key()).is_ok());
         }
     }
 }
@@ -0,0 +1,298 @@
+use crate::{settings::PacketEncoding, CrcStatus, Error, Packet, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use helium_proto::{routing_information::Data as RoutingData, Eui, RoutingInformation, Window};
+use prost::Message;
+
+/// Encodes the packets saved by the store. Every codec has its own tag,
+/// which is saved along with the packets so that they are decoded with the
+/// codec they were saved with, even if the configured encoding changed
+/// since.
+pub trait PacketCodec: Send + Sync {
+    fn tag(&self) -> u8;
+    fn encode(&self, packet: &Packet, buf: &mut BytesMut) -> Result;
+    fn decode(&self, buf: &[u8]) -> Result<Packet>;
+}
+
+/// The default codec, the protobuf encoding of the packet. Larger than the
+/// compact encoding but carries every field of the packet, including those
+/// added by later protocol versions.
+#[derive(Debug)]
+pub struct ProtobufCodec;
+
+/// A fixed layout of the packet fields without protobuf field tags and
+/// varints, which is cheaper to decode on slow platforms. Packet fields it
+/// does not know about are not saved.
+#[derive(Debug)]
+pub struct CompactCodec;
+
+const CODECS: [&dyn PacketCodec; 2] = [&ProtobufCodec, &CompactCodec];
+
+impl PacketEncoding {
+    pub fn codec(&self) -> &'static dyn PacketCodec {
+        match self {
+            Self::Protobuf => &ProtobufCodec,
+            Self::Compact => &CompactCodec,
+        }
+    }
+}
+
+/// Returns the codec with the given tag, if any.
+pub fn codec_for_tag(tag: u8) -> Option<&'static dyn PacketCodec> {
+    CODECS.iter().copied().find(|codec| codec.tag() == tag)
+}
+
+impl PacketCodec for ProtobufCodec {
+    fn tag(&self) -> u8 {
+        1
+    }
+
+    fn encode(&self, packet: &Packet, buf: &mut BytesMut) -> Result {
+        buf.put_u8(crc_to_u8(packet.crc_status()));
+        Message::encode(&**packet, buf)?;
+        Ok(())
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Packet> {
+        let mut buf = buf;
+        let crc = crc_from_u8(get_u8(&mut buf)?)?;
+        let packet = helium_proto::Packet::decode(buf)?;
+        Ok(Packet::from(packet).with_crc_status(crc))
+    }
+}
+
+const ROUTING_NONE: u8 = 0;
+const ROUTING_EMPTY: u8 = 1;
+const ROUTING_DEVADDR: u8 = 2;
+const ROUTING_EUI: u8 = 3;
+
+impl PacketCodec for CompactCodec {
+    fn tag(&self) -> u8 {
+        2
+    }
+
+    fn encode(&self, packet: &Packet, buf: &mut BytesMut) -> Result {
+        buf.put_u8(crc_to_u8(packet.crc_status()));
+        buf.put_u32(packet.oui);
+        buf.put_i32(packet.r#type);
+        buf.put_u64(packet.timestamp);
+        buf.put_f32(packet.signal_strength);
+        buf.put_f32(packet.frequency);
+        buf.put_f32(packet.snr);
+        if packet.datarate.len() > u8::MAX as usize {
+            return Err(Error::custom("packet datarate too long"));
+        }
+        buf.put_u8(packet.datarate.len() as u8);
+        buf.put_slice(packet.datarate.as_bytes());
+        if packet.payload.len() > u16::MAX as usize {
+            return Err(Error::custom("packet payload too long"));
+        }
+        buf.put_u16(packet.payload.len() as u16);
+        buf.put_slice(&packet.payload);
+        match packet.routing.as_ref().map(|routing| &routing.data) {
+            None => buf.put_u8(ROUTING_NONE),
+            Some(None) => buf.put_u8(ROUTING_EMPTY),
+            Some(Some(RoutingData::Devaddr(dev_addr))) => {
+                buf.put_u8(ROUTING_DEVADDR);
+                buf.put_u32(*dev_addr);
+            }
+            Some(Some(RoutingData::Eui(eui))) => {
+                buf.put_u8(ROUTING_EUI);
+                buf.put_u64(eui.deveui);
+                buf.put_u64(eui.appeui);
+            }
+        }
+        // Only downlinks have a second receive window, so it is rare enough
+        // to keep in its protobuf encoding
+        match &packet.rx2_window {
+            None => buf.put_u8(0),
+            Some(window) => {
+                buf.put_u8(1);
+                buf.put_u16(window.encoded_len() as u16);
+                window.encode(buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Packet> {
+        let mut buf = buf;
+        let crc = crc_from_u8(get_u8(&mut buf)?)?;
+        need(&buf, 4 + 4 + 8 + 4 * 3)?;
+        let oui = buf.get_u32();
+        let r#type = buf.get_i32();
+        let timestamp = buf.get_u64();
+        let signal_strength = buf.get_f32();
+        let frequency = buf.get_f32();
+        let snr = buf.get_f32();
+        let datarate_len = get_u8(&mut buf)? as usize;
+        let datarate = String::from_utf8(take(&mut buf, datarate_len)?.to_vec())
+            .map_err(|_| decode_error("invalid datarate"))?;
+        need(&buf, 2)?;
+        let payload_len = buf.get_u16() as usize;
+        let payload = take(&mut buf, payload_len)?.to_vec();
+        let routing = match get_u8(&mut buf)? {
+            ROUTING_NONE => None,
+            ROUTING_EMPTY => Some(RoutingInformation { data: None }),
+            ROUTING_DEVADDR => {
+                need(&buf, 4)?;
+                Some(RoutingInformation {
+                    data: Some(RoutingData::Devaddr(buf.get_u32())),
+                })
+            }
+            ROUTING_EUI => {
+                need(&buf, 16)?;
+                let deveui = buf.get_u64();
+                let appeui = buf.get_u64();
+                Some(RoutingInformation {
+                    data: Some(RoutingData::Eui(Eui { deveui, appeui })),
+                })
+            }
+            _ => return Err(decode_error("invalid routing")),
+        };
+        let rx2_window = match get_u8(&mut buf)? {
+            0 => None,
+            1 => {
+                need(&buf, 2)?;
+                let window_len = buf.get_u16() as usize;
+                Some(Window::decode(take(&mut buf, window_len)?)?)
+            }
+            _ => return Err(decode_error("invalid rx2 window")),
+        };
+        if !buf.is_empty() {
+            return Err(decode_error("trailing data"));
+        }
+        let packet = helium_proto::Packet {
+            oui,
+            r#type,
+            payload,
+            timestamp,
+            signal_strength,
+            frequency,
+            datarate,
+            snr,
+            routing,
+            rx2_window,
+        };
+        Ok(Packet::from(packet).with_crc_status(crc))
+    }
+}
+
+fn crc_to_u8(crc: CrcStatus) -> u8 {
+    match crc {
+        CrcStatus::Ok => 0,
+        CrcStatus::Failed => 1,
+        CrcStatus::Unknown => 2,
+    }
+}
+
+fn crc_from_u8(crc: u8) -> Result<CrcStatus> {
+    match crc {
+        0 => Ok(CrcStatus::Ok),
+        1 => Ok(CrcStatus::Failed),
+        2 => Ok(CrcStatus::Unknown),
+        _ => Err(decode_error("invalid crc status")),
+    }
+}
+
+fn decode_error(msg: &'static str) -> Error {
+    Error::Decode(prost::DecodeError::new(msg).into())
+}
+
+fn need(buf: &&[u8], len: usize) -> Result {
+    if buf.len() < len {
+        return Err(decode_error("not enough data"));
+    }
+    Ok(())
+}
+
+fn get_u8(buf: &mut &[u8]) -> Result<u8> {
+    need(buf, 1)?;
+    Ok(buf.get_u8())
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    need(buf, len)?;
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_packets() -> Vec<Packet> {
+        let uplink = helium_proto::Packet {
+            oui: 1,
+            r#type: 0,
+            payload: vec![0x40, 4, 3, 2, 1, 0, 1, 0, 0xde, 0xad],
+            timestamp: 1_000_000,
+            signal_strength: -80.5,
+            frequency: 903.9,
+            datarate: "SF7BW125".to_string(),
+            snr: 7.25,
+            routing: Some(RoutingInformation {
+                data: Some(RoutingData::Devaddr(0x01020304)),
+            }),
+            rx2_window: None,
+        };
+        let join = helium_proto::Packet {
+            payload: vec![0; 23],
+            routing: Some(RoutingInformation {
+                data: Some(RoutingData::Eui(Eui {
+                    deveui: 1,
+                    appeui: 2,
+                })),
+            }),
+            ..uplink.clone()
+        };
+        let downlink = helium_proto::Packet {
+            routing: None,
+            rx2_window: Some(Window {
+                timestamp: 2_000_000,
+                ..Default::default()
+            }),
+            ..uplink.clone()
+        };
+        let unrouted = helium_proto::Packet {
+            routing: Some(RoutingInformation { data: None }),
+            datarate: String::new(),
+            payload: vec![],
+            ..uplink.clone()
+        };
+        vec![
+            Packet::from(uplink).with_crc_status(CrcStatus::Ok),
+            Packet::from(join),
+            Packet::from(downlink).with_crc_status(CrcStatus::Failed),
+            Packet::from(unrouted),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for encoding in [PacketEncoding::Protobuf, PacketEncoding::Compact].iter() {
+            let codec = encoding.codec();
+            assert_eq!(codec.tag(), codec_for_tag(codec.tag()).unwrap().tag());
+            for packet in mk_packets() {
+                let mut buf = BytesMut::new();
+                codec.encode(&packet, &mut buf).unwrap();
+                assert_eq!(packet, codec.decode(&buf).unwrap(), "{:?}", encoding);
+            }
+        }
+        assert!(codec_for_tag(0).is_none());
+    }
+
+    #[test]
+    fn compact_rejects_truncated() {
+        for packet in mk_packets() {
+            let mut buf = BytesMut::new();
+            CompactCodec.encode(&packet, &mut buf).unwrap();
+            for len in 0..buf.len() {
+                assert!(CompactCodec.decode(&buf[..len]).is_err());
+            }
+            let mut trailing = buf.to_vec();
+            trailing.push(0);
+            assert!(CompactCodec.decode(&trailing).is_err());
+        }
+    }
+}
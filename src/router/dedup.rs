@@ -0,0 +1,108 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The content hashes of recently forwarded downlinks, so that a downlink a
+/// router sends again is only transmitted once. Hashes are forgotten a fixed
+/// window after they were forwarded. A window of zero disables
+/// deduplication.
+///
+/// Clones share the same hashes, so that a downlink more than one router
+/// sends is only transmitted once when the router clients share the
+/// deduplication, like those started by the dispatcher do.
+#[derive(Debug, Clone)]
+pub struct DownlinkDedup {
+    window: Duration,
+    forwarded: Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>,
+}
+
+impl DownlinkDedup {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            forwarded: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.window > Duration::from_secs(0)
+    }
+
+    /// Returns whether a downlink with the given hash was forwarded within
+    /// the window before the given time. Never true when deduplication is
+    /// disabled.
+    pub fn is_duplicate(&self, hash: &[u8], now: Instant) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let mut forwarded = self.forwarded.lock().expect("downlink dedup");
+        while let Some((at, _)) = forwarded.front() {
+            if now.saturating_duration_since(*at) < self.window {
+                break;
+            }
+            forwarded.pop_front();
+        }
+        forwarded.iter().any(|(_, forwarded)| forwarded == hash)
+    }
+
+    /// Records that a downlink with the given hash was forwarded at the
+    /// given time. Downlinks that failed to be delivered are not recorded,
+    /// so that the router sending them again is not mistaken for a
+    /// duplicate.
+    pub fn record(&self, hash: Vec<u8>, now: Instant) {
+        if self.is_enabled() {
+            self.forwarded
+                .lock()
+                .expect("downlink dedup")
+                .push_back((now, hash));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.forwarded.lock().expect("downlink dedup").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_within_window() {
+        let dedup = DownlinkDedup::new(Duration::from_millis(500));
+        let start = Instant::now();
+        // Only forwarded downlinks are duplicates
+        assert!(!dedup.is_duplicate(&[1], start));
+        assert!(!dedup.is_duplicate(&[1], start));
+        dedup.record(vec![1], start);
+        dedup.record(vec![2], start);
+        assert!(dedup.is_duplicate(&[1], start + Duration::from_millis(499)));
+        // Forwarded hashes are forgotten after the window
+        assert!(!dedup.is_duplicate(&[1], start + Duration::from_millis(500)));
+        assert!(dedup.is_empty());
+    }
+
+    #[test]
+    fn shared_by_clones() {
+        let dedup = DownlinkDedup::new(Duration::from_millis(500));
+        let now = Instant::now();
+        dedup.clone().record(vec![1], now);
+        assert!(dedup.is_duplicate(&[1], now));
+        assert_eq!(1, dedup.len());
+    }
+
+    #[test]
+    fn disabled() {
+        let dedup = DownlinkDedup::new(Duration::from_millis(0));
+        let now = Instant::now();
+        dedup.record(vec![1], now);
+        assert!(!dedup.is_duplicate(&[1], now));
+        assert!(dedup.is_empty());
+    }
+}
@@ -1,16 +1,29 @@
-use super::{RouterClient, Routing};
+use super::{
+    client::reconnect_delay, selector, DownlinkDedup, EconomyMode, GatewayLookups, OfferLimiter,
+    ReconnectPriority, RouterClient, RouterClientConfig, Routing, RunExit, ValidationGovernor,
+};
 use crate::{
     service::gateway::{self, GatewayService},
-    CacheSettings, KeyedUri, Keypair, Packet, Region, Result, Settings,
+    settings::{DispatchSettings, RouterSelection},
+    CacheSettings, ClientSettings, Error, KeyedUri, OuiKeypairs, Packet, Region, Result, Settings,
 };
 use futures::{
     future::join_all,
     task::{Context, Poll},
+    Future,
 };
 use http::uri::Uri;
 use slog::{debug, info, o, warn, Logger};
 use slog_scope;
-use std::{collections::HashMap, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{sync::mpsc, task::JoinHandle, time};
 use tokio_stream::{self as stream, StreamExt};
 
@@ -18,10 +31,30 @@ use tokio_stream::{self as stream, StreamExt};
 pub enum Dispatch {
     Packet(Packet),
     Gateway(GatewayService),
+    /// The owner of the OUI of the router changed
+    RouterOwner(Vec<u8>),
+    /// Offer the waiting packets of the DevAddr right away
+    FlushDevAddr(u32),
+    /// A packet another router rejected, along with the offers made for it
+    Redispatch(Redispatch),
+    /// The region of the gateway changed
+    Region(Region),
+}
+
+/// A packet a router rejected with a reason that hands it to the default
+/// router instead
+#[derive(Debug, Clone)]
+pub struct Redispatch {
+    /// The uri of the router that rejected the packet
+    pub uri: Uri,
+    pub packet: Packet,
+    /// The number of times the packet was offered so far, which carries over
+    /// to the maximum offer attempts of the default router
+    pub offers: u32,
 }
 
 pub struct Dispatcher {
-    keypair: Arc<Keypair>,
+    keypairs: OuiKeypairs,
     region: Region,
     downlinks: mpsc::Sender<Packet>,
     uplinks: mpsc::Receiver<Packet>,
@@ -30,6 +63,20 @@ pub struct Dispatcher {
     gateway: GatewayService,
     default_router: KeyedUri,
     cache_settings: CacheSettings,
+    client_settings: ClientSettings,
+    dispatch_settings: DispatchSettings,
+    economy_mode: EconomyMode,
+    gateway_lookups: GatewayLookups,
+    validations: ValidationGovernor,
+    offer_limiter: OfferLimiter,
+    reconnect_priority: ReconnectPriority,
+    downlink_dedup: DownlinkDedup,
+    redispatch: mpsc::Sender<Redispatch>,
+    redispatched: mpsc::Receiver<Redispatch>,
+    flushes: mpsc::Sender<u32>,
+    flushed: mpsc::Receiver<u32>,
+    region_changes: mpsc::Sender<Region>,
+    region_changed: mpsc::Receiver<Region>,
     routers: HashMap<RouterKey, RouterEntry>,
 }
 
@@ -39,11 +86,25 @@ struct RouterKey {
     uri: Uri,
 }
 
+/// The shortest wait before trying to create a router client again
+const MIN_START_RETRY: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 struct RouterEntry {
     routing: Routing,
+    weight: u32,
     dispatch: mpsc::Sender<Dispatch>,
-    join_handle: JoinHandle<Result>,
+    /// Whether the router client is created and running. Creating it is
+    /// retried in the background for as long as it fails, for example while
+    /// the router is unreachable.
+    started: Arc<AtomicBool>,
+    join_handle: JoinHandle<Result<RunExit>>,
+}
+
+impl RouterEntry {
+    fn is_started(&self) -> bool {
+        self.started.load(Ordering::Relaxed)
+    }
 }
 
 impl Dispatcher {
@@ -58,9 +119,14 @@ impl Dispatcher {
         let routers = HashMap::with_capacity(5);
         let default_router = settings.default_router().clone();
         let cache_settings = settings.cache.clone();
+        let client_settings = settings.client.clone();
+        let dispatch_settings = settings.dispatch.clone();
         let gateway = GatewayService::random_new(&gateways)?;
+        let (redispatch, redispatched) = mpsc::channel(10);
+        let (flushes, flushed) = mpsc::channel(10);
+        let (region_changes, region_changed) = mpsc::channel(10);
         Ok(Self {
-            keypair: settings.keypair.clone(),
+            keypairs: settings.keypairs(),
             region: settings.region.clone(),
             uplinks,
             downlinks,
@@ -70,9 +136,50 @@ impl Dispatcher {
             routing_height: 0,
             default_router,
             cache_settings,
+            client_settings,
+            dispatch_settings,
+            economy_mode: EconomyMode::new(settings.client.economy_mode),
+            gateway_lookups: GatewayLookups::new(settings.client.gateway_lookups),
+            validations: ValidationGovernor::new(settings.client.concurrent_validations),
+            offer_limiter: OfferLimiter::new(settings.client.max_offer_rate),
+            reconnect_priority: ReconnectPriority::new(Duration::from_millis(
+                settings.client.reconnect_priority,
+            )),
+            downlink_dedup: DownlinkDedup::new(Duration::from_millis(
+                settings.client.downlink_dedup,
+            )),
+            redispatch,
+            redispatched,
+            flushes,
+            flushed,
+            region_changes,
+            region_changed,
         })
     }
 
+    /// Returns the economy mode flag shared by all router clients started by
+    /// this dispatcher, initially set from the economy mode setting.
+    /// Enabling it suppresses offers on all of them.
+    pub fn economy_mode(&self) -> EconomyMode {
+        self.economy_mode.clone()
+    }
+
+    /// Returns a sender for DevAddrs whose waiting packets all router
+    /// clients started by this dispatcher offer right away, for example to
+    /// drain a device that is migrated to another gateway.
+    pub fn devaddr_flush(&self) -> mpsc::Sender<u32> {
+        self.flushes.clone()
+    }
+
+    /// Returns a sender for changes of the gateway region, for example when
+    /// the gateway is told its region after it started. Router clients
+    /// started by this dispatcher switch to the new region, and handle their
+    /// offered packets according to the region change policy, and router
+    /// clients started later use it.
+    pub fn region_change(&self) -> mpsc::Sender<Region> {
+        self.region_changes.clone()
+    }
+
     pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
         let logger = logger.new(o!("module" => "dispatcher"));
         info!(logger, "starting");
@@ -135,6 +242,14 @@ impl Dispatcher {
                     Some(packet) => self.handle_uplink(&packet, logger).await,
                     None => warn!(logger, "ignoring closed uplinks channel"),
                 },
+                // The dispatcher holds a sender so this never closes
+                Some(redispatch) = self.redispatched.recv() => {
+                    self.handle_redispatch(redispatch, logger).await
+                },
+                // The dispatcher holds a sender so this never closes
+                Some(dev_addr) = self.flushed.recv() => self.handle_flush(dev_addr).await,
+                // The dispatcher holds a sender so this never closes
+                Some(region) = self.region_changed.recv() => self.handle_region_change(region, logger).await,
             }
         }
     }
@@ -149,24 +264,47 @@ impl Dispatcher {
         }
     }
 
+    /// Returns the routers to dispatch the given packet to. Depending on the
+    /// selection policy this is either every matching router, or one matching
+    /// router per OUI picked by weight. Routers whose client is not started
+    /// yet are left out.
+    fn select_routers(&self, packet: &Packet) -> Vec<&RouterEntry> {
+        let matching = self.routers.iter().filter(|(_, entry)| {
+            entry.is_started() && entry.routing.matches_routing_info(packet.routing())
+        });
+        if self.dispatch_settings.selection == RouterSelection::All {
+            return matching.map(|(_, entry)| entry).collect();
+        }
+        let mut by_oui: BTreeMap<u32, Vec<&RouterEntry>> = BTreeMap::new();
+        for (key, entry) in matching {
+            by_oui.entry(key.oui).or_default().push(entry);
+        }
+        let mut rng = rand::thread_rng();
+        by_oui
+            .values()
+            .filter_map(|entries| {
+                let weights: Vec<u32> = entries.iter().map(|entry| entry.weight).collect();
+                selector::select_weighted(&weights, &mut rng).map(|index| entries[index])
+            })
+            .collect()
+    }
+
     async fn handle_uplink(&self, packet: &Packet, logger: &Logger) {
-        let mut handled = false;
-        for router_entry in self.routers.values() {
-            if router_entry.routing.matches_routing_info(packet.routing()) {
-                match router_entry
-                    .dispatch
-                    .send(Dispatch::Packet(packet.clone()))
-                    .await
-                {
-                    Ok(()) => (),
-                    Err(_) => warn!(logger, "ignoring router dispatch error"),
-                }
-                handled = true;
+        let selected = self.select_routers(packet);
+        let handled = !selected.is_empty();
+        for router_entry in selected {
+            match router_entry
+                .dispatch
+                .send(Dispatch::Packet(packet.clone()))
+                .await
+            {
+                Ok(()) => (),
+                Err(_) => warn!(logger, "ignoring router dispatch error"),
             }
         }
         if !handled {
             for (router_key, router_entry) in &self.routers {
-                if router_key.uri == self.default_router.uri {
+                if router_key.uri == self.default_router.uri && router_entry.is_started() {
                     debug!(logger, "sending to default router");
                     let _ = router_entry
                         .dispatch
@@ -177,6 +315,48 @@ impl Dispatcher {
         }
     }
 
+    async fn handle_redispatch(&self, redispatch: Redispatch, logger: &Logger) {
+        if redispatch.uri == self.default_router.uri {
+            debug!(logger, "dropping packet rejected by default router");
+            return;
+        }
+        for (router_key, router_entry) in &self.routers {
+            if router_key.uri == self.default_router.uri && router_entry.is_started() {
+                debug!(logger, "sending rejected packet to default router";
+                    "rejected_by" => redispatch.uri.to_string());
+                let _ = router_entry
+                    .dispatch
+                    .send(Dispatch::Redispatch(redispatch.clone()))
+                    .await;
+            }
+        }
+    }
+
+    async fn handle_region_change(&mut self, region: Region, logger: &Logger) {
+        if region == self.region {
+            return;
+        }
+        info!(logger, "changing region";
+            "from" => self.region.to_string(),
+            "to" => region.to_string());
+        self.region = region.clone();
+        for router_entry in self.routers.values() {
+            let _ = router_entry
+                .dispatch
+                .send(Dispatch::Region(region.clone()))
+                .await;
+        }
+    }
+
+    async fn handle_flush(&self, dev_addr: u32) {
+        for router_entry in self.routers.values() {
+            let _ = router_entry
+                .dispatch
+                .send(Dispatch::FlushDevAddr(dev_addr))
+                .await;
+        }
+    }
+
     async fn handle_routing_update(
         &mut self,
         response: &gateway::Response,
@@ -246,6 +426,17 @@ impl Dispatcher {
                 }
             }
         }
+        // Routers of the oui resolve state channel owner rotations by the
+        // owner the gateway reports
+        for (key, entry) in self.routers.iter_mut() {
+            if key.oui == routing.oui && entry.routing.owner != routing.owner {
+                entry.routing.owner = routing.owner.clone();
+                let _ = entry
+                    .dispatch
+                    .send(Dispatch::RouterOwner(routing.owner.clone()))
+                    .await;
+            }
+        }
         // Remove any routers that are not in the new oui uri list
         self.routers.retain(|key, entry| {
             if key.oui == routing.oui && !entry.routing.contains_uri(&key.uri) {
@@ -270,29 +461,103 @@ impl Dispatcher {
         // We start the router scope at the root logger to avoid picking up the
         // previously set KV pairs (which causes dupes)
         let logger = slog_scope::logger();
+        // Refuse a misconfigured client before it connects or creates its
+        // store
+        let problems = RouterClient::validate_config(
+            &uri,
+            &self.keypairs.for_oui(routing.oui),
+            &self.cache_settings,
+        );
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+            return Err(Error::custom(format!(
+                "invalid router config: {}",
+                problems.join(", ")
+            )));
+        }
         let (dispatch, dispatch_receiver) = mpsc::channel(10);
-        let mut client = RouterClient::new(
-            routing.oui,
-            self.region.clone(),
-            uri,
-            self.gateway.clone(),
-            self.downlinks.clone(),
-            self.keypair.clone(),
-            self.cache_settings.clone(),
-        )
-        .await?;
-        let join_handle =
-            tokio::spawn(async move { client.run(dispatch_receiver, shutdown, &logger).await });
+        let weight = self.dispatch_settings.weight(&uri.public_key);
+        let started = Arc::new(AtomicBool::new(false));
+        // The client is created in the router task, so that probing an
+        // unreachable router neither holds up the dispatcher nor drops the
+        // router until the next routing update
+        let retry =
+            Duration::from_millis(self.client_settings.reconnect_backoff).max(MIN_START_RETRY);
+        let config = RouterClientConfig::default()
+            .with_oui(routing.oui)
+            .with_region(self.region.clone())
+            .with_cache_settings(self.cache_settings.clone())
+            .with_settings(self.client_settings.clone())
+            .with_economy_mode(self.economy_mode.clone())
+            .with_gateway_lookups(self.gateway_lookups.clone())
+            .with_validation_governor(self.validations.clone())
+            .with_offer_limiter(self.offer_limiter.clone())
+            .with_reconnect_priority(self.reconnect_priority.clone())
+            .with_downlink_dedup(self.downlink_dedup.clone())
+            .with_redispatch(self.redispatch.clone())
+            .with_router_owner(routing.owner.clone());
+        let gateway = self.gateway.clone();
+        let downlinks = self.downlinks.clone();
+        let keypairs = self.keypairs.clone();
+        let client_started = started.clone();
+        let join_handle = tokio::spawn(async move {
+            let start_logger = logger.new(o!("uri" => uri.uri.to_string()));
+            let mut client = retry_start(retry, &shutdown, &start_logger, || {
+                RouterClient::new(
+                    config.clone(),
+                    uri.clone(),
+                    gateway.clone(),
+                    downlinks.clone(),
+                    keypairs.clone(),
+                )
+            })
+            .await?;
+            client_started.store(true, Ordering::Relaxed);
+            client.run(dispatch_receiver, shutdown, &logger).await
+        });
         Ok(RouterEntry {
             routing,
+            weight,
             dispatch,
+            started,
             join_handle,
         })
     }
 }
 
+/// Runs the given attempt to create a router client until it succeeds,
+/// waiting the given retry delay after the first failure and doubling it
+/// after every further one, up to the longest reconnect backoff. Returns the
+/// last failure when shut down before an attempt succeeds.
+async fn retry_start<T, F, Fut>(
+    retry: Duration,
+    shutdown: &triggered::Listener,
+    logger: &Logger,
+    mut attempt: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempts = 0;
+    loop {
+        let err = match attempt().await {
+            Ok(client) => return Ok(client),
+            Err(err) => err,
+        };
+        let delay = reconnect_delay(retry.as_millis() as u64, attempts);
+        warn!(logger, "failed to construct router: {:?}", err;
+            "retry" => delay.as_millis() as u64);
+        attempts = attempts.saturating_add(1);
+        tokio::select! {
+            _ = shutdown.clone() => return Err(err),
+            _ = time::sleep(delay) => (),
+        }
+    }
+}
+
 impl std::future::Future for RouterEntry {
-    type Output = std::result::Result<Result, tokio::task::JoinError>;
+    type Output = std::result::Result<Result<RunExit>, tokio::task::JoinError>;
 
     fn poll(
         mut self: Pin<&mut Self>,
@@ -301,3 +566,81 @@ impl std::future::Future for RouterEntry {
         Pin::new(&mut self.join_handle).poll(cxt)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn mk_settings(economy_mode: bool) -> Settings {
+        let mut config = config::Config::new();
+        config
+            .merge(config::File::with_name("config/default.toml"))
+            .expect("default config");
+        let keypair = std::env::temp_dir().join("gateway-rs-test-dispatcher.key");
+        config
+            .set("keypair", keypair.to_str().expect("keypair path"))
+            .expect("keypair setting");
+        config
+            .set("client.economy_mode", economy_mode)
+            .expect("economy mode setting");
+        config.try_into().expect("settings")
+    }
+
+    #[tokio::test]
+    async fn economy_mode_setting() {
+        for enabled in [false, true].iter() {
+            let (downlinks, _) = mpsc::channel(10);
+            let (_, uplinks) = mpsc::channel(10);
+            let dispatcher =
+                Dispatcher::new(downlinks, uplinks, &mk_settings(*enabled)).expect("dispatcher");
+            // The flag shared with the router clients starts out as set
+            let economy_mode = dispatcher.economy_mode();
+            assert_eq!(*enabled, economy_mode.is_enabled());
+            economy_mode.set(!enabled);
+            assert_eq!(!enabled, dispatcher.economy_mode().is_enabled());
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_start() {
+        let (_shutdown, listener) = triggered::trigger();
+        let logger = Logger::root(slog::Discard, o!());
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result = retry_start(Duration::from_millis(10), &listener, &logger, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 {
+                    Err(Error::router_unreachable("http://127.0.0.1:1"))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+        assert_eq!(3, result.unwrap());
+        // The retries wait 10ms and then 20ms
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn gives_up_start_on_shutdown() {
+        use crate::error::ServiceError;
+        let (shutdown, listener) = triggered::trigger();
+        let logger = Logger::root(slog::Discard, o!());
+        let mut attempts = 0;
+        let result: Result<()> = retry_start(Duration::from_secs(60), &listener, &logger, || {
+            attempts += 1;
+            shutdown.trigger();
+            async { Err(Error::router_unreachable("http://127.0.0.1:1")) }
+        })
+        .await;
+        assert_eq!(1, attempts);
+        assert!(matches!(
+            result,
+            Err(Error::Service(ServiceError::Unreachable(_)))
+        ));
+    }
+}
@@ -0,0 +1,320 @@
+use crate::{settings::DownlinkSendMode, Packet, PacketId};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Semaphore,
+    },
+    task::JoinHandle,
+    time,
+};
+
+/// The outcome of handing a downlink to the gateway
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DownlinkDelivery {
+    /// The downlink was queued for the gateway
+    Sent,
+    /// The downlink channel stayed full for the entire timeout and the
+    /// downlink was dropped
+    TimedOut,
+    /// The downlink channel was full and the downlink was dropped without
+    /// waiting for room
+    Full,
+    /// The gateway side of the downlink channel is gone. This is terminal
+    /// and no further downlinks can be delivered
+    Closed,
+}
+
+/// Hands a downlink to the gateway. A full downlink channel is treated as
+/// transient backpressure and waited on for up to the given timeout, while a
+/// closed channel is reported right away.
+pub async fn deliver(
+    downlinks: &mpsc::Sender<Packet>,
+    packet: Packet,
+    timeout: Duration,
+) -> DownlinkDelivery {
+    match downlinks.try_send(packet) {
+        Ok(()) => DownlinkDelivery::Sent,
+        Err(TrySendError::Closed(_)) => DownlinkDelivery::Closed,
+        Err(TrySendError::Full(packet)) => {
+            match time::timeout(timeout, downlinks.send(packet)).await {
+                Ok(Ok(())) => DownlinkDelivery::Sent,
+                Ok(Err(_)) => DownlinkDelivery::Closed,
+                Err(_) => DownlinkDelivery::TimedOut,
+            }
+        }
+    }
+}
+
+/// Hands a downlink to the gateway without waiting. A full downlink channel
+/// drops the downlink.
+pub fn try_deliver(downlinks: &mpsc::Sender<Packet>, packet: Packet) -> DownlinkDelivery {
+    match downlinks.try_send(packet) {
+        Ok(()) => DownlinkDelivery::Sent,
+        Err(TrySendError::Closed(_)) => DownlinkDelivery::Closed,
+        Err(TrySendError::Full(_)) => DownlinkDelivery::Full,
+    }
+}
+
+/// Hands a downlink to the gateway in the given mode, waiting for room in a
+/// full downlink channel up to the given timeout only in the wait mode.
+pub async fn deliver_with(
+    mode: DownlinkSendMode,
+    downlinks: &mpsc::Sender<Packet>,
+    packet: Packet,
+    timeout: Duration,
+) -> DownlinkDelivery {
+    match mode {
+        DownlinkSendMode::Wait => deliver(downlinks, packet, timeout).await,
+        DownlinkSendMode::Drop => try_deliver(downlinks, packet),
+    }
+}
+
+/// A downlink handed to the gateway by a `DownlinkDispatcher`, with the
+/// outcome of the delivery
+#[derive(Debug)]
+pub struct DispatchedDownlink {
+    /// The id of the uplink the downlink answers
+    pub id: PacketId,
+    pub packet: Packet,
+    pub delivery: DownlinkDelivery,
+}
+
+/// The latest delivery task of a device, with whether it has finished
+#[derive(Debug)]
+struct DeviceDelivery {
+    task: JoinHandle<()>,
+    done: Arc<AtomicBool>,
+}
+
+/// Hands downlinks to the gateway concurrently across devices while keeping
+/// the downlinks of each device in order, so that a device whose downlink
+/// waits on a full downlink channel does not hold up the downlinks of other
+/// devices past their receive windows.
+///
+/// Each downlink is delivered by its own task, which first waits for the
+/// delivery of the previous downlink of the same device. At most the given
+/// number of deliveries are in progress at once. Outcomes are reported in
+/// the order deliveries finish.
+#[derive(Debug)]
+pub struct DownlinkDispatcher {
+    downlinks: mpsc::Sender<Packet>,
+    mode: DownlinkSendMode,
+    timeout: Duration,
+    limit: Arc<Semaphore>,
+    devices: HashMap<Option<u32>, DeviceDelivery>,
+    results_tx: mpsc::UnboundedSender<DispatchedDownlink>,
+    results: mpsc::UnboundedReceiver<DispatchedDownlink>,
+}
+
+impl DownlinkDispatcher {
+    pub fn new(downlinks: mpsc::Sender<Packet>, timeout: Duration, limit: usize) -> Self {
+        let (results_tx, results) = mpsc::unbounded_channel();
+        Self {
+            downlinks,
+            mode: DownlinkSendMode::Wait,
+            timeout,
+            limit: Arc::new(Semaphore::new(limit.max(1))),
+            devices: HashMap::new(),
+            results_tx,
+            results,
+        }
+    }
+
+    /// Delivers downlinks in the given mode, the default waits for room in a
+    /// full downlink channel.
+    pub fn with_send_mode(mut self, mode: DownlinkSendMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Starts delivering the given downlink after the earlier downlinks of
+    /// the same device. Join accepts, which do not carry a readable DevAddr,
+    /// are kept in order among themselves.
+    pub fn dispatch(&mut self, id: PacketId, packet: Packet) {
+        self.devices
+            .retain(|_, delivery| !delivery.done.load(Ordering::Acquire));
+        let device = packet.downlink_dev_addr();
+        let previous = self.devices.remove(&device);
+        let downlinks = self.downlinks.clone();
+        let mode = self.mode;
+        let timeout = self.timeout;
+        let limit = self.limit.clone();
+        let results = self.results_tx.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let task_done = done.clone();
+        let task = tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.task.await;
+            }
+            let _permit = limit.acquire_owned().await.expect("downlink limit");
+            let delivery = deliver_with(mode, &downlinks, packet.clone(), timeout).await;
+            let _ = results.send(DispatchedDownlink {
+                id,
+                packet,
+                delivery,
+            });
+            task_done.store(true, Ordering::Release);
+        });
+        self.devices.insert(device, DeviceDelivery { task, done });
+    }
+
+    /// Waits for the next finished delivery.
+    pub async fn delivered(&mut self) -> DispatchedDownlink {
+        // The dispatcher holds a sender so the channel never closes
+        self.results.recv().await.expect("downlink results")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_packet() -> Packet {
+        Packet::from(helium_proto::Packet::default())
+    }
+
+    #[tokio::test]
+    async fn closed_receiver() {
+        let (downlinks, receiver) = mpsc::channel(1);
+        drop(receiver);
+        assert_eq!(
+            DownlinkDelivery::Closed,
+            deliver(&downlinks, mk_packet(), Duration::from_secs(1)).await
+        );
+    }
+
+    fn mk_data_down(dev_addr: u32, fcnt: u8) -> Packet {
+        let [a, b, c, d] = dev_addr.to_le_bytes();
+        Packet::from(helium_proto::Packet {
+            payload: vec![0x60, a, b, c, d, 0, fcnt, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn ordered_per_device() {
+        let (downlinks, mut receiver) = mpsc::channel(10);
+        let mut dispatcher = DownlinkDispatcher::new(downlinks, Duration::from_secs(1), 4);
+        let sent = [(1, 1), (2, 1), (1, 2), (2, 2), (1, 3)];
+        for (dev_addr, fcnt) in sent.iter() {
+            let packet = mk_data_down(*dev_addr, *fcnt);
+            dispatcher.dispatch(packet.id(), packet);
+        }
+        let mut received = vec![];
+        for _ in 0..sent.len() {
+            let packet = receiver.recv().await.unwrap();
+            assert_eq!(
+                DownlinkDelivery::Sent,
+                dispatcher.delivered().await.delivery
+            );
+            // The low DevAddr byte and frame counter of the downlink
+            received.push((packet.payload()[1] as u32, packet.payload()[6]));
+        }
+        for dev_addr in 1..=2 {
+            let fcnts: Vec<u8> = received
+                .iter()
+                .filter(|(received, _)| *received == dev_addr)
+                .map(|(_, fcnt)| *fcnt)
+                .collect();
+            let expected: Vec<u8> = sent
+                .iter()
+                .filter(|(sent, _)| *sent == dev_addr)
+                .map(|(_, fcnt)| *fcnt)
+                .collect();
+            assert_eq!(expected, fcnts);
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_across_devices() {
+        // A full channel nobody drains, every delivery times out
+        let (downlinks, _receiver) = mpsc::channel(1);
+        downlinks.try_send(mk_packet()).unwrap();
+        let mut dispatcher = DownlinkDispatcher::new(downlinks, Duration::from_millis(200), 4);
+        let first = mk_data_down(1, 1);
+        let second = mk_data_down(1, 2);
+        let other = mk_data_down(2, 1);
+        for packet in [first.clone(), second.clone(), other.clone()].iter() {
+            dispatcher.dispatch(packet.id(), packet.clone());
+        }
+        let mut finished = vec![];
+        for _ in 0..3 {
+            let dispatched = dispatcher.delivered().await;
+            assert_eq!(DownlinkDelivery::TimedOut, dispatched.delivery);
+            finished.push(dispatched.id);
+        }
+        // The other device did not wait behind both downlinks of the first,
+        // which are delivered one after the other
+        assert_eq!(second.id(), finished[2]);
+        assert!(finished[..2].contains(&first.id()));
+        assert!(finished[..2].contains(&other.id()));
+    }
+
+    #[tokio::test]
+    async fn momentarily_full() {
+        let (downlinks, mut receiver) = mpsc::channel(1);
+        downlinks.try_send(mk_packet()).unwrap();
+        let drain = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(50)).await;
+            receiver.recv().await;
+            receiver
+        });
+        assert_eq!(
+            DownlinkDelivery::Sent,
+            deliver(&downlinks, mk_packet(), Duration::from_secs(5)).await
+        );
+        // Nobody drains the channel anymore, so the next one times out
+        let _receiver = drain.await.unwrap();
+        assert_eq!(
+            DownlinkDelivery::TimedOut,
+            deliver(&downlinks, mk_packet(), Duration::from_millis(50)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn full_send_modes() {
+        // A full channel nobody drains
+        let (downlinks, _receiver) = mpsc::channel(1);
+        downlinks.try_send(mk_packet()).unwrap();
+        let start = time::Instant::now();
+        assert_eq!(
+            DownlinkDelivery::Full,
+            deliver_with(
+                DownlinkSendMode::Drop,
+                &downlinks,
+                mk_packet(),
+                Duration::from_secs(5)
+            )
+            .await
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            DownlinkDelivery::TimedOut,
+            deliver_with(
+                DownlinkSendMode::Wait,
+                &downlinks,
+                mk_packet(),
+                Duration::from_millis(50)
+            )
+            .await
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let mut dispatcher = DownlinkDispatcher::new(downlinks, Duration::from_secs(5), 1)
+            .with_send_mode(DownlinkSendMode::Drop);
+        let packet = mk_data_down(1, 1);
+        dispatcher.dispatch(packet.id(), packet);
+        let dispatched = time::timeout(Duration::from_secs(1), dispatcher.delivered())
+            .await
+            .expect("dropped without waiting");
+        assert_eq!(DownlinkDelivery::Full, dispatched.delivery);
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A shared flag that, when enabled, stops router clients from sending
+/// offers so that no router DC is spent on behalf of this gateway. Packets
+/// are still stored and expire as usual while the flag is set.
+///
+/// Clones share the same flag, which allows an external policy to toggle it
+/// for all clients at once.
+#[derive(Debug, Clone, Default)]
+pub struct EconomyMode(Arc<AtomicBool>);
+
+impl EconomyMode {
+    pub fn new(enabled: bool) -> Self {
+        let economy_mode = Self::default();
+        economy_mode.set(enabled);
+        economy_mode
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The economy mode as seen by a single router client, which remembers the
+/// state of the shared flag at its last check to tell when it changed.
+#[derive(Debug)]
+pub struct EconomyState {
+    mode: EconomyMode,
+    active: bool,
+}
+
+impl EconomyState {
+    pub fn new(mode: EconomyMode) -> Self {
+        Self {
+            mode,
+            active: false,
+        }
+    }
+
+    pub fn mode(&self) -> &EconomyMode {
+        &self.mode
+    }
+
+    /// Checks the shared flag. Returns whether economy mode is enabled and
+    /// whether that changed since the last check.
+    pub fn check(&mut self) -> (bool, bool) {
+        let enabled = self.mode.is_enabled();
+        let changed = enabled != self.active;
+        self.active = enabled;
+        (enabled, changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_reports_changes() {
+        let mode = EconomyMode::default();
+        let mut state = EconomyState::new(mode.clone());
+        assert_eq!((false, false), state.check());
+        mode.set(true);
+        assert_eq!((true, true), state.check());
+        assert_eq!((true, false), state.check());
+        // Clones share the flag
+        state.mode().set(false);
+        assert!(!mode.is_enabled());
+        assert_eq!((false, true), state.check());
+        assert_eq!((false, false), state.check());
+    }
+}
@@ -0,0 +1,41 @@
+/// The number of events buffered per subscriber. Subscribers that fall
+/// further behind lose the oldest events.
+pub const EVENT_CAPACITY: usize = 32;
+
+/// Typed router client activity, published to subscribers of a client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    /// A state channel connection to the router was set up
+    Connected,
+    /// The state channel connection to the router was lost
+    ConnectionLost,
+    /// A banner was received and accepted for the given state channel
+    BannerReceived { sc_id: String },
+    /// An offer was sent for the packet with the given hash
+    Offered { packet_hash: Vec<u8> },
+    /// A purchase was received and accepted for the given state channel
+    Purchased { sc_id: String },
+    /// The router rejected an offer
+    Rejected,
+    /// A downlink was forwarded to the gateway
+    DownlinkForwarded,
+    /// State channel lookups against the gateway service keep failing,
+    /// state channel messages are held until it recovers
+    GatewayUnavailable,
+    /// The gateway service recovered from being unavailable
+    GatewayAvailable,
+}
+
+/// A transition in the lifecycle of a state channel known to a router
+/// client, published to subscribers of its state channel lifecycle.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScLifecycle {
+    /// A state channel the client did not know before was accepted
+    Created { sc_id: String },
+    /// A known state channel was accepted with an advanced nonce
+    Updated { sc_id: String, nonce: u64 },
+    /// A known state channel reached its expiry block height
+    Expired { sc_id: String },
+    /// A known state channel was removed from the store
+    Archived { sc_id: String },
+}
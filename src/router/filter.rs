@@ -1,3 +1,7 @@
+use crate::{
+    settings::{DevAddrAction, DevAddrRule},
+    PublicKey,
+};
 use bytes::{Buf, BufMut};
 use helium_proto::Eui;
 use std::{fmt, hash::Hasher, sync::Arc};
@@ -12,6 +16,16 @@ pub struct DevAddrFilter {
     mask: u32,
 }
 
+/// The manually configured DevAddr ranges that apply to a single router,
+/// independent of the routing the router advertises. A DevAddr in a denied
+/// range is never allowed. Without any allowed ranges all other DevAddrs are
+/// allowed, otherwise only those in an allowed range.
+#[derive(Clone, Debug, Default)]
+pub struct DevAddrRules {
+    allow: Vec<(u32, u32)>,
+    deny: Vec<(u32, u32)>,
+}
+
 impl fmt::Debug for EuiFilter {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("EuiFilter")
@@ -70,6 +84,38 @@ impl DevAddrFilter {
     }
 }
 
+impl DevAddrRules {
+    /// Collects the rules for the router with the given public key, which
+    /// are those for that router and those without a router.
+    pub fn for_router(rules: &[DevAddrRule], public_key: &PublicKey) -> Self {
+        let public_key = public_key.to_string();
+        let mut result = Self::default();
+        for rule in rules {
+            if rule
+                .router
+                .as_ref()
+                .map_or(false, |router| *router != public_key)
+            {
+                continue;
+            }
+            let range = (rule.start, rule.end);
+            match rule.action {
+                DevAddrAction::Allow => result.allow.push(range),
+                DevAddrAction::Deny => result.deny.push(range),
+            }
+        }
+        result
+    }
+
+    pub fn allows(&self, dev_addr: u32) -> bool {
+        let contains = |(start, end): &(u32, u32)| (*start..=*end).contains(&dev_addr);
+        if self.deny.iter().any(contains) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(contains)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -86,6 +132,59 @@ mod tests {
         }
     }
 
+    mod rules {
+        use super::*;
+        use crate::test_support::mk_public_key;
+
+        fn mk_rule(
+            router: Option<&str>,
+            start: u32,
+            end: u32,
+            action: DevAddrAction,
+        ) -> DevAddrRule {
+            DevAddrRule {
+                router: router.map(str::to_string),
+                start,
+                end,
+                action,
+            }
+        }
+
+        #[test]
+        fn allow_and_deny() {
+            let public_key = mk_public_key();
+            let other = mk_public_key().to_string();
+            let rules = [
+                mk_rule(None, 0x4800_0000, 0x48ff_ffff, DevAddrAction::Allow),
+                mk_rule(None, 0x4800_0010, 0x4800_001f, DevAddrAction::Deny),
+                mk_rule(Some(&other), 0x4800_0000, 0x4800_00ff, DevAddrAction::Deny),
+            ];
+            let rules = DevAddrRules::for_router(&rules, &public_key);
+            assert!(rules.allows(0x4800_0000));
+            assert!(rules.allows(0x48ff_ffff));
+            assert!(!rules.allows(0x4800_0010));
+            assert!(!rules.allows(0x4800_001f));
+            // Outside of the allowed range
+            assert!(!rules.allows(0x4900_0000));
+            assert!(!rules.allows(0x47ff_ffff));
+        }
+
+        #[test]
+        fn deny_only() {
+            let public_key = mk_public_key();
+            let rules = [mk_rule(
+                Some(&public_key.to_string()),
+                0x100,
+                0x1ff,
+                DevAddrAction::Deny,
+            )];
+            let rules = DevAddrRules::for_router(&rules, &public_key);
+            assert!(!rules.allows(0x180));
+            assert!(rules.allows(0x200));
+            assert!(DevAddrRules::default().allows(0x180));
+        }
+    }
+
     mod eui {
         use super::*;
         #[test]
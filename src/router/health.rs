@@ -0,0 +1,106 @@
+use helium_proto::BlockchainStateChannelMessageV1;
+use std::collections::VecDeque;
+
+/// Tracks the availability of the gateway service used to validate state
+/// channels.
+///
+/// After a configured number of consecutive failed lookups the gateway is
+/// considered unavailable until a state channel message is handled
+/// successfully again. While the gateway is unavailable, state channel
+/// messages that need it are held, up to a capacity after which the oldest
+/// held message is dropped, so they can be handled once it recovers. A
+/// threshold of zero disables tracking.
+#[derive(Debug)]
+pub struct GatewayHealth {
+    threshold: u32,
+    failures: u32,
+    capacity: usize,
+    held: VecDeque<BlockchainStateChannelMessageV1>,
+}
+
+impl GatewayHealth {
+    pub fn new(threshold: u32, capacity: usize) -> Self {
+        Self {
+            threshold,
+            failures: 0,
+            capacity,
+            held: VecDeque::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold > 0
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.failures < self.threshold || !self.is_enabled()
+    }
+
+    /// Records a failed lookup. Returns true if the gateway became
+    /// unavailable because of it.
+    pub fn record_failure(&mut self) -> bool {
+        let was_available = self.is_available();
+        self.failures = self.failures.saturating_add(1);
+        was_available && !self.is_available()
+    }
+
+    /// Records a successfully handled message. Returns true if the gateway
+    /// recovered because of it.
+    pub fn record_success(&mut self) -> bool {
+        let was_available = self.is_available();
+        self.failures = 0;
+        !was_available
+    }
+
+    /// Holds the given message until the gateway is available again.
+    /// Returns true if the oldest held message was dropped to make room.
+    pub fn hold(&mut self, message: BlockchainStateChannelMessageV1) -> bool {
+        self.held.push_back(message);
+        if self.held.len() > self.capacity {
+            self.held.pop_front();
+            return true;
+        }
+        false
+    }
+
+    /// Takes all held messages, oldest first
+    pub fn take_held(&mut self) -> VecDeque<BlockchainStateChannelMessageV1> {
+        std::mem::take(&mut self.held)
+    }
+
+    pub fn held_count(&self) -> usize {
+        self.held.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outage_and_recovery() {
+        let mut health = GatewayHealth::new(2, 1);
+        assert!(health.is_available());
+        assert!(!health.record_failure());
+        assert!(health.record_failure());
+        assert!(!health.is_available());
+        assert!(!health.record_failure());
+
+        assert!(!health.hold(BlockchainStateChannelMessageV1::default()));
+        assert!(health.hold(BlockchainStateChannelMessageV1::default()));
+        assert_eq!(1, health.held_count());
+
+        assert!(health.record_success());
+        assert!(health.is_available());
+        assert!(!health.record_success());
+        assert_eq!(1, health.take_held().len());
+        assert_eq!(0, health.held_count());
+    }
+
+    #[test]
+    fn disabled() {
+        let mut health = GatewayHealth::new(0, 1);
+        assert!(!health.record_failure());
+        assert!(health.is_available());
+    }
+}
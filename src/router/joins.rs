@@ -0,0 +1,150 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// The DevAddrs of devices that recently joined through this gateway, each
+/// remembered for a fixed time after its join accept was forwarded. A time
+/// of zero disables tracking, in which case every device is considered
+/// joined.
+///
+/// The DevAddr a join accept assigns is encrypted, so it is learned from the
+/// device instead: every forwarded join accept admits the first data uplink
+/// of a DevAddr not seen before that arrives within the tracked time, and
+/// that DevAddr is then remembered as joined at the time of the accept.
+#[derive(Debug)]
+pub struct RecentJoins {
+    ttl: Duration,
+    joins: HashMap<u32, Instant>,
+    accepts: VecDeque<Instant>,
+}
+
+impl RecentJoins {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            joins: HashMap::new(),
+            accepts: VecDeque::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ttl > Duration::from_secs(0)
+    }
+
+    /// Records a join of the given DevAddr at the given time, forgetting
+    /// joins that expired by then.
+    pub fn record(&mut self, dev_addr: u32, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.prune(now);
+        self.joins.insert(dev_addr, now);
+    }
+
+    /// Records a join accept forwarded at the given time whose DevAddr is not
+    /// known yet.
+    pub fn record_accept(&mut self, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.prune(now);
+        self.accepts.push_back(now);
+    }
+
+    /// Returns whether a data uplink of the given DevAddr at the given time
+    /// is from a recently joined device. A DevAddr not seen before is taken
+    /// to be the device of the oldest forwarded join accept that no uplink
+    /// was admitted for yet, if any. Always true when tracking is disabled.
+    pub fn admit(&mut self, dev_addr: u32, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        self.prune(now);
+        if self.joins.contains_key(&dev_addr) {
+            return true;
+        }
+        match self.accepts.pop_front() {
+            Some(accepted) => {
+                self.joins.insert(dev_addr, accepted);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether the given DevAddr joined within the tracked time
+    /// before the given time. Always true when tracking is disabled.
+    pub fn contains(&self, dev_addr: u32, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        self.joins.get(&dev_addr).map_or(false, |joined| {
+            now.saturating_duration_since(*joined) <= self.ttl
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.joins.len()
+    }
+
+    /// Forgets the joins and join accepts that expired by the given time
+    fn prune(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        let expired = |joined: &Instant| now.saturating_duration_since(*joined) > ttl;
+        self.joins.retain(|_, joined| !expired(joined));
+        while self.accepts.front().map_or(false, expired) {
+            self.accepts.pop_front();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.joins.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled() {
+        let mut joins = RecentJoins::new(Duration::from_secs(0));
+        let now = Instant::now();
+        joins.record(1, now);
+        assert!(joins.is_empty());
+        assert!(joins.contains(2, now));
+    }
+
+    #[test]
+    fn joins_expire() {
+        let mut joins = RecentJoins::new(Duration::from_secs(60));
+        let start = Instant::now();
+        joins.record(1, start);
+        assert!(joins.contains(1, start + Duration::from_secs(60)));
+        assert!(!joins.contains(2, start));
+        assert!(!joins.contains(1, start + Duration::from_secs(61)));
+        // Recording prunes expired joins
+        joins.record(2, start + Duration::from_secs(61));
+        assert_eq!(1, joins.len());
+        assert!(joins.contains(2, start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn learns_accepted_dev_addrs() {
+        let mut joins = RecentJoins::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(!joins.admit(1, start));
+        // Each accept admits one new DevAddr, which stays joined
+        joins.record_accept(start);
+        assert!(joins.admit(1, start + Duration::from_secs(1)));
+        assert!(!joins.admit(2, start + Duration::from_secs(1)));
+        assert!(joins.admit(1, start + Duration::from_secs(2)));
+        // for the tracked time after the accept
+        assert!(joins.contains(1, start + Duration::from_secs(60)));
+        assert!(!joins.admit(1, start + Duration::from_secs(61)));
+        // Accepts no uplink arrived for in time expire as well
+        joins.record_accept(start + Duration::from_secs(61));
+        assert!(!joins.admit(3, start + Duration::from_secs(122)));
+    }
+}
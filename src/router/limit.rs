@@ -0,0 +1,75 @@
+use futures::Future;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// A shared limit on the number of tasks running some work at the same time.
+///
+/// Clones share the same limit, so that work started by many router clients
+/// on the same runtime runs a few at a time instead of all at once. Work
+/// beyond the limit waits for earlier work to finish. The default does not
+/// limit anything.
+#[derive(Debug, Clone, Default)]
+pub struct ConcurrencyLimit(Option<Arc<Semaphore>>);
+
+/// The limit on concurrent state channel gateway lookups across all router
+/// clients, which keeps the load a burst of banners puts on the gateway
+/// bounded.
+pub type GatewayLookups = ConcurrencyLimit;
+
+/// The limit on router clients validating a state channel at the same time,
+/// so that a burst of banners and purchases is validated a few at a time.
+pub type ValidationGovernor = ConcurrencyLimit;
+
+impl ConcurrencyLimit {
+    /// Creates a limit of the given number of concurrent runs, zero does not
+    /// limit runs.
+    pub fn new(limit: usize) -> Self {
+        if limit == 0 {
+            return Self(None);
+        }
+        Self(Some(Arc::new(Semaphore::new(limit))))
+    }
+
+    /// Runs the given work once there is room under the limit.
+    pub async fn run<F: Future>(&self, work: F) -> F::Output {
+        let _permit = match &self.0 {
+            Some(semaphore) => Some(semaphore.acquire().await.expect("limit semaphore")),
+            None => None,
+        };
+        work.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn concurrent_runs_bounded() {
+        for (limit, expected) in [(3, 3), (0, 20)] {
+            let limit = ConcurrencyLimit::new(limit);
+            let active = Arc::new(AtomicUsize::new(0));
+            let max_active = Arc::new(AtomicUsize::new(0));
+            let runs = (0..20).map(|_| {
+                let limit = limit.clone();
+                let active = active.clone();
+                let max_active = max_active.clone();
+                async move {
+                    limit
+                        .run(async {
+                            let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                            max_active.fetch_max(now_active, Ordering::SeqCst);
+                            for _ in 0..5 {
+                                tokio::task::yield_now().await;
+                            }
+                            active.fetch_sub(1, Ordering::SeqCst);
+                        })
+                        .await
+                }
+            });
+            futures::future::join_all(runs).await;
+            assert_eq!(expected, max_active.load(Ordering::SeqCst));
+        }
+    }
+}
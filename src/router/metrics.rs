@@ -0,0 +1,583 @@
+use crate::{router::StatsdSink, Region};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::Duration,
+};
+
+/// The number of most recent hold times kept to compute percentiles from
+pub const HOLD_TIME_SAMPLES: usize = 100;
+
+/// Why a packet was not sent to the router
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropReason {
+    /// The uplink failed its CRC check
+    Crc,
+    /// The uplink is an identical copy of a packet that is already waiting
+    /// or queued, for example one forwarded twice
+    Duplicate,
+    /// The region of the uplink could not be determined
+    Region,
+    /// The uplink was below the minimum SNR
+    LowSnr,
+    /// The uplink is from a device that did not recently join through this
+    /// gateway
+    NotJoined,
+    /// The DevAddr of the uplink is denied to the router by the manual
+    /// DevAddr rules, the prefilter applied to uplinks before they are
+    /// offered
+    Denied,
+    /// The payload of the uplink exceeds the maximum payload size of its
+    /// region at its datarate
+    Oversized,
+    /// The packet was offered the maximum number of times without being
+    /// purchased
+    MaxOfferAttempts,
+    /// The uplink came within the minimum offer interval of an earlier
+    /// uplink of the device
+    Throttled,
+    /// The uplink was left out by the uplink sampling
+    Sampled,
+    /// The router rejected the offer for the packet
+    Rejected,
+    /// The router did not answer the offer for the packet in time
+    OfferTimeout,
+    /// The packet was removed from the store on compaction for being past
+    /// its maximum age
+    Expired,
+}
+
+/// Counts of state channel activity for a single device
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DevAddrCounts {
+    pub offers: u64,
+    pub purchases: u64,
+    pub rejects: u64,
+    /// Why the most recently dropped packet of the device was not sent
+    pub last_drop: Option<DropReason>,
+}
+
+/// Counts of traffic in a single region
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct RegionCounts {
+    pub uplinks: u64,
+    pub offers: u64,
+    pub purchases: u64,
+}
+
+/// Per DevAddr activity counters. The number of tracked devices is capped,
+/// evicting the least recently updated device when a new one needs to be
+/// tracked. A capacity of zero disables tracking.
+#[derive(Debug)]
+pub struct DevAddrMetrics {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<u32, (u64, DevAddrCounts)>,
+}
+
+impl DevAddrMetrics {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn get(&self, dev_addr: u32) -> Option<&DevAddrCounts> {
+        self.entries.get(&dev_addr).map(|(_, counts)| counts)
+    }
+
+    pub fn record_offer(&mut self, dev_addr: Option<u32>) {
+        if let Some(counts) = self.counts_mut(dev_addr) {
+            counts.offers += 1;
+        }
+    }
+
+    pub fn record_purchase(&mut self, dev_addr: Option<u32>) {
+        if let Some(counts) = self.counts_mut(dev_addr) {
+            counts.purchases += 1;
+        }
+    }
+
+    pub fn record_reject(&mut self, dev_addr: Option<u32>) {
+        if let Some(counts) = self.counts_mut(dev_addr) {
+            counts.rejects += 1;
+        }
+    }
+
+    pub fn record_drop(&mut self, dev_addr: Option<u32>, reason: DropReason) {
+        if let Some(counts) = self.counts_mut(dev_addr) {
+            counts.last_drop = Some(reason);
+        }
+    }
+
+    fn counts_mut(&mut self, dev_addr: Option<u32>) -> Option<&mut DevAddrCounts> {
+        let dev_addr = dev_addr?;
+        if self.capacity == 0 {
+            return None;
+        }
+        if !self.entries.contains_key(&dev_addr) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (tick, _))| *tick)
+                .map(|(dev_addr, _)| *dev_addr);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self
+            .entries
+            .entry(dev_addr)
+            .or_insert_with(|| (tick, DevAddrCounts::default()));
+        entry.0 = tick;
+        Some(&mut entry.1)
+    }
+}
+
+/// Aggregate activity counters of a router client.
+#[derive(Debug, Default)]
+pub struct ClientMetrics {
+    offers: u64,
+    purchases: u64,
+    rejects: u64,
+    downlinks: u64,
+    dc_spent: u64,
+    offer_timeouts: u64,
+    packet_drifts: u64,
+    low_snr_drops: u64,
+    crc_drops: u64,
+    denied_drops: u64,
+    throttled_drops: u64,
+    sampled_drops: u64,
+    oversized_drops: u64,
+    max_offer_attempts_exceeded: u64,
+    duplicate_purchases: u64,
+    expired_purchases: u64,
+    duplicate_downlinks: u64,
+    redispatched: u64,
+    hold_times: VecDeque<u64>,
+    regions: BTreeMap<String, RegionCounts>,
+    statsd: StatsdSink,
+}
+
+/// A point in time copy of the activity counters of a router client,
+/// suitable for serializing to a monitoring endpoint.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    pub offers: u64,
+    pub purchases: u64,
+    pub rejects: u64,
+    /// Downlinks forwarded to the gateway
+    pub downlinks: u64,
+    /// Purchases per offer, 0 when nothing was offered yet
+    pub acceptance_ratio: f64,
+    /// DC paid for purchased packets
+    pub dc_spent: u64,
+    /// Offered packets dropped because the router neither purchased nor
+    /// rejected them in time
+    pub offer_timeouts: u64,
+    /// Times the packet counts in router summaries drifted from the packets
+    /// sent
+    pub packet_drifts: u64,
+    /// Uplinks dropped for being below the minimum SNR
+    pub low_snr_drops: u64,
+    /// Uplinks dropped for failing their CRC check
+    pub crc_drops: u64,
+    /// Uplinks dropped because the manual DevAddr rules deny them to the
+    /// router
+    pub denied_drops: u64,
+    /// Uplinks dropped for coming within the minimum offer interval of an
+    /// earlier uplink of the same device
+    pub throttled_drops: u64,
+    /// Uplinks dropped instead of offered because the uplink sampling left
+    /// them out
+    pub sampled_drops: u64,
+    /// Packets dropped instead of offered because their payload exceeds the
+    /// maximum payload size of their region at their datarate
+    pub oversized_drops: u64,
+    /// Packets dropped instead of offered again because they were offered
+    /// the maximum number of times
+    pub max_offer_attempts_exceeded: u64,
+    /// Purchases ignored because they were for an already sent packet
+    pub duplicate_purchases: u64,
+    /// Purchases ignored because they were for a packet that expired before
+    /// it was purchased
+    pub expired_purchases: u64,
+    /// Downlinks dropped because the same downlink was recently forwarded
+    pub duplicate_downlinks: u64,
+    /// Rejected packets handed to the default router
+    pub redispatched: u64,
+    /// Uplink, offer and purchase counts by region name
+    pub regions: BTreeMap<String, RegionCounts>,
+    /// Hold time percentiles in milliseconds over the most recently sent
+    /// packets
+    pub hold_time: HoldTimePercentiles,
+    /// State channel messages dropped because they arrived faster than they
+    /// could be handled
+    pub dropped_messages: u64,
+    /// Reject log and metrics writes that failed on the background writer
+    pub background_write_failures: u64,
+}
+
+/// The activity of a single run of a router client, reported when it stops
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SessionSummary {
+    pub offers: u64,
+    pub purchases: u64,
+    pub rejects: u64,
+    /// Downlinks forwarded to the gateway
+    pub downlinks: u64,
+    /// DC paid for purchased packets
+    pub dc_spent: u64,
+    /// How long the client ran
+    pub uptime: Duration,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct HoldTimePercentiles {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+impl ClientMetrics {
+    /// Emits offers, purchases, rejects, downlinks, spent DC and hold times
+    /// to the given StatsD sink as they are recorded, in addition to
+    /// counting them.
+    pub fn with_statsd(mut self, statsd: StatsdSink) -> Self {
+        self.statsd = statsd;
+        self
+    }
+
+    pub fn record_offer(&mut self) {
+        self.offers += 1;
+        self.statsd.count("offers", 1);
+    }
+
+    pub fn record_purchase(&mut self, dc: u64) {
+        self.purchases += 1;
+        self.dc_spent += dc;
+        self.statsd.count("purchases", 1);
+        self.statsd.count("dc_spent", dc);
+    }
+
+    pub fn record_reject(&mut self) {
+        self.rejects += 1;
+        self.statsd.count("rejects", 1);
+    }
+
+    /// Returns the number of purchased packets so far.
+    pub fn purchases(&self) -> u64 {
+        self.purchases
+    }
+
+    pub fn record_downlink(&mut self) {
+        self.downlinks += 1;
+        self.statsd.count("downlinks", 1);
+    }
+
+    pub fn record_offer_timeouts(&mut self, count: usize) {
+        self.offer_timeouts += count as u64;
+    }
+
+    pub fn record_packet_drift(&mut self) {
+        self.packet_drifts += 1;
+    }
+
+    pub fn record_low_snr_drop(&mut self) {
+        self.low_snr_drops += 1;
+    }
+
+    pub fn record_crc_drop(&mut self) {
+        self.crc_drops += 1;
+    }
+
+    pub fn record_denied_drop(&mut self) {
+        self.denied_drops += 1;
+    }
+
+    pub fn record_throttled_drop(&mut self) {
+        self.throttled_drops += 1;
+    }
+
+    pub fn record_sampled_drop(&mut self) {
+        self.sampled_drops += 1;
+    }
+
+    pub fn record_oversized_drop(&mut self) {
+        self.oversized_drops += 1;
+    }
+
+    pub fn record_max_offer_attempts_exceeded(&mut self) {
+        self.max_offer_attempts_exceeded += 1;
+    }
+
+    pub fn record_duplicate_purchase(&mut self) {
+        self.duplicate_purchases += 1;
+    }
+
+    pub fn record_expired_purchase(&mut self) {
+        self.expired_purchases += 1;
+    }
+
+    pub fn record_duplicate_downlink(&mut self) {
+        self.duplicate_downlinks += 1;
+    }
+
+    pub fn record_redispatch(&mut self) {
+        self.redispatched += 1;
+    }
+
+    pub fn record_region_uplink(&mut self, region: &Region) {
+        self.region_mut(region).uplinks += 1;
+    }
+
+    pub fn record_region_offer(&mut self, region: &Region) {
+        self.region_mut(region).offers += 1;
+    }
+
+    pub fn record_region_purchase(&mut self, region: &Region) {
+        self.region_mut(region).purchases += 1;
+    }
+
+    fn region_mut(&mut self, region: &Region) -> &mut RegionCounts {
+        self.regions.entry(region.to_string()).or_default()
+    }
+
+    pub fn record_hold_time(&mut self, hold_time: Duration) {
+        self.statsd.timing("hold_time", hold_time);
+        self.hold_times.push_back(hold_time.as_millis() as u64);
+        if self.hold_times.len() > HOLD_TIME_SAMPLES {
+            self.hold_times.pop_front();
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let acceptance_ratio = if self.offers == 0 {
+            0.0
+        } else {
+            self.purchases as f64 / self.offers as f64
+        };
+        let mut hold_times: Vec<u64> = self.hold_times.iter().copied().collect();
+        hold_times.sort_unstable();
+        MetricsSnapshot {
+            offers: self.offers,
+            purchases: self.purchases,
+            rejects: self.rejects,
+            downlinks: self.downlinks,
+            acceptance_ratio,
+            dc_spent: self.dc_spent,
+            offer_timeouts: self.offer_timeouts,
+            packet_drifts: self.packet_drifts,
+            low_snr_drops: self.low_snr_drops,
+            crc_drops: self.crc_drops,
+            denied_drops: self.denied_drops,
+            throttled_drops: self.throttled_drops,
+            sampled_drops: self.sampled_drops,
+            oversized_drops: self.oversized_drops,
+            max_offer_attempts_exceeded: self.max_offer_attempts_exceeded,
+            duplicate_purchases: self.duplicate_purchases,
+            expired_purchases: self.expired_purchases,
+            duplicate_downlinks: self.duplicate_downlinks,
+            redispatched: self.redispatched,
+            regions: self.regions.clone(),
+            hold_time: HoldTimePercentiles {
+                p50: percentile(&hold_times, 50),
+                p90: percentile(&hold_times, 90),
+                p99: percentile(&hold_times, 99),
+            },
+            dropped_messages: 0,
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// Returns the activity since the given earlier snapshot, for a session
+    /// of the given length.
+    pub fn since(&self, earlier: &MetricsSnapshot, uptime: Duration) -> SessionSummary {
+        SessionSummary {
+            offers: self.offers.saturating_sub(earlier.offers),
+            purchases: self.purchases.saturating_sub(earlier.purchases),
+            rejects: self.rejects.saturating_sub(earlier.rejects),
+            downlinks: self.downlinks.saturating_sub(earlier.downlinks),
+            dc_spent: self.dc_spent.saturating_sub(earlier.dc_spent),
+            uptime,
+        }
+    }
+}
+
+/// Nearest rank percentile of the given sorted samples, 0 if there are none
+fn percentile(sorted: &[u64], percent: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (percent * sorted.len() + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emits_statsd() {
+        use std::net::UdpSocket;
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let statsd =
+            StatsdSink::new(&listener.local_addr().unwrap().to_string(), "router").unwrap();
+        let mut metrics = ClientMetrics::default().with_statsd(statsd);
+        metrics.record_offer();
+        metrics.record_purchase(2);
+        metrics.record_hold_time(Duration::from_millis(120));
+        let mut lines = vec![];
+        let mut buf = [0; 512];
+        for _ in 0..4 {
+            let len = listener.recv(&mut buf).unwrap();
+            lines.push(String::from_utf8(buf[..len].to_vec()).unwrap());
+        }
+        assert_eq!(
+            vec![
+                "helium_gateway.router.offers:1|c",
+                "helium_gateway.router.purchases:1|c",
+                "helium_gateway.router.dc_spent:2|c",
+                "helium_gateway.router.hold_time:120|ms",
+            ],
+            lines
+        );
+        // Emitting does not change what is counted
+        assert_eq!(1, metrics.snapshot().offers);
+        assert_eq!(2, metrics.snapshot().dc_spent);
+    }
+
+    #[test]
+    fn snapshot_reflects_activity() {
+        let mut metrics = ClientMetrics::default();
+        assert_eq!(MetricsSnapshot::default(), metrics.snapshot());
+        for _ in 0..4 {
+            metrics.record_offer();
+        }
+        metrics.record_purchase(2);
+        metrics.record_purchase(1);
+        metrics.record_reject();
+        for millis in 1..=100 {
+            metrics.record_hold_time(Duration::from_millis(millis));
+        }
+        let snapshot = metrics.snapshot();
+        assert_eq!(4, snapshot.offers);
+        assert_eq!(2, snapshot.purchases);
+        assert_eq!(1, snapshot.rejects);
+        assert_eq!(3, snapshot.dc_spent);
+        assert!((snapshot.acceptance_ratio - 0.5).abs() < f64::EPSILON);
+        assert_eq!(
+            HoldTimePercentiles {
+                p50: 50,
+                p90: 90,
+                p99: 99
+            },
+            snapshot.hold_time
+        );
+
+        let json = serde_json::to_value(&snapshot).expect("serialized snapshot");
+        assert_eq!(4, json["offers"]);
+        assert_eq!(90, json["hold_time"]["p90"]);
+    }
+
+    #[test]
+    fn per_region_counts() {
+        let us915 = Region::from_i32(0).unwrap();
+        let eu868 = Region::from_i32(1).unwrap();
+        let mut metrics = ClientMetrics::default();
+        metrics.record_region_uplink(&us915);
+        metrics.record_region_offer(&us915);
+        metrics.record_region_purchase(&us915);
+        metrics.record_region_uplink(&eu868);
+        metrics.record_region_uplink(&eu868);
+        metrics.record_region_offer(&eu868);
+        let snapshot = metrics.snapshot();
+        assert_eq!(2, snapshot.regions.len());
+        assert_eq!(
+            RegionCounts {
+                uplinks: 1,
+                offers: 1,
+                purchases: 1,
+            },
+            snapshot.regions["US915"]
+        );
+        assert_eq!(
+            RegionCounts {
+                uplinks: 2,
+                offers: 1,
+                purchases: 0,
+            },
+            snapshot.regions["EU868"]
+        );
+
+        let json = serde_json::to_value(&snapshot).expect("serialized snapshot");
+        assert_eq!(2, json["regions"]["EU868"]["uplinks"]);
+    }
+
+    #[test]
+    fn per_devaddr_counts() {
+        let mut metrics = DevAddrMetrics::new(10);
+        metrics.record_offer(Some(1));
+        metrics.record_offer(Some(1));
+        metrics.record_purchase(Some(1));
+        metrics.record_offer(Some(2));
+        metrics.record_reject(Some(2));
+        metrics.record_offer(None);
+        assert_eq!(
+            Some(&DevAddrCounts {
+                offers: 2,
+                purchases: 1,
+                rejects: 0,
+                last_drop: None,
+            }),
+            metrics.get(1)
+        );
+        assert_eq!(
+            Some(&DevAddrCounts {
+                offers: 1,
+                purchases: 0,
+                rejects: 1,
+                last_drop: None,
+            }),
+            metrics.get(2)
+        );
+    }
+
+    #[test]
+    fn last_drop_reason() {
+        let mut metrics = DevAddrMetrics::new(10);
+        metrics.record_offer(Some(1));
+        assert_eq!(None, metrics.get(1).unwrap().last_drop);
+        metrics.record_drop(Some(1), DropReason::Rejected);
+        metrics.record_drop(Some(1), DropReason::OfferTimeout);
+        metrics.record_drop(Some(2), DropReason::LowSnr);
+        metrics.record_drop(None, DropReason::Region);
+        assert_eq!(
+            Some(DropReason::OfferTimeout),
+            metrics.get(1).unwrap().last_drop
+        );
+        assert_eq!(Some(DropReason::LowSnr), metrics.get(2).unwrap().last_drop);
+    }
+
+    #[test]
+    fn evicts_least_recently_updated() {
+        let mut metrics = DevAddrMetrics::new(2);
+        metrics.record_offer(Some(1));
+        metrics.record_offer(Some(2));
+        // Touch 1 so 2 becomes the least recently updated
+        metrics.record_purchase(Some(1));
+        metrics.record_offer(Some(3));
+        assert!(metrics.get(1).is_some());
+        assert!(metrics.get(2).is_none());
+        assert!(metrics.get(3).is_some());
+    }
+}
@@ -0,0 +1,168 @@
+//! A scriptable in process router for running router clients against a real
+//! state channel stream in tests and benchmarks.
+use crate::service::router::CONDUIT_CAPACITY;
+use helium_proto::{
+    services::router::{StateChannel as StateChannelRpc, StateChannelServer},
+    BlockchainStateChannelMessageV1,
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::mpsc, time};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{
+    transport::{Certificate, Identity, Server, ServerTlsConfig},
+    Code, Request, Response, Status, Streaming,
+};
+
+/// A step the mock router takes on a state channel stream
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Sends the given message to the client
+    Send(BlockchainStateChannelMessageV1),
+    /// Waits for the next message from the client
+    Receive,
+    /// Waits for the given time
+    Pause(Duration),
+    /// Fails the stream with the given status code
+    Fail(Code),
+    /// Closes the stream
+    Close,
+}
+
+/// A router serving state channel streams from scripts. Each new stream runs
+/// the next script, and streams beyond the given scripts stay open without
+/// sending anything. All messages received from clients are forwarded to
+/// `received`.
+pub struct MockRouter {
+    pub uri: String,
+    pub received: mpsc::UnboundedReceiver<BlockchainStateChannelMessageV1>,
+    connections: Arc<AtomicUsize>,
+    shutdown: triggered::Trigger,
+}
+
+struct MockService {
+    scripts: Arc<Mutex<VecDeque<Vec<Step>>>>,
+    connections: Arc<AtomicUsize>,
+    received: mpsc::UnboundedSender<BlockchainStateChannelMessageV1>,
+}
+
+impl MockRouter {
+    /// Starts a router on a local port running the given scripts
+    pub async fn start(scripts: Vec<Vec<Step>>) -> Self {
+        Self::start_with(scripts, None).await
+    }
+
+    /// Starts a router serving TLS with the given identity. With a client CA
+    /// clients must present a certificate issued by it.
+    pub async fn start_tls(
+        scripts: Vec<Vec<Step>>,
+        identity: Identity,
+        client_ca: Option<Certificate>,
+    ) -> Self {
+        let mut tls = ServerTlsConfig::new().identity(identity);
+        if let Some(client_ca) = client_ca {
+            tls = tls.client_ca_root(client_ca);
+        }
+        Self::start_with(scripts, Some(tls)).await
+    }
+
+    async fn start_with(scripts: Vec<Vec<Step>>, tls: Option<ServerTlsConfig>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("mock router listener");
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let uri = format!(
+            "{}://{}",
+            scheme,
+            listener.local_addr().expect("local address")
+        );
+        let (shutdown, shutdown_listener) = triggered::trigger();
+        let (received_tx, received) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let service = MockService {
+            scripts: Arc::new(Mutex::new(scripts.into())),
+            connections: connections.clone(),
+            received: received_tx,
+        };
+        let mut server = Server::builder();
+        if let Some(tls) = tls {
+            server = server.tls_config(tls).expect("mock router tls");
+        }
+        tokio::spawn(
+            server
+                .add_service(StateChannelServer::new(service))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown_listener),
+        );
+        Self {
+            uri,
+            received,
+            connections,
+            shutdown,
+        }
+    }
+
+    /// Returns the number of state channel streams clients opened
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockRouter {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+    }
+}
+
+#[tonic::async_trait]
+impl StateChannelRpc for MockService {
+    type MsgStream = ReceiverStream<Result<BlockchainStateChannelMessageV1, Status>>;
+
+    async fn msg(
+        &self,
+        request: Request<Streaming<BlockchainStateChannelMessageV1>>,
+    ) -> Result<Response<Self::MsgStream>, Status> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        let steps = self
+            .scripts
+            .lock()
+            .expect("mock scripts")
+            .pop_front()
+            .unwrap_or_default();
+        let received = self.received.clone();
+        let (tx, rx) = mpsc::channel(CONDUIT_CAPACITY);
+        tokio::spawn(async move {
+            let mut inbound = request.into_inner();
+            for step in steps {
+                match step {
+                    Step::Send(msg) => {
+                        if tx.send(Ok(msg)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Step::Receive => match inbound.message().await {
+                        Ok(Some(msg)) => {
+                            let _ = received.send(msg);
+                        }
+                        _ => return,
+                    },
+                    Step::Pause(duration) => time::sleep(duration).await,
+                    Step::Fail(code) => {
+                        let _ = tx.send(Err(Status::new(code, "scripted failure"))).await;
+                        return;
+                    }
+                    Step::Close => return,
+                }
+            }
+            while let Ok(Some(msg)) = inbound.message().await {
+                let _ = received.send(msg);
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
@@ -1,11 +1,64 @@
+pub mod accounting;
+pub mod banner;
+pub mod buffer;
+pub mod capture;
 pub mod client;
+pub mod codec;
+pub mod dedup;
 pub mod dispatcher;
+pub mod downlink;
+pub mod economy;
+pub mod event;
 pub mod filter;
+pub mod health;
+pub mod joins;
+pub mod limit;
+pub mod metrics;
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock;
+pub mod offer_limit;
+pub mod owners;
+pub mod priority;
+pub mod recent;
 pub mod routing;
+pub mod selector;
+pub mod sent;
+pub mod statsd;
 pub mod store;
+pub mod tap;
+pub mod throttle;
+pub mod trace;
+pub mod writer;
 
-pub use client::RouterClient;
-pub use dispatcher::{Dispatch, Dispatcher};
-pub use filter::{DevAddrFilter, EuiFilter};
+pub use accounting::{PacketAccounting, PacketDrift};
+pub use banner::BannerState;
+pub use buffer::MessageBuffer;
+pub use capture::DownlinkCapture;
+pub use client::{ConfigProblem, ExitReason, RouterClient, RouterClientConfig, RunExit};
+pub use codec::{CompactCodec, PacketCodec, ProtobufCodec};
+pub use dedup::DownlinkDedup;
+pub use dispatcher::{Dispatch, Dispatcher, Redispatch};
+pub use downlink::{DispatchedDownlink, DownlinkDelivery, DownlinkDispatcher};
+pub use economy::{EconomyMode, EconomyState};
+pub use event::{ClientEvent, ScLifecycle};
+pub use filter::{DevAddrFilter, DevAddrRules, EuiFilter};
+pub use health::GatewayHealth;
+pub use joins::RecentJoins;
+pub use limit::{ConcurrencyLimit, GatewayLookups, ValidationGovernor};
+pub use metrics::{
+    ClientMetrics, DevAddrCounts, DevAddrMetrics, DropReason, MetricsSnapshot, RegionCounts,
+    SessionSummary,
+};
+pub use offer_limit::{LimitedOffers, OfferLimiter};
+pub use owners::OwnerResolver;
+pub use priority::ReconnectPriority;
+pub use recent::{MatchedUplink, RecentUplinks};
 pub use routing::Routing;
+pub use selector::StateChannelSelector;
+pub use sent::SentPackets;
+pub use statsd::StatsdSink;
 pub use store::{QuePacket, RouterStore};
+pub use tap::{MessageTap, SignedExport, TapMessage};
+pub use throttle::{OfferThrottle, UplinkSampling};
+pub use trace::{DecisionTrace, TraceCheck, TraceEntry, TraceStep, TraceTarget};
+pub use writer::{BackgroundWrite, BackgroundWriter};
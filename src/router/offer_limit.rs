@@ -0,0 +1,160 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+/// A shared limit on the rate of offers sent across all router clients.
+///
+/// Clones share the same token bucket, which holds up to one second worth of
+/// offers so that short bursts go out right away. Offers beyond the limit
+/// are told how long until the bucket refills, so that callers can come back
+/// later rather than wait. The default does not limit offers.
+#[derive(Debug, Clone, Default)]
+pub struct OfferLimiter(Option<Arc<Mutex<TokenBucket>>>);
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+impl OfferLimiter {
+    /// Creates a limit of the given number of offers per second, zero does
+    /// not limit offers.
+    pub fn new(rate: u32) -> Self {
+        if rate == 0 {
+            return Self(None);
+        }
+        Self(Some(Arc::new(Mutex::new(TokenBucket::new(rate)))))
+    }
+
+    /// Takes an offer under the limit at the given time. Returns how long
+    /// until an offer may be sent if the limit is reached, in which case
+    /// nothing is taken.
+    pub fn try_acquire(&self, now: Instant) -> Option<Duration> {
+        self.0
+            .as_ref()
+            .and_then(|bucket| bucket.lock().expect("offer bucket").take(now))
+    }
+}
+
+/// The shared offer rate limit as seen by a single router client. Once the
+/// limit is reached the offers of the client are held back until the limit
+/// allows more, so that they resume in order rather than interleave with
+/// offers made in between.
+#[derive(Debug)]
+pub struct LimitedOffers {
+    limiter: OfferLimiter,
+    resume_at: Option<time::Instant>,
+}
+
+impl LimitedOffers {
+    pub fn new(limiter: OfferLimiter) -> Self {
+        Self {
+            limiter,
+            resume_at: None,
+        }
+    }
+
+    /// Returns when the held back offers resume, if they are held back.
+    pub fn resume_at(&self) -> Option<time::Instant> {
+        self.resume_at
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.resume_at.is_some()
+    }
+
+    /// Takes an offer under the limit at the given time. If the limit is
+    /// reached offers are held back for the returned wait.
+    pub fn try_acquire(&mut self, now: Instant) -> Option<Duration> {
+        let wait = self.limiter.try_acquire(now)?;
+        self.resume_at = Some(time::Instant::now() + wait);
+        Some(wait)
+    }
+
+    /// Stops holding back offers.
+    pub fn resume(&mut self) {
+        self.resume_at = None;
+    }
+}
+
+impl TokenBucket {
+    /// A full bucket, which starts refilling from its first use
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: None,
+        }
+    }
+
+    /// Takes a token at the given time. Returns how long until a token is
+    /// available if there is none left.
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = self.updated.map_or(0.0, |updated| {
+            now.saturating_duration_since(updated).as_secs_f64()
+        });
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = Some(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(4);
+        for _ in 0..4 {
+            assert!(bucket.take(start).is_none());
+        }
+        assert_eq!(Some(Duration::from_millis(250)), bucket.take(start));
+        assert!(bucket.take(start + Duration::from_millis(250)).is_none());
+        // Idle time refills at most one second worth of tokens
+        let idle = start + Duration::from_secs(10);
+        for _ in 0..4 {
+            assert!(bucket.take(idle).is_none());
+        }
+        assert!(bucket.take(idle).is_some());
+    }
+
+    #[test]
+    fn held_until_resumed() {
+        let now = Instant::now();
+        let mut offers = LimitedOffers::new(OfferLimiter::new(1));
+        assert!(offers.try_acquire(now).is_none());
+        assert!(!offers.is_held());
+        assert_eq!(Some(Duration::from_secs(1)), offers.try_acquire(now));
+        assert!(offers.is_held());
+        assert!(offers.resume_at().is_some());
+        offers.resume();
+        assert!(!offers.is_held());
+        // Clients sharing the limiter share its offers
+        let limiter = OfferLimiter::new(1);
+        let mut first = LimitedOffers::new(limiter.clone());
+        let mut second = LimitedOffers::new(limiter);
+        assert!(first.try_acquire(now).is_none());
+        assert!(second.try_acquire(now).is_some());
+        assert!(!first.is_held());
+        assert!(second.is_held());
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = OfferLimiter::new(0);
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert!(limiter.try_acquire(now).is_none());
+        }
+    }
+}
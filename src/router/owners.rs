@@ -0,0 +1,179 @@
+use crate::{
+    settings::{OwnerRotation, ValidationSettings},
+    PublicKey, StateChannel,
+};
+use std::{borrow::Cow, convert::TryFrom};
+
+/// Resolves which state channel owners to trust when the validation settings
+/// restrict the accepted owners.
+///
+/// A router may rotate the key it signs state channels with, which would get
+/// its new state channels rejected against the configured owners. With the
+/// trust rotation policy a state channel signed by an unknown owner is
+/// accepted as a rotation when the gateway reports its owner as the owner of
+/// the OUI of the router, and a state channel of a trusted owner was accepted
+/// from the same router before. The new owner is only trusted from then on
+/// once its state channel passed all validation and was accepted.
+#[derive(Debug)]
+pub struct OwnerResolver {
+    validation: ValidationSettings,
+    trusted: Option<Vec<u8>>,
+    router_owner: Option<Vec<u8>>,
+}
+
+impl OwnerResolver {
+    pub fn new(validation: ValidationSettings) -> Self {
+        Self {
+            validation,
+            trusted: None,
+            router_owner: None,
+        }
+    }
+
+    /// Sets the owner of the OUI of the router as reported by the gateway
+    pub fn set_router_owner(&mut self, owner: Vec<u8>) {
+        self.router_owner = Some(owner);
+    }
+
+    /// Returns the validation settings with the owners of accepted rotations
+    /// included in the accepted owners.
+    pub fn validation(&self) -> &ValidationSettings {
+        &self.validation
+    }
+
+    /// Returns the validation settings to validate the given state channel
+    /// against, which accept its owner if it is a trusted rotation.
+    pub fn validation_for(&self, sc: &StateChannel) -> Cow<ValidationSettings> {
+        match self.rotation(sc) {
+            Some(_) => {
+                let mut validation = self.validation.clone();
+                validation
+                    .owners
+                    .extend(PublicKey::try_from(sc.owner()).ok());
+                Cow::Owned(validation)
+            }
+            None => Cow::Borrowed(&self.validation),
+        }
+    }
+
+    /// Returns the previous owner if the given state channel is signed by a
+    /// rotated key that is trusted under the rotation policy.
+    pub fn rotation(&self, sc: &StateChannel) -> Option<&[u8]> {
+        if self.validation.owner_rotation != OwnerRotation::Trust
+            || self.is_accepted(sc.owner())
+            || self.router_owner.as_deref() != Some(sc.owner())
+        {
+            return None;
+        }
+        self.trusted.as_deref()
+    }
+
+    /// Records the owner of an accepted state channel as the current owner
+    /// of the router if it is an accepted owner or a trusted rotation, which
+    /// is accepted by the validation settings from then on.
+    pub fn accept(&mut self, sc: &StateChannel) {
+        if self.rotation(sc).is_some() {
+            self.validation
+                .owners
+                .extend(PublicKey::try_from(sc.owner()).ok());
+        }
+        if self.is_accepted(sc.owner()) {
+            self.trusted = Some(sc.owner().to_vec());
+        }
+    }
+
+    fn is_accepted(&self, owner: &[u8]) -> bool {
+        self.validation.owners.is_empty()
+            || self
+                .validation
+                .owners
+                .iter()
+                .any(|accepted| accepted.to_vec() == owner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::StateChannelError,
+        test_support::{mk_public_key, mk_state_channel},
+        Error,
+    };
+    use helium_proto::BlockchainStateChannelV1;
+
+    fn mk_sc(owner: &PublicKey) -> StateChannel {
+        let sc = BlockchainStateChannelV1 {
+            id: vec![1],
+            owner: owner.to_vec(),
+            credits: 100,
+            ..Default::default()
+        };
+        mk_state_channel(&sc, 1000, 100)
+    }
+
+    fn mk_resolver(owner: &PublicKey, owner_rotation: OwnerRotation) -> OwnerResolver {
+        OwnerResolver::new(ValidationSettings {
+            owners: vec![owner.clone()],
+            owner_rotation,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn trusted_rotation() {
+        let (owner, rotated) = (mk_public_key(), mk_public_key());
+        let mut resolver = mk_resolver(&owner, OwnerRotation::Trust);
+        resolver.set_router_owner(rotated.to_vec());
+        let rotated_sc = mk_sc(&rotated);
+        // Without a previously trusted owner there is nothing to rotate from
+        assert!(resolver.rotation(&rotated_sc).is_none());
+
+        resolver.accept(&mk_sc(&owner));
+        assert_eq!(Some(&owner.to_vec()[..]), resolver.rotation(&rotated_sc));
+        assert!(rotated_sc
+            .is_valid_with(&resolver.validation_for(&rotated_sc), 0)
+            .is_ok());
+        // The rotated owner is only trusted once its state channel is
+        // accepted
+        assert!(rotated_sc.is_valid_with(resolver.validation(), 0).is_err());
+        resolver.accept(&rotated_sc);
+        assert!(rotated_sc.is_valid_with(resolver.validation(), 0).is_ok());
+        // The original owner is still accepted
+        assert!(mk_sc(&owner)
+            .is_valid_with(resolver.validation(), 0)
+            .is_ok());
+    }
+
+    #[test]
+    fn untrusted_rotation() {
+        let (owner, rotated) = (mk_public_key(), mk_public_key());
+        let mut resolver = mk_resolver(&owner, OwnerRotation::Reject);
+        resolver.set_router_owner(rotated.to_vec());
+        resolver.accept(&mk_sc(&owner));
+        let rotated_sc = mk_sc(&rotated);
+        assert!(resolver.rotation(&rotated_sc).is_none());
+        assert!(matches!(
+            rotated_sc.is_valid_with(&resolver.validation_for(&rotated_sc), 0),
+            Err(Error::StateChannel(StateChannelError::InvalidOwner))
+        ));
+    }
+
+    #[test]
+    fn rotation_to_other_owner() {
+        // An active state channel of an owner the gateway does not report
+        // for the router is not a rotation
+        let (owner, rotated, other) = (mk_public_key(), mk_public_key(), mk_public_key());
+        let mut resolver = mk_resolver(&owner, OwnerRotation::Trust);
+        resolver.accept(&mk_sc(&owner));
+        let other_sc = mk_sc(&other);
+        assert!(resolver.rotation(&other_sc).is_none());
+        resolver.set_router_owner(rotated.to_vec());
+        assert!(resolver.rotation(&other_sc).is_none());
+        resolver.accept(&other_sc);
+        assert!(matches!(
+            other_sc.is_valid_with(resolver.validation(), 0),
+            Err(Error::StateChannel(StateChannelError::InvalidOwner))
+        ));
+    }
+}
@@ -0,0 +1,247 @@
+use http::uri::Uri;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The time it takes recorded traffic to count for half as much
+const TRAFFIC_HALF_LIFE: Duration = Duration::from_secs(600);
+
+/// How long a router that is not reconnecting is kept in the ranking after
+/// its last recorded purchase
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+
+/// A shared ranking of router clients by their recent traffic, used to
+/// stagger reconnects after an outage that dropped many routers at once.
+///
+/// Clones share the same ranking. Each client records its traffic, the
+/// packets purchased by its router, under the OUI and uri of the router.
+/// Purchases count for less the longer ago they were, so the ranking
+/// follows recent traffic. A reconnecting router waits an extra step for
+/// every busier router that is reconnecting as well, so the busiest routers
+/// reconnect first and the others follow one step apart, while a router
+/// that reconnects on its own does not wait. The default does not delay
+/// reconnects.
+#[derive(Debug, Clone, Default)]
+pub struct ReconnectPriority(Option<Arc<Ranking>>);
+
+#[derive(Debug)]
+struct Ranking {
+    step: Duration,
+    routers: Mutex<HashMap<(u32, String), RouterTraffic>>,
+}
+
+#[derive(Debug)]
+struct RouterTraffic {
+    /// The decayed number of purchases as of the last update
+    purchases: f64,
+    updated: Instant,
+    reconnecting: bool,
+}
+
+impl RouterTraffic {
+    fn new(now: Instant) -> Self {
+        Self {
+            purchases: 0.0,
+            updated: now,
+            reconnecting: false,
+        }
+    }
+
+    /// The decayed number of purchases at the given time
+    fn purchases_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.purchases * 0.5f64.powf(elapsed / TRAFFIC_HALF_LIFE.as_secs_f64())
+    }
+}
+
+impl ReconnectPriority {
+    /// Creates a ranking that delays reconnects by the given step for every
+    /// busier reconnecting router, a zero step does not delay reconnects.
+    pub fn new(step: Duration) -> Self {
+        if step == Duration::from_secs(0) {
+            return Self(None);
+        }
+        Self(Some(Arc::new(Ranking {
+            step,
+            routers: Mutex::new(HashMap::new()),
+        })))
+    }
+
+    /// Records a packet purchased by the given router at the given time.
+    pub fn record_purchase(&self, oui: u32, uri: &Uri, now: Instant) {
+        self.update(oui, uri, now, |router| {
+            router.purchases = router.purchases_at(now) + 1.0;
+            router.updated = now;
+        });
+    }
+
+    /// Records that the given router lost its connection and is waiting to
+    /// reconnect.
+    pub fn lost(&self, oui: u32, uri: &Uri, now: Instant) {
+        self.update(oui, uri, now, |router| router.reconnecting = true);
+    }
+
+    /// Records that the given router is connected again.
+    pub fn reconnected(&self, oui: u32, uri: &Uri, now: Instant) {
+        self.update(oui, uri, now, |router| router.reconnecting = false);
+    }
+
+    /// Removes the given router from the ranking, for example when its
+    /// client stops.
+    pub fn forget(&self, oui: u32, uri: &Uri) {
+        if let Some(ranking) = &self.0 {
+            ranking
+                .routers
+                .lock()
+                .expect("reconnect ranking")
+                .remove(&(oui, uri.to_string()));
+        }
+    }
+
+    /// Returns the extra wait at the given time before the given router
+    /// reconnects, one step for every reconnecting router with more recent
+    /// traffic. Routers that are neither reconnecting nor purchased packets
+    /// for a while are forgotten.
+    pub fn delay(&self, oui: u32, uri: &Uri, now: Instant) -> Duration {
+        let ranking = match &self.0 {
+            Some(ranking) => ranking,
+            None => return Duration::from_secs(0),
+        };
+        let key = (oui, uri.to_string());
+        let mut routers = ranking.routers.lock().expect("reconnect ranking");
+        routers.retain(|other, router| {
+            *other == key
+                || router.reconnecting
+                || now.saturating_duration_since(router.updated) < FORGET_AFTER
+        });
+        let purchases = routers
+            .get(&key)
+            .map_or(0.0, |router| router.purchases_at(now));
+        let busier = routers
+            .iter()
+            .filter(|(other, router)| {
+                **other != key && router.reconnecting && router.purchases_at(now) > purchases
+            })
+            .count();
+        ranking.step * busier as u32
+    }
+
+    fn update<F: FnOnce(&mut RouterTraffic)>(&self, oui: u32, uri: &Uri, now: Instant, f: F) {
+        if let Some(ranking) = &self.0 {
+            let mut routers = ranking.routers.lock().expect("reconnect ranking");
+            f(routers
+                .entry((oui, uri.to_string()))
+                .or_insert_with(|| RouterTraffic::new(now)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_uri(port: u16) -> Uri {
+        format!("http://127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn busier_routers_first() {
+        let step = Duration::from_millis(100);
+        let priority = ReconnectPriority::new(step);
+        let now = Instant::now();
+        let routers = [(1, 0), (2, 50), (3, 100), (4, 50)];
+        for (port, purchases) in routers.iter() {
+            for _ in 0..*purchases {
+                priority.record_purchase(1, &mk_uri(*port), now);
+            }
+            priority.lost(1, &mk_uri(*port), now);
+        }
+        let delays: Vec<Duration> = routers
+            .iter()
+            .map(|(port, _)| priority.clone().delay(1, &mk_uri(*port), now))
+            .collect();
+        assert_eq!(vec![step * 3, step, Duration::from_secs(0), step], delays);
+        // Routers that are connected again do not hold others back
+        priority.reconnected(1, &mk_uri(3), now);
+        assert_eq!(Duration::from_secs(0), priority.delay(1, &mk_uri(2), now));
+        priority.forget(1, &mk_uri(2));
+        priority.forget(1, &mk_uri(4));
+        assert_eq!(Duration::from_secs(0), priority.delay(1, &mk_uri(1), now));
+    }
+
+    #[test]
+    fn keyed_by_oui_and_uri() {
+        let step = Duration::from_millis(100);
+        let priority = ReconnectPriority::new(step);
+        let now = Instant::now();
+        // The same router uri serving two OUIs ranks separately
+        priority.record_purchase(2, &mk_uri(1), now);
+        priority.lost(2, &mk_uri(1), now);
+        priority.lost(1, &mk_uri(1), now);
+        assert_eq!(step, priority.delay(1, &mk_uri(1), now));
+        assert_eq!(Duration::from_secs(0), priority.delay(2, &mk_uri(1), now));
+    }
+
+    #[test]
+    fn ranks_recent_traffic() {
+        let step = Duration::from_millis(100);
+        let priority = ReconnectPriority::new(step);
+        let start = Instant::now();
+        // Lots of traffic long ago counts for less than a little just now
+        for _ in 0..100 {
+            priority.record_purchase(1, &mk_uri(1), start);
+        }
+        let now = start + TRAFFIC_HALF_LIFE * 10;
+        for _ in 0..10 {
+            priority.record_purchase(1, &mk_uri(2), now);
+        }
+        priority.lost(1, &mk_uri(1), now);
+        priority.lost(1, &mk_uri(2), now);
+        assert_eq!(step, priority.delay(1, &mk_uri(1), now));
+        assert_eq!(Duration::from_secs(0), priority.delay(1, &mk_uri(2), now));
+    }
+
+    #[test]
+    fn single_reconnect_not_delayed() {
+        let priority = ReconnectPriority::new(Duration::from_millis(100));
+        let now = Instant::now();
+        for _ in 0..10 {
+            priority.record_purchase(1, &mk_uri(1), now);
+        }
+        // A busier router that is connected does not delay a reconnect
+        priority.lost(1, &mk_uri(2), now);
+        assert_eq!(Duration::from_secs(0), priority.delay(1, &mk_uri(2), now));
+    }
+
+    #[test]
+    fn forgets_idle_routers() {
+        let priority = ReconnectPriority::new(Duration::from_millis(100));
+        let start = Instant::now();
+        priority.record_purchase(1, &mk_uri(1), start);
+        priority.record_purchase(1, &mk_uri(2), start);
+        priority.lost(1, &mk_uri(2), start);
+        priority.delay(1, &mk_uri(3), start + FORGET_AFTER);
+        // Only the reconnecting router is kept
+        let ranking = priority.0.as_ref().unwrap();
+        let mut kept: Vec<String> = ranking
+            .routers
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|(_, uri)| uri.clone())
+            .collect();
+        kept.sort();
+        assert_eq!(vec![mk_uri(2).to_string()], kept);
+    }
+
+    #[test]
+    fn no_delay_by_default() {
+        let priority = ReconnectPriority::default();
+        let now = Instant::now();
+        priority.record_purchase(1, &mk_uri(1), now);
+        priority.lost(1, &mk_uri(1), now);
+        assert_eq!(Duration::from_secs(0), priority.delay(1, &mk_uri(2), now));
+    }
+}
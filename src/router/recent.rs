@@ -0,0 +1,145 @@
+use crate::{router::QuePacket, Packet, PacketId};
+use helium_proto::routing_information::Data as RoutingData;
+use lorawan::PHYPayloadFrame;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How long after an uplink was delivered a downlink for the same device is
+/// still accepted. This covers the join accept delay plus router hold time.
+pub const RECENT_UPLINK_WINDOW: Duration = Duration::from_secs(10);
+
+/// Tracks the routing information of recently delivered uplinks so that
+/// downlinks can be checked against devices we actually sent packets for.
+///
+/// Each uplink is tracked with its packet id, which serves as the token that
+/// correlates a downlink with the uplink it answers since the protocol does
+/// not carry an explicit correlation field.
+#[derive(Debug)]
+pub struct RecentUplinks {
+    window: Duration,
+    capacity: usize,
+    entries: VecDeque<(Instant, RoutingData, MatchedUplink)>,
+}
+
+/// The recently delivered uplink a downlink answers
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedUplink {
+    pub id: PacketId,
+    /// The concentrator timestamp of the uplink in microseconds
+    pub timestamp: u64,
+}
+
+impl RecentUplinks {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, packet: &QuePacket) {
+        let routing_data = match packet.routing() {
+            Some(routing) => match &routing.data {
+                Some(data) => data.clone(),
+                None => return,
+            },
+            None => return,
+        };
+        let uplink = MatchedUplink {
+            id: packet.id().clone(),
+            timestamp: packet.timestamp,
+        };
+        self.entries
+            .push_back((Instant::now(), routing_data, uplink));
+        if self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Matches the given downlink against recently delivered uplinks,
+    /// returning the most recent uplink it answers. Data
+    /// downlinks are matched by DevAddr, join accepts by any recently
+    /// delivered join request since their payload is encrypted. Returns
+    /// `None` for unsolicited downlinks.
+    pub fn match_downlink(&mut self, downlink: &Packet) -> Option<MatchedUplink> {
+        self.prune();
+        let matched = match Packet::parse_frame(lorawan::Direction::Downlink, downlink.payload()) {
+            Ok(PHYPayloadFrame::MACPayload(payload)) => {
+                let dev_addr = payload.dev_addr();
+                self.entries
+                    .iter()
+                    .rev()
+                    .find(|(_, data, _)| data == &RoutingData::Devaddr(dev_addr))
+            }
+            Ok(PHYPayloadFrame::JoinAccept(_)) => self
+                .entries
+                .iter()
+                .rev()
+                .find(|(_, data, _)| matches!(data, RoutingData::Eui(_))),
+            _ => None,
+        };
+        matched.map(|(_, _, uplink)| uplink.clone())
+    }
+
+    fn prune(&mut self) {
+        while let Some((received, _, _)) = self.entries.front() {
+            if received.elapsed() <= self.window {
+                break;
+            }
+            self.entries.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_proto::RoutingInformation;
+
+    fn mk_uplink(dev_addr: u32) -> QuePacket {
+        QuePacket::from(Packet::from(helium_proto::Packet {
+            routing: Some(RoutingInformation {
+                data: Some(RoutingData::Devaddr(dev_addr)),
+            }),
+            payload: dev_addr.to_le_bytes().to_vec(),
+            ..Default::default()
+        }))
+    }
+
+    fn mk_downlink(dev_addr: u32) -> Packet {
+        // Unconfirmed down MHDR, DevAddr, FCtrl, FCnt and MIC
+        let mut payload = vec![0x60];
+        payload.extend_from_slice(&dev_addr.to_le_bytes());
+        payload.extend_from_slice(&[0, 1, 0, 0xde, 0xad, 0xbe, 0xef]);
+        Packet::from(helium_proto::Packet {
+            payload,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn matching_downlink() {
+        let mut recent = RecentUplinks::new(RECENT_UPLINK_WINDOW, 10);
+        let uplink = mk_uplink(0x01020304);
+        recent.record(&uplink);
+        recent.record(&mk_uplink(0x05060708));
+        // The downlink is correlated with the uplink it answers
+        assert_eq!(
+            Some(uplink.id().clone()),
+            recent
+                .match_downlink(&mk_downlink(0x01020304))
+                .map(|uplink| uplink.id)
+        );
+    }
+
+    #[test]
+    fn unsolicited_downlink() {
+        let mut recent = RecentUplinks::new(RECENT_UPLINK_WINDOW, 10);
+        assert!(recent.match_downlink(&mk_downlink(0x01020304)).is_none());
+        recent.record(&mk_uplink(0x01020304));
+        assert!(recent.match_downlink(&mk_downlink(0x05060708)).is_none());
+    }
+}
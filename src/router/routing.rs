@@ -8,6 +8,7 @@ use std::{convert::TryFrom, sync::Arc};
 #[derive(Clone, Debug)]
 pub struct Routing {
     pub(crate) oui: u32,
+    pub(crate) owner: Vec<u8>,
     pub(crate) uris: Vec<KeyedUri>,
     filters: Vec<EuiFilter>,
     subnets: Vec<DevAddrFilter>,
@@ -39,6 +40,7 @@ impl Routing {
         let filters = r.filters.iter().map(EuiFilter::from_bin).collect();
         let subnets = r.subnets.iter().map(DevAddrFilter::from_bin).collect();
         let oui = r.oui;
+        let owner = r.owner.clone();
         let uris = r
             .addresses
             .iter()
@@ -75,6 +77,7 @@ impl Routing {
             .collect();
         Ok(Self {
             oui,
+            owner,
             filters,
             subnets,
             uris,
@@ -0,0 +1,236 @@
+use crate::{settings::ScSelection, Region, StateChannel, StateChannelKey};
+use rand::Rng;
+use std::{cmp::Ordering, collections::HashMap};
+
+/// Picks an index into the given weights with a probability proportional to
+/// its weight. Returns `None` if there are no weights or all weights are zero.
+pub fn select_weighted<R: Rng + ?Sized>(weights: &[u32], rng: &mut R) -> Option<usize> {
+    let total: u64 = weights.iter().map(|weight| *weight as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut pick = rng.gen_range(0..total);
+    for (index, weight) in weights.iter().enumerate() {
+        let weight = *weight as u64;
+        if pick < weight {
+            return Some(index);
+        }
+        pick -= weight;
+    }
+    None
+}
+
+/// Picks the state channel to offer against among the known state channels
+/// of a router according to the configured selection. Tracks the order in
+/// which state channels were received in banners for selecting the latest,
+/// and the region routers purchased packets in against each state channel.
+#[derive(Debug)]
+pub struct StateChannelSelector {
+    selection: ScSelection,
+    tick: u64,
+    banners: HashMap<String, u64>,
+    regions: HashMap<String, Region>,
+}
+
+impl StateChannelSelector {
+    pub fn new(selection: ScSelection) -> Self {
+        Self {
+            selection,
+            tick: 0,
+            banners: HashMap::new(),
+            regions: HashMap::new(),
+        }
+    }
+
+    /// Notes that the state channel with the given id was received in a
+    /// banner.
+    pub fn record_banner(&mut self, sc_id: String) {
+        self.tick += 1;
+        self.banners.insert(sc_id, self.tick);
+    }
+
+    /// Notes that a router purchased a packet in the given region against
+    /// the state channel with the given id.
+    pub fn record_region(&mut self, sc_id: String, region: Region) {
+        if self.regions.get(&sc_id) != Some(&region) {
+            self.tick += 1;
+            self.regions.insert(sc_id, region);
+        }
+    }
+
+    /// Returns whether the state channel with the given id can be offered
+    /// against for packets in the given region. State channels are only
+    /// known to belong to a region once a packet was purchased against them,
+    /// state channels without a known region are assumed to be usable.
+    pub fn is_valid_region(&self, sc_id: &str, region: &Region) -> bool {
+        self.regions
+            .get(sc_id)
+            .map_or(true, |sc_region| sc_region == region)
+    }
+
+    /// Returns the region noted for the state channel with the given id
+    pub fn region(&self, sc_id: &str) -> Option<&Region> {
+        self.regions.get(sc_id)
+    }
+
+    /// Returns the number of banners and state channel regions noted so far,
+    /// which changes whenever the selection may change.
+    pub fn generation(&self) -> u64 {
+        self.tick
+    }
+
+    /// Returns the selected state channel among the given ones for packets
+    /// in the given region, ignoring state channels that expired at or before
+    /// the given block height and state channels of another region. A height
+    /// of 0 means the height is not known and considers all state channels.
+    /// Ties go to the lowest state channel id so the selection does not
+    /// depend on the order of the given state channels.
+    pub fn select<'a>(
+        &self,
+        scs: &'a [StateChannel],
+        height: u64,
+        region: &Region,
+    ) -> Option<&'a StateChannel> {
+        scs.iter()
+            .filter(|sc| height == 0 || sc.expiry_at_block() > height)
+            .filter(|sc| self.is_valid_region(&sc.id_key(), region))
+            .min_by(|a, b| {
+                self.preference(a, b)
+                    .then_with(|| a.id_key().cmp(&b.id_key()))
+            })
+    }
+
+    /// Orders the preferred of two state channels first
+    fn preference(&self, a: &StateChannel, b: &StateChannel) -> Ordering {
+        match self.selection {
+            ScSelection::Balance => b.remaining_balance().cmp(&a.remaining_balance()),
+            ScSelection::Expiry => a.expiry_at_block().cmp(&b.expiry_at_block()),
+            ScSelection::Latest => self.banner_tick(b).cmp(&self.banner_tick(a)),
+        }
+    }
+
+    fn banner_tick(&self, sc: &StateChannel) -> u64 {
+        self.banners.get(&sc.id_key()).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn selection_matches_weights() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let weights = [1, 3, 0, 6];
+        let mut counts = [0u32; 4];
+        for _ in 0..10_000 {
+            counts[select_weighted(&weights, &mut rng).unwrap()] += 1;
+        }
+        assert_eq!(0, counts[2]);
+        for (count, expected) in counts.iter().zip([1_000, 3_000, 0, 6_000].iter()) {
+            assert!(
+                (*count as i64 - *expected as i64).abs() < 300,
+                "{} not close to {}",
+                count,
+                expected
+            );
+        }
+    }
+
+    fn mk_sc(id: u8, credits: u64, num_dcs: u64, expiry_at_block: u64) -> StateChannel {
+        let sc = helium_proto::BlockchainStateChannelV1 {
+            id: vec![id],
+            credits,
+            summaries: vec![helium_proto::BlockchainStateChannelSummaryV1 {
+                client_pubkeybin: vec![1],
+                num_packets: num_dcs,
+                num_dcs,
+            }],
+            ..Default::default()
+        };
+        crate::test_support::mk_state_channel(&sc, expiry_at_block, credits)
+    }
+
+    fn us915() -> Region {
+        Region::from_i32(0).unwrap()
+    }
+
+    fn selected_id(selector: &StateChannelSelector, scs: &[StateChannel], height: u64) -> u8 {
+        selector
+            .select(scs, height, &us915())
+            .expect("selected sc")
+            .id()[0]
+    }
+
+    #[test]
+    fn select_state_channel() {
+        let mk_scs = || {
+            vec![
+                mk_sc(1, 100, 10, 500),
+                mk_sc(2, 100, 50, 300),
+                mk_sc(3, 200, 20, 200),
+            ]
+        };
+        let scs = mk_scs();
+        let mut reversed = mk_scs();
+        reversed.reverse();
+
+        let selector = StateChannelSelector::new(ScSelection::Balance);
+        assert_eq!(3, selected_id(&selector, &scs, 0));
+        // State channel 3 has expired at this height
+        assert_eq!(1, selected_id(&selector, &scs, 250));
+
+        let selector = StateChannelSelector::new(ScSelection::Expiry);
+        assert_eq!(3, selected_id(&selector, &scs, 0));
+        assert_eq!(2, selected_id(&selector, &scs, 250));
+        assert!(selector.select(&scs, 500, &us915()).is_none());
+
+        let mut selector = StateChannelSelector::new(ScSelection::Latest);
+        // Without banners the lowest id is selected regardless of order
+        assert_eq!(
+            selected_id(&selector, &scs, 0),
+            selected_id(&selector, &reversed, 0)
+        );
+        selector.record_banner(scs[0].id_key());
+        selector.record_banner(scs[1].id_key());
+        assert_eq!(2, selected_id(&selector, &scs, 0));
+        selector.record_banner(scs[0].id_key());
+        assert_eq!(1, selected_id(&selector, &reversed, 0));
+    }
+
+    #[test]
+    fn select_state_channel_region() {
+        let eu868 = Region::from_i32(1).unwrap();
+        let scs = vec![mk_sc(1, 100, 10, 500), mk_sc(2, 100, 10, 500)];
+        let mut selector = StateChannelSelector::new(ScSelection::Latest);
+        selector.record_banner(scs[0].id_key());
+        assert!(selector.is_valid_region(&scs[0].id_key(), &eu868));
+        assert_eq!(1, selected_id(&selector, &scs, 0));
+
+        // Once a packet in another region was purchased against the latest
+        // state channel, it is no longer selected for this region
+        let generation = selector.generation();
+        selector.record_region(scs[0].id_key(), eu868.clone());
+        assert!(selector.generation() > generation);
+        assert!(!selector.is_valid_region(&scs[0].id_key(), &us915()));
+        assert_eq!(Some(&eu868), selector.region(&scs[0].id_key()));
+        assert_eq!(2, selected_id(&selector, &scs, 0));
+        assert_eq!(
+            1,
+            selector.select(&scs, 0, &eu868).expect("selected sc").id()[0]
+        );
+
+        // Noting the same region again does not change the selection
+        let generation = selector.generation();
+        selector.record_region(scs[0].id_key(), eu868);
+        assert_eq!(generation, selector.generation());
+    }
+
+    #[test]
+    fn no_weights() {
+        let mut rng = StdRng::seed_from_u64(42);
+        assert_eq!(None, select_weighted(&[], &mut rng));
+        assert_eq!(None, select_weighted(&[0, 0], &mut rng));
+    }
+}
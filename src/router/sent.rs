@@ -0,0 +1,90 @@
+use crate::PacketId;
+use std::collections::VecDeque;
+
+/// The packets most recently sent to the router, keyed by their id and
+/// concentrator timestamp, so that a purchase the router sends again for an
+/// already sent packet can be told apart from a purchase for the next queued
+/// packet. A retransmitted payload has the same id as the sent one but its
+/// own timestamp, so its purchase is not mistaken for a duplicate. The
+/// oldest packet is forgotten once the capacity is reached.
+#[derive(Debug)]
+pub struct SentPackets {
+    capacity: usize,
+    packets: VecDeque<(PacketId, u64)>,
+}
+
+impl SentPackets {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            packets: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, id: PacketId, timestamp: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.packets.len() >= self.capacity {
+            self.packets.pop_front();
+        }
+        self.packets.push_back((id, timestamp));
+    }
+
+    /// Returns whether the packet with the given id and timestamp was
+    /// recently sent.
+    pub fn contains(&self, id: &PacketId, timestamp: u64) -> bool {
+        self.packets
+            .iter()
+            .any(|(sent_id, sent_timestamp)| sent_id == id && *sent_timestamp == timestamp)
+    }
+
+    /// Returns whether any packet with the given hash was recently sent. An
+    /// empty hash, as sent by routers that do not identify the purchased
+    /// packet, never matches.
+    pub fn contains_hash(&self, packet_hash: &[u8]) -> bool {
+        !packet_hash.is_empty()
+            && self
+                .packets
+                .iter()
+                .any(|(sent_id, _)| sent_id.as_ref() == packet_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Packet;
+
+    fn mk_id(payload: u8) -> PacketId {
+        Packet::from(helium_proto::Packet {
+            payload: vec![payload],
+            ..Default::default()
+        })
+        .id()
+    }
+
+    #[test]
+    fn forgets_oldest() {
+        let mut sent = SentPackets::new(2);
+        sent.record(mk_id(1), 10);
+        sent.record(mk_id(2), 20);
+        assert!(sent.contains(&mk_id(1), 10));
+        sent.record(mk_id(3), 30);
+        assert!(!sent.contains(&mk_id(1), 10));
+        assert!(!sent.contains_hash(mk_id(1).as_ref()));
+        assert!(sent.contains(&mk_id(2), 20));
+        assert!(sent.contains(&mk_id(3), 30));
+        assert!(!sent.contains_hash(&[]));
+    }
+
+    #[test]
+    fn keyed_by_timestamp() {
+        let mut sent = SentPackets::new(2);
+        sent.record(mk_id(1), 10);
+        assert!(sent.contains(&mk_id(1), 10));
+        // A retransmission of the same payload
+        assert!(!sent.contains(&mk_id(1), 11));
+        assert!(sent.contains_hash(mk_id(1).as_ref()));
+    }
+}
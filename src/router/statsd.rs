@@ -0,0 +1,108 @@
+use crate::{Error, Result};
+use std::{
+    fmt,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+/// The prefix of the names of all metrics emitted to StatsD
+pub const STATSD_PREFIX: &str = "helium_gateway";
+
+/// Emits the counters and timers of a router client to a StatsD server over
+/// UDP, named `helium_gateway.<router>.<metric>`.
+///
+/// Emitting never blocks: metrics go out on a non-blocking socket and are
+/// dropped when they can not be sent right away, which StatsD over UDP
+/// already allows for. Clones share the same socket. The default emits
+/// nothing.
+#[derive(Clone, Default)]
+pub struct StatsdSink(Option<Arc<Sink>>);
+
+struct Sink {
+    socket: UdpSocket,
+    target: SocketAddr,
+    scope: String,
+}
+
+impl fmt::Debug for StatsdSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(sink) => f
+                .debug_struct("StatsdSink")
+                .field("target", &sink.target)
+                .field("scope", &sink.scope)
+                .finish(),
+            None => f.write_str("StatsdSink(disabled)"),
+        }
+    }
+}
+
+impl StatsdSink {
+    /// Creates a sink emitting the metrics of the given router to the StatsD
+    /// server at the given address, for example `127.0.0.1:8125` or
+    /// `statsd:8125`. A host name is resolved once, here, and the first
+    /// address it resolves to is used.
+    pub fn new(addr: &str, router: &str) -> Result<Self> {
+        let target = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| Error::custom(format!("invalid statsd address {}", addr)))?;
+        let bind_addr = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self(Some(Arc::new(Sink {
+            socket,
+            target,
+            scope: format!("{}.{}", STATSD_PREFIX, router),
+        }))))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Adds the given value to the counter with the given name.
+    pub fn count(&self, name: &str, value: u64) {
+        self.emit(name, value, "c")
+    }
+
+    /// Records the given duration for the timer with the given name.
+    pub fn timing(&self, name: &str, duration: Duration) {
+        self.emit(name, duration.as_millis() as u64, "ms")
+    }
+
+    fn emit(&self, name: &str, value: u64, kind: &str) {
+        if let Some(sink) = &self.0 {
+            let line = format!("{}.{}:{}|{}", sink.scope, name, value, kind);
+            // A full socket buffer or an unreachable server drops the metric
+            let _ = sink.socket.send_to(line.as_bytes(), sink.target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sink_addresses() {
+        assert!(!StatsdSink::default().is_enabled());
+        // A disabled sink silently emits nothing
+        StatsdSink::default().count("offers", 1);
+        assert!(StatsdSink::new("not an address", "router").is_err());
+        assert!(StatsdSink::new("localhost", "router").is_err());
+        assert!(StatsdSink::new("127.0.0.1:8125", "router")
+            .unwrap()
+            .is_enabled());
+        // Host names are resolved
+        assert!(StatsdSink::new("localhost:8125", "router")
+            .unwrap()
+            .is_enabled());
+    }
+}
@@ -1,33 +1,171 @@
 use crate::{
+    clock,
     error::{Error, StateChannelError},
-    CacheSettings, Packet, Result, StateChannel, StateChannelKey,
+    router::codec,
+    settings::PacketEncoding,
+    CacheSettings, Packet, PacketId, Region, Result, SharedClock, StateChannel, StateChannelKey,
 };
+use bytes::{Buf, BufMut, BytesMut};
 use std::{
     collections::VecDeque,
     convert::TryFrom,
     io,
     ops::Deref,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
-use tokio::fs;
+use tokio::{fs, sync::RwLock};
 
+/// The packet queues and persisted state channels of a router client.
+///
+/// Clones share the same underlying store. The packet queues and the state
+/// channel files on disk are each guarded by their own async read/write lock
+/// so that the store can be used from multiple tasks: queries take the read
+/// lock and can run concurrently, while updates take the write lock. Slow
+/// state channel writes do not hold up packet handling.
+///
+/// Packets move through two separate queues. A packet is first stored as
+/// waiting until it is offered, at which point it moves to the queued
+/// packets until the matching purchase or rejection dequeues it. Each queue
+/// has its own capacity bound and expiry policy.
+///
+/// Packets are kept in memory. The waiting packets can be saved next to the
+/// records of the store on shutdown, in the configured packet encoding, to
+/// be restored when the store is used again, see `save_packets`. State
+/// channels are written to disk as they change, each version in its
+/// protobuf encoding prefixed by its expiry block and original DC amount,
+/// see `StateChannel::to_vec`.
+#[derive(Clone)]
 pub struct RouterStore {
     path: PathBuf,
-    waiting_packets: VecDeque<QuePacket>,
-    queued_packets: VecDeque<QuePacket>,
-    max_packets: u16,
+    clock: SharedClock,
+    packets: Arc<RwLock<Packets>>,
+    /// Guards the state channel files on disk
+    disk: Arc<RwLock<()>>,
+    sc_generation: Arc<AtomicU64>,
+    max_state_channels: usize,
+    evicted_state_channels: Arc<AtomicU64>,
+    max_disk_usage: u64,
+    /// The bytes taken up by the persisted state channels, kept up to date
+    /// with every write and removal
+    sc_bytes: Arc<AtomicU64>,
+    quarantine: PathBuf,
+    /// The bytes taken up by the quarantine directory of this store
+    quarantine_bytes: Arc<AtomicU64>,
+    /// The state channel that is never evicted, the one offers are made
+    /// against
+    protected_sc: Arc<Mutex<Option<String>>>,
+    disk_evictions: Arc<AtomicU64>,
+    disk_evicted_bytes: Arc<AtomicU64>,
+    /// The ids of the state channels removed since they were last taken
+    removed_scs: Arc<Mutex<Vec<String>>>,
+    quarantined: Vec<PathBuf>,
+    /// Where the reject log, metrics and saved packets of the store are
+    /// kept
+    records: PathBuf,
+    packet_encoding: PacketEncoding,
+}
+
+struct Packets {
+    waiting: PacketQueue,
+    queued: PacketQueue,
+    /// The hashes of the most recently expired queued packets, up to the
+    /// maximum number of packets
+    expired: VecDeque<Vec<u8>>,
+}
+
+/// A bounded queue of packets in one state of their lifecycle.
+#[derive(Debug)]
+struct PacketQueue {
+    max_packets: usize,
+    max_age: Duration,
+    packets: VecDeque<QuePacket>,
 }
 
 #[derive(Debug)]
 pub struct QuePacket {
     received: Instant,
+    offered: Option<Instant>,
+    offers: u32,
+    region: Option<Region>,
+    id: PacketId,
     packet: Packet,
 }
 
 impl QuePacket {
+    /// Creates a packet that was received at the given time.
+    pub fn new(packet: Packet, received: Instant) -> Self {
+        let id = packet.id();
+        Self {
+            received,
+            offered: None,
+            offers: 0,
+            region: None,
+            id,
+            packet,
+        }
+    }
+
+    pub fn id(&self) -> &PacketId {
+        &self.id
+    }
+
+    /// Returns when the packet was received.
+    pub fn received(&self) -> Instant {
+        self.received
+    }
+
     pub fn hold_time(&self) -> Duration {
-        self.received.elapsed()
+        self.hold_time_at(Instant::now())
+    }
+
+    /// Returns how long the packet has been held at the given time. The hold
+    /// time is derived from the monotonic receipt time, so it keeps growing
+    /// while the packet waits in the store.
+    pub fn hold_time_at(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.received)
+    }
+
+    /// Returns how much longer the packet is useful at the given time, until
+    /// the last receive window for an answer to it closes in the given
+    /// region. Zero once the window has closed.
+    pub fn ttl_at(&self, now: Instant, region: &Region) -> Duration {
+        let (_, rx2) = region.rx_windows(self.packet.is_join_request());
+        rx2.saturating_sub(self.hold_time_at(now))
+    }
+
+    /// Returns how long before the given time the packet was offered, if it
+    /// was offered.
+    pub fn offer_time_at(&self, now: Instant) -> Option<Duration> {
+        self.offered
+            .map(|offered| now.saturating_duration_since(offered))
+    }
+
+    /// Returns the number of times the packet was offered. Offers of a
+    /// packet that is requeued after being offered add up.
+    pub fn offers(&self) -> u32 {
+        self.offers
+    }
+
+    /// Returns the region the packet was offered in, if it was offered.
+    pub fn region(&self) -> Option<&Region> {
+        self.region.as_ref()
+    }
+
+    pub fn with_region(mut self, region: Region) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    /// Counts the given number of earlier offers of the packet, made by
+    /// another router before it was redispatched.
+    pub fn with_offers(mut self, offers: u32) -> Self {
+        self.offers = offers;
+        self
     }
 
     pub fn packet(&self) -> &Packet {
@@ -45,59 +183,489 @@ impl Deref for QuePacket {
 
 impl From<Packet> for QuePacket {
     fn from(packet: Packet) -> Self {
-        let received = Instant::now();
-        Self { received, packet }
+        Self::new(packet, Instant::now())
+    }
+}
+
+impl PacketQueue {
+    fn new(max_packets: usize, max_age: Duration) -> Self {
+        Self {
+            max_packets,
+            max_age,
+            packets: VecDeque::new(),
+        }
+    }
+
+    fn push_back(&mut self, packet: QuePacket) {
+        self.packets.push_back(packet);
+        if self.packets.len() > self.max_packets {
+            self.packets.pop_front();
+        }
+    }
+
+    fn push_front(&mut self, packet: QuePacket) {
+        self.packets.push_front(packet);
+        if self.packets.len() > self.max_packets {
+            self.packets.pop_back();
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<QuePacket> {
+        self.packets.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    fn dc(&self) -> u64 {
+        self.packets.iter().map(|packet| packet.dc_payload()).sum()
+    }
+
+    /// Removes packets expired at the given time, returning the removed
+    /// packets. Waiting packets expire by the time since they were received,
+    /// queued packets by the time since they were offered.
+    fn expire(&mut self, now: Instant) -> Vec<QuePacket> {
+        let max_age = self.max_age;
+        let (expired, kept): (Vec<_>, Vec<_>) = self.packets.drain(..).partition(|packet| {
+            packet
+                .offer_time_at(now)
+                .unwrap_or_else(|| packet.hold_time_at(now))
+                > max_age
+        });
+        self.packets = kept.into();
+        expired
+    }
+}
+
+impl Packets {
+    /// Remembers the given queued packets as expired, forgetting the oldest
+    /// expired packets beyond the capacity of the queued packets.
+    fn record_expired(&mut self, expired: &[QuePacket]) {
+        for packet in expired {
+            self.expired.push_back(packet.id().as_ref().to_vec());
+        }
+        while self.expired.len() > self.queued.max_packets {
+            self.expired.pop_front();
+        }
     }
 }
 
 impl RouterStore {
+    /// Opens the store with the given name, migrating state channels
+    /// persisted by an older version of the store to the current layout.
+    /// Opening a store written by a newer version fails. The persisted state
+    /// channels were validated by the gateway of an earlier run, the client
+    /// revalidates them before trusting them again.
+    ///
+    /// Corrupt data, for example from a write cut short by a power loss, is
+    /// moved to a quarantine directory next to the stores rather than failing
+    /// the open. State channel versions that do not decode are quarantined
+    /// one by one, while an unreadable store version quarantines the whole
+    /// store, which then starts out fresh.
     pub async fn new(name: &str, settings: &CacheSettings) -> Result<Self> {
         let path = settings.store.join(name);
+        let quarantine = settings.store.join(QUARANTINE_DIR).join(name);
         fs::create_dir_all(&path).await?;
-        clean_dir(&path).await?;
-        let max_packets = settings.max_packets;
-        let waiting_packets = VecDeque::new();
-        let queued_packets = VecDeque::new();
+        let mut quarantined = vec![];
+        if has_corrupt_version(&path).await? {
+            quarantined.push(quarantine_entry(&path, &path, &quarantine).await?);
+            fs::create_dir_all(&path).await?;
+        }
+        quarantined.extend(migrate(&path, &quarantine).await?);
+        quarantined.extend(quarantine_corrupt(&path, &quarantine).await?);
+        let mut sc_bytes = 0;
+        for sc_id in sc_ids(&path).await? {
+            sc_bytes += dir_bytes(&path.join(sc_id)).await?;
+        }
+        let quarantine_bytes = dir_bytes(&quarantine).await?;
+        let max_packets = settings.max_packets as usize;
+        let packets = Packets {
+            waiting: PacketQueue::new(max_packets, Duration::from_secs(settings.max_packet_age)),
+            queued: PacketQueue::new(max_packets, Duration::from_secs(settings.max_queued_age)),
+            expired: VecDeque::new(),
+        };
         Ok(Self {
             path,
-            waiting_packets,
-            queued_packets,
-            max_packets,
+            clock: clock::system(),
+            packets: Arc::new(RwLock::new(packets)),
+            disk: Arc::new(RwLock::new(())),
+            sc_generation: Arc::new(AtomicU64::new(0)),
+            max_state_channels: settings.max_state_channels,
+            evicted_state_channels: Arc::new(AtomicU64::new(0)),
+            max_disk_usage: settings.max_disk_usage,
+            sc_bytes: Arc::new(AtomicU64::new(sc_bytes)),
+            quarantine,
+            quarantine_bytes: Arc::new(AtomicU64::new(quarantine_bytes)),
+            protected_sc: Arc::new(Mutex::new(None)),
+            disk_evictions: Arc::new(AtomicU64::new(0)),
+            disk_evicted_bytes: Arc::new(AtomicU64::new(0)),
+            removed_scs: Arc::new(Mutex::new(vec![])),
+            quarantined,
+            records: settings.store.join(RECORDS_DIR).join(name),
+            packet_encoding: settings.packet_encoding,
         })
     }
 
-    pub fn store_waiting_packet(&mut self, packet: Packet) -> Result {
-        self.waiting_packets.push_back(QuePacket::from(packet));
-        if self.waiting_packets.len() > self.max_packets as usize {
-            self.waiting_packets.pop_front();
+    /// Returns where the corrupt data found when the store was opened was
+    /// moved to, if any.
+    pub fn quarantined(&self) -> &[PathBuf] {
+        &self.quarantined
+    }
+
+    /// Returns the directory next to the stores where the reject log and
+    /// the metrics of this store are kept.
+    pub fn records(&self) -> &Path {
+        &self.records
+    }
+
+    /// Appends the given line to the reject log. A log grown past its
+    /// maximum size is moved aside, replacing the log moved aside before.
+    pub async fn append_reject_log(&self, line: &str) -> Result {
+        use tokio::io::AsyncWriteExt;
+        fs::create_dir_all(&self.records).await?;
+        let log_path = self.records.join(REJECT_LOG_FILE);
+        match fs::metadata(&log_path).await {
+            Ok(metadata) if metadata.len() >= MAX_REJECT_LOG_BYTES => {
+                fs::rename(&log_path, log_path.with_extension("log.old")).await?
+            }
+            _ => (),
+        }
+        let mut log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .await?;
+        log.write_all(format!("{}\n", line).as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Replaces the persisted metrics with the given serialized metrics.
+    pub async fn write_metrics(&self, metrics: &[u8]) -> Result {
+        fs::create_dir_all(&self.records).await?;
+        fs::write(self.records.join(METRICS_FILE), metrics).await?;
+        Ok(())
+    }
+
+    /// Saves the waiting packets, along with their offers and how long ago
+    /// they were received, in the configured packet encoding. The packets
+    /// are restored by `load_packets`, usually after a restart. Returns the
+    /// number of saved packets.
+    pub async fn save_packets(&self) -> Result<usize> {
+        let codec = self.packet_encoding.codec();
+        let mut buf = BytesMut::new();
+        buf.put_u8(codec.tag());
+        buf.put_u64(unix_millis(self.clock.system_now()));
+        let packets = self.packets.read().await;
+        let now = self.clock.now();
+        let mut encoded = BytesMut::new();
+        for packet in packets.waiting.packets.iter() {
+            encoded.clear();
+            codec.encode(packet.packet(), &mut encoded)?;
+            buf.put_u64(packet.hold_time_at(now).as_millis() as u64);
+            buf.put_u32(packet.offers());
+            buf.put_u32(encoded.len() as u32);
+            buf.put_slice(&encoded);
+        }
+        let saved = packets.waiting.len();
+        drop(packets);
+        fs::create_dir_all(&self.records).await?;
+        fs::write(self.records.join(PACKETS_FILE), &buf).await?;
+        Ok(saved)
+    }
+
+    /// Restores the packets saved by `save_packets` as waiting packets, with
+    /// the time since they were saved added to their age, and removes the
+    /// saved packets. Packets that expired in the meantime or that are still
+    /// waiting are dropped. The packets are decoded with the encoding they
+    /// were saved in. Returns the number of restored packets.
+    pub async fn load_packets(&self) -> Result<usize> {
+        let path = self.records.join(PACKETS_FILE);
+        let data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        // Saved packets are only ever restored once
+        fs::remove_file(&path).await?;
+        let mut buf = &data[..];
+        if buf.remaining() < 9 {
+            return Err(Error::custom("saved packets truncated"));
+        }
+        let codec = codec::codec_for_tag(buf.get_u8())
+            .ok_or_else(|| Error::custom("unknown saved packet encoding"))?;
+        let saved_at = buf.get_u64();
+        let since_saved = unix_millis(self.clock.system_now()).saturating_sub(saved_at);
+        let mut restored = vec![];
+        while buf.has_remaining() {
+            if buf.remaining() < 16 {
+                return Err(Error::custom("saved packets truncated"));
+            }
+            let age = Duration::from_millis(buf.get_u64().saturating_add(since_saved));
+            let offers = buf.get_u32();
+            let len = buf.get_u32() as usize;
+            if buf.remaining() < len {
+                return Err(Error::custom("saved packets truncated"));
+            }
+            let packet = codec.decode(&buf[..len])?;
+            buf.advance(len);
+            restored.push((packet, age, offers));
+        }
+        let mut packets = self.packets.write().await;
+        let now = self.clock.now();
+        let max_age = packets.waiting.max_age;
+        let mut count = 0;
+        for (packet, age, offers) in restored {
+            let received = match now.checked_sub(age) {
+                Some(received) if age <= max_age => received,
+                _ => continue,
+            };
+            if packets
+                .waiting
+                .packets
+                .iter()
+                .any(|waiting| waiting.packet() == &packet)
+            {
+                continue;
+            }
+            packets
+                .waiting
+                .push_back(QuePacket::new(packet, received).with_offers(offers));
+            count += 1;
         }
+        Ok(count.min(packets.waiting.len()))
+    }
+
+    /// Use the given clock for packet receive and offer times and their
+    /// expiry.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub async fn store_waiting_packet(&self, packet: Packet) -> Result {
+        self.store_waiting_offered_packet(packet, 0).await
+    }
+
+    /// Stores a waiting packet that was already offered the given number of
+    /// times, by another router before it was redispatched.
+    pub async fn store_waiting_offered_packet(&self, packet: Packet, offers: u32) -> Result {
+        let now = self.clock.now();
+        let packet = QuePacket::new(packet, now).with_offers(offers);
+        self.packets.write().await.waiting.push_back(packet);
         Ok(())
     }
 
-    pub fn pop_waiting_packet(&mut self) -> Option<QuePacket> {
-        self.waiting_packets.pop_front()
+    /// Returns a counter that changes with every write or removal of state
+    /// channels, so what is derived from the known state channels can be
+    /// cached until they change.
+    pub fn sc_generation(&self) -> u64 {
+        self.sc_generation.load(Ordering::Relaxed)
     }
 
-    pub fn que_packet(&mut self, packet: QuePacket) -> Result {
-        self.queued_packets.push_back(packet);
-        if self.queued_packets.len() > self.max_packets as usize {
-            self.queued_packets.pop_front();
+    fn bump_sc_generation(&self) {
+        self.sc_generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn pop_waiting_packet(&self) -> Option<QuePacket> {
+        self.packets.write().await.waiting.pop_front()
+    }
+
+    /// Pops the next waiting packet if offering it keeps the estimated DC
+    /// owed for queued, offered but not yet purchased, packets within the
+    /// given cap. A cap of 0 means there is no cap.
+    pub async fn pop_offerable_packet(&self, max_inflight_dc: u64) -> Option<QuePacket> {
+        let mut packets = self.packets.write().await;
+        if max_inflight_dc > 0 {
+            let packet_dc = packets.waiting.packets.front()?.dc_payload();
+            if packets.queued.dc() + packet_dc > max_inflight_dc {
+                return None;
+            }
+        }
+        packets.waiting.pop_front()
+    }
+
+    /// Pops the oldest waiting packet of the given DevAddr, leaving the
+    /// waiting packets of other devices in place, under the same in flight
+    /// DC cap as `pop_offerable_packet`.
+    pub async fn pop_offerable_devaddr_packet(
+        &self,
+        dev_addr: u32,
+        max_inflight_dc: u64,
+    ) -> Option<QuePacket> {
+        let mut packets = self.packets.write().await;
+        let index = packets
+            .waiting
+            .packets
+            .iter()
+            .position(|packet| packet.dev_addr() == Some(dev_addr))?;
+        if max_inflight_dc > 0 {
+            let packet_dc = packets.waiting.packets[index].dc_payload();
+            if packets.queued.dc() + packet_dc > max_inflight_dc {
+                return None;
+            }
         }
+        packets.waiting.packets.remove(index)
+    }
+
+    /// Returns the estimated DC owed for queued packets.
+    pub async fn inflight_dc(&self) -> u64 {
+        self.packets.read().await.queued.dc()
+    }
+
+    /// Returns a previously popped waiting packet to the front of the waiting
+    /// list so it is the next one to be offered again.
+    pub async fn requeue_waiting_packet(&self, packet: QuePacket) -> Result {
+        self.packets.write().await.waiting.push_front(packet);
         Ok(())
     }
 
-    pub fn deque_packet(&mut self) -> Option<QuePacket> {
-        self.queued_packets.pop_front()
+    /// Returns whether an identical copy of the given packet is waiting or
+    /// queued. Packets with the same payload
+    /// but received differently, for example at another time, are not
+    /// copies.
+    pub async fn contains_packet(&self, packet: &Packet) -> bool {
+        let id = packet.id();
+        let is_copy = |queued: &QuePacket| queued.id() == &id && queued.packet() == packet;
+        let packets = self.packets.read().await;
+        packets
+            .waiting
+            .packets
+            .iter()
+            .chain(packets.queued.packets.iter())
+            .any(is_copy)
+    }
+
+    /// Removes the waiting and queued packets that are past their maximum
+    /// age, returning the removed packets.
+    pub async fn expire_packets(&self) -> Vec<QuePacket> {
+        let mut packets = self.packets.write().await;
+        let now = self.clock.now();
+        let mut expired = packets.queued.expire(now);
+        packets.record_expired(&expired);
+        expired.extend(packets.waiting.expire(now));
+        expired
+    }
+
+    /// Moves an offered packet to the queued packets where it waits for the
+    /// purchase or rejection of its offer. The queued expiry starts counting
+    /// from this point.
+    pub async fn que_packet(&self, mut packet: QuePacket) -> Result {
+        packet.offered = Some(self.clock.now());
+        packet.offers = packet.offers.saturating_add(1);
+        self.packets.write().await.queued.push_back(packet);
+        Ok(())
+    }
+
+    pub async fn deque_packet(&self) -> Option<QuePacket> {
+        self.packets.write().await.queued.pop_front()
+    }
+
+    /// Removes the queued packet the router purchased. Purchases that name
+    /// the purchased packet by its hash take that packet, so queued packets
+    /// dropped before their purchase do not shift which packet a purchase
+    /// delivers. Purchases without a hash take the oldest queued packet.
+    pub async fn deque_purchased_packet(&self, packet_hash: &[u8]) -> Option<QuePacket> {
+        if packet_hash.is_empty() {
+            return self.deque_packet().await;
+        }
+        let mut packets = self.packets.write().await;
+        let index = packets
+            .queued
+            .packets
+            .iter()
+            .position(|packet| packet.id().as_ref() == packet_hash)?;
+        packets.queued.packets.remove(index)
+    }
+
+    /// Returns the id and concentrator timestamp of the queued packet a
+    /// purchase with the given hash would take, if the hash names one.
+    pub async fn purchased_packet(&self, packet_hash: &[u8]) -> Option<(PacketId, u64)> {
+        if packet_hash.is_empty() {
+            return None;
+        }
+        self.packets
+            .read()
+            .await
+            .queued
+            .packets
+            .iter()
+            .find(|packet| packet.id().as_ref() == packet_hash)
+            .map(|packet| (packet.id().clone(), packet.packet().timestamp))
+    }
+
+    /// Puts a dequeued packet back at the front of the queued packets, for
+    /// example when its purchase could not be handled.
+    pub async fn requeue_queued_packet(&self, packet: QuePacket) {
+        self.packets.write().await.queued.push_front(packet);
+    }
+
+    /// Drops queued packets that were offered more than the maximum queued
+    /// age ago without being purchased or rejected, as compaction would.
+    /// Returns the dropped packets.
+    pub async fn expire_offers(&self) -> Vec<QuePacket> {
+        let mut packets = self.packets.write().await;
+        let expired = packets.queued.expire(self.clock.now());
+        packets.record_expired(&expired);
+        expired
+    }
+
+    /// Returns whether the queued packet with the given hash recently expired
+    /// before it was purchased. An empty hash, as sent by routers that do not
+    /// identify the purchased packet, never matches.
+    pub async fn is_expired_offer(&self, packet_hash: &[u8]) -> bool {
+        !packet_hash.is_empty()
+            && self
+                .packets
+                .read()
+                .await
+                .expired
+                .iter()
+                .any(|hash| hash == packet_hash)
+    }
+
+    /// Returns the time until the longest waiting queued packet exceeds the
+    /// maximum queued age.
+    pub async fn next_offer_expiry(&self) -> Option<Duration> {
+        let packets = self.packets.read().await;
+        let age = packets
+            .queued
+            .packets
+            .iter()
+            .filter_map(|packet| packet.offer_time_at(self.clock.now()))
+            .max()?;
+        Some(packets.queued.max_age.saturating_sub(age))
+    }
+
+    /// Moves all queued packets back to the front of the waiting packets, in
+    /// order, so they are offered again. Returns the number of moved packets.
+    pub async fn requeue_queued_packets(&self) -> usize {
+        let mut packets = self.packets.write().await;
+        let count = packets.queued.len();
+        while let Some(mut packet) = packets.queued.packets.pop_back() {
+            packet.offered = None;
+            packet.region = None;
+            packets.waiting.push_front(packet);
+        }
+        count
+    }
+
+    /// Returns the number of waiting and queued packets.
+    pub async fn packet_counts(&self) -> (usize, usize) {
+        let packets = self.packets.read().await;
+        (packets.waiting.len(), packets.queued.len())
     }
 
     pub async fn state_channel_count(&self) -> Result<usize> {
-        Ok(file_names(&self.path).await?.len())
+        let _disk = self.disk.read().await;
+        Ok(sc_ids(&self.path).await?.len())
     }
 
     pub async fn get_state_channel<S>(&self, sk: S) -> Result<Option<StateChannel>>
     where
         S: StateChannelKey,
     {
+        let _disk = self.disk.read().await;
         let sc_id = sk.id_key();
         let hashes = self.get_state_channel_hashes(&sc_id).await?;
         if hashes.is_empty() {
@@ -109,26 +677,356 @@ impl RouterStore {
         Ok(Some(StateChannel::try_from(&data[..])?))
     }
 
+    /// Returns the known state channels. State channels with conflicting
+    /// versions are left out since there is no single known version of them.
+    pub async fn state_channels(&self) -> Result<Vec<StateChannel>> {
+        let _disk = self.disk.read().await;
+        let mut scs = vec![];
+        for sc_id in sc_ids(&self.path).await? {
+            let hashes = self.get_state_channel_hashes(&sc_id).await?;
+            if hashes.len() != 1 {
+                continue;
+            }
+            let data = fs::read(self.path.join(&sc_id).join(&hashes[0])).await?;
+            scs.push(StateChannel::try_from(&data[..])?);
+        }
+        Ok(scs)
+    }
+
     pub async fn append_state_channel(&self, sc_id: &str, sc: &StateChannel) -> Result {
+        let _disk = self.disk.write().await;
+        self.bump_sc_generation();
+        self.make_room_for(sc_id).await?;
+        fs::create_dir_all(self.path.join(sc_id)).await?;
         let sc_hash = sc.hash_key();
         let known_hashes = self.get_state_channel_hashes(sc_id).await?;
         // Only add if we don't already have it to save writing multiple times
         if known_hashes.contains(&sc_hash) {
             return Ok(());
         }
+        let data = sc.to_vec()?;
+        self.make_disk_room_for(sc_id, data.len() as u64, 0).await?;
         let file_path = self.path.join(sc_id).join(sc_hash);
-        fs::write(file_path, sc.to_vec()?)
-            .await
-            .map_err(Error::from)
+        fs::write(file_path, &data).await?;
+        self.sc_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
     }
 
     pub async fn overwrite_state_channel(&self, sc_id: &str, sc: &StateChannel) -> Result {
+        let _disk = self.disk.write().await;
+        self.bump_sc_generation();
+        self.make_room_for(sc_id).await?;
+        let data = sc.to_vec()?;
+        let replaced = self.state_channel_bytes(sc_id).await?;
+        self.make_disk_room_for(sc_id, data.len() as u64, replaced)
+            .await?;
         let sc_path = self.path.join(sc_id);
         clean_dir(&sc_path).await?;
+        self.untrack_bytes(replaced);
         let sc_hash = sc.hash_key();
-        fs::write(&sc_path.join(sc_hash), sc.to_vec()?)
+        fs::write(&sc_path.join(sc_hash), &data).await?;
+        self.sc_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stores the given state channel as the known, and thus active, state
+    /// channel for its id, replacing any other known versions of it. This
+    /// skips all validation and is meant for setting up tests.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn insert_active_state_channel(&self, sc: &StateChannel) -> Result {
+        self.overwrite_state_channel(&sc.id_key(), sc).await
+    }
+
+    /// Removes the given state channel, including any conflicting versions
+    /// kept for it.
+    pub async fn remove_state_channel(&self, sc_id: &str) -> Result {
+        let _disk = self.disk.write().await;
+        self.bump_sc_generation();
+        self.remove_state_channel_dir(sc_id).await
+    }
+
+    /// Returns the ids of the state channels removed from the store since
+    /// the last call, in the order they were removed, whether they were
+    /// removed explicitly, compacted or evicted to make room.
+    pub fn take_removed_state_channels(&self) -> Vec<String> {
+        std::mem::take(&mut *self.removed_scs.lock().expect("removed scs lock"))
+    }
+
+    /// Removes the directory of the given state channel, recording its id as
+    /// removed if it was there. Every removal of a state channel goes
+    /// through here. Callers hold the disk write lock.
+    async fn remove_state_channel_dir(&self, sc_id: &str) -> Result {
+        let bytes = self.state_channel_bytes(sc_id).await?;
+        match fs::remove_dir_all(self.path.join(sc_id)).await {
+            Ok(()) => {
+                self.untrack_bytes(bytes);
+                self.removed_scs
+                    .lock()
+                    .expect("removed scs lock")
+                    .push(sc_id.to_string());
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Compacts the store by removing expired waiting and queued packets, each
+    /// according to its own maximum age, and state channels, including any
+    /// conflicting versions kept for them, that expired at or before the
+    /// given block height. Conflicting versions kept for rejected state
+    /// channels are removed one by one once they expire, even while another
+    /// version of the state channel is still live. A height of 0 means the
+    /// current height is not known and leaves state channels untouched.
+    ///
+    /// Orphaned entries are removed at any height: files next to the state
+    /// channel directories, other than the store version, and state channel
+    /// directories without any versions left in them.
+    ///
+    /// Compaction holds the disk write lock while it walks the state
+    /// channels so it never interleaves with other state channel updates.
+    /// Returns the number of removed entries.
+    pub async fn compact(&self, height: u64) -> Result<usize> {
+        self.compact_state_channels(height)
             .await
-            .map_err(Error::from)
+            .map(|(removed, _)| removed)
+    }
+
+    /// Compacts the store like `compact`, and returns the ids of the removed
+    /// state channels along with the number of removed entries.
+    pub async fn compact_state_channels(&self, height: u64) -> Result<(usize, Vec<String>)> {
+        let mut removed = {
+            let mut packets = self.packets.write().await;
+            let now = self.clock.now();
+            let expired = packets.queued.expire(now);
+            packets.record_expired(&expired);
+            packets.waiting.expire(now).len() + expired.len()
+        };
+        let _disk = self.disk.write().await;
+        removed += self.remove_orphans().await?;
+        let mut removed_scs = vec![];
+        if height == 0 {
+            return Ok((removed, removed_scs));
+        }
+        self.bump_sc_generation();
+        for sc_id in sc_ids(&self.path).await? {
+            let expired_hashes = self.expired_versions(&sc_id, height).await?;
+            if expired_hashes.len() == self.get_state_channel_hashes(&sc_id).await?.len() {
+                self.remove_state_channel_dir(&sc_id).await?;
+                removed += 1;
+                removed_scs.push(sc_id);
+                continue;
+            }
+            for sc_hash in expired_hashes {
+                let version = self.path.join(&sc_id).join(sc_hash);
+                let bytes = fs::metadata(&version).await?.len();
+                fs::remove_file(version).await?;
+                self.untrack_bytes(bytes);
+                removed += 1;
+            }
+        }
+        Ok((removed, removed_scs))
+    }
+
+    /// Removes files next to the state channel directories, other than the
+    /// store version file, and empty state channel directories. Returns the
+    /// number of removed entries. Callers hold the disk write lock.
+    async fn remove_orphans(&self) -> Result<usize> {
+        let mut removed = 0;
+        let mut entries = fs::read_dir(&self.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_name() == VERSION_FILE {
+                continue;
+            }
+            if !entry.file_type().await?.is_dir() {
+                let bytes = entry.metadata().await?.len();
+                fs::remove_file(entry.path()).await?;
+                self.untrack_bytes(bytes);
+                removed += 1;
+            } else if file_names(entry.path()).await?.is_empty() {
+                fs::remove_dir(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the number of state channels evicted to stay within the
+    /// maximum number of state channels.
+    pub fn evicted_state_channels(&self) -> u64 {
+        self.evicted_state_channels.load(Ordering::Relaxed)
+    }
+
+    /// Evicts the state channels expiring first until a state channel with
+    /// the given, not yet known, id fits within the maximum number of state
+    /// channels. State channels that can not be read are evicted first, the
+    /// protected state channel never is. Callers hold the disk write lock.
+    async fn make_room_for(&self, sc_id: &str) -> Result {
+        if self.max_state_channels == 0 {
+            return Ok(());
+        }
+        let mut sc_ids = sc_ids(&self.path).await?;
+        if sc_ids.iter().any(|known| known == sc_id) {
+            return Ok(());
+        }
+        let protected = self.protected_state_channel();
+        let kept = sc_ids.len();
+        let mut expiries = Vec::with_capacity(kept);
+        for known in sc_ids.drain(..) {
+            if Some(&known) == protected.as_ref() {
+                continue;
+            }
+            let expiry = self.expiry_at_block(&known).await?;
+            expiries.push((expiry, known));
+        }
+        expiries.sort();
+        let excess = (kept + 1).saturating_sub(self.max_state_channels);
+        for (_, evicted) in expiries.into_iter().take(excess) {
+            self.remove_state_channel_dir(&evicted).await?;
+            self.evicted_state_channels.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes the persisted state channels and the
+    /// quarantined data of this store take up on disk, which is what the
+    /// maximum disk usage limits. Packets are only kept in memory and do not
+    /// count towards it.
+    pub fn disk_usage(&self) -> u64 {
+        self.sc_bytes.load(Ordering::Relaxed) + self.quarantine_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Keeps the state channel with the given id, if any, from being
+    /// evicted, in place of the one kept before. The client protects the
+    /// state channel selected for offers.
+    pub fn protect_state_channel(&self, sc_id: Option<String>) {
+        *self.protected_sc.lock().expect("protected sc lock") = sc_id;
+    }
+
+    /// Takes the given number of bytes off the tracked state channel bytes.
+    /// Files written next to the store while it is open are not tracked, so
+    /// this saturates rather than wrapping when they are removed.
+    fn untrack_bytes(&self, bytes: u64) {
+        let _ = self
+            .sc_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
+
+    fn protected_state_channel(&self) -> Option<String> {
+        self.protected_sc.lock().expect("protected sc lock").clone()
+    }
+
+    /// Returns the number of state channels evicted to stay within the
+    /// maximum disk usage.
+    pub fn disk_evictions(&self) -> u64 {
+        self.disk_evictions.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes freed by evicting state channels to stay
+    /// within the maximum disk usage.
+    pub fn disk_evicted_bytes(&self) -> u64 {
+        self.disk_evicted_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Evicts data until writing the given number of bytes for the state
+    /// channel with the given id stays within the maximum disk usage. The
+    /// given number of replaced bytes, of versions the write replaces, do
+    /// not count. Quarantined data is evicted first. Next go state channels
+    /// with conflicting versions, which are kept around for rejected state
+    /// channels, then the state channels expiring first, so expired state
+    /// channels go before live ones. Neither the written nor the protected
+    /// state channel is evicted. When evicting all other data is not enough
+    /// the write still goes ahead. The store is only walked when eviction
+    /// is needed. Callers hold the disk write lock.
+    async fn make_disk_room_for(&self, sc_id: &str, bytes: u64, replaced: u64) -> Result {
+        if self.max_disk_usage == 0 {
+            return Ok(());
+        }
+        let mut used = (self.disk_usage() + bytes).saturating_sub(replaced);
+        if used <= self.max_disk_usage {
+            return Ok(());
+        }
+        let quarantined = self.quarantine_bytes.load(Ordering::Relaxed);
+        if quarantined > 0 {
+            match fs::remove_dir_all(&self.quarantine).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            }
+            self.quarantine_bytes.store(0, Ordering::Relaxed);
+            used -= quarantined;
+            self.disk_evictions.fetch_add(1, Ordering::Relaxed);
+            self.disk_evicted_bytes
+                .fetch_add(quarantined, Ordering::Relaxed);
+        }
+        let protected = self.protected_state_channel();
+        let mut candidates = vec![];
+        for known in sc_ids(&self.path).await? {
+            if used <= self.max_disk_usage {
+                break;
+            }
+            if known == sc_id || Some(&known) == protected.as_ref() {
+                continue;
+            }
+            let known_bytes = self.state_channel_bytes(&known).await?;
+            let conflicting = self.get_state_channel_hashes(&known).await?.len() > 1;
+            let expiry = self.expiry_at_block(&known).await?;
+            candidates.push((!conflicting, expiry, known, known_bytes));
+        }
+        candidates.sort();
+        for (_, _, evicted, evicted_bytes) in candidates {
+            if used <= self.max_disk_usage {
+                break;
+            }
+            self.remove_state_channel_dir(&evicted).await?;
+            used -= evicted_bytes;
+            self.disk_evictions.fetch_add(1, Ordering::Relaxed);
+            self.disk_evicted_bytes
+                .fetch_add(evicted_bytes, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Returns the bytes taken up on disk by the known versions of a state
+    /// channel.
+    async fn state_channel_bytes(&self, sc_id: &str) -> Result<u64> {
+        let mut bytes = 0;
+        for sc_hash in self.get_state_channel_hashes(sc_id).await? {
+            bytes += fs::metadata(self.path.join(sc_id).join(sc_hash))
+                .await?
+                .len();
+        }
+        Ok(bytes)
+    }
+
+    /// Returns the latest expiry of the known versions of a state channel, 0
+    /// if none of them can be read.
+    async fn expiry_at_block(&self, sc_id: &str) -> Result<u64> {
+        let mut expiry = 0;
+        for sc_hash in self.get_state_channel_hashes(sc_id).await? {
+            let data = fs::read(self.path.join(sc_id).join(sc_hash)).await?;
+            if let Ok(sc) = StateChannel::try_from(&data[..]) {
+                expiry = expiry.max(sc.expiry_at_block());
+            }
+        }
+        Ok(expiry)
+    }
+
+    /// Returns the hashes of the versions of a state channel that expired at
+    /// or before the given height or can not be read.
+    async fn expired_versions(&self, sc_id: &str, height: u64) -> Result<Vec<String>> {
+        let mut expired = vec![];
+        for sc_hash in self.get_state_channel_hashes(sc_id).await? {
+            let data = fs::read(self.path.join(sc_id).join(&sc_hash)).await?;
+            match StateChannel::try_from(&data[..]) {
+                Ok(sc) if sc.expiry_at_block() > height => (),
+                _ => expired.push(sc_hash),
+            }
+        }
+        Ok(expired)
     }
 
     async fn get_state_channel_hashes(&self, sc_id: &str) -> Result<Vec<String>> {
@@ -140,6 +1038,161 @@ impl RouterStore {
     }
 }
 
+/// The current version of the on disk store layout. State channels are kept
+/// in a directory per state channel id, with a file per known version of the
+/// state channel named by its hash.
+pub const STORE_VERSION: u32 = 1;
+
+/// The name of the file at the root of the store that holds the store version
+const VERSION_FILE: &str = "store_version";
+
+/// The directory next to the stores that corrupt store data is moved to
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// The directory next to the stores that reject logs and metrics are kept in
+const RECORDS_DIR: &str = "records";
+const REJECT_LOG_FILE: &str = "rejects.log";
+const METRICS_FILE: &str = "metrics.json";
+const PACKETS_FILE: &str = "packets";
+/// The size past which the reject log is moved aside
+const MAX_REJECT_LOG_BYTES: u64 = 1024 * 1024;
+
+/// Returns the milliseconds from the unix epoch to the given time, 0 for
+/// times before it.
+fn unix_millis(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Returns whether the store at the given path has a version file that can
+/// not be read as a version
+async fn has_corrupt_version(path: &Path) -> Result<bool> {
+    match fs::read_to_string(path.join(VERSION_FILE)).await {
+        Ok(version) => Ok(version.trim().parse::<u32>().is_err()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => Ok(true),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Moves the corrupt entries of the store at the given path to the given
+/// quarantine directory and returns where they were moved. Corrupt entries
+/// are files next to the state channel directories, state channel versions
+/// that do not decode and versions of a state channel with another id than
+/// the one of their directory, which lookups by id would otherwise miss or
+/// mistake for another state channel. State channel directories left empty
+/// are removed.
+async fn quarantine_corrupt(path: &Path, quarantine: &Path) -> Result<Vec<PathBuf>> {
+    let mut quarantined = vec![];
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let entry_path = entry.path();
+        if entry.file_name() == VERSION_FILE {
+            continue;
+        }
+        if !entry.file_type().await?.is_dir() {
+            quarantined.push(quarantine_entry(path, &entry_path, quarantine).await?);
+            continue;
+        }
+        let sc_id = entry.file_name();
+        let mut versions = fs::read_dir(&entry_path).await?;
+        let mut remaining = 0;
+        while let Some(version) = versions.next_entry().await? {
+            let is_valid = version.file_type().await?.is_file()
+                && StateChannel::try_from(&fs::read(version.path()).await?[..])
+                    .map_or(false, |sc| sc_id.to_str() == Some(&sc.id_key()));
+            if is_valid {
+                remaining += 1;
+            } else {
+                quarantined.push(quarantine_entry(path, &version.path(), quarantine).await?);
+            }
+        }
+        if remaining == 0 {
+            fs::remove_dir(&entry_path).await?;
+        }
+    }
+    Ok(quarantined)
+}
+
+/// Moves the given entry of the store at the given path to the same place in
+/// the given quarantine directory, replacing anything quarantined there
+/// before, and returns where it was moved.
+async fn quarantine_entry(path: &Path, entry: &Path, quarantine: &Path) -> Result<PathBuf> {
+    let target = match entry.strip_prefix(path) {
+        Ok(relative) if relative.as_os_str().is_empty() => quarantine.to_path_buf(),
+        Ok(relative) => quarantine.join(relative),
+        Err(_) => return Err(Error::custom("store entry outside of store")),
+    };
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    match fs::metadata(&target).await {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&target).await?,
+        Ok(_) => fs::remove_file(&target).await?,
+        Err(_) => (),
+    }
+    fs::rename(entry, &target).await?;
+    Ok(target)
+}
+
+/// Migrates the store at the given path to the current store version and
+/// returns where entries the migration did not carry over were quarantined.
+/// Stores without a version file were written before the store was
+/// versioned and are treated as version 0.
+async fn migrate(path: &Path, quarantine: &Path) -> Result<Vec<PathBuf>> {
+    let version = match fs::read_to_string(path.join(VERSION_FILE)).await {
+        Ok(version) => version
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| Error::custom(format!("invalid store version: {:?}", version)))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err.into()),
+    };
+    if version > STORE_VERSION {
+        return Err(Error::custom(format!(
+            "store version {} is newer than supported version {}",
+            version, STORE_VERSION
+        )));
+    }
+    let mut quarantined = vec![];
+    for from_version in version..STORE_VERSION {
+        match from_version {
+            0 => quarantined.extend(migrate_v0(path, quarantine).await?),
+            _ => {
+                return Err(Error::custom(format!(
+                    "no migration from store version {}",
+                    from_version
+                )))
+            }
+        }
+    }
+    if version < STORE_VERSION {
+        fs::write(path.join(VERSION_FILE), STORE_VERSION.to_string()).await?;
+    }
+    Ok(quarantined)
+}
+
+/// Unversioned stores use the same state channel layout as version 1 but may
+/// contain stray files next to the state channel directories, which are
+/// quarantined. Returns where they were moved.
+async fn migrate_v0(path: &Path, quarantine: &Path) -> Result<Vec<PathBuf>> {
+    let mut quarantined = vec![];
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            quarantined.push(quarantine_entry(path, &entry.path(), quarantine).await?);
+        }
+    }
+    Ok(quarantined)
+}
+
+/// Returns the ids of the state channels in the store at the given path
+async fn sc_ids(path: &Path) -> io::Result<Vec<String>> {
+    let mut names = file_names(path).await?;
+    names.retain(|name| name != VERSION_FILE);
+    Ok(names)
+}
+
 async fn clean_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
     fs::create_dir_all(&path).await?;
     let mut entries = fs::read_dir(path).await?;
@@ -154,6 +1207,29 @@ async fn clean_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
     Ok(())
 }
 
+/// Returns the bytes taken up by the file at the given path or the files
+/// below it, 0 if there is nothing at the path.
+async fn dir_bytes(path: &Path) -> io::Result<u64> {
+    let mut bytes = 0;
+    let mut paths = vec![path.to_path_buf()];
+    while let Some(path) = paths.pop() {
+        let metadata = match fs::symlink_metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        if !metadata.is_dir() {
+            bytes += metadata.len();
+            continue;
+        }
+        let mut entries = fs::read_dir(&path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            paths.push(entry.path());
+        }
+    }
+    Ok(bytes)
+}
+
 async fn file_names<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
     use futures::StreamExt;
     use tokio_stream::wrappers::ReadDirStream;
@@ -169,3 +1245,830 @@ async fn file_names<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
         .await;
     Ok(names)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, MockClock};
+    use helium_proto::BlockchainStateChannelV1;
+
+    fn store_dir() -> PathBuf {
+        std::env::temp_dir().join("gateway-rs-test")
+    }
+
+    async fn mk_store(name: &str) -> RouterStore {
+        let _ = fs::remove_dir_all(store_dir().join(name)).await;
+        open_store(name).await.expect("router store")
+    }
+
+    async fn open_store(name: &str) -> Result<RouterStore> {
+        RouterStore::new(name, &mk_settings()).await
+    }
+
+    fn mk_settings() -> CacheSettings {
+        CacheSettings {
+            store: store_dir(),
+            max_packets: 10,
+            max_packet_age: 5,
+            max_queued_age: 2,
+            compact_interval: 60,
+            max_state_channels: 0,
+            max_disk_usage: 0,
+            packet_encoding: PacketEncoding::Protobuf,
+        }
+    }
+
+    fn mk_packet(payload: u8) -> Packet {
+        Packet::from(helium_proto::Packet {
+            payload: vec![payload],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn packet_ttl() {
+        use helium_proto::{routing_information::Data as RoutingData, Eui, RoutingInformation};
+        let start = Instant::now();
+        let us915 = Region::from_i32(0).unwrap();
+        let eu868 = Region::from_i32(1).unwrap();
+        let uplink = QuePacket::new(mk_packet(1), start);
+        // The RX2 window of a data uplink closes two seconds in
+        assert_eq!(Duration::from_secs(2), uplink.ttl_at(start, &us915));
+        assert_eq!(Duration::from_secs(2), uplink.ttl_at(start, &eu868));
+        assert_eq!(
+            Duration::from_millis(1500),
+            uplink.ttl_at(start + Duration::from_millis(500), &us915)
+        );
+        assert_eq!(
+            Duration::from_secs(0),
+            uplink.ttl_at(start + Duration::from_secs(3), &us915)
+        );
+
+        // Join requests are answered in the later join accept windows
+        let join = QuePacket::new(
+            Packet::from(helium_proto::Packet {
+                routing: Some(RoutingInformation {
+                    data: Some(RoutingData::Eui(Eui::default())),
+                }),
+                ..Default::default()
+            }),
+            start,
+        );
+        assert_eq!(Duration::from_secs(6), join.ttl_at(start, &us915));
+        assert_eq!(
+            Duration::from_secs(3),
+            join.ttl_at(start + Duration::from_secs(3), &eu868)
+        );
+    }
+
+    #[tokio::test]
+    async fn save_and_load_packets() {
+        for encoding in [PacketEncoding::Protobuf, PacketEncoding::Compact].iter() {
+            let name = format!("save_and_load_packets_{:?}", encoding);
+            let _ = fs::remove_dir_all(store_dir().join(&name)).await;
+            let _ = fs::remove_dir_all(store_dir().join(RECORDS_DIR).join(&name)).await;
+            let settings = CacheSettings {
+                packet_encoding: *encoding,
+                ..mk_settings()
+            };
+            let clock = MockClock::default();
+            let store = RouterStore::new(&name, &settings)
+                .await
+                .unwrap()
+                .with_clock(Arc::new(clock.clone()));
+            assert_eq!(0, store.load_packets().await.unwrap());
+            store.store_waiting_packet(mk_packet(1)).await.unwrap();
+            clock.advance(Duration::from_secs(3));
+            store
+                .store_waiting_offered_packet(mk_packet(2), 2)
+                .await
+                .unwrap();
+            store.store_waiting_packet(mk_packet(3)).await.unwrap();
+            assert_eq!(3, store.save_packets().await.unwrap());
+
+            // The oldest packet expires while the store is closed
+            clock.advance(Duration::from_millis(2001));
+            let store = RouterStore::new(&name, &settings)
+                .await
+                .unwrap()
+                .with_clock(Arc::new(clock.clone()));
+            assert_eq!(2, store.load_packets().await.unwrap());
+            let packet = store.pop_waiting_packet().await.unwrap();
+            assert_eq!(&mk_packet(2), packet.packet());
+            assert_eq!(2, packet.offers());
+            assert_eq!(
+                Duration::from_millis(2001),
+                packet.hold_time_at(clock.now())
+            );
+            let packet = store.pop_waiting_packet().await.unwrap();
+            assert_eq!(&mk_packet(3), packet.packet());
+            assert_eq!(0, packet.offers());
+            assert!(store.pop_waiting_packet().await.is_none());
+            // Saved packets are restored once, and not next to themselves
+            store.store_waiting_packet(mk_packet(4)).await.unwrap();
+            assert_eq!(1, store.save_packets().await.unwrap());
+            assert_eq!(0, store.load_packets().await.unwrap());
+            assert_eq!((1, 0), store.packet_counts().await);
+            assert_eq!(0, store.load_packets().await.unwrap());
+
+            // Saved packets that do not decode are dropped
+            fs::write(store.records().join(PACKETS_FILE), b"\x01\x02")
+                .await
+                .unwrap();
+            assert!(store.load_packets().await.is_err());
+            assert_eq!(0, store.load_packets().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn requeue_preserves_order() {
+        let store = mk_store("requeue_preserves_order").await;
+        for payload in 1..=3 {
+            store
+                .store_waiting_packet(mk_packet(payload))
+                .await
+                .unwrap();
+        }
+        // The first offer succeeds and gets queued, the second one fails and
+        // goes back to waiting
+        let first = store.pop_waiting_packet().await.unwrap();
+        store.que_packet(first).await.unwrap();
+        let second = store.pop_waiting_packet().await.unwrap();
+        store.requeue_waiting_packet(second).await.unwrap();
+
+        let mut waiting = vec![];
+        while let Some(packet) = store.pop_waiting_packet().await {
+            waiting.push(packet.payload()[0]);
+        }
+        assert_eq!(vec![2, 3], waiting);
+        assert_eq!(1, store.deque_packet().await.unwrap().payload()[0]);
+    }
+
+    fn mk_state_channel(id: u8, expiry_at_block: u64) -> StateChannel {
+        let sc = BlockchainStateChannelV1 {
+            id: vec![id],
+            ..Default::default()
+        };
+        crate::test_support::mk_state_channel(&sc, expiry_at_block, 100)
+    }
+
+    #[tokio::test]
+    async fn evicts_state_channels_over_cap() {
+        let name = "evicts_state_channels_over_cap";
+        let _ = fs::remove_dir_all(store_dir().join(name)).await;
+        let settings = CacheSettings {
+            max_state_channels: 3,
+            ..mk_settings()
+        };
+        let store = RouterStore::new(name, &settings).await.unwrap();
+        // Channels expiring in a shuffled order
+        for (id, expiry_at_block) in [(1, 300), (2, 100), (3, 500), (4, 200), (5, 400)].iter() {
+            let sc = mk_state_channel(*id, *expiry_at_block);
+            store
+                .overwrite_state_channel(&sc.id_key(), &sc)
+                .await
+                .unwrap();
+            assert!(store.state_channel_count().await.unwrap() <= 3);
+        }
+        assert_eq!(2, store.evicted_state_channels());
+        assert_eq!(
+            vec![
+                mk_state_channel(2, 100).id_key(),
+                mk_state_channel(4, 200).id_key()
+            ],
+            store.take_removed_state_channels()
+        );
+        assert!(store.take_removed_state_channels().is_empty());
+        let mut kept: Vec<u8> = store
+            .state_channels()
+            .await
+            .unwrap()
+            .iter()
+            .map(|sc| sc.id()[0])
+            .collect();
+        kept.sort_unstable();
+        assert_eq!(vec![1, 3, 5], kept);
+
+        // Updating a known channel does not evict another one
+        let sc = mk_state_channel(1, 300);
+        store.append_state_channel(&sc.id_key(), &sc).await.unwrap();
+        assert_eq!(3, store.state_channel_count().await.unwrap());
+        assert_eq!(2, store.evicted_state_channels());
+    }
+
+    #[tokio::test]
+    async fn evicts_state_channels_over_disk_budget() {
+        let name = "evicts_state_channels_over_disk_budget";
+        let _ = fs::remove_dir_all(store_dir().join(name)).await;
+        let sc_bytes = mk_state_channel(1, 100).to_vec().unwrap().len() as u64;
+        let settings = CacheSettings {
+            max_disk_usage: 3 * sc_bytes,
+            ..mk_settings()
+        };
+        let store = RouterStore::new(name, &settings).await.unwrap();
+        // A rejected channel with a conflicting version
+        let rejected = mk_state_channel(1, 900);
+        store
+            .append_state_channel(&rejected.id_key(), &rejected)
+            .await
+            .unwrap();
+        let rejected = mk_state_channel(1, 950);
+        store
+            .append_state_channel(&rejected.id_key(), &rejected)
+            .await
+            .unwrap();
+        assert_eq!(2 * sc_bytes, store.disk_usage());
+
+        // Filling up evicts the conflicting channel before the one expiring
+        // first
+        for (id, expiry_at_block) in [(2, 300), (3, 100), (4, 500), (5, 200)].iter() {
+            let sc = mk_state_channel(*id, *expiry_at_block);
+            store
+                .overwrite_state_channel(&sc.id_key(), &sc)
+                .await
+                .unwrap();
+            assert!(store.disk_usage() <= 3 * sc_bytes);
+        }
+        assert_eq!(2, store.disk_evictions());
+        assert_eq!(3 * sc_bytes, store.disk_evicted_bytes());
+        assert_eq!(
+            vec![
+                mk_state_channel(1, 900).id_key(),
+                mk_state_channel(3, 100).id_key()
+            ],
+            store.take_removed_state_channels()
+        );
+        let mut kept: Vec<u8> = store
+            .state_channels()
+            .await
+            .unwrap()
+            .iter()
+            .map(|sc| sc.id()[0])
+            .collect();
+        kept.sort_unstable();
+        assert_eq!(vec![2, 4, 5], kept);
+
+        // Replacing a known channel frees its old version first
+        let sc = mk_state_channel(2, 300);
+        store.insert_active_state_channel(&sc).await.unwrap();
+        assert_eq!(2, store.disk_evictions());
+        assert_eq!(3 * sc_bytes, store.disk_usage());
+
+        // The tracked usage matches what a reopened store finds on disk
+        drop(store);
+        let store = RouterStore::new(name, &settings).await.unwrap();
+        assert_eq!(3 * sc_bytes, store.disk_usage());
+    }
+
+    #[tokio::test]
+    async fn keeps_protected_state_channel() {
+        let name = "keeps_protected_state_channel";
+        let _ = fs::remove_dir_all(store_dir().join(name)).await;
+        let sc_bytes = mk_state_channel(1, 100).to_vec().unwrap().len() as u64;
+        let settings = CacheSettings {
+            max_state_channels: 2,
+            max_disk_usage: 2 * sc_bytes,
+            ..mk_settings()
+        };
+        let store = RouterStore::new(name, &settings).await.unwrap();
+        let selected = mk_state_channel(1, 100);
+        store.insert_active_state_channel(&selected).await.unwrap();
+        store.protect_state_channel(Some(selected.id_key()));
+        let sc = mk_state_channel(2, 200);
+        store.insert_active_state_channel(&sc).await.unwrap();
+
+        // The selected channel expires first but the other one goes, both
+        // for the state channel and the disk budget
+        let sc = mk_state_channel(3, 300);
+        store.insert_active_state_channel(&sc).await.unwrap();
+        assert_eq!(1, store.evicted_state_channels());
+        assert_eq!(0, store.disk_evictions());
+        let rejected = mk_state_channel(3, 350);
+        store
+            .append_state_channel(&rejected.id_key(), &rejected)
+            .await
+            .unwrap();
+        assert_eq!(0, store.disk_evictions());
+        assert!(store.get_state_channel(vec![1]).await.unwrap().is_some());
+        assert!(store.get_state_channel(vec![2]).await.unwrap().is_none());
+
+        // Without protection the selected channel is evicted like any other
+        store.protect_state_channel(None);
+        let sc = mk_state_channel(4, 400);
+        store.insert_active_state_channel(&sc).await.unwrap();
+        assert!(store.get_state_channel(vec![1]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn evicts_quarantine_over_disk_budget() {
+        let name = "evicts_quarantine_over_disk_budget";
+        let quarantine = store_dir().join(QUARANTINE_DIR).join(name);
+        let _ = fs::remove_dir_all(store_dir().join(name)).await;
+        let _ = fs::remove_dir_all(&quarantine).await;
+        let sc_bytes = mk_state_channel(1, 100).to_vec().unwrap().len() as u64;
+        fs::create_dir_all(quarantine.join("garbled"))
+            .await
+            .unwrap();
+        fs::write(quarantine.join("garbled").join("version"), vec![0; 10])
+            .await
+            .unwrap();
+        fs::write(quarantine.join("stray"), vec![0; 5])
+            .await
+            .unwrap();
+        let settings = CacheSettings {
+            max_disk_usage: 2 * sc_bytes,
+            ..mk_settings()
+        };
+        let store = RouterStore::new(name, &settings).await.unwrap();
+        assert_eq!(15, store.disk_usage());
+        let sc = mk_state_channel(1, 100);
+        store.insert_active_state_channel(&sc).await.unwrap();
+        assert_eq!(sc_bytes + 15, store.disk_usage());
+
+        // The quarantine goes before any state channel
+        let sc = mk_state_channel(2, 200);
+        store.insert_active_state_channel(&sc).await.unwrap();
+        assert_eq!(1, store.disk_evictions());
+        assert_eq!(15, store.disk_evicted_bytes());
+        assert_eq!(2 * sc_bytes, store.disk_usage());
+        assert_eq!(2, store.state_channel_count().await.unwrap());
+        assert!(fs::metadata(&quarantine).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_log_moves_aside() {
+        let store = mk_store("reject_log_moves_aside").await;
+        let records = store.records().to_path_buf();
+        let _ = fs::remove_dir_all(&records).await;
+        let log_path = records.join(REJECT_LOG_FILE);
+        store.append_reject_log("first").await.unwrap();
+        store.append_reject_log("second").await.unwrap();
+        assert_eq!(
+            "first\nsecond\n",
+            fs::read_to_string(&log_path).await.unwrap()
+        );
+
+        // A full log is moved aside before the next line
+        fs::write(&log_path, vec![b'x'; MAX_REJECT_LOG_BYTES as usize])
+            .await
+            .unwrap();
+        store.append_reject_log("fresh").await.unwrap();
+        assert_eq!("fresh\n", fs::read_to_string(&log_path).await.unwrap());
+        let moved = fs::metadata(log_path.with_extension("log.old"))
+            .await
+            .unwrap();
+        assert_eq!(MAX_REJECT_LOG_BYTES, moved.len());
+    }
+
+    #[tokio::test]
+    async fn compact_removes_expired() {
+        let store = mk_store("compact_removes_expired").await;
+        for (id, expiry_at_block) in [(1, 100), (2, 200)].iter() {
+            let sc = mk_state_channel(*id, *expiry_at_block);
+            store
+                .overwrite_state_channel(&sc.id_key(), &sc)
+                .await
+                .unwrap();
+        }
+        store
+            .que_packet(QuePacket::from(mk_packet(1)))
+            .await
+            .unwrap();
+        store.packets.write().await.queued.packets[0].offered =
+            Some(Instant::now() - Duration::from_secs(10));
+        store.store_waiting_packet(mk_packet(2)).await.unwrap();
+
+        assert_eq!(2, store.compact(150).await.unwrap());
+        assert!(store.get_state_channel(vec![1]).await.unwrap().is_none());
+        assert!(store.get_state_channel(vec![2]).await.unwrap().is_some());
+        assert!(store.deque_packet().await.is_none());
+        assert!(store.pop_waiting_packet().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn compact_removes_rejects_and_orphans() {
+        let store = mk_store("compact_removes_rejects_and_orphans").await;
+        // A rejected channel whose conflicting version expires first
+        for expiry_at_block in [100, 200].iter() {
+            let sc = mk_state_channel(1, *expiry_at_block);
+            store.append_state_channel(&sc.id_key(), &sc).await.unwrap();
+        }
+        let active = mk_state_channel(2, 200);
+        store.insert_active_state_channel(&active).await.unwrap();
+        fs::write(store.path.join("stray"), b"stray").await.unwrap();
+        fs::create_dir_all(store.path.join("empty")).await.unwrap();
+
+        // Orphans go at any height
+        assert_eq!(2, store.compact(0).await.unwrap());
+        assert_eq!(2, store.state_channel_count().await.unwrap());
+        assert!(store.get_state_channel(vec![1]).await.is_err());
+
+        // Only the expired conflicting version goes
+        assert_eq!(1, store.compact(150).await.unwrap());
+        let kept = store.get_state_channel(vec![1]).await.unwrap().unwrap();
+        assert_eq!(200, kept.expiry_at_block());
+        assert!(store.get_state_channel(vec![2]).await.unwrap().is_some());
+        assert!(fs::metadata(store.path.join(VERSION_FILE)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn packet_lifecycle() {
+        let store = mk_store("packet_lifecycle").await;
+        store.store_waiting_packet(mk_packet(1)).await.unwrap();
+        assert_eq!((1, 0), store.packet_counts().await);
+        let packet = store.pop_waiting_packet().await.unwrap();
+        assert!(packet.offer_time_at(Instant::now()).is_none());
+        store.que_packet(packet).await.unwrap();
+        assert_eq!((0, 1), store.packet_counts().await);
+        let packet = store.deque_packet().await.unwrap();
+        assert_eq!(1, packet.payload()[0]);
+        assert!(packet.offer_time_at(Instant::now()).is_some());
+        assert_eq!((0, 0), store.packet_counts().await);
+    }
+
+    #[tokio::test]
+    async fn inflight_dc_cap() {
+        let store = mk_store("inflight_dc_cap").await;
+        for payload in 1..=4 {
+            store
+                .store_waiting_packet(mk_packet(payload))
+                .await
+                .unwrap();
+        }
+        // Each packet costs 1 DC, so only 3 can be in flight
+        while let Some(packet) = store.pop_offerable_packet(3).await {
+            store.que_packet(packet).await.unwrap();
+        }
+        assert_eq!(3, store.inflight_dc().await);
+        assert_eq!((1, 3), store.packet_counts().await);
+        // A purchase reduces the exposure and offers can resume
+        store.deque_packet().await.unwrap();
+        let packet = store.pop_offerable_packet(3).await.unwrap();
+        assert_eq!(4, packet.payload()[0]);
+        // Without a cap everything is offerable
+        store.requeue_waiting_packet(packet).await.unwrap();
+        store
+            .que_packet(QuePacket::from(mk_packet(5)))
+            .await
+            .unwrap();
+        assert!(store.pop_offerable_packet(0).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn requeue_queued() {
+        let store = mk_store("requeue_queued").await;
+        let region = Region::from_i32(0).unwrap();
+        for payload in 1..=3 {
+            store
+                .store_waiting_packet(mk_packet(payload))
+                .await
+                .unwrap();
+        }
+        for _ in 0..2 {
+            let packet = store.pop_waiting_packet().await.unwrap();
+            store
+                .que_packet(packet.with_region(region.clone()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(2, store.requeue_queued_packets().await);
+        let mut waiting = vec![];
+        while let Some(packet) = store.pop_waiting_packet().await {
+            assert!(packet.region().is_none());
+            waiting.push(packet.payload()[0]);
+        }
+        assert_eq!(vec![1, 2, 3], waiting);
+    }
+
+    #[tokio::test]
+    async fn offer_timeout() {
+        let clock = MockClock::default();
+        let store = mk_store("offer_timeout")
+            .await
+            .with_clock(Arc::new(clock.clone()));
+        store
+            .que_packet(QuePacket::from(mk_packet(1)))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_millis(1200));
+        store
+            .que_packet(QuePacket::from(mk_packet(2)))
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(Duration::from_millis(800)),
+            store.next_offer_expiry().await
+        );
+        clock.advance(Duration::from_millis(1000));
+        // Only the packet offered more than the maximum queued age ago is
+        // dropped
+        let expired = store.expire_offers().await;
+        assert_eq!(1, expired.len());
+        assert_eq!(1, expired[0].payload()[0]);
+        assert!(store.is_expired_offer(&mk_packet(1).hash()).await);
+        assert!(!store.is_expired_offer(&mk_packet(2).hash()).await);
+        assert!(!store.is_expired_offer(&[]).await);
+        assert_eq!(
+            Some(Duration::from_millis(1000)),
+            store.next_offer_expiry().await
+        );
+        assert_eq!(2, store.deque_packet().await.unwrap().payload()[0]);
+        assert!(store.next_offer_expiry().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn deque_purchased_packet() {
+        let store = mk_store("deque_purchased_packet").await;
+        for payload in 1..=3 {
+            store
+                .que_packet(QuePacket::from(mk_packet(payload)))
+                .await
+                .unwrap();
+        }
+        let purchased = store.deque_purchased_packet(&mk_packet(2).hash()).await;
+        assert_eq!(2, purchased.unwrap().payload()[0]);
+        assert!(store
+            .deque_purchased_packet(&mk_packet(2).hash())
+            .await
+            .is_none());
+        // Without a hash the oldest queued packet is purchased
+        assert_eq!(
+            1,
+            store.deque_purchased_packet(&[]).await.unwrap().payload()[0]
+        );
+        assert_eq!((0, 1), store.packet_counts().await);
+    }
+
+    #[tokio::test]
+    async fn separate_expiry() {
+        let store = mk_store("separate_expiry").await;
+        let received = Instant::now() - Duration::from_secs(3);
+        for payload in 1..=2 {
+            store
+                .store_waiting_packet(mk_packet(payload))
+                .await
+                .unwrap();
+        }
+        let offered = store.pop_waiting_packet().await.unwrap();
+        store.que_packet(offered).await.unwrap();
+        {
+            let mut packets = store.packets.write().await;
+            packets.waiting.packets[0].received = received;
+            packets.queued.packets[0].received = received;
+        }
+        // Both packets were received past the queued maximum age, but only
+        // the time since the offer counts for the queued packet
+        assert_eq!(0, store.compact(0).await.unwrap());
+        store.packets.write().await.queued.packets[0].offered = Some(received);
+        assert_eq!(1, store.compact(0).await.unwrap());
+        assert_eq!((1, 0), store.packet_counts().await);
+    }
+
+    #[tokio::test]
+    async fn mock_clock_expiry() {
+        let clock = MockClock::default();
+        let store = mk_store("mock_clock_expiry")
+            .await
+            .with_clock(Arc::new(clock.clone()));
+        store.store_waiting_packet(mk_packet(1)).await.unwrap();
+        let packet = store.pop_waiting_packet().await.unwrap();
+        store.que_packet(packet).await.unwrap();
+        store.store_waiting_packet(mk_packet(2)).await.unwrap();
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(0, store.compact(0).await.unwrap());
+        clock.advance(Duration::from_secs(1));
+        // Past the queued maximum age but not the waiting one
+        assert_eq!(1, store.compact(0).await.unwrap());
+        assert_eq!((1, 0), store.packet_counts().await);
+        let packet = store.pop_waiting_packet().await.unwrap();
+        assert_eq!(Duration::from_secs(3), packet.hold_time_at(clock.now()));
+        assert_eq!(clock.now() - Duration::from_secs(3), packet.received());
+        store.requeue_waiting_packet(packet).await.unwrap();
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(1, store.compact(0).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn migrate_unversioned_store() {
+        let name = "migrate_unversioned_store";
+        let path = store_dir().join(name);
+        let quarantine = store_dir().join(QUARANTINE_DIR).join(name);
+        let _ = fs::remove_dir_all(&path).await;
+        let _ = fs::remove_dir_all(&quarantine).await;
+        // Lay out an unversioned store by hand, with a stray file in it
+        let sc = mk_state_channel(1, 100);
+        let sc_path = path.join(sc.id_key());
+        fs::create_dir_all(&sc_path).await.unwrap();
+        fs::write(sc_path.join(sc.hash_key()), sc.to_vec().unwrap())
+            .await
+            .unwrap();
+        fs::write(path.join("stray"), b"stray").await.unwrap();
+
+        let store = open_store(name).await.unwrap();
+        assert_eq!(
+            STORE_VERSION.to_string(),
+            fs::read_to_string(path.join(VERSION_FILE)).await.unwrap()
+        );
+        assert_eq!(1, store.state_channel_count().await.unwrap());
+        let migrated = store.get_state_channel(vec![1]).await.unwrap().unwrap();
+        assert_eq!(sc.hash_key(), migrated.hash_key());
+        assert_eq!(100, migrated.expiry_at_block());
+        // The stray file is moved aside rather than deleted
+        assert!(!path.join("stray").exists());
+        assert_eq!(vec![quarantine.join("stray")], store.quarantined());
+        assert_eq!(
+            b"stray".to_vec(),
+            fs::read(quarantine.join("stray")).await.unwrap()
+        );
+        // Reopening a current store leaves it untouched
+        drop(store);
+        let store = open_store(name).await.unwrap();
+        assert_eq!(1, store.state_channel_count().await.unwrap());
+        assert!(store.quarantined().is_empty());
+    }
+
+    #[tokio::test]
+    async fn quarantine_corrupt_state_channels() {
+        let name = "quarantine_corrupt_state_channels";
+        let path = store_dir().join(name);
+        let quarantine = store_dir().join(QUARANTINE_DIR).join(name);
+        let _ = fs::remove_dir_all(&path).await;
+        let _ = fs::remove_dir_all(&quarantine).await;
+        let store = open_store(name).await.unwrap();
+        assert!(store.quarantined().is_empty());
+        let sc = mk_state_channel(1, 100);
+        store
+            .overwrite_state_channel(&sc.id_key(), &sc)
+            .await
+            .unwrap();
+        drop(store);
+        // A truncated second version of the state channel, a state channel
+        // with only a garbled version and a stray file
+        let sc_path = path.join(sc.id_key());
+        fs::write(sc_path.join("truncated"), &sc.to_vec().unwrap()[..4])
+            .await
+            .unwrap();
+        fs::create_dir_all(path.join("garbled")).await.unwrap();
+        fs::write(path.join("garbled").join("version"), b"\xff\xff\xff")
+            .await
+            .unwrap();
+        fs::write(path.join("stray"), b"stray").await.unwrap();
+
+        let store = open_store(name).await.unwrap();
+        let mut quarantined = store.quarantined().to_vec();
+        quarantined.sort();
+        let mut expected = vec![
+            quarantine.join(sc.id_key()).join("truncated"),
+            quarantine.join("garbled").join("version"),
+            quarantine.join("stray"),
+        ];
+        expected.sort();
+        assert_eq!(expected, quarantined);
+        assert!(expected.iter().all(|path| path.exists()));
+        // The valid state channel version is recovered
+        assert_eq!(1, store.state_channel_count().await.unwrap());
+        let recovered = store.get_state_channel(vec![1]).await.unwrap().unwrap();
+        assert_eq!(sc.hash_key(), recovered.hash_key());
+        assert_eq!(1, store.state_channels().await.unwrap().len());
+        // Reopening the recovered store finds nothing else to quarantine
+        drop(store);
+        assert!(open_store(name).await.unwrap().quarantined().is_empty());
+    }
+
+    #[tokio::test]
+    async fn quarantine_mismatched_state_channels() {
+        let name = "quarantine_mismatched_state_channels";
+        let path = store_dir().join(name);
+        let quarantine = store_dir().join(QUARANTINE_DIR).join(name);
+        let _ = fs::remove_dir_all(&path).await;
+        let _ = fs::remove_dir_all(&quarantine).await;
+        let store = open_store(name).await.unwrap();
+        let sc = mk_state_channel(1, 100);
+        store
+            .overwrite_state_channel(&sc.id_key(), &sc)
+            .await
+            .unwrap();
+        drop(store);
+        // A state channel stored under the id of another one, once next to
+        // a version of that state channel and once on its own
+        let other = mk_state_channel(2, 200);
+        let misplaced = |dir: &str| path.join(dir).join(other.hash_key());
+        fs::create_dir_all(path.join("alias")).await.unwrap();
+        for misplaced in [misplaced(&sc.id_key()), misplaced("alias")].iter() {
+            fs::write(misplaced, other.to_vec().unwrap()).await.unwrap();
+        }
+
+        let store = open_store(name).await.unwrap();
+        let mut quarantined = store.quarantined().to_vec();
+        quarantined.sort();
+        let mut expected = vec![
+            quarantine.join(sc.id_key()).join(other.hash_key()),
+            quarantine.join("alias").join(other.hash_key()),
+        ];
+        expected.sort();
+        assert_eq!(expected, quarantined);
+        assert!(!path.join("alias").exists());
+        // Only the consistent version is left, and the misplaced state
+        // channel is not found by either id
+        let known = store.state_channels().await.unwrap();
+        assert_eq!(1, known.len());
+        assert_eq!(sc.hash_key(), known[0].hash_key());
+        assert!(store.get_state_channel(vec![2]).await.unwrap().is_none());
+        drop(store);
+        assert!(open_store(name).await.unwrap().quarantined().is_empty());
+    }
+
+    #[tokio::test]
+    async fn quarantine_corrupt_version() {
+        let name = "quarantine_corrupt_version";
+        let path = store_dir().join(name);
+        let quarantine = store_dir().join(QUARANTINE_DIR).join(name);
+        let _ = fs::remove_dir_all(&path).await;
+        let store = open_store(name).await.unwrap();
+        let sc = mk_state_channel(1, 100);
+        store
+            .overwrite_state_channel(&sc.id_key(), &sc)
+            .await
+            .unwrap();
+        drop(store);
+        fs::write(path.join(VERSION_FILE), b"\0\0").await.unwrap();
+
+        // The whole store is quarantined and starts out fresh
+        let store = open_store(name).await.unwrap();
+        assert_eq!(vec![quarantine.clone()], store.quarantined());
+        assert!(quarantine.join(sc.id_key()).exists());
+        assert_eq!(0, store.state_channel_count().await.unwrap());
+        assert_eq!(
+            STORE_VERSION.to_string(),
+            fs::read_to_string(path.join(VERSION_FILE)).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn refuse_downgrade() {
+        let name = "refuse_downgrade";
+        let path = store_dir().join(name);
+        let _ = fs::remove_dir_all(&path).await;
+        fs::create_dir_all(&path).await.unwrap();
+        fs::write(path.join(VERSION_FILE), (STORE_VERSION + 1).to_string())
+            .await
+            .unwrap();
+        assert!(open_store(name).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn concurrent_access() {
+        let store = mk_store("concurrent_access").await;
+        let sc = mk_state_channel(1, 100);
+        store
+            .overwrite_state_channel(&sc.id_key(), &sc)
+            .await
+            .unwrap();
+        let readers: Vec<_> = (0..10)
+            .map(|_| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        assert_eq!(1, store.state_channel_count().await.unwrap());
+                        assert!(store.get_state_channel(vec![1]).await.unwrap().is_some());
+                    }
+                })
+            })
+            .collect();
+        let writer = {
+            let store = store.clone();
+            tokio::spawn(async move {
+                for payload in 0..10 {
+                    store
+                        .store_waiting_packet(mk_packet(payload))
+                        .await
+                        .unwrap();
+                    store
+                        .overwrite_state_channel(&sc.id_key(), &sc)
+                        .await
+                        .unwrap();
+                }
+            })
+        };
+        for reader in readers {
+            reader.await.unwrap();
+        }
+        writer.await.unwrap();
+        let mut waiting = 0;
+        while store.pop_waiting_packet().await.is_some() {
+            waiting += 1;
+        }
+        assert_eq!(10, waiting);
+
+        // Packets are handled while a state channel write holds the disk
+        let _disk = store.disk.write().await;
+        tokio::time::timeout(Duration::from_secs(1), async {
+            store.store_waiting_packet(mk_packet(1)).await.unwrap();
+            assert_eq!((1, 0), store.packet_counts().await);
+            assert!(store.pop_waiting_packet().await.is_some());
+        })
+        .await
+        .expect("packets handled without the disk lock");
+    }
+}
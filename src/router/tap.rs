@@ -0,0 +1,354 @@
+use crate::{clock, Error, Keypair, Packet, Result, SharedClock};
+use helium_crypto::{PublicKey, Sign, Verify};
+use helium_proto::{BlockchainStateChannelMessageV1, Message};
+use std::{
+    collections::VecDeque,
+    ops::Range,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A raw message seen by a router client, as recorded by a `MessageTap`
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum TapMessage {
+    /// An uplink handed to the client
+    #[prost(message, tag = "1")]
+    Uplink(helium_proto::Packet),
+    /// A message received from the router
+    #[prost(message, tag = "2")]
+    Received(BlockchainStateChannelMessageV1),
+    /// An offer or packet sent to the router
+    #[prost(message, tag = "3")]
+    Sent(BlockchainStateChannelMessageV1),
+    /// A downlink handed to the gateway
+    #[prost(message, tag = "4")]
+    Downlink(helium_proto::Packet),
+}
+
+/// The serialized form of a single tapped message
+#[derive(Clone, PartialEq, prost::Message)]
+struct TapEntry {
+    #[prost(oneof = "TapMessage", tags = "1, 2, 3, 4")]
+    message: Option<TapMessage>,
+    /// When the message was recorded, in milliseconds since the unix epoch
+    #[prost(uint64, tag = "5")]
+    recorded: u64,
+}
+
+/// Tapped messages signed with the keypair of a gateway, a portable record
+/// of the message history that can be handed to others, for example to
+/// settle a dispute with a router. The signature covers the exported time
+/// range along with the messages and the times they were recorded at.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SignedExport {
+    /// The public key of the signing keypair
+    #[prost(bytes, tag = "1")]
+    pub public_key: Vec<u8>,
+    /// The exported messages in the form `MessageTap::export` returns
+    #[prost(bytes, tag = "2")]
+    pub messages: Vec<u8>,
+    /// The signature over the exported time range and messages
+    #[prost(bytes, tag = "3")]
+    pub signature: Vec<u8>,
+    /// The start of the exported time range, in milliseconds since the unix
+    /// epoch
+    #[prost(uint64, tag = "4")]
+    pub start: u64,
+    /// The end of the exported time range, in milliseconds since the unix
+    /// epoch
+    #[prost(uint64, tag = "5")]
+    pub end: u64,
+}
+
+impl TapMessage {
+    /// Returns whether the client produced this message, as opposed to
+    /// handling it
+    pub fn is_output(&self) -> bool {
+        matches!(self, Self::Sent(_) | Self::Downlink(_))
+    }
+}
+
+/// A bounded log of the raw messages going in and out of a router client,
+/// kept in the order they were seen so that the log can be replayed against
+/// a fresh client. A capacity of zero disables the tap.
+#[derive(Debug)]
+pub struct MessageTap {
+    capacity: usize,
+    clock: SharedClock,
+    messages: VecDeque<(SystemTime, TapMessage)>,
+}
+
+impl MessageTap {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            clock: clock::system(),
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Use the given clock for the time messages are recorded at.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn record_uplink(&mut self, packet: &Packet) {
+        self.record(|| TapMessage::Uplink(packet.clone().to_packet()))
+    }
+
+    pub fn record_received(&mut self, message: &BlockchainStateChannelMessageV1) {
+        self.record(|| TapMessage::Received(message.clone()))
+    }
+
+    pub fn record_sent(&mut self, message: &BlockchainStateChannelMessageV1) {
+        self.record(|| TapMessage::Sent(message.clone()))
+    }
+
+    pub fn record_downlink(&mut self, packet: &Packet) {
+        self.record(|| TapMessage::Downlink(packet.clone().to_packet()))
+    }
+
+    fn record<F: FnOnce() -> TapMessage>(&mut self, message: F) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.messages
+            .push_back((self.clock.system_now(), message()));
+        if self.messages.len() > self.capacity {
+            self.messages.pop_front();
+        }
+    }
+
+    /// Returns the tapped messages, oldest first.
+    pub fn messages(&self) -> Vec<TapMessage> {
+        self.messages
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    /// Serializes the tapped messages, oldest first, as a sequence of length
+    /// delimited protobuf entries.
+    pub fn export(&self) -> Vec<u8> {
+        encode_entries(self.messages.iter())
+    }
+
+    /// Serializes the tapped messages recorded within the given time range,
+    /// like `export`.
+    pub fn export_between(&self, range: &Range<SystemTime>) -> Vec<u8> {
+        encode_entries(
+            self.messages
+                .iter()
+                .filter(|(recorded, _)| range.contains(recorded)),
+        )
+    }
+
+    /// Decodes messages serialized by `export`.
+    pub fn decode(buf: &[u8]) -> Result<Vec<TapMessage>> {
+        Ok(Self::decode_recorded(buf)?
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect())
+    }
+
+    /// Decodes messages serialized by `export` along with when each was
+    /// recorded.
+    pub fn decode_recorded(mut buf: &[u8]) -> Result<Vec<(SystemTime, TapMessage)>> {
+        let mut messages = vec![];
+        while !buf.is_empty() {
+            let entry = TapEntry::decode_length_delimited(&mut buf)?;
+            if let Some(message) = entry.message {
+                messages.push((from_millis(entry.recorded), message));
+            }
+        }
+        Ok(messages)
+    }
+}
+
+impl SignedExport {
+    /// Signs the given messages, exported for the given time range, with the
+    /// given keypair.
+    pub fn sign(keypair: &Keypair, range: &Range<SystemTime>, messages: Vec<u8>) -> Result<Self> {
+        let mut export = Self {
+            public_key: keypair.public_key().to_vec(),
+            messages,
+            signature: vec![],
+            start: to_millis(range.start),
+            end: to_millis(range.end),
+        };
+        export.signature = keypair.sign(&export.signed_bytes())?;
+        Ok(export)
+    }
+
+    /// Returns the time range the messages were exported for.
+    pub fn range(&self) -> Range<SystemTime> {
+        from_millis(self.start)..from_millis(self.end)
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.encode(&mut buf).expect("encoded signed export");
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        Ok(Self::decode(buf)?)
+    }
+
+    /// Checks that the export was signed by the given public key and has
+    /// not been altered since, and returns the exported messages with when
+    /// each was recorded, to the millisecond. Messages recorded outside the
+    /// exported time range fail the check.
+    pub fn verify(&self, public_key: &PublicKey) -> Result<Vec<(SystemTime, TapMessage)>> {
+        if self.public_key != public_key.to_vec() {
+            return Err(Error::custom("export signed by another key"));
+        }
+        public_key.verify(&self.signed_bytes(), &self.signature)?;
+        let messages = MessageTap::decode_recorded(&self.messages)?;
+        // Times are compared to the millisecond they are recorded with, at
+        // which a message recorded just before the end shares its millisecond
+        let outside = |recorded: &SystemTime| {
+            let recorded = to_millis(*recorded);
+            recorded < self.start || recorded > self.end
+        };
+        if messages.iter().any(|(recorded, _)| outside(recorded)) {
+            return Err(Error::custom("export message outside its time range"));
+        }
+        Ok(messages)
+    }
+
+    /// The time range and messages the signature is made over
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16 + self.messages.len());
+        buf.extend_from_slice(&self.start.to_be_bytes());
+        buf.extend_from_slice(&self.end.to_be_bytes());
+        buf.extend_from_slice(&self.messages);
+        buf
+    }
+}
+
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        .as_millis() as u64
+}
+
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+fn encode_entries<'a, I>(entries: I) -> Vec<u8>
+where
+    I: Iterator<Item = &'a (SystemTime, TapMessage)>,
+{
+    let mut buf = vec![];
+    for (recorded, message) in entries {
+        TapEntry {
+            message: Some(message.clone()),
+            recorded: to_millis(*recorded),
+        }
+        .encode_length_delimited(&mut buf)
+        .expect("encoded tap entry");
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_packet(timestamp: u64) -> Packet {
+        Packet::from(helium_proto::Packet {
+            timestamp,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut tap = MessageTap::new(10);
+        tap.record_uplink(&mk_packet(1));
+        tap.record_received(&BlockchainStateChannelMessageV1::default());
+        tap.record_downlink(&mk_packet(2));
+        let messages = MessageTap::decode(&tap.export()).unwrap();
+        assert_eq!(tap.messages(), messages);
+        let outputs: Vec<bool> = messages.iter().map(TapMessage::is_output).collect();
+        assert_eq!(vec![false, false, true], outputs);
+        assert!(MessageTap::decode(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn tap_prunes_past_capacity() {
+        let mut tap = MessageTap::new(2);
+        for timestamp in 1..=3 {
+            tap.record_uplink(&mk_packet(timestamp));
+        }
+        let timestamps: Vec<u64> = tap
+            .messages()
+            .iter()
+            .map(|message| match message {
+                TapMessage::Uplink(packet) => packet.timestamp,
+                other => panic!("unexpected message {:?}", other),
+            })
+            .collect();
+        assert_eq!(vec![2, 3], timestamps);
+    }
+
+    #[test]
+    fn signed_export() {
+        use crate::test_support::mk_keypair;
+        use crate::{Clock, MockClock};
+        use std::sync::Arc;
+        let keypair = mk_keypair();
+        let clock = MockClock::default();
+        let mut tap = MessageTap::new(10).with_clock(Arc::new(clock.clone()));
+        let mut times = vec![];
+        for timestamp in 1..=3 {
+            times.push(clock.system_now());
+            tap.record_uplink(&mk_packet(timestamp));
+            clock.advance(Duration::from_secs(1));
+        }
+        // Only the second uplink was recorded within the range
+        let export_range = times[1]..times[2];
+        let export =
+            SignedExport::sign(&keypair, &export_range, tap.export_between(&export_range)).unwrap();
+        let export = SignedExport::from_bytes(&export.to_vec()).unwrap();
+        // Times are kept to the millisecond
+        let millis = |time| from_millis(to_millis(time));
+        assert_eq!(millis(times[1])..millis(times[2]), export.range());
+        assert_eq!(
+            vec![(
+                millis(times[1]),
+                TapMessage::Uplink(mk_packet(2).to_packet())
+            )],
+            export.verify(keypair.public_key()).unwrap()
+        );
+
+        let mut tampered = export.clone();
+        tampered.messages[0] ^= 1;
+        assert!(tampered.verify(keypair.public_key()).is_err());
+        let mut tampered = export.clone();
+        tampered.signature[0] ^= 1;
+        assert!(tampered.verify(keypair.public_key()).is_err());
+        // The range is covered by the signature
+        let mut tampered = export.clone();
+        tampered.end += 1000;
+        assert!(tampered.verify(keypair.public_key()).is_err());
+        assert!(export.verify(mk_keypair().public_key()).is_err());
+        // Messages recorded outside of the signed range fail the check
+        let widened = SignedExport::sign(&keypair, &export_range, tap.export()).unwrap();
+        assert!(widened.verify(keypair.public_key()).is_err());
+    }
+
+    #[test]
+    fn tap_disabled() {
+        let mut tap = MessageTap::new(0);
+        tap.record_uplink(&mk_packet(1));
+        assert!(tap.messages().is_empty());
+        assert!(tap.export().is_empty());
+    }
+}
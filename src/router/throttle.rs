@@ -0,0 +1,131 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// The time uplinks of each DevAddr were last offered, limiting offers for a
+/// single device to one per fixed interval. An interval of zero disables the
+/// limit.
+#[derive(Debug)]
+pub struct OfferThrottle {
+    interval: Duration,
+    offered: HashMap<u32, Instant>,
+}
+
+impl OfferThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            offered: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.interval > Duration::from_secs(0)
+    }
+
+    /// Returns whether an uplink of the given DevAddr may be offered at the
+    /// given time, that is whether no uplink of it was offered within the
+    /// interval before. Always true when the limit is disabled.
+    pub fn allows(&self, dev_addr: u32, now: Instant) -> bool {
+        match self.offered.get(&dev_addr) {
+            Some(offered) => now.saturating_duration_since(*offered) >= self.interval,
+            None => true,
+        }
+    }
+
+    /// Records that an uplink of the given DevAddr was offered at the given
+    /// time.
+    pub fn record(&mut self, dev_addr: u32, now: Instant) {
+        if self.is_enabled() {
+            self.offered.insert(dev_addr, now);
+        }
+    }
+
+    /// Forgets offers that are a full interval old at the given time, which
+    /// no longer hold back any uplinks.
+    pub fn prune(&mut self, now: Instant) {
+        let interval = self.interval;
+        self.offered
+            .retain(|_, offered| now.saturating_duration_since(*offered) < interval);
+    }
+
+    pub fn len(&self) -> usize {
+        self.offered.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offered.is_empty()
+    }
+}
+
+/// Offers the first of every configured number of uplinks, leaving out the
+/// rest. A sampling of 0 or 1 offers every uplink.
+#[derive(Debug)]
+pub struct UplinkSampling {
+    every: u64,
+    seen: u64,
+}
+
+impl UplinkSampling {
+    pub fn new(every: u32) -> Self {
+        Self {
+            every: every as u64,
+            seen: 0,
+        }
+    }
+
+    /// Returns whether the next uplink is offered.
+    pub fn sample(&mut self) -> bool {
+        let sampled = self.every <= 1 || self.seen % self.every == 0;
+        self.seen = self.seen.wrapping_add(1);
+        sampled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled() {
+        let mut throttle = OfferThrottle::new(Duration::from_secs(0));
+        let now = Instant::now();
+        assert!(throttle.allows(1, now));
+        throttle.record(1, now);
+        assert!(throttle.allows(1, now));
+        assert!(throttle.is_empty());
+    }
+
+    #[test]
+    fn one_offer_per_interval() {
+        let mut throttle = OfferThrottle::new(Duration::from_secs(10));
+        let start = Instant::now();
+        // Uplinks that are let through but not offered do not count
+        assert!(throttle.allows(1, start));
+        assert!(throttle.allows(1, start));
+        throttle.record(1, start);
+        assert!(!throttle.allows(1, start + Duration::from_secs(9)));
+        // Other devices are not affected
+        assert!(throttle.allows(2, start + Duration::from_secs(9)));
+        throttle.record(2, start + Duration::from_secs(9));
+        assert!(throttle.allows(1, start + Duration::from_secs(10)));
+        // Pruning forgets offers a full interval old
+        throttle.prune(start + Duration::from_secs(10));
+        assert_eq!(1, throttle.len());
+        assert!(!throttle.allows(2, start + Duration::from_secs(18)));
+        throttle.prune(start + Duration::from_secs(19));
+        assert!(throttle.is_empty());
+    }
+
+    #[test]
+    fn sampling() {
+        for every in [0, 1].iter() {
+            let mut sampling = UplinkSampling::new(*every);
+            assert!((0..5).all(|_| sampling.sample()));
+        }
+        let mut sampling = UplinkSampling::new(3);
+        let sampled: Vec<bool> = (0..7).map(|_| sampling.sample()).collect();
+        assert_eq!(vec![true, false, false, true, false, false, true], sampled);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{clock, router::DropReason, Packet, PacketId, SharedClock};
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+/// The maximum number of packet traces kept, the oldest trace is dropped to
+/// make room for a new one
+const MAX_TRACES: usize = 100;
+
+/// Selects the packets whose handling is traced
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceTarget {
+    /// All packets of the given DevAddr
+    DevAddr(u32),
+    /// The packet with the given id
+    Packet(PacketId),
+}
+
+/// A check an uplink has to pass before it goes to the router
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceCheck {
+    Crc,
+    /// The uplink is not a duplicate of a packet the client still holds
+    Dedup,
+    Region,
+    Snr,
+    /// The DevAddr rules, recent joins and offer interval of the device
+    Filter,
+    Sampling,
+}
+
+/// A decision made in the handling of a traced packet
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceStep {
+    /// The uplink was handed to the client
+    Received,
+    /// The uplink passed the given check
+    Passed(TraceCheck),
+    /// The packet was held to be offered later
+    Waiting,
+    /// The packet was offered to the router
+    Offered,
+    /// The router purchased the packet against the given state channel
+    Purchased { sc_id: String },
+    /// The router rejected the offer for the packet
+    Rejected,
+    /// The packet was sent to the router
+    Sent,
+    /// The packet was dropped for the given reason
+    Dropped(DropReason),
+}
+
+/// A traced decision with the time it was made
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub at: SystemTime,
+    pub step: TraceStep,
+}
+
+impl fmt::Display for TraceEntry {
+    /// Formats the step with the time it was made at, in milliseconds since
+    /// the unix epoch
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = self
+            .at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+        write!(f, "{:?}@{}", self.step, at.as_millis())
+    }
+}
+
+/// An opt in record of every decision made for selected packets, from
+/// receiving the uplink to sending or dropping it, for debugging the
+/// handling of a single device or packet in the field. Nothing is recorded
+/// until a target is traced.
+#[derive(Debug)]
+pub struct DecisionTrace {
+    targets: Vec<TraceTarget>,
+    clock: SharedClock,
+    traces: VecDeque<(PacketId, Vec<TraceEntry>)>,
+    /// The traced packets sent or dropped since the completed traces were
+    /// last taken
+    completed: Vec<PacketId>,
+}
+
+impl Default for DecisionTrace {
+    fn default() -> Self {
+        Self {
+            targets: vec![],
+            clock: clock::system(),
+            traces: VecDeque::new(),
+            completed: vec![],
+        }
+    }
+}
+
+impl DecisionTrace {
+    /// Creates a trace of the packets of the given DevAddrs, like those
+    /// configured in the settings.
+    pub fn for_devaddrs(dev_addrs: &[u32]) -> Self {
+        let mut trace = Self::default();
+        for dev_addr in dev_addrs {
+            trace.trace(TraceTarget::DevAddr(*dev_addr));
+        }
+        trace
+    }
+
+    /// Use the given clock for the time decisions are recorded at.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Starts tracing the packets matching the given target.
+    pub fn trace(&mut self, target: TraceTarget) {
+        if !self.targets.contains(&target) {
+            self.targets.push(target);
+        }
+    }
+
+    pub fn is_traced(&self, packet: &Packet) -> bool {
+        self.targets.iter().any(|target| match target {
+            TraceTarget::DevAddr(dev_addr) => packet.dev_addr() == Some(*dev_addr),
+            TraceTarget::Packet(id) => &packet.id() == id,
+        })
+    }
+
+    /// Records a decision for the given packet if it is traced.
+    pub fn record(&mut self, packet: &Packet, step: TraceStep) {
+        if !self.is_traced(packet) {
+            return;
+        }
+        let entry = TraceEntry {
+            at: self.clock.system_now(),
+            step,
+        };
+        let id = packet.id();
+        if matches!(entry.step, TraceStep::Sent | TraceStep::Dropped(_))
+            && !self.completed.contains(&id)
+        {
+            self.completed.push(id.clone());
+        }
+        match self.traces.iter_mut().find(|(traced, _)| traced == &id) {
+            Some((_, entries)) => entries.push(entry),
+            None => {
+                if self.traces.len() == MAX_TRACES {
+                    self.traces.pop_front();
+                }
+                self.traces.push_back((id, vec![entry]));
+            }
+        }
+    }
+
+    /// Returns the decisions recorded for the packet with the given id,
+    /// oldest first.
+    pub fn trace_of(&self, id: &PacketId) -> Option<&[TraceEntry]> {
+        self.traces
+            .iter()
+            .find(|(traced, _)| traced == id)
+            .map(|(_, entries)| entries.as_slice())
+    }
+
+    /// Returns the traces of the packets that were sent or dropped since the
+    /// last call, in the order their handling ended.
+    pub fn take_completed(&mut self) -> Vec<(PacketId, Vec<TraceEntry>)> {
+        let completed = std::mem::take(&mut self.completed);
+        completed
+            .into_iter()
+            .filter_map(|id| {
+                let entries = self.trace_of(&id)?.to_vec();
+                Some((id, entries))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, MockClock};
+    use std::{sync::Arc, time::Duration};
+
+    fn mk_packet(payload: u8, dev_addr: u32) -> Packet {
+        use helium_proto::{routing_information::Data as RoutingData, RoutingInformation};
+        Packet::from(helium_proto::Packet {
+            payload: vec![payload],
+            routing: Some(RoutingInformation {
+                data: Some(RoutingData::Devaddr(dev_addr)),
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn traces_targets() {
+        let clock = MockClock::default();
+        let mut trace = DecisionTrace::default().with_clock(Arc::new(clock.clone()));
+        let (first, second, other) = (mk_packet(1, 1), mk_packet(2, 1), mk_packet(3, 2));
+        // Nothing is traced without a target
+        trace.record(&first, TraceStep::Received);
+        assert!(trace.trace_of(&first.id()).is_none());
+
+        trace.trace(TraceTarget::DevAddr(1));
+        let start = clock.system_now();
+        trace.record(&first, TraceStep::Received);
+        clock.advance(Duration::from_millis(10));
+        trace.record(&first, TraceStep::Sent);
+        trace.record(&second, TraceStep::Received);
+        trace.record(&other, TraceStep::Received);
+        assert_eq!(
+            vec![
+                TraceEntry {
+                    at: start,
+                    step: TraceStep::Received
+                },
+                TraceEntry {
+                    at: start + Duration::from_millis(10),
+                    step: TraceStep::Sent
+                },
+            ],
+            trace.trace_of(&first.id()).unwrap()
+        );
+        assert_eq!(1, trace.trace_of(&second.id()).unwrap().len());
+        assert!(trace.trace_of(&other.id()).is_none());
+
+        trace.trace(TraceTarget::Packet(other.id()));
+        trace.record(&other, TraceStep::Received);
+        assert_eq!(1, trace.trace_of(&other.id()).unwrap().len());
+
+        // Only the sent packet completed its handling, once
+        let completed = trace.take_completed();
+        assert_eq!(1, completed.len());
+        assert_eq!(first.id(), completed[0].0);
+        assert_eq!(2, completed[0].1.len());
+        assert!(trace.take_completed().is_empty());
+        trace.record(&second, TraceStep::Dropped(DropReason::Expired));
+        assert_eq!(second.id(), trace.take_completed()[0].0);
+    }
+
+    #[test]
+    fn traces_devaddrs() {
+        let trace = DecisionTrace::for_devaddrs(&[1, 3, 1]);
+        assert_eq!(2, trace.targets.len());
+        assert!(trace.is_traced(&mk_packet(1, 1)));
+        assert!(!trace.is_traced(&mk_packet(2, 2)));
+        assert!(trace.is_traced(&mk_packet(3, 3)));
+        assert!(!DecisionTrace::for_devaddrs(&[]).is_traced(&mk_packet(1, 1)));
+    }
+
+    #[test]
+    fn keeps_latest_traces() {
+        let mut trace = DecisionTrace::default();
+        trace.trace(TraceTarget::DevAddr(1));
+        for payload in 0..=MAX_TRACES {
+            trace.record(&mk_packet(payload as u8, 1), TraceStep::Received);
+        }
+        assert!(trace.trace_of(&mk_packet(0, 1).id()).is_none());
+        assert!(trace.trace_of(&mk_packet(1, 1).id()).is_some());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{
+    router::{MetricsSnapshot, RouterStore},
+    Result,
+};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::{
+    mpsc::{self, error::SendError},
+    oneshot, Semaphore,
+};
+
+/// A store write that message handling does not need to wait for, unlike
+/// the writes of state channels, which have to be durable before the client
+/// acts on them
+#[derive(Debug)]
+pub enum BackgroundWrite {
+    /// Append a line to the reject log
+    RejectLog(String),
+    /// Replace the persisted metrics
+    Metrics(MetricsSnapshot),
+}
+
+#[derive(Debug)]
+enum Job {
+    Write(BackgroundWrite),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Performs non-critical store writes on a background task so that message
+/// handling is not held up by storage latency.
+///
+/// Writes are queued up to the given capacity and performed in the order
+/// they were made. When the queue is full a write waits for room rather than
+/// being performed inline ahead of the queued writes. With a capacity of
+/// zero all writes are performed inline. Clones share the same queue and
+/// background task, which ends once all clones are dropped.
+#[derive(Clone)]
+pub struct BackgroundWriter {
+    store: RouterStore,
+    queue: Option<mpsc::Sender<Job>>,
+    failures: Arc<AtomicU64>,
+}
+
+impl BackgroundWriter {
+    pub fn new(store: RouterStore, capacity: usize) -> Self {
+        Self::spawn(store, capacity, None)
+    }
+
+    /// Creates a writer whose background task waits for a permit of the
+    /// given semaphore before each write, which holds up background writes
+    /// in tests.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn with_gate(store: RouterStore, capacity: usize, gate: Arc<Semaphore>) -> Self {
+        Self::spawn(store, capacity, Some(gate))
+    }
+
+    fn spawn(store: RouterStore, capacity: usize, gate: Option<Arc<Semaphore>>) -> Self {
+        let failures = Arc::new(AtomicU64::new(0));
+        if capacity == 0 {
+            return Self {
+                store,
+                queue: None,
+                failures,
+            };
+        }
+        let (queue, mut jobs) = mpsc::channel(capacity);
+        let task_store = store.clone();
+        let task_failures = failures.clone();
+        tokio::spawn(async move {
+            while let Some(job) = jobs.recv().await {
+                match job {
+                    Job::Write(write) => {
+                        if let Some(gate) = &gate {
+                            if let Ok(permit) = gate.acquire().await {
+                                permit.forget();
+                            }
+                        }
+                        if apply(&task_store, write).await.is_err() {
+                            task_failures.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    Job::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Self {
+            store,
+            queue: Some(queue),
+            failures,
+        }
+    }
+
+    /// Queues the given write for the background task, or performs it right
+    /// away without a queue or once the background task is gone. Only inline
+    /// writes report errors, the failures of background writes are counted
+    /// instead.
+    pub async fn write(&self, write: BackgroundWrite) -> Result {
+        let write = match &self.queue {
+            Some(queue) => match queue.send(Job::Write(write)).await {
+                Ok(()) => return Ok(()),
+                Err(SendError(Job::Write(write))) => write,
+                Err(_) => return Ok(()),
+            },
+            None => write,
+        };
+        apply(&self.store, write).await
+    }
+
+    /// Waits for the writes queued so far to be performed.
+    pub async fn flush(&self) {
+        if let Some(queue) = &self.queue {
+            let (done, flushed) = oneshot::channel();
+            if queue.send(Job::Flush(done)).await.is_ok() {
+                let _ = flushed.await;
+            }
+        }
+    }
+
+    /// Returns the number of background writes that failed.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+async fn apply(store: &RouterStore, write: BackgroundWrite) -> Result {
+    match write {
+        BackgroundWrite::RejectLog(line) => store.append_reject_log(&line).await,
+        BackgroundWrite::Metrics(metrics) => {
+            store.write_metrics(&serde_json::to_vec(&metrics)?).await
+        }
+    }
+}
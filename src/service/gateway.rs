@@ -51,6 +51,7 @@ impl Response {
 pub struct GatewayService {
     pub uri: KeyedUri,
     client: ServiceClient,
+    height: u64,
 }
 
 impl GatewayService {
@@ -61,6 +62,7 @@ impl GatewayService {
         Ok(Self {
             uri: keyed_uri,
             client: ServiceClient::new(channel),
+            height: 0,
         })
     }
 
@@ -79,17 +81,36 @@ impl GatewayService {
         })
     }
 
+    /// Returns the highest block height reported by this gateway service so
+    /// far, or 0 if no response carrying a height has been received yet.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Records a block height known from elsewhere, such as a gateway this
+    /// one replaces, keeping the highest height seen so far.
+    pub fn record_height(&mut self, height: u64) {
+        self.height = self.height.max(height);
+    }
+
+    /// Sets the block height as if it was reported by this gateway service.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn with_height(mut self, height: u64) -> Self {
+        self.height = height;
+        self
+    }
+
     pub async fn is_active(&mut self, id: &[u8], owner: &[u8]) -> Result<GatewayScIsActiveRespV1> {
-        match self
+        let resp = self
             .client
             .is_active_sc(GatewayScIsActiveReqV1 {
                 sc_owner: owner.into(),
                 sc_id: id.into(),
             })
             .await?
-            .into_inner()
-            .msg
-        {
+            .into_inner();
+        self.height = self.height.max(resp.height);
+        match resp.msg {
             Some(gateway_resp_v1::Msg::IsActiveResp(resp)) => {
                 let GatewayScIsActiveRespV1 {
                     sc_id, sc_owner, ..
@@ -1,11 +1,12 @@
-use crate::{service::CONNECT_TIMEOUT, KeyedUri, Result};
+use crate::{service::CONNECT_TIMEOUT, settings::TlsSettings, Error, KeyedUri, Result};
 use helium_proto::{
     services::{self, Channel, Endpoint},
     BlockchainStateChannelMessageV1,
 };
-use std::time::Duration;
-use tokio::sync::mpsc;
+use std::{fs, time::Duration};
+use tokio::{sync::mpsc, time};
 use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
 
 type RouterClient = services::router::RouterClient<Channel>;
 type StateChannelClient = services::router::StateChannelClient<Channel>;
@@ -13,6 +14,7 @@ type StateChannelClient = services::router::StateChannelClient<Channel>;
 #[derive(Debug)]
 pub struct Service {
     pub uri: KeyedUri,
+    endpoint: Endpoint,
     router_client: RouterClient,
     state_channel_client: StateChannelClient,
 }
@@ -60,6 +62,16 @@ impl StateChannelService {
         Ok(())
     }
 
+    /// Drops the current conduit, if any. The next send or connect sets up a
+    /// fresh one.
+    pub fn disconnect(&mut self) {
+        self.conduit = None;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.conduit.is_some()
+    }
+
     pub async fn mk_conduit(
         &mut self,
     ) -> Result<(
@@ -77,18 +89,46 @@ impl StateChannelService {
 }
 
 impl Service {
-    pub fn new(keyed_uri: KeyedUri) -> Result<Self> {
-        let router_channel = Endpoint::from(keyed_uri.uri.clone())
-            .timeout(Duration::from_secs(CONNECT_TIMEOUT))
-            .connect_lazy()?;
+    /// Creates a service for the given router. A non zero keepalive interval
+    /// sends HTTP/2 pings on the connection, including while it is idle, and
+    /// fails its streams when a ping is not answered within the interval.
+    pub fn new(keyed_uri: KeyedUri, tls: &TlsSettings, keepalive: Duration) -> Result<Self> {
+        let mut endpoint =
+            Endpoint::from(keyed_uri.uri.clone()).timeout(Duration::from_secs(CONNECT_TIMEOUT));
+        if keepalive > Duration::from_secs(0) {
+            endpoint = endpoint
+                .http2_keep_alive_interval(keepalive)
+                .keep_alive_timeout(keepalive)
+                .keep_alive_while_idle(true);
+        }
+        if let Some(tls_config) = mk_tls_config(&keyed_uri, tls)? {
+            endpoint = endpoint.tls_config(tls_config)?;
+        }
+        let router_channel = endpoint.connect_lazy()?;
         let state_channel = router_channel.clone();
         Ok(Self {
             uri: keyed_uri,
+            endpoint,
             router_client: RouterClient::new(router_channel),
             state_channel_client: StateChannelClient::new(state_channel),
         })
     }
 
+    /// Connects to the router right away, rather than on first use like the
+    /// service does, to check that it is reachable. The probe connection is
+    /// dropped again.
+    pub async fn probe(&self) -> Result {
+        match time::timeout(
+            Duration::from_secs(CONNECT_TIMEOUT),
+            self.endpoint.connect(),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(()),
+            _ => Err(Error::router_unreachable(&self.uri.uri)),
+        }
+    }
+
     pub async fn route(
         &mut self,
         msg: BlockchainStateChannelMessageV1,
@@ -103,3 +143,259 @@ impl Service {
         })
     }
 }
+
+/// Builds the TLS configuration for the given router. Returns `None` when no
+/// TLS options are set, leaving the connection as given by the router uri.
+fn mk_tls_config(keyed_uri: &KeyedUri, tls: &TlsSettings) -> Result<Option<ClientTlsConfig>> {
+    if !tls.is_enabled() {
+        return Ok(None);
+    }
+    if keyed_uri.uri.scheme_str() != Some("https") {
+        return Err(Error::custom(format!(
+            "tls configured for plaintext router uri {}",
+            keyed_uri.uri
+        )));
+    }
+    let read_pem = |path: &std::path::PathBuf| {
+        fs::read(path)
+            .map_err(|err| Error::custom(format!("unable to read {}: {}", path.display(), err)))
+    };
+    let mut config = ClientTlsConfig::new();
+    if let Some(ca) = &tls.ca {
+        config = config.ca_certificate(Certificate::from_pem(read_pem(ca)?));
+    }
+    match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => {
+            config = config.identity(Identity::from_pem(read_pem(cert)?, read_pem(key)?));
+        }
+        (None, None) => (),
+        _ => {
+            return Err(Error::custom(
+                "tls client certificate and key must be set together",
+            ))
+        }
+    }
+    if let Some(domain) = &tls.domain {
+        config = config.domain_name(domain);
+    }
+    Ok(Some(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::mk_keyed_uri;
+
+    #[tokio::test]
+    async fn probe() {
+        use crate::{error::ServiceError, router::mock::MockRouter};
+        let tls = TlsSettings::default();
+        let router = MockRouter::start(vec![]).await;
+        let reachable =
+            Service::new(mk_keyed_uri(&router.uri), &tls, Duration::from_secs(0)).unwrap();
+        reachable.probe().await.unwrap();
+
+        let unreachable = Service::new(
+            mk_keyed_uri("http://127.0.0.1:1"),
+            &tls,
+            Duration::from_secs(0),
+        )
+        .unwrap();
+        match unreachable.probe().await {
+            Err(Error::Service(ServiceError::Unreachable(uri))) => {
+                assert!(uri.starts_with("http://127.0.0.1:1"))
+            }
+            other => panic!("unexpected probe result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_handshake() {
+        use crate::router::mock::{MockRouter, Step};
+        use std::path::{Path, PathBuf};
+        // Test certificates for "localhost" issued by a test CA
+        let fixture = |name: &str| -> PathBuf {
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/tls")
+                .join(name)
+        };
+        let read = |name: &str| fs::read(fixture(name)).expect("tls fixture");
+        let mut router = MockRouter::start_tls(
+            vec![vec![Step::Receive]],
+            Identity::from_pem(read("router.pem"), read("router.key")),
+            Some(Certificate::from_pem(read("ca.pem"))),
+        )
+        .await;
+        let mtls = TlsSettings {
+            ca: Some(fixture("ca.pem")),
+            cert: Some(fixture("client.pem")),
+            key: Some(fixture("client.key")),
+            domain: Some("localhost".to_string()),
+        };
+        let connect = |uri: &str, tls: &TlsSettings| {
+            Service::new(mk_keyed_uri(uri), tls, Duration::from_secs(0))
+                .and_then(|mut service| service.state_channel())
+        };
+
+        // Mutual TLS connects and delivers messages
+        let mut state_channel = connect(&router.uri, &mtls).unwrap();
+        state_channel.connect().await.unwrap();
+        state_channel
+            .send(BlockchainStateChannelMessageV1::default())
+            .await
+            .unwrap();
+        let received = time::timeout(Duration::from_secs(5), router.received.recv()).await;
+        assert!(matches!(received, Ok(Some(_))));
+
+        // A client without a certificate is refused by the router
+        let server_only = TlsSettings {
+            cert: None,
+            key: None,
+            ..mtls.clone()
+        };
+        let mut state_channel = connect(&router.uri, &server_only).unwrap();
+        assert!(state_channel.connect().await.is_err());
+
+        // As is a plaintext connection to the TLS router
+        let plaintext = router.uri.replace("https://", "http://");
+        let mut state_channel = connect(&plaintext, &TlsSettings::default()).unwrap();
+        assert!(state_channel.connect().await.is_err());
+        assert_eq!(1, router.connections());
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        use crate::router::mock::MockRouter;
+        use std::sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        };
+        use tokio::net::{TcpListener, TcpStream};
+
+        /// Copies bytes from one socket to the other until either closes,
+        /// dropping them instead once the blackhole is set
+        async fn pipe(
+            from: Arc<TcpStream>,
+            to: Arc<TcpStream>,
+            blackhole: Arc<AtomicBool>,
+            forwarded: Arc<AtomicUsize>,
+        ) -> std::io::Result<()> {
+            let mut buf = [0u8; 4096];
+            loop {
+                from.readable().await?;
+                let read = match from.try_read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(read) => read,
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    Err(err) => return Err(err),
+                };
+                if blackhole.load(Ordering::SeqCst) {
+                    continue;
+                }
+                forwarded.fetch_add(read, Ordering::SeqCst);
+                let mut written = 0;
+                while written < read {
+                    to.writable().await?;
+                    match to.try_write(&buf[written..read]) {
+                        Ok(count) => written += count,
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+        }
+
+        // A proxy in front of the router that can silently stop forwarding
+        // while keeping the connection open, like a NAT that timed it out
+        let router = MockRouter::start(vec![]).await;
+        let target = router.uri.trim_start_matches("http://").to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_uri = format!("http://{}", listener.local_addr().unwrap());
+        let blackhole = Arc::new(AtomicBool::new(false));
+        let forwarded = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let blackhole = blackhole.clone();
+            let forwarded = forwarded.clone();
+            async move {
+                while let Ok((client, _)) = listener.accept().await {
+                    let client = Arc::new(client);
+                    let router = Arc::new(TcpStream::connect(&target).await.unwrap());
+                    for (from, to) in vec![(client.clone(), router.clone()), (router, client)] {
+                        tokio::spawn(pipe(from, to, blackhole.clone(), forwarded.clone()));
+                    }
+                }
+            }
+        });
+
+        let mut state_channel = Service::new(
+            mk_keyed_uri(&proxy_uri),
+            &TlsSettings::default(),
+            Duration::from_secs(1),
+        )
+        .and_then(|mut service| service.state_channel())
+        .unwrap();
+        state_channel.connect().await.unwrap();
+
+        // An idle but live connection is kept open by the keepalive pings
+        let connected = forwarded.load(Ordering::SeqCst);
+        let idle = time::timeout(Duration::from_secs(3), state_channel.message()).await;
+        assert!(idle.is_err());
+        assert!(forwarded.load(Ordering::SeqCst) > connected);
+
+        // A connection that died silently fails the stream once a ping goes
+        // unanswered
+        blackhole.store(true, Ordering::SeqCst);
+        let dead = time::timeout(Duration::from_secs(10), state_channel.message()).await;
+        assert!(matches!(dead, Ok(Err(_))));
+    }
+
+    #[test]
+    fn default_tls() {
+        let tls = TlsSettings::default();
+        assert!(mk_tls_config(&mk_keyed_uri("http://127.0.0.1:8080"), &tls)
+            .unwrap()
+            .is_none());
+        assert!(mk_tls_config(&mk_keyed_uri("https://127.0.0.1:8080"), &tls)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn tls_for_plaintext_uri() {
+        let tls = TlsSettings {
+            domain: Some("router.example.com".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            mk_tls_config(&mk_keyed_uri("http://127.0.0.1:8080"), &tls),
+            Err(Error::Custom(msg)) if msg.contains("plaintext")
+        ));
+        let uri = mk_keyed_uri("https://127.0.0.1:8080");
+        assert!(mk_tls_config(&uri, &tls).unwrap().is_some());
+    }
+
+    #[test]
+    fn tls_incomplete_identity() {
+        let dir = std::env::temp_dir().join("gateway-rs-test");
+        fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("tls_incomplete_identity.pem");
+        fs::write(&cert, b"").unwrap();
+        let tls = TlsSettings {
+            cert: Some(cert),
+            ..Default::default()
+        };
+        let uri = mk_keyed_uri("https://127.0.0.1:8080");
+        assert!(matches!(
+            mk_tls_config(&uri, &tls),
+            Err(Error::Custom(msg)) if msg.contains("certificate and key")
+        ));
+        let tls = TlsSettings {
+            ca: Some(dir.join("tls_missing_ca.pem")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            mk_tls_config(&uri, &tls),
+            Err(Error::Custom(msg)) if msg.contains("unable to read")
+        ));
+    }
+}
@@ -1,5 +1,5 @@
-use crate::{keypair, region, releases, KeyedUri, Keypair, Region, Result};
-use config::{Config, Environment, File};
+use crate::{keypair, region, releases, KeyedUri, Keypair, OuiKeypairs, PublicKey, Region, Result};
+use config::{Config, Environment, File, FileFormat};
 use http::uri::Uri;
 use serde::Deserialize;
 use std::{
@@ -26,6 +26,10 @@ pub struct Settings {
     /// one is generated and saved in that location.
     #[serde(deserialize_with = "keypair::deserialize")]
     pub keypair: Arc<Keypair>,
+    /// Keypairs to sign router messages for specific OUIs with, by OUI. OUIs
+    /// without a keypair here use the gateway keypair (default: [])
+    #[serde(deserialize_with = "keypair::deserialize_oui_keypairs")]
+    pub oui_keypairs: HashMap<u32, Arc<Keypair>>,
     /// The lorawan region to use. This value should line up with the configured
     /// region of the semtech packet forwarder. Defaults to "US91%"
     #[serde(deserialize_with = "region::deserialize")]
@@ -42,6 +46,10 @@ pub struct Settings {
     pub gateways: Vec<KeyedUri>,
     /// Cache settings
     pub cache: CacheSettings,
+    /// Router client settings
+    pub client: ClientSettings,
+    /// Uplink dispatch settings
+    pub dispatch: DispatchSettings,
 }
 
 /// Settings for log method and level to be used by the running service.
@@ -87,6 +95,526 @@ pub struct CacheSettings {
     pub store: PathBuf,
     // Maximum number of packets to queue up per router client
     pub max_packets: u16,
+    // Age in seconds after which waiting packets, which have not been offered
+    // yet, are removed when the store is compacted
+    pub max_packet_age: u64,
+    // Seconds after being offered after which queued packets that have not
+    // been purchased or rejected are dropped, and removed when the store is
+    // compacted
+    pub max_queued_age: u64,
+    // Interval in seconds between store compactions
+    pub compact_interval: u64,
+    // Maximum number of state channels to keep per router client, the state
+    // channels expiring first are evicted to make room, except for the one
+    // offers are made against. 0 does not limit the number of state channels
+    pub max_state_channels: usize,
+    // Maximum number of bytes the persisted state channels and quarantined
+    // data of a router client may take up on disk. Quarantined data and then
+    // lower priority state channels, never the one offers are made against,
+    // are evicted to stay within it. 0 does not limit disk usage
+    pub max_disk_usage: u64,
+    // The encoding of the waiting packets saved on shutdown, which are offered
+    // again after a restart unless they expired by then
+    pub packet_encoding: PacketEncoding,
+}
+
+/// Settings for the router clients
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientSettings {
+    /// The number of DC a purchase may fall short of the expected packet cost
+    /// before it is considered underpaid. This absorbs rounding differences
+    /// in router summary calculations (default: 0)
+    pub purchase_dc_tolerance: u64,
+    /// The number of most recently forwarded downlinks to keep for export,
+    /// for example to replay against a concentrator simulator. Zero disables
+    /// the capture (default: 0)
+    pub downlink_capture: usize,
+    /// The number of most recent raw messages going in and out of a router
+    /// client to keep for export, for example to replay against a fresh
+    /// client. Zero disables the tap (default: 0)
+    pub message_tap: usize,
+    /// Seconds to wait for the first banner after connecting to a router
+    /// before giving up on the connection attempt. Waiting packets are kept
+    /// when the timeout expires. Zero waits forever (default: 0)
+    pub banner_timeout: u64,
+    /// Whether to reconnect to the router when the banner timeout expires
+    /// (default: true)
+    pub banner_retry: bool,
+    /// Milliseconds to wait for a burst of banners to settle before offering
+    /// the waiting packets, so that a router sending several banners in a
+    /// row gets a single pass of offers. Each banner restarts the wait. Zero
+    /// offers after every banner (default: 0)
+    pub banner_debounce: u64,
+    /// Milliseconds a burst of banners may hold back offers in total, counted
+    /// from the first banner of the burst, so that a router that keeps
+    /// sending banners still gets offers. Zero does not bound the wait
+    /// (default: 2000)
+    pub banner_debounce_max: u64,
+    /// Milliseconds to hold banners so that a banner and purchase for the
+    /// same state channel are applied in nonce order whatever order they
+    /// arrive in. A purchase applies the held banners it follows first, the
+    /// rest are applied once the window passes. Zero applies every message
+    /// as it arrives (default: 0)
+    pub sc_reorder_window: u64,
+    /// The maximum milliseconds of random delay before the first connect to
+    /// the router after the client starts, which spreads out the connects of
+    /// many gateways restarting at once. Uplinks in the meantime are held to
+    /// be offered once connected. Zero connects right away (default: 0)
+    pub connect_jitter: u64,
+    /// What to do when the router closes the state channel stream (terminate
+    /// or reconnect, default: reconnect)
+    pub stream_close: StreamClosePolicy,
+    /// Milliseconds to wait before reconnecting a closed or lost state
+    /// channel stream. The wait doubles with every reconnect that is closed
+    /// or lost again before any message is received, up to a minute
+    /// (default: 1000)
+    pub reconnect_backoff: u64,
+    /// Milliseconds to wait for room in a full downlink channel before a
+    /// downlink is dropped (default: 1000)
+    pub downlink_timeout: u64,
+    /// How to hand a downlink to a full downlink channel (wait for room up to
+    /// the downlink timeout, or drop it right away, default: wait)
+    pub downlink_send: DownlinkSendMode,
+    /// The number of devices downlinks are delivered to concurrently, while
+    /// the downlinks of each device stay in order. Zero delivers downlinks
+    /// one at a time as they arrive (default: 0)
+    pub downlink_concurrency: usize,
+    /// Milliseconds within which a downlink with the same payload as an
+    /// already forwarded one is dropped as a duplicate, for example when a
+    /// router sends a downlink again or more than one router sends it.
+    /// Downlinks that failed to be delivered are not duplicates. Zero
+    /// forwards every downlink (default: 0)
+    pub downlink_dedup: u64,
+    /// The maximum number of devices to keep offer, purchase and reject
+    /// counts for. Zero disables per device counts (default: 0)
+    pub devaddr_metrics: usize,
+    /// The maximum estimated DC that may be owed for offered packets that
+    /// were not purchased yet. Further offers are paused until purchases
+    /// bring the exposure back under the cap. The exposure never exceeds the
+    /// balance left on the selected state channel, with or without the cap.
+    /// Zero disables the cap (default: 0)
+    pub max_inflight_dc: u64,
+    /// The number of times a packet may be offered before it is dropped for
+    /// good instead of offered again, which breaks loops of a packet that is
+    /// re-offered and never purchased. Offers made by a router that rejected
+    /// the packet to the default router count too. Zero does not limit
+    /// offers (default: 0)
+    pub max_offer_attempts: u32,
+    /// The number of non-critical store writes, the reject log and the
+    /// metrics kept next to the store, queued for a background task so that
+    /// message handling does not wait on them. State channel writes always
+    /// complete before the client moves on. A full queue holds up the next
+    /// write until there is room, and zero writes everything inline
+    /// (default: 0)
+    pub background_writes: usize,
+    /// How to handle a purchase that arrives before any banner was received
+    /// (seed or reject, default: seed)
+    pub early_purchase: EarlyPurchasePolicy,
+    /// How to handle a purchase for an offered packet that already expired
+    /// while waiting for its purchase, as identified by the packet hash in
+    /// the purchase (ignore or dequeue, default: ignore)
+    pub expired_purchase: ExpiredPurchasePolicy,
+    /// The state channel protocol version of the router, which decides the
+    /// fields populated in offers and packets sent to it (v1, v2 or auto,
+    /// default: v2)
+    pub router_protocol: RouterProtocol,
+    /// The number of received state channel messages to buffer while
+    /// earlier ones are being handled. When the buffer is full the oldest
+    /// banner is dropped; purchases are never dropped (default: 32)
+    pub message_buffer: usize,
+    /// How to handle an uplink whose region can not be determined from its
+    /// frequency, one outside the band of the gateway region that is shared
+    /// by several regions or in no region's band (default, drop or reject,
+    /// default: default)
+    pub region_fallback: RegionFallback,
+    /// What to do with offered, not yet purchased, packets when the region of
+    /// the client changes (keep or reoffer, default: keep)
+    pub region_change: RegionChangePolicy,
+    /// Which of the known, unexpired, state channels of a router offers are
+    /// made against (balance, expiry or latest, default: latest)
+    pub sc_selection: ScSelection,
+    /// The maximum number of concurrent state channel lookups against the
+    /// gateway across all router clients. Further lookups wait for earlier
+    /// ones to finish. Zero does not limit lookups (default: 0)
+    pub gateway_lookups: usize,
+    /// The maximum number of router clients validating a state channel at
+    /// the same time, which keeps a burst of banners and purchases across
+    /// many clients from starving the runtime. Further validations wait for
+    /// earlier ones to finish. Zero does not limit validations (default: 0)
+    pub concurrent_validations: usize,
+    /// The maximum number of offers per second sent across all router
+    /// clients, with bursts of up to one second worth of offers. Further
+    /// packets stay waiting while the client goes on handling other messages,
+    /// and are offered once the limit allows. Zero does not limit offers
+    /// (default: 0)
+    pub max_offer_rate: u32,
+    /// Whether to start in economy mode, in which no router client sends
+    /// offers so that no router DC is spent on behalf of this gateway, for
+    /// example while the account balance is low. Packets are still stored
+    /// and expire as usual (default: false)
+    pub economy_mode: bool,
+    /// Milliseconds to stagger the reconnects of router clients by, busiest
+    /// first. Once its backoff passed, a reconnect waits this long for every
+    /// other reconnecting router client that purchased more packets
+    /// recently, so that after an outage the routers with the most traffic
+    /// are back first. A router that reconnects on its own does not wait.
+    /// Zero does not stagger reconnects (default: 0)
+    pub reconnect_priority: u64,
+    /// The number of consecutive failed state channel lookups after which
+    /// the gateway service is considered unavailable. State channel messages
+    /// are then held, up to `message_buffer` of them, until the gateway
+    /// recovers. Zero disables outage handling (default: 0)
+    pub gateway_failures: u32,
+    /// Seconds between attempts to handle held state channel messages while
+    /// the gateway service is unavailable (default: 5)
+    pub gateway_retry: u64,
+    /// Whether to re-validate the known state channels against a new gateway
+    /// service when the gateway is swapped, removing those that are expired
+    /// or no longer active by the view of the new gateway (default: false)
+    pub revalidate_on_gateway_swap: bool,
+    /// The number of packets the count in a router summary may differ from
+    /// the packets sent before the drift is reported (default: 0)
+    pub packet_drift: u64,
+    /// The minimum SNR in dB of uplinks to deliver, weaker uplinks are
+    /// dropped. Not set by default, which delivers uplinks of any SNR
+    pub min_snr: Option<f32>,
+    /// Seconds between keepalive pings on the router connection, which
+    /// detect connections that died silently, for example due to a NAT
+    /// timeout. A lost connection is reconnected after the reconnect
+    /// backoff. Zero disables keepalives, in which case a lost connection
+    /// stops the client (default: 0)
+    pub keepalive: u64,
+    /// Whether to connect to the router when the client is created, failing
+    /// the creation with an unreachable router error if it can not be
+    /// reached. Otherwise the router is first connected to when a message
+    /// is sent. The dispatcher keeps retrying to create the client of an
+    /// unreachable router, with the reconnect backoff, and sends its uplinks
+    /// to the default router until then (default: false)
+    pub probe_router: bool,
+    /// Seconds a device is remembered after its join accept was forwarded.
+    /// When set, data uplinks are only offered for devices that joined
+    /// through this gateway within that time. Since join accepts are
+    /// encrypted, each forwarded join accept admits the first data uplink of
+    /// a DevAddr not seen before as the joined device. Zero offers uplinks
+    /// for any device (default: 0)
+    pub recent_join: u64,
+    /// The minimum milliseconds between offers for the same DevAddr. Further
+    /// uplinks of the device within that time are dropped. Zero does not
+    /// limit offers per device (default: 0)
+    pub devaddr_offer_interval: u64,
+    /// Offer only the first of every given number of uplinks to the router
+    /// and drop the others, which sends a fraction of the traffic to a
+    /// router for load testing. Zero and one offer every uplink (default: 0)
+    pub uplink_sampling: u32,
+    /// Whether to log the full contents of a state channel that fails
+    /// validation at debug level. State channels are public on chain, so
+    /// nothing is redacted (default: false)
+    pub log_rejected_sc: bool,
+    /// What to do with a packet whose offer a router rejected, by the reason
+    /// code of the rejection. Packets rejected with a code that is not listed
+    /// are dropped (default: [])
+    pub reject_actions: Vec<RejectActionSetting>,
+    /// Manual rules restricting the DevAddr ranges each router is offered
+    /// uplinks for, independent of the routing the router advertises.
+    /// Uplinks in a denied range are dropped, and when a router has allowed
+    /// ranges only uplinks in one of them are offered (default: [])
+    pub devaddr_rules: Vec<DevAddrRule>,
+    /// The DevAddrs whose packets have every decision made for them traced,
+    /// from receiving the uplink to sending or dropping it. The trace of
+    /// each packet is logged once it is sent or dropped (default: [])
+    pub trace_devaddrs: Vec<u32>,
+    /// A site or deployment identifier added to the log records of router
+    /// clients, for attributing activity to where the gateway runs. Not set
+    /// by default
+    pub site: Option<String>,
+    /// The UDP address of a StatsD server to emit the counters and timers of
+    /// each router client to, for example `127.0.0.1:8125` or `statsd:8125`.
+    /// A host name is resolved once when the client starts. Metrics are named
+    /// by router and never block the client. Not set by default
+    pub statsd: Option<String>,
+    /// Additional validation for newly seen state channels
+    pub validation: ValidationSettings,
+    /// TLS options for router connections. Without any options routers are
+    /// connected to as given by their uri
+    #[serde(default)]
+    pub tls: TlsSettings,
+}
+
+/// TLS options for router connections.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TlsSettings {
+    /// PEM file with the CA certificates to verify routers against. Uses the
+    /// system roots when not set
+    pub ca: Option<PathBuf>,
+    /// PEM file with the client certificate for mutual TLS. Requires `key`
+    pub cert: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate
+    pub key: Option<PathBuf>,
+    /// Server name to verify the router certificate against instead of the
+    /// host of the router uri
+    pub domain: Option<String>,
+}
+
+impl TlsSettings {
+    /// Whether any TLS option is set
+    pub fn is_enabled(&self) -> bool {
+        self.ca.is_some() || self.cert.is_some() || self.key.is_some() || self.domain.is_some()
+    }
+}
+
+/// Additional validation applied to newly seen state channels. The defaults
+/// do not add any checks beyond the standard state channel validation.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ValidationSettings {
+    /// The minimum number of blocks a state channel must have left before it
+    /// expires. Zero disables the check (default: 0)
+    pub expiration_slack: u64,
+    /// Whether to reject state channels whose expiration block has already
+    /// passed at the current block height of the gateway (default: false)
+    pub reject_expired: bool,
+    /// The state channel owners to accept. An empty list accepts any owner
+    /// (default: [])
+    #[serde(deserialize_with = "keypair::deserialize_public_keys")]
+    pub owners: Vec<PublicKey>,
+    /// The minimum remaining DC balance of a state channel (default: 0)
+    pub min_balance: u64,
+    /// Whether to trust a state channel signed by an owner that is not in
+    /// `owners` as an owner key rotation of a router that was previously
+    /// trusted (reject or trust, default: reject)
+    pub owner_rotation: OwnerRotation,
+}
+
+/// The policy for state channels signed by a rotated owner key
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OwnerRotation {
+    /// Reject state channels of owners that are not configured
+    Reject,
+    /// Accept the new owner of a router that was previously trusted once the
+    /// gateway confirms its state channel is active
+    Trust,
+}
+
+impl Default for OwnerRotation {
+    fn default() -> Self {
+        Self::Reject
+    }
+}
+
+/// The state channel protocol version of a router, which decides the
+/// message fields sent to it
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouterProtocol {
+    /// The original protocol. Offers carry neither the region nor the frame
+    /// counter, and packets neither the region nor the hold time
+    V1,
+    /// The current protocol with all message fields
+    V2,
+    /// Speak the current protocol until the router turns out to speak the
+    /// original one, which is detected from a purchase that does not
+    /// identify the purchased packet or from a run of rejects without any
+    /// purchase. A purchase identifying its packet settles on the current
+    /// protocol
+    Auto,
+}
+
+/// The policy for a purchase that arrives before any banner
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EarlyPurchasePolicy {
+    /// Accept the state channel in the purchase as if it was advertised in a
+    /// banner
+    Seed,
+    /// Reject the purchase, leaving the queued packets untouched
+    Reject,
+}
+
+/// The policy for a purchase of an offered packet that already expired
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiredPurchasePolicy {
+    /// Count and ignore the purchase, sending nothing for it
+    Ignore,
+    /// Handle the purchase like any other, delivering the next queued packet
+    /// in place of the expired one
+    Dequeue,
+}
+
+/// The action for a packet whose offer was rejected with a given reason code
+#[derive(Debug, Deserialize, Clone)]
+pub struct RejectActionSetting {
+    /// The reason code of the rejection
+    pub code: u32,
+    pub action: RejectAction,
+}
+
+/// A manually allowed or denied range of DevAddrs for routers
+#[derive(Debug, Deserialize, Clone)]
+pub struct DevAddrRule {
+    /// The public key of the router the rule applies to. Rules without a
+    /// router apply to all routers
+    pub router: Option<String>,
+    /// The first DevAddr of the range
+    pub start: u32,
+    /// The last DevAddr of the range, inclusive
+    pub end: u32,
+    pub action: DevAddrAction,
+}
+
+/// Whether a DevAddr range is allowed or denied
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DevAddrAction {
+    Allow,
+    Deny,
+}
+
+/// What to do with a packet whose offer a router rejected
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RejectAction {
+    /// Drop the packet, counting it as rejected
+    Drop,
+    /// Hand the packet to the default router, for example when the router
+    /// does not know the device
+    Redispatch,
+}
+
+/// The policy for an uplink whose region can not be determined
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionFallback {
+    /// Assume the configured gateway region
+    Default,
+    /// Silently drop the uplink
+    Drop,
+    /// Fail handling the uplink, which logs it as a failed uplink
+    Reject,
+}
+
+/// The policy for offered packets when the client region changes
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionChangePolicy {
+    /// Leave the packets queued and deliver them under the region they were
+    /// offered in when purchased. This is the default since the router
+    /// already accepted the terms of those offers
+    Keep,
+    /// Return the packets to the waiting packets so they are offered again
+    /// under the new region
+    Reoffer,
+}
+
+/// The policy for a state channel stream the router closed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamClosePolicy {
+    /// Stop the router client
+    Terminate,
+    /// Reconnect to the router after a backoff
+    Reconnect,
+}
+
+/// How the store encodes saved packets, see `router::codec`
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PacketEncoding {
+    /// The protobuf encoding of the packets
+    Protobuf,
+    /// A fixed binary layout of the packet fields
+    Compact,
+}
+
+/// How to hand a downlink to a full downlink channel
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DownlinkSendMode {
+    /// Wait for room in the channel, up to the downlink timeout
+    Wait,
+    /// Try to send once and drop the downlink if the channel is full, so
+    /// that a backed up gateway does not hold up the client
+    Drop,
+}
+
+/// How to pick the state channel to offer against when a router has more
+/// than one unexpired state channel
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScSelection {
+    /// The state channel with the most remaining balance
+    Balance,
+    /// The state channel that expires first
+    Expiry,
+    /// The state channel most recently received in a banner
+    Latest,
+}
+
+/// Settings for dispatching uplinks to routers
+#[derive(Debug, Deserialize, Clone)]
+pub struct DispatchSettings {
+    /// How to pick between the routers of an OUI that match an uplink (all or
+    /// weighted, default: all)
+    pub selection: RouterSelection,
+    /// Selection weights of routers. Routers without a configured weight get
+    /// a weight of 1, a weight of 0 excludes a router from weighted selection
+    /// (default: [])
+    pub weights: Vec<RouterWeight>,
+}
+
+/// The selection weight of a router
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouterWeight {
+    /// The router public key
+    pub public_key: String,
+    pub weight: u32,
+}
+
+/// The policy for picking between the routers of an OUI for an uplink
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouterSelection {
+    /// Send the uplink to every matching router
+    All,
+    /// Send the uplink to one of the matching routers, picked at random
+    /// proportional to the router weights
+    Weighted,
+}
+
+impl DispatchSettings {
+    /// Returns the selection weight of the router with the given public key
+    pub fn weight(&self, public_key: &PublicKey) -> u32 {
+        let public_key = public_key.to_string();
+        self.weights
+            .iter()
+            .find(|router| router.public_key == public_key)
+            .map_or(1, |router| router.weight)
+    }
+}
+
+/// The bundled default configuration
+const DEFAULT_CONFIG: &str = include_str!("../config/default.toml");
+
+/// Reads a section of the bundled default configuration
+fn default_section<'de, T: Deserialize<'de>>(key: &str) -> T {
+    let mut c = Config::new();
+    c.merge(File::from_str(DEFAULT_CONFIG, FileFormat::Toml))
+        .expect("default config");
+    c.get(key).expect("default config section")
+}
+
+impl Default for CacheSettings {
+    /// The cache settings of the bundled default configuration
+    fn default() -> Self {
+        default_section("cache")
+    }
+}
+
+impl Default for ClientSettings {
+    /// The client settings of the bundled default configuration
+    fn default() -> Self {
+        default_section("client")
+    }
 }
 
 impl Settings {
@@ -112,6 +640,14 @@ impl Settings {
         c.try_into().map_err(|e| e.into())
     }
 
+    /// Returns the keypairs to sign router messages with
+    pub fn keypairs(&self) -> OuiKeypairs {
+        self.oui_keypairs.iter().fold(
+            OuiKeypairs::new(self.keypair.clone()),
+            |keypairs, (oui, keypair)| keypairs.with_oui(*oui, keypair.clone()),
+        )
+    }
+
     pub fn default_router(&self) -> &KeyedUri {
         &self.router[&self.update.channel.to_string()]
     }
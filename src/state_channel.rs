@@ -2,7 +2,8 @@ use crate::{
     error::{StateChannelError, StateChannelSummaryError},
     router::QuePacket,
     service::gateway::GatewayService,
-    Error, Keypair, MsgSign, MsgVerify, Packet, Region, Result,
+    settings::{RouterProtocol, ValidationSettings},
+    Error, Keypair, MsgSign, MsgVerify, Packet, Region, RegionProfile, Result,
 };
 use bytes::{Buf, BufMut, BytesMut};
 use helium_crypto::PublicKey;
@@ -14,44 +15,77 @@ use helium_proto::{
     Message,
 };
 use sha2::{Digest, Sha256};
-use std::{cmp::max, convert::TryFrom, mem};
+use std::{convert::TryFrom, mem};
 
 #[derive(Debug)]
 pub struct StateChannelMessage(pub(crate) Msg);
 
 impl StateChannelMessage {
+    /// Constructs a signed packet for the given uplink. Routers on the
+    /// original protocol are sent neither the region nor the hold time.
     pub fn packet(
         packet: Packet,
         keypair: &Keypair,
         region: Region,
         hold_time: u64,
+        protocol: RouterProtocol,
     ) -> Result<Self> {
+        let legacy = protocol == RouterProtocol::V1;
         let mut packet = BlockchainStateChannelPacketV1 {
             packet: Some(packet.to_packet()),
             signature: vec![],
             hotspot: keypair.public_key().into(),
-            region: region.into(),
-            hold_time,
+            region: if legacy { 0 } else { region.into() },
+            hold_time: if legacy { 0 } else { hold_time },
         };
         packet.signature = packet.sign(keypair)?;
         Ok(StateChannelMessage::from(packet))
     }
 
-    pub fn offer(packet: Packet, keypair: &Keypair, region: Region) -> Result<Self> {
+    /// Constructs a signed offer for the given packet. The region profile
+    /// decides which of the optional offer fields are populated, and routers
+    /// on the original protocol are sent neither the region nor the frame
+    /// counter.
+    pub fn offer(
+        packet: Packet,
+        keypair: &Keypair,
+        profile: &RegionProfile,
+        protocol: RouterProtocol,
+    ) -> Result<Self> {
+        let legacy = protocol == RouterProtocol::V1;
         let frame = Packet::parse_frame(lorawan::Direction::Uplink, packet.payload())?;
         let mut offer = BlockchainStateChannelOfferV1 {
             packet_hash: packet.hash(),
             payload_size: packet.payload().len() as u64,
-            fcnt: frame.fcnt().unwrap_or(0) as u32,
+            fcnt: if profile.fcnt && !legacy {
+                frame.fcnt().unwrap_or(0) as u32
+            } else {
+                0
+            },
             hotspot: keypair.public_key().into(),
-            region: region.into(),
-            routing: Packet::routing_information(&frame)?,
+            region: if legacy { 0 } else { (&profile.region).into() },
+            routing: if profile.routing {
+                Packet::routing_information(&frame)?
+            } else {
+                None
+            },
             signature: vec![],
         };
         offer.signature = offer.sign(keypair)?;
         Ok(Self::from(offer))
     }
 
+    /// Decodes a state channel message as received from a router. Arbitrary
+    /// input results in an error rather than a panic.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        match BlockchainStateChannelMessageV1::decode(buf)?.msg {
+            Some(msg) => Ok(Self(msg)),
+            None => Err(Error::Decode(
+                prost::DecodeError::new("missing state channel message").into(),
+            )),
+        }
+    }
+
     pub fn msg(&self) -> &Msg {
         &self.0
     }
@@ -118,7 +152,7 @@ pub enum StateChannelCausality {
     Conflict,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct StateChannel {
     sc: BlockchainStateChannelV1,
     expiry_at_block: u64,
@@ -221,13 +255,58 @@ impl StateChannel {
         Ok(())
     }
 
-    pub fn is_valid_purchase(&self, purchase_sc: &Self, packet: Option<&QuePacket>) -> Result {
+    /// Applies the additional validation settings to this state channel. The
+    /// given height is the current block height, with 0 meaning the height is
+    /// unknown, which skips the expiration check.
+    pub fn is_valid_with(&self, settings: &ValidationSettings, height: u64) -> Result {
+        if settings.reject_expired && height > 0 && self.expiry_at_block <= height {
+            return Err(StateChannelError::expired());
+        }
+        if settings.expiration_slack > 0
+            && height > 0
+            && self.expiry_at_block < height + settings.expiration_slack
+        {
+            return Err(StateChannelError::expiring());
+        }
+        if !settings.owners.is_empty()
+            && !settings
+                .owners
+                .iter()
+                .any(|owner| owner.to_vec() == self.sc.owner)
+        {
+            return Err(StateChannelError::invalid_owner());
+        }
+        if self.remaining_balance() < settings.min_balance {
+            return Err(StateChannelError::low_balance());
+        }
+        Ok(())
+    }
+
+    /// Validates a purchase against this, the last known, state channel.
+    ///
+    /// The given `dc_tolerance` is the number of DC the purchase may fall
+    /// short of the packet cost before it is considered underpaid. A purchase
+    /// that drops the summary of the given gateway from a state channel it
+    /// contributed to is invalid, since that would erase what the gateway was
+    /// accounted for.
+    pub fn is_valid_purchase(
+        &self,
+        public_key: &PublicKey,
+        purchase_sc: &Self,
+        packet: Option<&QuePacket>,
+        dc_tolerance: u64,
+    ) -> Result {
         let budget_dc = purchase_sc.amount();
         let total_dc = purchase_sc.total_dcs();
-        let remaining_dc = max(0, budget_dc - total_dc);
+        let remaining_dc = budget_dc.saturating_sub(total_dc);
         if self.is_overpaid(purchase_sc) {
             return Err(StateChannelError::overpaid());
         }
+        if self.get_summary(public_key).is_some() && purchase_sc.get_summary(public_key).is_none() {
+            return Err(StateChannelError::invalid_summary(
+                StateChannelSummaryError::Dropped,
+            ));
+        }
         if packet.is_none() {
             // The packet was not given, accept the purchase as is
             return Ok(());
@@ -238,7 +317,7 @@ impl StateChannel {
             // packet
             return Err(StateChannelError::low_balance());
         }
-        if (total_dc - self.total_dcs()) < packet_dc {
+        if total_dc.saturating_sub(self.total_dcs()) + dc_tolerance < packet_dc {
             // We did not get paid enough for this packet since the total_dcs in
             // the purchase did not increase at least by packet_dc
             return Err(StateChannelError::underpaid());
@@ -246,6 +325,19 @@ impl StateChannel {
         Ok(())
     }
 
+    /// Applies the banner nonce rule to a banner for this, the last known,
+    /// state channel. Returns true if the banner advances the state channel
+    /// and needs to be processed, and false if it is identical to this state
+    /// channel, in which case there is nothing to update. A banner with a
+    /// lower nonce is rejected. A banner with the same nonce but different
+    /// content is processed so that the conflict is detected.
+    pub fn is_banner_update(&self, sc: &BlockchainStateChannelV1) -> Result<bool> {
+        if sc.nonce < self.sc.nonce {
+            return Err(StateChannelError::stale_nonce());
+        }
+        Ok(sc.nonce > self.sc.nonce || sc != &self.sc)
+    }
+
     pub fn is_overpaid(&self, newer: &StateChannel) -> bool {
         self.original_dc_amount < newer.total_dcs()
     }
@@ -299,7 +391,13 @@ impl StateChannel {
         self.sc
             .summaries
             .iter()
-            .fold(0, |acc, summary| acc + summary.num_dcs)
+            .fold(0, |acc: u64, summary| acc.saturating_add(summary.num_dcs))
+    }
+
+    /// Returns the DC left on this state channel, the credits minus the DC
+    /// of all summaries
+    pub fn remaining_balance(&self) -> u64 {
+        self.amount().saturating_sub(self.total_dcs())
     }
 
     pub fn get_summary(&self, public_key: &PublicKey) -> Option<&BlockchainStateChannelSummaryV1> {
@@ -327,14 +425,36 @@ impl StateChannel {
         Ok(())
     }
 
+    /// Sets the block height at which this state channel expires, so tests
+    /// can expire a state channel on demand.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn with_expiry_at_block(mut self, expiry_at_block: u64) -> Self {
+        self.expiry_at_block = expiry_at_block;
+        self
+    }
+
+    /// Returns the block height at which this state channel expires
+    pub fn expiry_at_block(&self) -> u64 {
+        self.expiry_at_block
+    }
+
     pub fn id(&self) -> &[u8] {
         &self.sc.id
     }
 
+    /// Returns the public key bytes of the owner of this state channel
+    pub fn owner(&self) -> &[u8] {
+        &self.sc.owner
+    }
+
     pub fn amount(&self) -> u64 {
         self.sc.credits
     }
 
+    pub fn nonce(&self) -> u64 {
+        self.sc.nonce
+    }
+
     pub fn hash_key(&self) -> String {
         base64::encode_config(self.hash(), base64::URL_SAFE_NO_PAD)
     }
@@ -395,3 +515,283 @@ impl GetStateChannel for BlockchainStateChannelV1 {
         Some(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_support::{mk_keypair, mk_public_key},
+        Packet,
+    };
+
+    fn mk_state_channel(num_dcs: u64) -> StateChannel {
+        StateChannel {
+            sc: BlockchainStateChannelV1 {
+                credits: 100,
+                summaries: vec![BlockchainStateChannelSummaryV1 {
+                    client_pubkeybin: vec![1],
+                    num_packets: num_dcs,
+                    num_dcs,
+                }],
+                ..Default::default()
+            },
+            expiry_at_block: 0,
+            original_dc_amount: 100,
+        }
+    }
+
+    impl StateChannel {
+        fn with_nonce(mut self, nonce: u64) -> Self {
+            self.sc.nonce = nonce;
+            self
+        }
+    }
+
+    fn mk_packet(dc: usize) -> QuePacket {
+        QuePacket::from(Packet::from(helium_proto::Packet {
+            payload: vec![0; dc * 24],
+            ..Default::default()
+        }))
+    }
+
+    fn mk_uplink() -> Packet {
+        // Unconfirmed data up with DevAddr 0x01020304 and FCnt 7
+        Packet::from(helium_proto::Packet {
+            payload: vec![0x40, 4, 3, 2, 1, 0, 7, 0, 0xde, 0xad, 0xbe, 0xef],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn region_offer_profile() {
+        let keypair = mk_keypair();
+        for region_value in [0, 1].iter() {
+            let profile = Region::from_i32(*region_value).unwrap().profile();
+            let offer = BlockchainStateChannelOfferV1::from(
+                StateChannelMessage::offer(mk_uplink(), &keypair, &profile, RouterProtocol::V2)
+                    .unwrap(),
+            );
+            assert_eq!(*region_value, offer.region);
+            assert_eq!(7, offer.fcnt);
+            assert!(offer.routing.is_some());
+            assert!(!offer.signature.is_empty());
+        }
+
+        let profile = RegionProfile {
+            fcnt: false,
+            routing: false,
+            ..Region::from_i32(1).unwrap().profile()
+        };
+        let offer = BlockchainStateChannelOfferV1::from(
+            StateChannelMessage::offer(mk_uplink(), &keypair, &profile, RouterProtocol::V2)
+                .unwrap(),
+        );
+        assert_eq!(1, offer.region);
+        assert_eq!(0, offer.fcnt);
+        assert!(offer.routing.is_none());
+    }
+
+    #[test]
+    fn protocol_fields() {
+        let keypair = mk_keypair();
+        let region = Region::from_i32(1).unwrap();
+        for protocol in [RouterProtocol::V1, RouterProtocol::V2].iter() {
+            let legacy = *protocol == RouterProtocol::V1;
+            let offer = BlockchainStateChannelOfferV1::from(
+                StateChannelMessage::offer(mk_uplink(), &keypair, &region.profile(), *protocol)
+                    .unwrap(),
+            );
+            assert_eq!(if legacy { 0 } else { 1 }, offer.region);
+            assert_eq!(if legacy { 0 } else { 7 }, offer.fcnt);
+            // Fields of the original protocol are always populated
+            assert!(offer.routing.is_some());
+            assert_eq!(12, offer.payload_size);
+            assert!(offer.verify(keypair.public_key()).is_ok());
+
+            let packet = BlockchainStateChannelPacketV1::from(
+                StateChannelMessage::packet(mk_uplink(), &keypair, region.clone(), 500, *protocol)
+                    .unwrap(),
+            );
+            assert_eq!(if legacy { 0 } else { 1 }, packet.region);
+            assert_eq!(if legacy { 0 } else { 500 }, packet.hold_time);
+            assert!(packet.packet.is_some());
+            assert!(packet.verify(keypair.public_key()).is_ok());
+        }
+    }
+
+    #[test]
+    fn banner_nonce() {
+        let known_sc = mk_state_channel(10).with_nonce(5);
+        let mut banner_sc = known_sc.sc.clone();
+        assert!(!known_sc.is_banner_update(&banner_sc).unwrap());
+        banner_sc.nonce = 6;
+        assert!(known_sc.is_banner_update(&banner_sc).unwrap());
+        banner_sc.nonce = 4;
+        assert!(matches!(
+            known_sc.is_banner_update(&banner_sc),
+            Err(Error::StateChannel(StateChannelError::StaleNonce))
+        ));
+    }
+
+    #[test]
+    fn validation_settings() {
+        let sc = mk_state_channel(10);
+        let default_settings = ValidationSettings::default();
+        assert!(sc.is_valid_with(&default_settings, 100).is_ok());
+        let strict_settings = ValidationSettings {
+            expiration_slack: 10,
+            min_balance: 95,
+            ..Default::default()
+        };
+        assert!(matches!(
+            sc.is_valid_with(&strict_settings, 0),
+            Err(Error::StateChannel(StateChannelError::LowBalance))
+        ));
+        // The state channel in the test expires at block 0
+        assert!(matches!(
+            sc.is_valid_with(&strict_settings, 100),
+            Err(Error::StateChannel(StateChannelError::Expiring))
+        ));
+    }
+
+    #[test]
+    fn reject_expired() {
+        let expired = StateChannel {
+            expiry_at_block: 100,
+            ..mk_state_channel(10)
+        };
+        let live = StateChannel {
+            expiry_at_block: 200,
+            ..mk_state_channel(10)
+        };
+        let settings = ValidationSettings {
+            reject_expired: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            expired.is_valid_with(&settings, 150),
+            Err(Error::StateChannel(StateChannelError::Expired))
+        ));
+        assert!(matches!(
+            expired.is_valid_with(&settings, 100),
+            Err(Error::StateChannel(StateChannelError::Expired))
+        ));
+        assert!(live.is_valid_with(&settings, 150).is_ok());
+        // An unknown height or the default settings do not reject
+        assert!(expired.is_valid_with(&settings, 0).is_ok());
+        assert!(expired
+            .is_valid_with(&ValidationSettings::default(), 150)
+            .is_ok());
+    }
+
+    #[test]
+    fn purchase_exceeding_credits() {
+        // Summaries that sum past the credits of the purchase must not
+        // underflow the remaining balance
+        let known = mk_state_channel(10);
+        let mut purchase = mk_state_channel(20);
+        purchase.sc.credits = 5;
+        let public_key = mk_public_key();
+        assert!(matches!(
+            known.is_valid_purchase(&public_key, &purchase, Some(&mk_packet(4)), 0),
+            Err(Error::StateChannel(StateChannelError::LowBalance))
+        ));
+    }
+
+    #[test]
+    fn remaining_balance() {
+        assert_eq!(90, mk_state_channel(10).remaining_balance());
+        let mut sc = mk_state_channel(30);
+        sc.sc.summaries.push(BlockchainStateChannelSummaryV1 {
+            client_pubkeybin: vec![2],
+            num_packets: 5,
+            num_dcs: 25,
+        });
+        assert_eq!(45, sc.remaining_balance());
+        // Summaries past the credits leave nothing, rather than underflowing
+        assert_eq!(0, mk_state_channel(120).remaining_balance());
+        sc.sc.credits = 0;
+        assert_eq!(0, sc.remaining_balance());
+    }
+
+    #[test]
+    fn overflowing_summaries() {
+        let mut sc = mk_state_channel(u64::MAX);
+        sc.sc.summaries.push(sc.sc.summaries[0].clone());
+        assert_eq!(u64::MAX, sc.total_dcs());
+        assert!(mk_state_channel(10).is_overpaid(&sc));
+    }
+
+    #[test]
+    fn decode_message() {
+        assert!(StateChannelMessage::decode(&[0xff, 0xff, 0xff]).is_err());
+        // An empty message decodes but carries no state channel message
+        assert!(StateChannelMessage::decode(&[]).is_err());
+        let banner = StateChannelMessage::from(BlockchainStateChannelBannerV1 {
+            sc: Some(mk_state_channel(10).sc),
+        })
+        .to_message();
+        let mut buf = vec![];
+        banner.encode(&mut buf).unwrap();
+        let message = StateChannelMessage::decode(&buf).unwrap();
+        assert!(message.state_channel().is_some());
+    }
+
+    #[test]
+    fn purchase_within_tolerance() {
+        let known = mk_state_channel(10);
+        let purchase = mk_state_channel(13);
+        let packet = mk_packet(4);
+        let public_key = mk_public_key();
+        assert!(known
+            .is_valid_purchase(&public_key, &purchase, Some(&packet), 0)
+            .is_err());
+        assert!(known
+            .is_valid_purchase(&public_key, &purchase, Some(&packet), 1)
+            .is_ok());
+    }
+
+    #[test]
+    fn purchase_beyond_tolerance() {
+        let known = mk_state_channel(10);
+        let purchase = mk_state_channel(12);
+        let packet = mk_packet(4);
+        let public_key = mk_public_key();
+        assert!(matches!(
+            known.is_valid_purchase(&public_key, &purchase, Some(&packet), 1),
+            Err(Error::StateChannel(StateChannelError::Underpaid))
+        ));
+    }
+
+    #[test]
+    fn purchase_dropping_summary() {
+        let public_key = mk_public_key();
+        let our_summary = |num_dcs| BlockchainStateChannelSummaryV1 {
+            client_pubkeybin: public_key.to_vec(),
+            num_packets: num_dcs,
+            num_dcs,
+        };
+        let mut known = mk_state_channel(10);
+        known.sc.summaries.push(our_summary(4));
+        let mut purchase = mk_state_channel(10);
+        purchase.sc.summaries.push(our_summary(5));
+        assert!(known
+            .is_valid_purchase(&public_key, &purchase, None, 0)
+            .is_ok());
+
+        // The next purchase omits our summary entirely
+        let dropped = mk_state_channel(20);
+        for packet in [None, Some(mk_packet(1))].iter() {
+            assert!(matches!(
+                purchase.is_valid_purchase(&public_key, &dropped, packet.as_ref(), 0),
+                Err(Error::StateChannel(StateChannelError::Summary(
+                    StateChannelSummaryError::Dropped
+                )))
+            ));
+        }
+        // A channel we never contributed to may go without our summary
+        assert!(mk_state_channel(10)
+            .is_valid_purchase(&public_key, &dropped, None, 0)
+            .is_ok());
+    }
+}
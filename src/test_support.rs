@@ -0,0 +1,45 @@
+//! Fixtures shared by the tests and benchmarks: freshly generated keys, and
+//! state channels made up as if the gateway had reported them.
+use crate::{KeyedUri, Keypair, PublicKey, StateChannel};
+use bytes::BufMut;
+use helium_crypto::{KeyTag, KeyType, Network};
+use helium_proto::{BlockchainStateChannelV1, Message};
+use std::{convert::TryFrom, sync::Arc};
+
+/// Generates a new keypair
+pub fn mk_keypair() -> Keypair {
+    Keypair::generate(
+        KeyTag {
+            network: Network::MainNet,
+            key_type: KeyType::Ed25519,
+        },
+        &mut rand::rngs::OsRng,
+    )
+}
+
+/// Returns the public key of a newly generated keypair
+pub fn mk_public_key() -> PublicKey {
+    mk_keypair().public_key().clone()
+}
+
+/// Returns the given uri keyed by a newly generated public key
+pub fn mk_keyed_uri(uri: &str) -> KeyedUri {
+    KeyedUri {
+        uri: uri.parse().expect("uri"),
+        public_key: Arc::new(mk_public_key()),
+    }
+}
+
+/// Returns the given state channel as the gateway would have reported it,
+/// expiring at the given block and with the given original DC amount.
+pub fn mk_state_channel(
+    sc: &BlockchainStateChannelV1,
+    expiry_at_block: u64,
+    original_dc_amount: u64,
+) -> StateChannel {
+    let mut buf = vec![];
+    buf.put_u64(expiry_at_block);
+    buf.put_u64(original_dc_amount);
+    sc.encode(&mut buf).expect("encoded state channel");
+    StateChannel::try_from(&buf[..]).expect("state channel")
+}